@@ -0,0 +1,19 @@
+#![no_main]
+
+//! Neither `parser::parse` nor `ArmCodeGenerator` may panic: whatever parses
+//! either compiles or is rejected with a `CompileError`.
+//!
+//! Run with `cargo fuzz run compile`. Inputs that crash should be added as
+//! regression tests to `src/arm_code_generator.rs`.
+
+use arm_compile::arm_code_generator::generate;
+use arm_compile::parser::parse;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        if let Ok(ast) = parse(source) {
+            let _ = generate(&ast);
+        }
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+
+//! `formatter::format_source` must never panic, and formatting its own output
+//! must not change it.
+//!
+//! Run with `cargo fuzz run fmt`. Inputs that crash should be added as
+//! regression tests to `src/formatter.rs`.
+
+use arm_compile::formatter::format_source;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        if let Ok(formatted) = format_source(source) {
+            let again = format_source(&formatted).expect("Formatted source doesn't parse");
+            assert_eq!(formatted, again);
+        }
+    }
+});
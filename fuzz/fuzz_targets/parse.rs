@@ -0,0 +1,16 @@
+#![no_main]
+
+//! `parser::parse` must never panic: any input either parses or is rejected
+//! with a `CompileError`.
+//!
+//! Run with `cargo fuzz run parse`. Inputs that crash should be added as
+//! regression tests to `src/parser.rs`.
+
+use arm_compile::parser::parse;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let _ = parse(source);
+    }
+});
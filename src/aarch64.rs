@@ -0,0 +1,461 @@
+//! The AArch64 assembly `aarch64_code_generator` produces, as data.
+//!
+//! Like `arm`, code is a list of `Line`s that print as GNU assembler syntax.
+//! Conditions and directives are written the same way on both architectures,
+//! so they are `arm`'s.
+
+pub use crate::arm::{Condition, Directive};
+use std::fmt;
+
+/// `x0` to `x30`, or `sp` as 31.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Register(pub u8);
+
+pub const X0: Register = Register(0);
+pub const X1: Register = Register(1);
+pub const X2: Register = Register(2);
+/// A scratch register for addresses, not used for arguments.
+pub const X9: Register = Register(9);
+/// The first register a called function has to preserve.
+pub const X19: Register = Register(19);
+pub const FP: Register = Register(29);
+pub const LR: Register = Register(30);
+pub const SP: Register = Register(31);
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            31 => write!(f, "sp"),
+            n => write!(f, "x{}", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    /// At most 12 bits in arithmetic and comparisons. `mov` takes 16 bits,
+    /// or the negation of 16 bits minus one.
+    Immediate(i64),
+    Register(Register),
+}
+
+impl From<Register> for Operand {
+    fn from(register: Register) -> Operand {
+        Operand::Register(register)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Immediate(value) => write!(f, "#{}", value),
+            Operand::Register(register) => write!(f, "{}", register),
+        }
+    }
+}
+
+/// The memory `ldr`, `str`, `ldp` and `stp` access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// `[base, #offset]`. Negative offsets go down to -256, positive ones
+    /// must be multiples of the access size.
+    Offset(Register, i32),
+    /// `[base, #offset]!`: adds the offset to `base` first.
+    PreIndexed(Register, i32),
+    /// `[base], #offset`: adds the offset to `base` after the access.
+    PostIndexed(Register, i32),
+    /// `[base, index, lsl #shift]`.
+    Indexed(Register, Register, u8),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Offset(base, 0) => write!(f, "[{}]", base),
+            Address::Offset(base, offset) => write!(f, "[{}, #{}]", base, offset),
+            Address::PreIndexed(base, offset) => write!(f, "[{}, #{}]!", base, offset),
+            Address::PostIndexed(base, offset) => write!(f, "[{}], #{}", base, offset),
+            Address::Indexed(base, index, 0) => write!(f, "[{}, {}]", base, index),
+            Address::Indexed(base, index, shift) => {
+                write!(f, "[{}, {}, lsl #{}]", base, index, shift)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+}
+
+impl ArithmeticOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            ArithmeticOp::Add => "add",
+            ArithmeticOp::Sub => "sub",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Move {
+        dest: Register,
+        src: Operand,
+    },
+    /// `ldr xD, =value`, which the assembler turns into a load from a
+    /// literal pool.
+    LoadConstant {
+        dest: Register,
+        value: u64,
+    },
+    Arithmetic {
+        op: ArithmeticOp,
+        dest: Register,
+        left: Register,
+        right: Operand,
+    },
+    Multiply {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    /// `sdiv`, where dividing by zero gives 0.
+    Divide {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    Compare {
+        left: Register,
+        right: Operand,
+    },
+    /// `cset`: 1 if `condition` holds and 0 otherwise.
+    SetIf {
+        dest: Register,
+        condition: Condition,
+    },
+    /// `csel`: `left` if `condition` holds and `right` otherwise.
+    Select {
+        dest: Register,
+        left: Register,
+        right: Register,
+        condition: Condition,
+    },
+    Load {
+        dest: Register,
+        address: Address,
+    },
+    Store {
+        src: Register,
+        address: Address,
+    },
+    /// `ldp`: loads two consecutive words.
+    LoadPair {
+        first: Register,
+        second: Register,
+        address: Address,
+    },
+    /// `stp`: stores two consecutive words.
+    StorePair {
+        first: Register,
+        second: Register,
+        address: Address,
+    },
+    /// `adr`: the address of a label.
+    LoadAddress {
+        dest: Register,
+        label: String,
+    },
+    Branch {
+        condition: Condition,
+        target: String,
+    },
+    /// `bl`: calls a function, with the return address in `x30`.
+    BranchLink(String),
+    /// `ret`: returns to the address in `x30`.
+    Return,
+}
+
+impl Instruction {
+    pub fn mov(dest: Register, src: impl Into<Operand>) -> Instruction {
+        Instruction::Move {
+            dest,
+            src: src.into(),
+        }
+    }
+
+    /// Puts `value` in `dest` with a `mov` if it fits, and from the literal
+    /// pool otherwise.
+    pub fn constant(dest: Register, value: u64) -> Instruction {
+        let signed = value as i64;
+        if (-0x10000..=0xffff).contains(&signed) {
+            Instruction::mov(dest, Operand::Immediate(signed))
+        } else {
+            Instruction::LoadConstant { dest, value }
+        }
+    }
+
+    pub fn add(dest: Register, left: Register, right: impl Into<Operand>) -> Instruction {
+        Instruction::Arithmetic {
+            op: ArithmeticOp::Add,
+            dest,
+            left,
+            right: right.into(),
+        }
+    }
+
+    pub fn sub(dest: Register, left: Register, right: impl Into<Operand>) -> Instruction {
+        Instruction::Arithmetic {
+            op: ArithmeticOp::Sub,
+            dest,
+            left,
+            right: right.into(),
+        }
+    }
+
+    pub fn cmp(left: Register, right: impl Into<Operand>) -> Instruction {
+        Instruction::Compare {
+            left,
+            right: right.into(),
+        }
+    }
+
+    pub fn ldr(dest: Register, address: Address) -> Instruction {
+        Instruction::Load { dest, address }
+    }
+
+    pub fn str(src: Register, address: Address) -> Instruction {
+        Instruction::Store { src, address }
+    }
+
+    /// Pushes `src` in a slot of its own, keeping `sp` 16-byte aligned.
+    pub fn push(src: Register) -> Instruction {
+        Instruction::str(src, Address::PreIndexed(SP, -16))
+    }
+
+    /// Pops what `push` pushed into `dest`.
+    pub fn pop(dest: Register) -> Instruction {
+        Instruction::ldr(dest, Address::PostIndexed(SP, 16))
+    }
+
+    pub fn b(target: &str) -> Instruction {
+        Instruction::Branch {
+            condition: Condition::Al,
+            target: target.to_string(),
+        }
+    }
+
+    pub fn bl(target: &str) -> Instruction {
+        Instruction::BranchLink(target.to_string())
+    }
+
+    /// The same branch, taken only under `condition`.
+    ///
+    /// # Panics
+    ///
+    /// If the instruction isn't a branch: AArch64 has no other conditional
+    /// instructions.
+    pub fn when(mut self, new: Condition) -> Instruction {
+        match &mut self {
+            Instruction::Branch { condition, .. } => *condition = new,
+            _ => panic!("{} can't be conditional", self),
+        }
+        self
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Move { dest, src } => write!(f, "mov {}, {}", dest, src),
+            Instruction::LoadConstant { dest, value } => write!(f, "ldr {}, ={}", dest, value),
+            Instruction::Arithmetic {
+                op,
+                dest,
+                left,
+                right,
+            } => write!(f, "{} {}, {}, {}", op.name(), dest, left, right),
+            Instruction::Multiply { dest, left, right } => {
+                write!(f, "mul {}, {}, {}", dest, left, right)
+            }
+            Instruction::Divide { dest, left, right } => {
+                write!(f, "sdiv {}, {}, {}", dest, left, right)
+            }
+            Instruction::Compare { left, right } => write!(f, "cmp {}, {}", left, right),
+            Instruction::SetIf { dest, condition } => {
+                write!(f, "cset {}, {}", dest, condition.suffix())
+            }
+            Instruction::Select {
+                dest,
+                left,
+                right,
+                condition,
+            } => write!(
+                f,
+                "csel {}, {}, {}, {}",
+                dest,
+                left,
+                right,
+                condition.suffix()
+            ),
+            Instruction::Load { dest, address } => write!(f, "ldr {}, {}", dest, address),
+            Instruction::Store { src, address } => write!(f, "str {}, {}", src, address),
+            Instruction::LoadPair {
+                first,
+                second,
+                address,
+            } => write!(f, "ldp {}, {}, {}", first, second, address),
+            Instruction::StorePair {
+                first,
+                second,
+                address,
+            } => write!(f, "stp {}, {}, {}", first, second, address),
+            Instruction::LoadAddress { dest, label } => write!(f, "adr {}, {}", dest, label),
+            Instruction::Branch {
+                condition: Condition::Al,
+                target,
+            } => write!(f, "b {}", target),
+            Instruction::Branch { condition, target } => {
+                write!(f, "b.{} {}", condition.suffix(), target)
+            }
+            Instruction::BranchLink(target) => write!(f, "bl {}", target),
+            Instruction::Return => write!(f, "ret"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Line {
+    Label(String),
+    Directive(Directive),
+    Instruction(Instruction),
+}
+
+impl From<Instruction> for Line {
+    fn from(instruction: Instruction) -> Line {
+        Line::Instruction(instruction)
+    }
+}
+
+impl From<Directive> for Line {
+    fn from(directive: Directive) -> Line {
+        Line::Directive(directive)
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Label(name) => write!(f, "{}:", name),
+            // Functions are set apart by a blank line.
+            Line::Directive(directive @ Directive::Global(_)) => write!(f, "\n{}", directive),
+            Line::Directive(directive) => write!(f, "\t{}", directive),
+            Line::Instruction(instruction) => write!(f, "\t{}", instruction),
+        }
+    }
+}
+
+/// Renders lines as assembly source.
+pub fn print(lines: &[Line]) -> String {
+    let mut out = String::new();
+    for line in lines {
+        out.push_str(&line.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printing() {
+        let lines: Vec<Line> = vec![
+            Directive::Global("main".to_string()).into(),
+            Line::Label("main".to_string()),
+            Instruction::StorePair {
+                first: FP,
+                second: LR,
+                address: Address::PreIndexed(SP, -16),
+            }
+            .into(),
+            Instruction::mov(FP, SP).into(),
+            Instruction::push(X0).into(),
+            Instruction::pop(X1).into(),
+            Instruction::ldr(X0, Address::Indexed(X1, X0, 3)).into(),
+            Instruction::str(X0, Address::Offset(FP, -16)).into(),
+            Instruction::Divide {
+                dest: X0,
+                left: X1,
+                right: X0,
+            }
+            .into(),
+            Instruction::SetIf {
+                dest: X0,
+                condition: Condition::Lt,
+            }
+            .into(),
+            Instruction::Select {
+                dest: X0,
+                left: X0,
+                right: X1,
+                condition: Condition::Eq,
+            }
+            .into(),
+            Instruction::b(".L1").when(Condition::Ge).into(),
+            Instruction::b(".L2").into(),
+            Directive::Asciz("%ld\n".to_string()).into(),
+            Instruction::LoadPair {
+                first: FP,
+                second: LR,
+                address: Address::PostIndexed(SP, 16),
+            }
+            .into(),
+            Instruction::Return.into(),
+        ];
+        assert_eq!(
+            "
+.global main
+main:
+	stp x29, x30, [sp, #-16]!
+	mov x29, sp
+	str x0, [sp, #-16]!
+	ldr x1, [sp], #16
+	ldr x0, [x1, x0, lsl #3]
+	str x0, [x29, #-16]
+	sdiv x0, x1, x0
+	cset x0, lt
+	csel x0, x0, x1, eq
+	b.ge .L1
+	b .L2
+	.asciz \"%ld\\n\"
+	ldp x29, x30, [sp], #16
+	ret
+",
+            print(&lines)
+        );
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(
+            "mov x0, #65535",
+            Instruction::constant(X0, 0xffff).to_string()
+        );
+        assert_eq!(
+            "ldr x0, =65536",
+            Instruction::constant(X0, 0x10000).to_string()
+        );
+        assert_eq!(
+            "mov x9, #-300",
+            Instruction::constant(X9, -300i64 as u64).to_string()
+        );
+        assert_eq!(
+            "ldr x0, =18446744073709486079",
+            Instruction::constant(X0, -0x10001i64 as u64).to_string()
+        );
+    }
+}
@@ -0,0 +1,754 @@
+//! Generates AArch64 code from the tree, the way `arm_code_generator` does
+//! for 32-bit ARM, but with 64-bit integers and signed division.
+//!
+//! Values are computed in `x0`, and temporaries are pushed in 16-byte slots
+//! since `sp` has to stay 16-byte aligned. A frame holds `x29` and `x30`
+//! above the eight argument registers, which are stored as the parameters
+//! whether the function takes them or not, and then the locals.
+
+use crate::aarch64::{
+    self, Address, Condition, Directive, Instruction, Line, Operand, Register, FP, LR, SP, X0, X1,
+    X19, X2, X9,
+};
+use crate::arm_code_generator::calls_itself_in_tail_position;
+use crate::ast::AST;
+use crate::error::CompileError;
+use crate::parser::parse;
+use crate::visitor::{AstVisitor, Visitor};
+use std::collections::HashMap;
+
+/// The number of arguments passed in registers, which is all a function can
+/// take.
+const MAX_ARGUMENTS: usize = 8;
+/// The bytes the parameters take below `x29`.
+const PARAMETERS_SIZE: i32 = 8 * MAX_ARGUMENTS as i32;
+
+#[derive(Default)]
+pub struct Aarch64CodeGenerator {
+    locals: HashMap<String, i32>,
+    next_local_offset: i32,
+    label_counter: usize,
+    /// The function being generated, its number of parameters and the label
+    /// following its prologue, if it calls itself in tail position.
+    self_tail_call: Option<(String, usize, String)>,
+}
+
+impl Aarch64CodeGenerator {
+    fn visit_infix_operands(
+        &mut self,
+        left: &AST,
+        right: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        left.visit(self, writer)?;
+        writer.push(Instruction::push(X0).into());
+        right.visit(self, writer)?;
+        writer.push(Instruction::pop(X1).into());
+        Ok(())
+    }
+
+    fn emit_fn_prologue(&mut self, writer: &mut Vec<Line>) {
+        writer.push(
+            Instruction::StorePair {
+                first: FP,
+                second: LR,
+                address: Address::PreIndexed(SP, -16),
+            }
+            .into(),
+        );
+        writer.push(Instruction::mov(FP, SP).into());
+        let size = Operand::Immediate(PARAMETERS_SIZE as i64);
+        writer.push(Instruction::sub(SP, SP, size).into());
+        for i in (0..MAX_ARGUMENTS).step_by(2) {
+            writer.push(
+                Instruction::StorePair {
+                    first: Register(i as u8),
+                    second: Register(i as u8 + 1),
+                    address: Address::Offset(SP, 8 * i as i32),
+                }
+                .into(),
+            );
+        }
+    }
+
+    /// Drops the frame and returns, with whatever is in `x0`.
+    fn emit_return(&mut self, writer: &mut Vec<Line>) {
+        writer.push(Instruction::mov(SP, FP).into());
+        writer.push(pop_frame().into());
+        writer.push(Instruction::Return.into());
+    }
+
+    /// Evaluates the arguments of a call into x0-x7.
+    fn emit_arguments(&mut self, args: &[AST], writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        match args.len() {
+            0 => Ok(()),
+            1 => args[0].visit(self, writer),
+            2..=MAX_ARGUMENTS => {
+                // Two arguments to a 16-byte slot, popped a pair at a time.
+                let slots = args.len().div_ceil(2);
+                let size = Operand::Immediate(16 * slots as i64);
+                writer.push(Instruction::sub(SP, SP, size).into());
+                for (i, arg) in args.iter().enumerate() {
+                    arg.visit(self, writer)?;
+                    writer.push(Instruction::str(X0, Address::Offset(SP, 8 * i as i32)).into());
+                }
+                for i in (0..args.len()).step_by(2) {
+                    let first = Register(i as u8);
+                    let address = Address::PostIndexed(SP, 16);
+                    if i + 1 < args.len() {
+                        let second = Register(i as u8 + 1);
+                        writer.push(
+                            Instruction::LoadPair {
+                                first,
+                                second,
+                                address,
+                            }
+                            .into(),
+                        );
+                    } else {
+                        writer.push(Instruction::ldr(first, address).into());
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(CompileError::CodeGenError(format!(
+                "More than {} arguments are not supported in function calls",
+                MAX_ARGUMENTS
+            ))),
+        }
+    }
+
+    /// Generates `return callee(args)` without growing the stack, like
+    /// `ArmCodeGenerator` does.
+    fn emit_tail_call(
+        &mut self,
+        callee: &str,
+        args: &[AST],
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        self.emit_arguments(args, writer)?;
+        match &self.self_tail_call {
+            Some((name, parameters, label)) if name == callee && *parameters == args.len() => {
+                for i in 0..args.len() {
+                    let parameter = Address::Offset(FP, 8 * i as i32 - PARAMETERS_SIZE);
+                    writer.push(Instruction::str(Register(i as u8), parameter).into());
+                }
+                let size = Operand::Immediate(PARAMETERS_SIZE as i64);
+                writer.push(Instruction::sub(SP, FP, size).into());
+                writer.push(Instruction::b(label).into());
+            }
+            _ => {
+                writer.push(Instruction::mov(SP, FP).into());
+                writer.push(pop_frame().into());
+                writer.push(Instruction::b(callee).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Branches to `label` when `condition` is `when`, comparing the
+    /// operands of a comparison directly.
+    fn emit_branch(
+        &mut self,
+        condition: &AST,
+        when: bool,
+        label: &str,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let (left, right, holds) = match condition {
+            AST::Not(term) => return self.emit_branch(term, !when, label, writer),
+            AST::Equal { left, right } => (left, right, Condition::Eq),
+            AST::NotEqual { left, right } => (left, right, Condition::Ne),
+            AST::LessThan { left, right } => (left, right, Condition::Lt),
+            AST::GreaterThan { left, right } => (left, right, Condition::Gt),
+            AST::LessThanEqual { left, right } => (left, right, Condition::Le),
+            AST::GreaterThanEqual { left, right } => (left, right, Condition::Ge),
+            _ => {
+                condition.visit(self, writer)?;
+                writer.push(Instruction::cmp(X0, Operand::Immediate(0)).into());
+                let taken = if when { Condition::Ne } else { Condition::Eq };
+                writer.push(Instruction::b(label).when(taken).into());
+                return Ok(());
+            }
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::cmp(X1, X0).into());
+        let taken = if when { holds } else { holds.inverse() };
+        writer.push(Instruction::b(label).when(taken).into());
+        Ok(())
+    }
+
+    /// Sets x0 to 1 if comparing the operands gives `condition`, and to 0
+    /// otherwise.
+    fn emit_comparison(
+        &mut self,
+        left: &AST,
+        right: &AST,
+        condition: Condition,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::cmp(X1, X0).into());
+        writer.push(
+            Instruction::SetIf {
+                dest: X0,
+                condition,
+            }
+            .into(),
+        );
+        Ok(())
+    }
+
+    fn new_label(&mut self) -> String {
+        self.label_counter += 1;
+        format!(".L{}", self.label_counter)
+    }
+
+    /// The address of a variable, going through x9 when it is too far below
+    /// `x29` for an offset.
+    fn local(&self, name: &str, writer: &mut Vec<Line>) -> Result<Address, CompileError> {
+        let offset =
+            self.locals.get(name).copied().ok_or_else(|| {
+                CompileError::CodeGenError(format!("Undefined variable: {}", name))
+            })?;
+        if offset >= -256 {
+            return Ok(Address::Offset(FP, offset));
+        }
+        writer.push(Instruction::constant(X9, offset as i64 as u64).into());
+        Ok(Address::Indexed(FP, X9, 0))
+    }
+}
+
+/// `ldp x29, x30, [sp], #16`.
+fn pop_frame() -> Instruction {
+    Instruction::LoadPair {
+        first: FP,
+        second: LR,
+        address: Address::PostIndexed(SP, 16),
+    }
+}
+
+/// Generates AArch64 code for an already parsed program.
+pub fn generate_lines(ast: &AST) -> Result<Vec<Line>, CompileError> {
+    let mut generator: Aarch64CodeGenerator = Default::default();
+    let mut lines = Vec::new();
+    ast.visit(&mut generator, &mut lines)?;
+    Ok(lines)
+}
+
+/// Generates AArch64 assembly for an already parsed program.
+pub fn generate(ast: &AST) -> Result<String, CompileError> {
+    Ok(aarch64::print(&generate_lines(ast)?))
+}
+
+/// Parses `source` and generates AArch64 assembly for it.
+pub fn compile(source: &str) -> Result<String, CompileError> {
+    generate(&parse(source)?)
+}
+
+impl Visitor<(), Vec<Line>> for Aarch64CodeGenerator {
+    fn visit_assert(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Assert(condition) = node else {
+            panic!("Expected Assert node, got: {:?}", node)
+        };
+        condition.visit(self, writer)?;
+        writer.push(Instruction::cmp(X0, Operand::Immediate(1)).into());
+        writer.push(Instruction::mov(X0, Operand::Immediate('T' as i64)).into());
+        writer.push(Instruction::mov(X1, Operand::Immediate('F' as i64)).into());
+        writer.push(
+            Instruction::Select {
+                dest: X0,
+                left: X0,
+                right: X1,
+                condition: Condition::Eq,
+            }
+            .into(),
+        );
+        writer.push(Instruction::bl("putchar").into());
+        Ok(())
+    }
+
+    fn visit_print(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Print(value) = node else {
+            panic!("Expected Print node, got: {:?}", node)
+        };
+        let fmt_label = format!(".Lprint_fmt_{}", self.label_counter);
+        let skip_label = format!(".Lskip_fmt_{}", self.label_counter);
+        self.label_counter += 1;
+
+        value.visit(self, writer)?;
+        writer.push(Instruction::b(&skip_label).into());
+        writer.push(Directive::Align(2).into());
+        writer.push(Line::Label(fmt_label.clone()));
+        writer.push(Directive::Asciz("%ld\n".to_string()).into());
+        writer.push(Directive::Align(2).into());
+        writer.push(Line::Label(skip_label));
+        writer.push(Instruction::mov(X1, X0).into());
+        writer.push(
+            Instruction::LoadAddress {
+                dest: X0,
+                label: fmt_label,
+            }
+            .into(),
+        );
+        writer.push(Instruction::bl("printf").into());
+        Ok(())
+    }
+
+    fn visit_array_length(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::ArrayLength(array) = node else {
+            panic!("Expected ArrayLength node, got: {:?}", node)
+        };
+        array.visit(self, writer)?;
+        writer.push(Instruction::ldr(X0, Address::Offset(X0, 0)).into());
+        Ok(())
+    }
+
+    fn visit_array_lookup(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::ArrayLookup { array, index } = node else {
+            panic!("Expected ArrayLookup node, got: {:?}", node)
+        };
+        self.visit_infix_operands(array, index, writer)?;
+        // Out of bounds, x2 stays 0.
+        let end = self.new_label();
+        writer.push(Instruction::ldr(X2, Address::Offset(X1, 0)).into());
+        writer.push(Instruction::cmp(X0, X2).into());
+        writer.push(Instruction::mov(X2, Operand::Immediate(0)).into());
+        writer.push(Instruction::b(&end).when(Condition::Hs).into());
+        writer.push(Instruction::add(X1, X1, Operand::Immediate(8)).into());
+        writer.push(Instruction::ldr(X2, Address::Indexed(X1, X0, 3)).into());
+        writer.push(Line::Label(end));
+        writer.push(Instruction::mov(X0, X2).into());
+        Ok(())
+    }
+
+    fn visit_array_literal(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::ArrayLiteral(array_items) = node else {
+            panic!("Expected ArrayLiteral node, got: {:?}", node)
+        };
+        let len = array_items.len() as u64;
+        writer.push(Instruction::constant(X0, 8 * (len + 1)).into());
+        writer.push(Instruction::bl("malloc").into());
+        writer.push(Instruction::push(X19).into());
+        writer.push(Instruction::mov(X19, X0).into());
+        writer.push(Instruction::constant(X0, len).into());
+        writer.push(Instruction::str(X0, Address::Offset(X19, 0)).into());
+        for (i, item) in array_items.iter().enumerate() {
+            item.visit(self, writer)?;
+            let element = Address::Offset(X19, 8 * (i as i32 + 1));
+            writer.push(Instruction::str(X0, element).into());
+        }
+        writer.push(Instruction::mov(X0, X19).into());
+        writer.push(Instruction::pop(X19).into());
+        Ok(())
+    }
+
+    fn visit_boolean(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Boolean(value) = node else {
+            panic!("Expected Boolean node, got: {:?}", node)
+        };
+        let value = Operand::Immediate(if *value { 1 } else { 0 });
+        writer.push(Instruction::mov(X0, value).into());
+        Ok(())
+    }
+
+    fn visit_number(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Number(number) = node else {
+            panic!("Expected Number node, got: {:?}", node)
+        };
+        writer.push(Instruction::constant(X0, *number).into());
+        Ok(())
+    }
+
+    fn visit_id(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Id(name) = node else {
+            panic!("Expected Id node, got: {:?}", node)
+        };
+        let address = self.local(name, writer)?;
+        writer.push(Instruction::ldr(X0, address).into());
+        Ok(())
+    }
+
+    fn visit_not(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Not(term) = node else {
+            panic!("Expected Not node, got: {:?}", node)
+        };
+        term.visit(self, writer)?;
+        writer.push(Instruction::cmp(X0, Operand::Immediate(0)).into());
+        writer.push(
+            Instruction::SetIf {
+                dest: X0,
+                condition: Condition::Eq,
+            }
+            .into(),
+        );
+        Ok(())
+    }
+
+    fn visit_equal(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Equal { left, right } = node else {
+            panic!("Expected Equal node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Eq, writer)
+    }
+
+    fn visit_not_equal(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::NotEqual { left, right } = node else {
+            panic!("Expected NotEqual node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Ne, writer)
+    }
+
+    fn visit_add(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Add { left, right } = node else {
+            panic!("Expected Add node, got: {:?}", node)
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::add(X0, X1, X0).into());
+        Ok(())
+    }
+
+    fn visit_subtract(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Subtract { left, right } = node else {
+            panic!("Expected Subtract node, got: {:?}", node)
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::sub(X0, X1, X0).into());
+        Ok(())
+    }
+
+    fn visit_multiply(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Multiply { left, right } = node else {
+            panic!("Expected Multiply node, got: {:?}", node)
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(
+            Instruction::Multiply {
+                dest: X0,
+                left: X0,
+                right: X1,
+            }
+            .into(),
+        );
+        Ok(())
+    }
+
+    fn visit_divide(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Divide { left, right } = node else {
+            panic!("Expected Divide node, got: {:?}", node)
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(
+            Instruction::Divide {
+                dest: X0,
+                left: X1,
+                right: X0,
+            }
+            .into(),
+        );
+        Ok(())
+    }
+
+    fn visit_less_than(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::LessThan { left, right } = node else {
+            panic!("Expected LessThan node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Lt, writer)
+    }
+
+    fn visit_greater_than(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::GreaterThan { left, right } = node else {
+            panic!("Expected GreaterThan node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Gt, writer)
+    }
+
+    fn visit_less_than_equal(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::LessThanEqual { left, right } = node else {
+            panic!("Expected LessThanEqual node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Le, writer)
+    }
+
+    fn visit_greater_than_equal(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::GreaterThanEqual { left, right } = node else {
+            panic!("Expected GreaterThanEqual node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Ge, writer)
+    }
+
+    fn visit_call(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Call { args, callee } = node else {
+            panic!("Expected Call node, got: {:?}", node)
+        };
+        self.emit_arguments(args, writer)?;
+        writer.push(Instruction::bl(callee).into());
+        Ok(())
+    }
+
+    fn visit_return(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Return { term } = node else {
+            panic!("Expected Return node, got: {:?}", node)
+        };
+        if let AST::Call { callee, args } = term.as_ref() {
+            return self.emit_tail_call(callee, args, writer);
+        }
+        term.visit(self, writer)?;
+        self.emit_return(writer);
+        Ok(())
+    }
+
+    fn visit_block(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Block(statements) = node else {
+            panic!("Expected Block node, got: {:?}", node)
+        };
+        for statement in statements {
+            statement.visit(self, writer)?;
+        }
+        Ok(())
+    }
+
+    fn visit_if(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::IfNode {
+            conditional,
+            consequence,
+            alternative,
+        } = node
+        else {
+            panic!("Expected IfNode node, got: {:?}", node)
+        };
+        let if_false_label = self.new_label();
+        let end_if_label = self.new_label();
+        self.emit_branch(conditional, false, &if_false_label, writer)?;
+        consequence.visit(self, writer)?;
+        writer.push(Instruction::b(&end_if_label).into());
+        writer.push(Line::Label(if_false_label));
+        alternative.visit(self, writer)?;
+        writer.push(Line::Label(end_if_label));
+        Ok(())
+    }
+
+    fn visit_function(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Function {
+            name,
+            parameters,
+            body,
+        } = node
+        else {
+            panic!("Expected Function node, got: {:?}", node)
+        };
+        if parameters.len() > MAX_ARGUMENTS {
+            return Err(CompileError::CodeGenError(format!(
+                "More than {} params is not supported",
+                MAX_ARGUMENTS
+            )));
+        }
+        writer.push(Directive::Global(name.clone()).into());
+        writer.push(Line::Label(name.clone()));
+        self.emit_fn_prologue(writer);
+        let self_tail_call = if calls_itself_in_tail_position(name, body) {
+            let label = self.new_label();
+            writer.push(Line::Label(label.clone()));
+            Some((name.clone(), parameters.len(), label))
+        } else {
+            None
+        };
+
+        let locals = parameters
+            .iter()
+            .enumerate()
+            .map(|(i, parameter)| (parameter.clone(), 8 * i as i32 - PARAMETERS_SIZE))
+            .collect();
+        // Labels are shared by the whole program, so the counter carries on
+        // from the enclosing scope.
+        let mut generator = Aarch64CodeGenerator {
+            locals,
+            next_local_offset: -PARAMETERS_SIZE,
+            label_counter: self.label_counter,
+            self_tail_call,
+        };
+        body.visit(&mut generator, writer)?;
+        self.label_counter = generator.label_counter;
+        // Without a `return`, functions return 0 like `undefined`.
+        writer.push(Instruction::mov(X0, Operand::Immediate(0)).into());
+        self.emit_return(writer);
+        Ok(())
+    }
+
+    fn visit_var(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Var { name, value } = node else {
+            panic!("Expected Var node, got: {:?}", node)
+        };
+        value.visit(self, writer)?;
+        writer.push(Instruction::push(X0).into());
+        self.next_local_offset -= 16;
+        self.locals.insert(name.to_string(), self.next_local_offset);
+        Ok(())
+    }
+
+    fn visit_assign(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Assign { name, value } = node else {
+            panic!("Expected Assign node, got: {:?}", node)
+        };
+        value.visit(self, writer)?;
+        let address = self.local(name, writer)?;
+        writer.push(Instruction::str(X0, address).into());
+        Ok(())
+    }
+
+    fn visit_while(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::While { conditional, body } = node else {
+            panic!("Expected While node, got: {:?}", node)
+        };
+        let loop_start = self.new_label();
+        let loop_end = self.new_label();
+        writer.push(Line::Label(loop_start.clone()));
+        self.emit_branch(conditional, false, &loop_end, writer)?;
+        body.visit(self, writer)?;
+        writer.push(Instruction::b(&loop_start).into());
+        writer.push(Line::Label(loop_end));
+        Ok(())
+    }
+
+    fn visit_undefined(&mut self, _: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        writer.push(Instruction::mov(X0, Operand::Immediate(0)).into());
+        Ok(())
+    }
+
+    fn visit_null(&mut self, _: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        writer.push(Instruction::mov(X0, Operand::Immediate(0)).into());
+        Ok(())
+    }
+
+    fn visit_main(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Main(statements) = node else {
+            panic!("Expected Main, got: {:?}", node)
+        };
+        writer.push(Directive::Global("main".to_string()).into());
+        writer.push(Line::Label("main".to_string()));
+        self.emit_fn_prologue(writer);
+        self.next_local_offset = -PARAMETERS_SIZE;
+        for statement in statements {
+            statement.visit(self, writer)?;
+        }
+        writer.push(Instruction::mov(X0, Operand::Immediate(0)).into());
+        self.emit_return(writer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aarch64_emulator;
+    use crate::generator::ProgramGenerator;
+
+    fn run(source: &str) -> String {
+        let lines = generate_lines(&parse(source).expect("Parse error")).expect("Compile failed");
+        let mut stdout = vec![];
+        let status = aarch64_emulator::run(&lines, &mut stdout, 1_000_000).expect("Run failed");
+        assert_eq!(0, status);
+        String::from_utf8(stdout).unwrap()
+    }
+
+    #[test]
+    fn integers_have_64_bits() {
+        assert_eq!(
+            "8589934592\n-1\nT",
+            run("function main() {
+                print(4294967296 * 2);
+                print(0 - 1);
+                assert(4294967296 > 1);
+            }")
+        );
+    }
+
+    #[test]
+    fn division_is_signed() {
+        assert_eq!(
+            "-3\n3\n0\n",
+            run("function main() { print((0 - 7) / 2); print((0 - 7) / (0 - 2)); print(7 / 0); }")
+        );
+    }
+
+    #[test]
+    fn many_locals() {
+        let vars: String = (0..40).map(|i| format!("var v{} = {};", i, i)).collect();
+        let assembly = compile(&format!("function main() {{ {} print(v39); }}", vars))
+            .expect("Compile failed");
+        assert!(assembly.contains("\tldr x0, [x29, x9]\n"), "{}", assembly);
+        assert_eq!(
+            "39\n780\n",
+            run(&format!(
+                "function main() {{ {} print(v39); v38 = {}; print(v38); }}",
+                vars,
+                (0..40)
+                    .map(|i| format!("v{}", i))
+                    .collect::<Vec<_>>()
+                    .join(" + ")
+            ))
+        );
+    }
+
+    #[test]
+    fn eight_arguments() {
+        assert_eq!(
+            "12345678\n",
+            run("function f(a, b, c, d, e, g, h, i) {
+                return ((((((a * 10 + b) * 10 + c) * 10 + d) * 10 + e) * 10 + g) * 10 + h) * 10 + i;
+            }
+            function main() { print(f(1, 2, 3, 4, 5, 6, 7, 8)); }")
+        );
+    }
+
+    #[test]
+    fn limits() {
+        assert!(matches!(
+            compile("function main() { f(1, 2, 3, 4, 5, 6, 7, 8, 9); }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+        assert!(matches!(
+            compile("function f(a, b, c, d, e, f, g, h, i) { }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+        assert!(matches!(
+            compile("function main() { print(x); }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+    }
+
+    #[test]
+    fn generated_programs_run() {
+        for seed in 0..20 {
+            let program = ProgramGenerator::new(seed).program();
+            let lines = generate_lines(&program).expect("Compile failed");
+            let mut stdout = vec![];
+            aarch64_emulator::run(&lines, &mut stdout, 100_000_000)
+                .unwrap_or_else(|e| panic!("seed {}: {}\n{}", seed, e, program));
+        }
+    }
+}
@@ -0,0 +1,553 @@
+//! Runs the AArch64 code `aarch64_code_generator` produces, as `emulator`
+//! runs ARM code, so both targets share the tests.
+//!
+//! There is no AArch64 encoder, so the emulator works on the instructions as
+//! data: it lays the lines out as the assembler would, with every
+//! instruction a word and strings in between, and runs the instruction at
+//! each word. Calls to `putchar`, `printf` and `malloc` go to shims in Rust.
+
+use crate::aarch64::{
+    Address, ArithmeticOp, Condition, Directive, Instruction, Line, Operand, Register,
+};
+use crate::error::CompileError;
+use crate::interpreter::runtime_error;
+use std::collections::HashMap;
+use std::io::Write;
+
+const CODE: u64 = 0x40_0000;
+/// The functions with shims, at `SHIMS` and the following words.
+const SHIM_NAMES: [&str; 3] = ["putchar", "printf", "malloc"];
+const SHIMS: u64 = 0xffff_0000;
+/// Where `main` returns to, to end the program.
+const EXIT: u64 = 0xffff_fff0;
+
+const HEAP: u64 = 0x1000_0000;
+const MAX_HEAP_SIZE: u64 = 256 << 20;
+const STACK_TOP: u64 = 0x8000_0000;
+const STACK_SIZE: u64 = 8 << 20;
+
+const LR: usize = 30;
+const SP: u8 = 31;
+
+/// Runs code from `main`, writing what it prints to `out`, and returns the
+/// exit status: the value `main` returns. `max_steps` bounds the number of
+/// instructions run.
+pub fn run<W: Write + ?Sized>(
+    lines: &[Line],
+    out: &mut W,
+    max_steps: usize,
+) -> Result<i32, CompileError> {
+    let program = Program::new(lines)?;
+    let entry = *program
+        .labels
+        .get("main")
+        .ok_or_else(|| runtime_error("No main to run"))?;
+    let mut machine = Machine {
+        registers: [0; 31],
+        sp: STACK_TOP,
+        pc: entry,
+        n: false,
+        z: false,
+        c: false,
+        v: false,
+        image: program.image.clone(),
+        heap: vec![],
+        stack: vec![0; STACK_SIZE as usize],
+        steps: 0,
+        max_steps,
+    };
+    machine.registers[LR] = EXIT;
+    machine.execute(&program, out)
+}
+
+/// Code laid out from `CODE`.
+struct Program<'a> {
+    /// The instruction at each word, if there is one there, and the address
+    /// of the label it refers to.
+    words: Vec<Option<(&'a Instruction, u64)>>,
+    labels: HashMap<&'a str, u64>,
+    /// The strings, with zeros where the instructions are.
+    image: Vec<u8>,
+}
+
+impl<'a> Program<'a> {
+    fn new(lines: &'a [Line]) -> Result<Program<'a>, CompileError> {
+        let mut image = vec![];
+        let mut labels = HashMap::new();
+        let mut instructions = vec![];
+        for line in lines {
+            match line {
+                Line::Label(name) => {
+                    labels.insert(name.as_str(), CODE + image.len() as u64);
+                }
+                Line::Directive(Directive::Global(_)) => {}
+                Line::Directive(Directive::Align(power)) => {
+                    image.resize(image.len().next_multiple_of(1 << power), 0)
+                }
+                Line::Directive(Directive::Asciz(text)) => {
+                    image.extend_from_slice(text.as_bytes());
+                    image.push(0);
+                }
+                Line::Instruction(instruction) => {
+                    if !image.len().is_multiple_of(4) {
+                        return Err(runtime_error(format!(
+                            "Unaligned instruction {}",
+                            instruction
+                        )));
+                    }
+                    instructions.push((image.len() / 4, instruction));
+                    image.extend_from_slice(&[0; 4]);
+                }
+            }
+        }
+        let mut words = vec![None; image.len().div_ceil(4)];
+        for (word, instruction) in instructions {
+            let target = match instruction {
+                Instruction::Branch { target: label, .. }
+                | Instruction::BranchLink(label)
+                | Instruction::LoadAddress { label, .. } => match labels.get(label.as_str()) {
+                    Some(address) => *address,
+                    None => match SHIM_NAMES.iter().position(|shim| shim == label) {
+                        Some(shim) => SHIMS + 4 * shim as u64,
+                        None => {
+                            let message = format!("Undefined symbol {}", label);
+                            return Err(CompileError::CodeGenError(message));
+                        }
+                    },
+                },
+                _ => 0,
+            };
+            words[word] = Some((instruction, target));
+        }
+        Ok(Program {
+            words,
+            labels,
+            image,
+        })
+    }
+}
+
+struct Machine {
+    /// `x0` to `x30`: `sp` is kept apart.
+    registers: [u64; 31],
+    sp: u64,
+    pc: u64,
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+    /// The code and strings, from `CODE`.
+    image: Vec<u8>,
+    /// From `HEAP` to the program break.
+    heap: Vec<u8>,
+    /// The bottom of the stack, up to `STACK_TOP`.
+    stack: Vec<u8>,
+    steps: usize,
+    max_steps: usize,
+}
+
+impl Machine {
+    fn execute<W: Write + ?Sized>(
+        &mut self,
+        program: &Program,
+        out: &mut W,
+    ) -> Result<i32, CompileError> {
+        loop {
+            if self.pc == EXIT {
+                return Ok(self.registers[0] as i32);
+            }
+            if (SHIMS..SHIMS + 4 * SHIM_NAMES.len() as u64).contains(&self.pc) {
+                self.shim(SHIM_NAMES[(self.pc - SHIMS) as usize / 4], out)?;
+                self.pc = self.registers[LR];
+                continue;
+            }
+            let word = self
+                .pc
+                .checked_sub(CODE)
+                .filter(|offset| offset.is_multiple_of(4))
+                .and_then(|offset| program.words.get(offset as usize / 4).copied().flatten());
+            let Some((instruction, target)) = word else {
+                return Err(runtime_error(format!("Jumped to {:#x}", self.pc)));
+            };
+            self.steps += 1;
+            if self.steps > self.max_steps {
+                return Err(runtime_error("Too many steps"));
+            }
+            self.pc += 4;
+            self.step(instruction, target)?;
+        }
+    }
+
+    fn get(&self, register: Register) -> u64 {
+        match register.0 {
+            SP => self.sp,
+            n => self.registers[n as usize],
+        }
+    }
+
+    fn set(&mut self, register: Register, value: u64) {
+        match register.0 {
+            SP => self.sp = value,
+            n => self.registers[n as usize] = value,
+        }
+    }
+
+    fn operand(&self, operand: Operand) -> u64 {
+        match operand {
+            Operand::Immediate(value) => value as u64,
+            Operand::Register(register) => self.get(register),
+        }
+    }
+
+    fn holds(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::Eq => self.z,
+            Condition::Ne => !self.z,
+            Condition::Hs => self.c,
+            Condition::Lo => !self.c,
+            Condition::Mi => self.n,
+            Condition::Pl => !self.n,
+            Condition::Vs => self.v,
+            Condition::Vc => !self.v,
+            Condition::Hi => self.c && !self.z,
+            Condition::Ls => !self.c || self.z,
+            Condition::Ge => self.n == self.v,
+            Condition::Lt => self.n != self.v,
+            Condition::Gt => !self.z && self.n == self.v,
+            Condition::Le => self.z || self.n != self.v,
+            Condition::Al => true,
+        }
+    }
+
+    /// The address an access goes to, after updating the base register if
+    /// the address writes it back. Like the hardware, fails if the base is
+    /// `sp` and it isn't 16-byte aligned.
+    fn address(&mut self, address: Address) -> Result<u64, CompileError> {
+        let (Address::Offset(base, _)
+        | Address::PreIndexed(base, _)
+        | Address::PostIndexed(base, _)
+        | Address::Indexed(base, _, _)) = address;
+        if base.0 == SP && !self.sp.is_multiple_of(16) {
+            return Err(runtime_error(format!("Misaligned stack {:#x}", self.sp)));
+        }
+        Ok(match address {
+            Address::Offset(base, offset) => self.get(base).wrapping_add(offset as u64),
+            Address::PreIndexed(base, offset) => {
+                let address = self.get(base).wrapping_add(offset as u64);
+                self.set(base, address);
+                address
+            }
+            Address::PostIndexed(base, offset) => {
+                let address = self.get(base);
+                self.set(base, address.wrapping_add(offset as u64));
+                address
+            }
+            Address::Indexed(base, index, shift) => {
+                self.get(base).wrapping_add(self.get(index) << shift)
+            }
+        })
+    }
+
+    fn step(&mut self, instruction: &Instruction, target: u64) -> Result<(), CompileError> {
+        match instruction {
+            Instruction::Move { dest, src } => self.set(*dest, self.operand(*src)),
+            Instruction::LoadConstant { dest, value } => self.set(*dest, *value),
+            Instruction::Arithmetic {
+                op,
+                dest,
+                left,
+                right,
+            } => {
+                let (left, right) = (self.get(*left), self.operand(*right));
+                let result = match op {
+                    ArithmeticOp::Add => left.wrapping_add(right),
+                    ArithmeticOp::Sub => left.wrapping_sub(right),
+                };
+                self.set(*dest, result);
+            }
+            Instruction::Multiply { dest, left, right } => {
+                self.set(*dest, self.get(*left).wrapping_mul(self.get(*right)))
+            }
+            Instruction::Divide { dest, left, right } => {
+                let (left, right) = (self.get(*left) as i64, self.get(*right) as i64);
+                let quotient = if right == 0 {
+                    0
+                } else {
+                    left.wrapping_div(right)
+                };
+                self.set(*dest, quotient as u64);
+            }
+            Instruction::Compare { left, right } => {
+                let (left, right) = (self.get(*left), self.operand(*right));
+                let result = left.wrapping_sub(right);
+                self.n = (result as i64) < 0;
+                self.z = result == 0;
+                self.c = left >= right;
+                self.v = ((left ^ right) & (left ^ result)) >> 63 == 1;
+            }
+            Instruction::SetIf { dest, condition } => {
+                self.set(*dest, self.holds(*condition) as u64)
+            }
+            Instruction::Select {
+                dest,
+                left,
+                right,
+                condition,
+            } => {
+                let chosen = if self.holds(*condition) { left } else { right };
+                self.set(*dest, self.get(*chosen));
+            }
+            Instruction::Load { dest, address } => {
+                let address = self.address(*address)?;
+                let value = self.load(address)?;
+                self.set(*dest, value);
+            }
+            Instruction::Store { src, address } => {
+                let value = self.get(*src);
+                let address = self.address(*address)?;
+                self.store(address, value)?;
+            }
+            Instruction::LoadPair {
+                first,
+                second,
+                address,
+            } => {
+                let address = self.address(*address)?;
+                let (low, high) = (self.load(address)?, self.load(address + 8)?);
+                self.set(*first, low);
+                self.set(*second, high);
+            }
+            Instruction::StorePair {
+                first,
+                second,
+                address,
+            } => {
+                let (low, high) = (self.get(*first), self.get(*second));
+                let address = self.address(*address)?;
+                self.store(address, low)?;
+                self.store(address + 8, high)?;
+            }
+            Instruction::LoadAddress { dest, .. } => self.set(*dest, target),
+            Instruction::Branch { condition, .. } => {
+                if self.holds(*condition) {
+                    self.pc = target;
+                }
+            }
+            Instruction::BranchLink(_) => {
+                self.registers[LR] = self.pc;
+                self.pc = target;
+            }
+            Instruction::Return => self.pc = self.registers[LR],
+        }
+        Ok(())
+    }
+
+    fn memory(&mut self, address: u64, size: u64) -> Result<&mut [u8], CompileError> {
+        let fault = || runtime_error(format!("Segmentation fault at {:#x}", address));
+        let (start, bytes) = if address >= STACK_TOP - STACK_SIZE {
+            (STACK_TOP - STACK_SIZE, &mut self.stack)
+        } else if address >= HEAP {
+            (HEAP, &mut self.heap)
+        } else if address >= CODE {
+            (CODE, &mut self.image)
+        } else {
+            return Err(fault());
+        };
+        let offset = (address - start) as usize;
+        bytes
+            .get_mut(offset..offset + size as usize)
+            .ok_or_else(fault)
+    }
+
+    fn load(&mut self, address: u64) -> Result<u64, CompileError> {
+        Ok(u64::from_le_bytes(
+            self.memory(address, 8)?.try_into().unwrap(),
+        ))
+    }
+
+    fn store(&mut self, address: u64, value: u64) -> Result<(), CompileError> {
+        self.memory(address, 8)?
+            .copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    /// The zero-terminated string at `address`.
+    fn string(&mut self, mut address: u64) -> Result<Vec<u8>, CompileError> {
+        let mut string = vec![];
+        loop {
+            match self.memory(address, 1)?[0] {
+                0 => return Ok(string),
+                byte => string.push(byte),
+            }
+            address += 1;
+        }
+    }
+
+    fn shim<W: Write + ?Sized>(&mut self, name: &str, out: &mut W) -> Result<(), CompileError> {
+        let argument = self.registers[1];
+        let result = match name {
+            "putchar" => {
+                out.write_all(&[self.registers[0] as u8])?;
+                self.registers[0] & 0xff
+            }
+            "printf" => {
+                let format = self.string(self.registers[0])?;
+                let mut printed = vec![];
+                let mut bytes = format.into_iter().peekable();
+                while let Some(byte) = bytes.next() {
+                    if byte != b'%' {
+                        printed.push(byte);
+                    } else if bytes.next_if_eq(&b'l').is_some() && bytes.next_if_eq(&b'd').is_some()
+                    {
+                        write!(printed, "{}", argument as i64)?;
+                    } else if bytes.next_if_eq(&b'%').is_some() {
+                        printed.push(b'%');
+                    } else {
+                        printed.push(byte);
+                    }
+                }
+                out.write_all(&printed)?;
+                printed.len() as u64
+            }
+            _ => {
+                let start = HEAP + self.heap.len() as u64;
+                let end = start.saturating_add(self.registers[0].next_multiple_of(8));
+                if end <= HEAP + MAX_HEAP_SIZE {
+                    self.heap.resize((end - HEAP) as usize, 0);
+                    start
+                } else {
+                    0
+                }
+            }
+        };
+        self.registers[0] = result;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aarch64::{Instruction, LR as LR_REGISTER, SP as SP_REGISTER, X0, X1};
+    use crate::aarch64_code_generator::generate_lines;
+    use crate::parser::parse;
+
+    fn run_lines(lines: &[Line]) -> Result<(i32, String), CompileError> {
+        let mut out = vec![];
+        let status = run(lines, &mut out, 1_000_000)?;
+        Ok((status, String::from_utf8(out).unwrap()))
+    }
+
+    fn run_source(source: &str) -> (i32, String) {
+        run_lines(&generate_lines(&parse(source).unwrap()).unwrap()).unwrap()
+    }
+
+    fn main(instructions: Vec<Instruction>) -> Vec<Line> {
+        let mut lines = vec![Line::Label("main".to_string())];
+        lines.extend(instructions.into_iter().map(Line::from));
+        lines.push(Instruction::Return.into());
+        lines
+    }
+
+    #[test]
+    fn arithmetic() {
+        let lines = main(vec![
+            Instruction::constant(X0, 7),
+            Instruction::constant(X1, -3i64 as u64),
+            Instruction::Multiply {
+                dest: X0,
+                left: X0,
+                right: X1,
+            },
+            Instruction::add(X0, X0, Operand::Immediate(30)),
+        ]);
+        assert_eq!((9, String::new()), run_lines(&lines).unwrap());
+    }
+
+    #[test]
+    fn division() {
+        for (left, right, quotient) in [(7, 2, 3), (-7, 2, -3), (7, 0, 0), (i64::MIN, -1, 0)] {
+            let lines = main(vec![
+                Instruction::constant(X0, left as u64),
+                Instruction::constant(X1, right as u64),
+                Instruction::Divide {
+                    dest: X0,
+                    left: X0,
+                    right: X1,
+                },
+            ]);
+            assert_eq!(quotient, run_lines(&lines).unwrap().0, "{}", left);
+        }
+    }
+
+    #[test]
+    fn conditions() {
+        for (left, right, condition, holds) in [
+            (1, 2, Condition::Lt, true),
+            (-1, 2, Condition::Lt, true),
+            (-1, 2, Condition::Lo, false),
+            (i64::MIN, 1, Condition::Gt, false),
+            (2, 2, Condition::Ge, true),
+            (2, 2, Condition::Hi, false),
+        ] {
+            let lines = main(vec![
+                Instruction::constant(X0, left as u64),
+                Instruction::constant(X1, right as u64),
+                Instruction::cmp(X0, X1),
+                Instruction::SetIf {
+                    dest: X0,
+                    condition,
+                },
+            ]);
+            let (status, _) = run_lines(&lines).unwrap();
+            assert_eq!(holds as i32, status, "{} {:?} {}", left, condition, right);
+        }
+    }
+
+    #[test]
+    fn memory_and_calls() {
+        let (status, output) = run_source(
+            "function f(a, b, c, d, e, f, g, h) { return a + b + c + d + e + f + g + h; }
+             function main() { var a = [5, 6]; print(a[1] + length(a) + a[2]); return f(1, 2, 3, 4, 5, 6, 7, 8); }",
+        );
+        assert_eq!((36, "8\n".to_string()), (status, output));
+    }
+
+    #[test]
+    fn errors() {
+        let jump = main(vec![Instruction::mov(LR_REGISTER, Operand::Immediate(4))]);
+        assert!(matches!(
+            run_lines(&jump),
+            Err(CompileError::RuntimeError(message, None)) if message.starts_with("Jumped to")
+        ));
+        let fault = main(vec![
+            Instruction::mov(X0, Operand::Immediate(8)),
+            Instruction::ldr(X0, Address::Offset(X0, 0)),
+        ]);
+        assert!(matches!(
+            run_lines(&fault),
+            Err(CompileError::RuntimeError(message, None)) if message.starts_with("Segmentation fault")
+        ));
+        let misaligned = main(vec![
+            Instruction::sub(SP_REGISTER, SP_REGISTER, Operand::Immediate(8)),
+            Instruction::push(X0),
+        ]);
+        assert!(matches!(
+            run_lines(&misaligned),
+            Err(CompileError::RuntimeError(message, None)) if message.starts_with("Misaligned stack")
+        ));
+        let looping = vec![
+            Line::Label("main".to_string()),
+            Instruction::b("main").into(),
+        ];
+        assert!(matches!(
+            run_lines(&looping),
+            Err(CompileError::RuntimeError(message, None)) if message == "Too many steps"
+        ));
+        let undefined = main(vec![Instruction::bl("nowhere")]);
+        assert!(matches!(
+            run_lines(&undefined),
+            Err(CompileError::CodeGenError(_))
+        ));
+    }
+}
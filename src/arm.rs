@@ -0,0 +1,975 @@
+//! The ARM assembly the code generators produce, as data.
+//!
+//! Code is a list of `Line`s: labels, directives and instructions. Printing
+//! them gives the GNU assembler syntax the generators used to write by hand,
+//! and `parse` reads that syntax back, so that passes like the peephole
+//! optimizer can work on either.
+
+use crate::error::CompileError;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Register(pub u8);
+
+pub const R0: Register = Register(0);
+pub const R1: Register = Register(1);
+pub const R2: Register = Register(2);
+pub const R3: Register = Register(3);
+pub const R4: Register = Register(4);
+pub const FP: Register = Register(11);
+pub const IP: Register = Register(12);
+pub const SP: Register = Register(13);
+pub const LR: Register = Register(14);
+pub const PC: Register = Register(15);
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            11 => write!(f, "fp"),
+            12 => write!(f, "ip"),
+            13 => write!(f, "sp"),
+            14 => write!(f, "lr"),
+            15 => write!(f, "pc"),
+            n => write!(f, "r{}", n),
+        }
+    }
+}
+
+/// When a conditional instruction runs, from the flags set by `cmp`. The
+/// variants are in the order of their encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Condition {
+    Eq,
+    Ne,
+    /// Unsigned higher or same.
+    Hs,
+    /// Unsigned lower.
+    Lo,
+    Mi,
+    Pl,
+    Vs,
+    Vc,
+    /// Unsigned higher.
+    Hi,
+    /// Unsigned lower or same.
+    Ls,
+    Ge,
+    Lt,
+    Gt,
+    Le,
+    /// Always.
+    Al,
+}
+
+impl Condition {
+    pub const ALL: [Condition; 15] = [
+        Condition::Eq,
+        Condition::Ne,
+        Condition::Hs,
+        Condition::Lo,
+        Condition::Mi,
+        Condition::Pl,
+        Condition::Vs,
+        Condition::Vc,
+        Condition::Hi,
+        Condition::Ls,
+        Condition::Ge,
+        Condition::Lt,
+        Condition::Gt,
+        Condition::Le,
+        Condition::Al,
+    ];
+
+    /// The suffix added to mnemonics, empty for `Al`.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Condition::Eq => "eq",
+            Condition::Ne => "ne",
+            Condition::Hs => "hs",
+            Condition::Lo => "lo",
+            Condition::Mi => "mi",
+            Condition::Pl => "pl",
+            Condition::Vs => "vs",
+            Condition::Vc => "vc",
+            Condition::Hi => "hi",
+            Condition::Ls => "ls",
+            Condition::Ge => "ge",
+            Condition::Lt => "lt",
+            Condition::Gt => "gt",
+            Condition::Le => "le",
+            Condition::Al => "",
+        }
+    }
+
+    fn from_suffix(suffix: &str) -> Option<Condition> {
+        match suffix {
+            "cs" => Some(Condition::Hs),
+            "cc" => Some(Condition::Lo),
+            "al" => Some(Condition::Al),
+            _ => Condition::ALL
+                .into_iter()
+                .find(|condition| condition.suffix() == suffix),
+        }
+    }
+
+    /// The condition holding exactly when this one doesn't. `Al` has none
+    /// and is its own.
+    pub fn inverse(self) -> Condition {
+        match self {
+            Condition::Al => Condition::Al,
+            // Conditions come in pairs differing in the lowest bit.
+            condition => Condition::ALL[condition as usize ^ 1],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Shift {
+    Lsl,
+    Lsr,
+    Asr,
+    Ror,
+}
+
+impl Shift {
+    pub fn name(self) -> &'static str {
+        match self {
+            Shift::Lsl => "lsl",
+            Shift::Lsr => "lsr",
+            Shift::Asr => "asr",
+            Shift::Ror => "ror",
+        }
+    }
+}
+
+/// The flexible second operand of data-processing instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    /// Only 8-bit values rotated right by an even amount can be encoded, see
+    /// `is_immediate`.
+    Immediate(u32),
+    Register(Register),
+    /// A register shifted by a constant amount.
+    Shifted(Register, Shift, u8),
+}
+
+impl From<Register> for Operand {
+    fn from(register: Register) -> Operand {
+        Operand::Register(register)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Immediate(value) => write!(f, "#{}", value),
+            Operand::Register(register) => write!(f, "{}", register),
+            Operand::Shifted(register, shift, amount) => {
+                write!(f, "{}, {} #{}", register, shift.name(), amount)
+            }
+        }
+    }
+}
+
+/// Whether a data-processing instruction can take `value` as an immediate:
+/// 8 bits rotated right by an even amount.
+pub fn is_immediate(value: u32) -> bool {
+    (0..16).any(|rotation| value.rotate_left(2 * rotation) <= 0xff)
+}
+
+/// The memory word `ldr` and `str` access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// `[base, #offset]`. The offset must be within 4095 either way.
+    Offset(Register, i32),
+    /// `[base, index, lsl #shift]`.
+    Indexed(Register, Register, u8),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Offset(base, 0) => write!(f, "[{}]", base),
+            Address::Offset(base, offset) => write!(f, "[{}, #{}]", base, offset),
+            Address::Indexed(base, index, 0) => write!(f, "[{}, {}]", base, index),
+            Address::Indexed(base, index, shift) => {
+                write!(f, "[{}, {}, lsl #{}]", base, index, shift)
+            }
+        }
+    }
+}
+
+/// The data-processing instructions taking a register and an `Operand`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    /// Reverse subtract: the operand minus the register.
+    Rsb,
+    And,
+    Orr,
+    Eor,
+}
+
+impl ArithmeticOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            ArithmeticOp::Add => "add",
+            ArithmeticOp::Sub => "sub",
+            ArithmeticOp::Rsb => "rsb",
+            ArithmeticOp::And => "and",
+            ArithmeticOp::Orr => "orr",
+            ArithmeticOp::Eor => "eor",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Move {
+        condition: Condition,
+        dest: Register,
+        src: Operand,
+    },
+    /// `mvn`: moves the bitwise negation of `src`.
+    MoveNot {
+        condition: Condition,
+        dest: Register,
+        src: Operand,
+    },
+    Arithmetic {
+        op: ArithmeticOp,
+        condition: Condition,
+        dest: Register,
+        left: Register,
+        right: Operand,
+    },
+    Multiply {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    /// `udiv`, where dividing by zero gives 0.
+    Divide {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    Compare {
+        left: Register,
+        right: Operand,
+    },
+    Load {
+        condition: Condition,
+        dest: Register,
+        address: Address,
+    },
+    Store {
+        condition: Condition,
+        src: Register,
+        address: Address,
+    },
+    /// `ldrb`: loads a byte, zero-extended.
+    LoadByte {
+        dest: Register,
+        address: Address,
+    },
+    /// `ldr rD, =value`, which the assembler turns into a load from a
+    /// literal pool.
+    LoadConstant {
+        dest: Register,
+        value: u32,
+    },
+    /// `adr`: the address of a label.
+    LoadAddress {
+        dest: Register,
+        label: String,
+    },
+    Push(Vec<Register>),
+    Pop(Vec<Register>),
+    Branch {
+        condition: Condition,
+        target: String,
+    },
+    /// `bl`: calls a function, with the return address in `lr`.
+    BranchLink(String),
+    /// `bx`: jumps to the address in a register.
+    BranchExchange(Register),
+    /// `svc`: makes the Linux system call numbered by `r7`.
+    SupervisorCall(u32),
+}
+
+impl Instruction {
+    pub fn mov(dest: Register, src: impl Into<Operand>) -> Instruction {
+        Instruction::Move {
+            condition: Condition::Al,
+            dest,
+            src: src.into(),
+        }
+    }
+
+    pub fn arithmetic(
+        op: ArithmeticOp,
+        dest: Register,
+        left: Register,
+        right: impl Into<Operand>,
+    ) -> Instruction {
+        Instruction::Arithmetic {
+            op,
+            condition: Condition::Al,
+            dest,
+            left,
+            right: right.into(),
+        }
+    }
+
+    pub fn add(dest: Register, left: Register, right: impl Into<Operand>) -> Instruction {
+        Instruction::arithmetic(ArithmeticOp::Add, dest, left, right)
+    }
+
+    pub fn sub(dest: Register, left: Register, right: impl Into<Operand>) -> Instruction {
+        Instruction::arithmetic(ArithmeticOp::Sub, dest, left, right)
+    }
+
+    pub fn cmp(left: Register, right: impl Into<Operand>) -> Instruction {
+        Instruction::Compare {
+            left,
+            right: right.into(),
+        }
+    }
+
+    pub fn ldr(dest: Register, address: Address) -> Instruction {
+        Instruction::Load {
+            condition: Condition::Al,
+            dest,
+            address,
+        }
+    }
+
+    pub fn str(src: Register, address: Address) -> Instruction {
+        Instruction::Store {
+            condition: Condition::Al,
+            src,
+            address,
+        }
+    }
+
+    pub fn b(target: &str) -> Instruction {
+        Instruction::Branch {
+            condition: Condition::Al,
+            target: target.to_string(),
+        }
+    }
+
+    pub fn bl(target: &str) -> Instruction {
+        Instruction::BranchLink(target.to_string())
+    }
+
+    /// The same instruction, run only under `condition`.
+    ///
+    /// # Panics
+    ///
+    /// If the instruction is one that is never conditional here, like `cmp`
+    /// or `push`.
+    pub fn when(mut self, new: Condition) -> Instruction {
+        match &mut self {
+            Instruction::Move { condition, .. }
+            | Instruction::MoveNot { condition, .. }
+            | Instruction::Arithmetic { condition, .. }
+            | Instruction::Load { condition, .. }
+            | Instruction::Store { condition, .. }
+            | Instruction::Branch { condition, .. } => *condition = new,
+            _ => panic!("{} can't be conditional", self),
+        }
+        self
+    }
+
+    pub fn condition(&self) -> Condition {
+        match self {
+            Instruction::Move { condition, .. }
+            | Instruction::MoveNot { condition, .. }
+            | Instruction::Arithmetic { condition, .. }
+            | Instruction::Load { condition, .. }
+            | Instruction::Store { condition, .. }
+            | Instruction::Branch { condition, .. } => *condition,
+            _ => Condition::Al,
+        }
+    }
+
+    /// Whether execution never goes on to the next instruction.
+    pub fn ends_flow(&self) -> bool {
+        match self {
+            Instruction::Branch { condition, .. } => *condition == Condition::Al,
+            Instruction::BranchExchange(_) => true,
+            Instruction::Pop(registers) => registers.contains(&PC),
+            _ => false,
+        }
+    }
+}
+
+fn register_list(registers: &[Register]) -> String {
+    let names: Vec<String> = registers.iter().map(Register::to_string).collect();
+    format!("{{{}}}", names.join(", "))
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let c = self.condition().suffix();
+        match self {
+            Instruction::Move { dest, src, .. } => write!(f, "mov{} {}, {}", c, dest, src),
+            Instruction::MoveNot { dest, src, .. } => write!(f, "mvn{} {}, {}", c, dest, src),
+            Instruction::Arithmetic {
+                op,
+                dest,
+                left,
+                right,
+                ..
+            } => write!(f, "{}{} {}, {}, {}", op.name(), c, dest, left, right),
+            Instruction::Multiply { dest, left, right } => {
+                write!(f, "mul {}, {}, {}", dest, left, right)
+            }
+            Instruction::Divide { dest, left, right } => {
+                write!(f, "udiv {}, {}, {}", dest, left, right)
+            }
+            Instruction::Compare { left, right } => write!(f, "cmp {}, {}", left, right),
+            Instruction::Load { dest, address, .. } => write!(f, "ldr{} {}, {}", c, dest, address),
+            Instruction::Store { src, address, .. } => write!(f, "str{} {}, {}", c, src, address),
+            Instruction::LoadByte { dest, address } => write!(f, "ldrb {}, {}", dest, address),
+            Instruction::LoadConstant { dest, value } => write!(f, "ldr {}, ={}", dest, value),
+            Instruction::LoadAddress { dest, label } => write!(f, "adr {}, {}", dest, label),
+            Instruction::Push(registers) => write!(f, "push {}", register_list(registers)),
+            Instruction::Pop(registers) => write!(f, "pop {}", register_list(registers)),
+            Instruction::Branch { target, .. } => write!(f, "b{} {}", c, target),
+            Instruction::BranchLink(target) => write!(f, "bl {}", target),
+            Instruction::BranchExchange(register) => write!(f, "bx {}", register),
+            Instruction::SupervisorCall(number) => write!(f, "svc #{}", number),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Directive {
+    /// Makes a function visible to the linker.
+    Global(String),
+    /// Aligns the next line to 2 to the power of this many bytes.
+    Align(u32),
+    /// A string followed by a zero byte.
+    Asciz(String),
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Directive::Global(name) => write!(f, ".global {}", name),
+            Directive::Align(power) => write!(f, ".align {}", power),
+            Directive::Asciz(text) => {
+                write!(f, ".asciz \"")?;
+                for c in text.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\0' => write!(f, "\\0")?,
+                        '\\' | '"' => write!(f, "\\{}", c)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Line {
+    Label(String),
+    Directive(Directive),
+    Instruction(Instruction),
+}
+
+impl From<Instruction> for Line {
+    fn from(instruction: Instruction) -> Line {
+        Line::Instruction(instruction)
+    }
+}
+
+impl From<Directive> for Line {
+    fn from(directive: Directive) -> Line {
+        Line::Directive(directive)
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Label(name) => write!(f, "{}:", name),
+            // Functions are set apart by a blank line.
+            Line::Directive(directive @ Directive::Global(_)) => write!(f, "\n{}", directive),
+            Line::Directive(directive) => write!(f, "\t{}", directive),
+            Line::Instruction(instruction) => write!(f, "\t{}", instruction),
+        }
+    }
+}
+
+/// Renders lines as assembly source.
+pub fn print(lines: &[Line]) -> String {
+    let mut out = String::new();
+    for line in lines {
+        out.push_str(&line.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+fn error(line: &str, message: &str) -> CompileError {
+    CompileError::SyntaxError(format!("{} in `{}`", message, line))
+}
+
+fn register(text: &str) -> Option<Register> {
+    match text {
+        "fp" => Some(FP),
+        "ip" => Some(IP),
+        "sp" => Some(SP),
+        "lr" => Some(LR),
+        "pc" => Some(PC),
+        _ => text
+            .strip_prefix('r')?
+            .parse()
+            .ok()
+            .filter(|n| *n < 16)
+            .map(Register),
+    }
+}
+
+/// Parses a number as written after `#` or `=`, in decimal, in hex with
+/// `0x`, or as a character in quotes. Negative numbers wrap around.
+fn number(text: &str) -> Option<u32> {
+    if let Some(negated) = text.strip_prefix('-') {
+        return number(negated).map(u32::wrapping_neg);
+    }
+    if let Some(hex) = text.strip_prefix("0x") {
+        return u32::from_str_radix(hex, 16).ok();
+    }
+    if let Some(quoted) = text.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        let mut chars = quoted.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c as u32),
+            _ => None,
+        };
+    }
+    text.parse().ok()
+}
+
+fn shift(text: &str) -> Option<(Shift, u8)> {
+    let (name, amount) = text.split_once(char::is_whitespace)?;
+    let shift = [Shift::Lsl, Shift::Lsr, Shift::Asr, Shift::Ror]
+        .into_iter()
+        .find(|shift| shift.name() == name)?;
+    let amount = number(amount.trim().strip_prefix('#')?)?;
+    Some((shift, u8::try_from(amount).ok().filter(|a| *a < 32)?))
+}
+
+/// An operand, with the shift after it if there is one.
+fn operand(operands: &[String]) -> Option<Operand> {
+    match operands {
+        [immediate] if immediate.starts_with('#') => {
+            Some(Operand::Immediate(number(&immediate[1..])?))
+        }
+        [register_name] => Some(Operand::Register(register(register_name)?)),
+        [register_name, shifted] => {
+            let (shift, amount) = shift(shifted)?;
+            Some(Operand::Shifted(register(register_name)?, shift, amount))
+        }
+        _ => None,
+    }
+}
+
+fn address(text: &str) -> Option<Address> {
+    let inner = text.strip_prefix('[')?.strip_suffix(']')?;
+    let parts = split_operands(inner);
+    let base = register(parts.first()?)?;
+    match &parts[1..] {
+        [] => Some(Address::Offset(base, 0)),
+        [offset] if offset.starts_with('#') => {
+            Some(Address::Offset(base, number(&offset[1..])? as i32))
+        }
+        [index] => Some(Address::Indexed(base, register(index)?, 0)),
+        [index, shifted] => match shift(shifted)? {
+            (Shift::Lsl, amount) => Some(Address::Indexed(base, register(index)?, amount)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn register_list_of(text: &str) -> Option<Vec<Register>> {
+    let inner = text.strip_prefix('{')?.strip_suffix('}')?;
+    inner.split(',').map(|name| register(name.trim())).collect()
+}
+
+/// Splits operands at the commas outside of `{}` and `[]`.
+fn split_operands(text: &str) -> Vec<String> {
+    let mut operands = vec![];
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in text.chars() {
+        match c {
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        operands.push(current.trim().to_string());
+    }
+    operands
+}
+
+const MNEMONICS: [&str; 24] = [
+    "push", "pop", "udiv", "mul", "mov", "mvn", "add", "sub", "rsb", "and", "orr", "eor", "lsl",
+    "lsr", "asr", "cmp", "ldrb", "ldr", "str", "adr", "svc", "bl", "bx", "b",
+];
+
+fn instruction(line: &str) -> Result<Instruction, CompileError> {
+    let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let (mnemonic, condition) = MNEMONICS
+        .iter()
+        .find_map(|mnemonic| {
+            let condition = Condition::from_suffix(word.strip_prefix(mnemonic)?)?;
+            Some((*mnemonic, condition))
+        })
+        .ok_or_else(|| error(line, "Unknown instruction"))?;
+    let operands = split_operands(rest);
+    let invalid = || error(line, "Invalid operands");
+    let reg = |i: usize| {
+        operands
+            .get(i)
+            .and_then(|o| register(o))
+            .ok_or_else(invalid)
+    };
+    let instruction = match (mnemonic, operands.len()) {
+        ("mov" | "mvn", _) => {
+            let src = operand(&operands[1.min(operands.len())..]).ok_or_else(invalid)?;
+            match mnemonic {
+                "mov" => Instruction::mov(reg(0)?, src),
+                _ => Instruction::MoveNot {
+                    condition: Condition::Al,
+                    dest: reg(0)?,
+                    src,
+                },
+            }
+        }
+        ("lsl" | "lsr" | "asr", 3) => {
+            let amount = operands[2].strip_prefix('#').and_then(number);
+            let shift = match mnemonic {
+                "lsl" => Shift::Lsl,
+                "lsr" => Shift::Lsr,
+                _ => Shift::Asr,
+            };
+            match amount.and_then(|amount| u8::try_from(amount).ok()) {
+                Some(amount) if amount < 32 => {
+                    Instruction::mov(reg(0)?, Operand::Shifted(reg(1)?, shift, amount))
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        ("add" | "sub" | "rsb" | "and" | "orr" | "eor", _) if operands.len() >= 3 => {
+            let op = match mnemonic {
+                "add" => ArithmeticOp::Add,
+                "sub" => ArithmeticOp::Sub,
+                "rsb" => ArithmeticOp::Rsb,
+                "and" => ArithmeticOp::And,
+                "orr" => ArithmeticOp::Orr,
+                _ => ArithmeticOp::Eor,
+            };
+            let right = operand(&operands[2..]).ok_or_else(invalid)?;
+            Instruction::arithmetic(op, reg(0)?, reg(1)?, right)
+        }
+        ("mul", 3) => Instruction::Multiply {
+            dest: reg(0)?,
+            left: reg(1)?,
+            right: reg(2)?,
+        },
+        ("udiv", 3) => Instruction::Divide {
+            dest: reg(0)?,
+            left: reg(1)?,
+            right: reg(2)?,
+        },
+        ("cmp", _) if operands.len() >= 2 => {
+            Instruction::cmp(reg(0)?, operand(&operands[1..]).ok_or_else(invalid)?)
+        }
+        ("ldr", 2) => match operands[1].strip_prefix('=') {
+            Some(value) => Instruction::LoadConstant {
+                dest: reg(0)?,
+                value: number(value).ok_or_else(invalid)?,
+            },
+            None => Instruction::ldr(reg(0)?, address(&operands[1]).ok_or_else(invalid)?),
+        },
+        ("str", 2) => Instruction::str(reg(0)?, address(&operands[1]).ok_or_else(invalid)?),
+        ("ldrb", 2) => Instruction::LoadByte {
+            dest: reg(0)?,
+            address: address(&operands[1]).ok_or_else(invalid)?,
+        },
+        ("adr", 2) => Instruction::LoadAddress {
+            dest: reg(0)?,
+            label: operands[1].clone(),
+        },
+        ("push", 1) => Instruction::Push(register_list_of(&operands[0]).ok_or_else(invalid)?),
+        ("pop", 1) => Instruction::Pop(register_list_of(&operands[0]).ok_or_else(invalid)?),
+        ("b", 1) => Instruction::b(&operands[0]),
+        ("bl", 1) => Instruction::bl(&operands[0]),
+        ("bx", 1) => Instruction::BranchExchange(reg(0)?),
+        ("svc", 1) => Instruction::SupervisorCall(
+            operands[0]
+                .strip_prefix('#')
+                .and_then(number)
+                .ok_or_else(invalid)?,
+        ),
+        _ => return Err(invalid()),
+    };
+    if condition == Condition::Al {
+        return Ok(instruction);
+    }
+    match instruction {
+        Instruction::Move { .. }
+        | Instruction::MoveNot { .. }
+        | Instruction::Arithmetic { .. }
+        | Instruction::Load { .. }
+        | Instruction::Store { .. }
+        | Instruction::Branch { .. } => Ok(instruction.when(condition)),
+        _ => Err(error(line, "Unsupported condition")),
+    }
+}
+
+fn directive(line: &str) -> Result<Directive, CompileError> {
+    let (name, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let argument = argument.trim();
+    match name {
+        ".global" | ".globl" if !argument.is_empty() => Ok(Directive::Global(argument.to_string())),
+        ".align" => argument
+            .parse()
+            .map(Directive::Align)
+            .map_err(|_| error(line, "Invalid alignment")),
+        ".asciz" => {
+            let quoted = argument
+                .strip_prefix('"')
+                .and_then(|a| a.strip_suffix('"'))
+                .ok_or_else(|| error(line, "Expected a string"))?;
+            let mut text = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                text.push(match c {
+                    '\\' => match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('0') => '\0',
+                        Some(c @ ('\\' | '"' | '\'')) => c,
+                        _ => return Err(error(line, "Unknown escape")),
+                    },
+                    c => c,
+                });
+            }
+            Ok(Directive::Asciz(text))
+        }
+        _ => Err(error(line, "Unknown directive")),
+    }
+}
+
+/// The part of a line before any `@` comment outside of a string.
+fn uncommented(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '@' if !quoted => return &line[..i],
+            '"' if !escaped => quoted = !quoted,
+            _ => {}
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    line
+}
+
+/// Parses assembly in the syntax `print` writes, with `@` comments.
+pub fn parse(assembly: &str) -> Result<Vec<Line>, CompileError> {
+    assembly
+        .lines()
+        .map(|line| uncommented(line).trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let label = line
+                .strip_suffix(':')
+                .filter(|label| !label.contains(char::is_whitespace));
+            if let Some(label) = label {
+                Ok(Line::Label(label.to_string()))
+            } else if line.starts_with('.') {
+                Ok(Line::Directive(directive(line)?))
+            } else {
+                Ok(Line::Instruction(instruction(line)?))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::ProgramGenerator;
+    use crate::{arm_code_generator, arm_emitter};
+
+    #[test]
+    fn printing() {
+        let lines: Vec<Line> = vec![
+            Directive::Global("main".to_string()).into(),
+            Line::Label("main".to_string()),
+            Instruction::Push(vec![FP, LR]).into(),
+            Instruction::mov(R0, Operand::Immediate('T' as u32))
+                .when(Condition::Eq)
+                .into(),
+            Instruction::mov(R0, Operand::Shifted(R1, Shift::Lsl, 2)).into(),
+            Instruction::ldr(R0, Address::Indexed(R1, R0, 2))
+                .when(Condition::Lo)
+                .into(),
+            Instruction::str(R0, Address::Offset(FP, -16)).into(),
+            Instruction::LoadConstant {
+                dest: IP,
+                value: 4380,
+            }
+            .into(),
+            Directive::Asciz("%d\n".to_string()).into(),
+            Instruction::b(".L1").when(Condition::Ge).into(),
+            Instruction::LoadByte {
+                dest: R0,
+                address: Address::Offset(R4, 1),
+            }
+            .into(),
+            Instruction::SupervisorCall(0).into(),
+            Instruction::Pop(vec![FP, PC]).into(),
+        ];
+        assert_eq!(
+            "
+.global main
+main:
+	push {fp, lr}
+	moveq r0, #84
+	mov r0, r1, lsl #2
+	ldrlo r0, [r1, r0, lsl #2]
+	str r0, [fp, #-16]
+	ldr ip, =4380
+	.asciz \"%d\\n\"
+	bge .L1
+	ldrb r0, [r4, #1]
+	svc #0
+	pop {fp, pc}
+",
+            print(&lines)
+        );
+    }
+
+    #[test]
+    fn parsing() {
+        let lines = parse(
+            "main:
+                push {fp,lr}
+                moveq r0, #'T'
+                lsllo r0, r0, #2
+                ldr r2, [r1]
+                ldr r0, =-1
+                bls .L2 @ a comment
+                ldrb r0, [r4]
+                svc #0
+                .align 2
+                .asciz \"a\\\"b@\\n\"",
+        )
+        .expect("Invalid assembly");
+        assert_eq!(
+            vec![
+                Line::Label("main".to_string()),
+                Instruction::Push(vec![FP, LR]).into(),
+                Instruction::mov(R0, Operand::Immediate(84))
+                    .when(Condition::Eq)
+                    .into(),
+                Instruction::mov(R0, Operand::Shifted(R0, Shift::Lsl, 2))
+                    .when(Condition::Lo)
+                    .into(),
+                Instruction::ldr(R2, Address::Offset(R1, 0)).into(),
+                Instruction::LoadConstant {
+                    dest: R0,
+                    value: u32::MAX
+                }
+                .into(),
+                Instruction::b(".L2").when(Condition::Ls).into(),
+                Instruction::LoadByte {
+                    dest: R0,
+                    address: Address::Offset(R4, 0)
+                }
+                .into(),
+                Instruction::SupervisorCall(0).into(),
+                Directive::Align(2).into(),
+                Directive::Asciz("a\"b@\n".to_string()).into(),
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for invalid in [
+            "frob r0, r1",
+            "mov r0",
+            "mov r16, #1",
+            "ldr r0, [r1, #x]",
+            "push {r0, r99}",
+            "bleq f",
+            "cmpeq r0, r1",
+            ".section .text",
+            ".asciz \"\\q\"",
+        ] {
+            assert!(
+                matches!(parse(invalid), Err(CompileError::SyntaxError(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn conditions() {
+        for condition in Condition::ALL {
+            assert_eq!(condition, condition.inverse().inverse());
+            assert_eq!(Some(condition), Condition::from_suffix(condition.suffix()));
+        }
+        assert_eq!(Condition::Ge, Condition::Lt.inverse());
+        assert_eq!(Condition::Lo, Condition::Hs.inverse());
+        assert_eq!(Condition::Le, Condition::Gt.inverse());
+    }
+
+    #[test]
+    fn immediates() {
+        for value in [0, 255, 0x3fc, 0xff000000, 0xf000000f] {
+            assert!(is_immediate(value), "{:#x}", value);
+        }
+        for value in [257, 4380, 0x1fe00001, u32::MAX] {
+            assert!(!is_immediate(value), "{:#x}", value);
+        }
+    }
+
+    #[test]
+    fn generated_code_round_trips() {
+        for seed in 0..100 {
+            let ast = ProgramGenerator::new(seed).program();
+            for lines in [
+                arm_code_generator::generate_lines(&ast),
+                arm_emitter::generate_lines(&ast),
+            ] {
+                let lines = lines.expect("Code generation failed");
+                assert_eq!(lines, parse(&print(&lines)).expect("Invalid assembly"));
+            }
+        }
+    }
+}
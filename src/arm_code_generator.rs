@@ -1,71 +1,243 @@
+use crate::arm::{
+    self, Address, Condition, Directive, Instruction, Line, Operand, Register, FP, IP, LR, PC, R0,
+    R1, R2, R3, R4, SP,
+};
 use crate::ast::AST;
+use crate::error::CompileError;
+use crate::parser::{nested_statements, parse};
 use crate::visitor::{AstVisitor, Visitor};
 use std::collections::HashMap;
-use std::io::Write;
 
-struct ArmCodeGenerator {
+#[derive(Default)]
+pub struct ArmCodeGenerator {
     pub(crate) locals: HashMap<String, isize>,
     pub(crate) next_local_offset: isize,
-    label_counter: i16,
-}
-
-impl Default for ArmCodeGenerator {
-    fn default() -> ArmCodeGenerator {
-        ArmCodeGenerator {
-            locals: Default::default(),
-            next_local_offset: 0,
-            label_counter: 0,
-        }
-    }
+    label_counter: usize,
+    /// The function being generated, its number of parameters and the label
+    /// following its prologue, if it calls itself in tail position.
+    self_tail_call: Option<(String, usize, String)>,
 }
 
 impl ArmCodeGenerator {
     fn visit_infix_operands(
         &mut self,
-        left: &Box<AST>,
-        right: &Box<AST>,
-        writer: &mut dyn Write,
-    ) -> () {
-        left.visit(self, writer);
-        writeln!(writer, "\tpush {{r0, ip}}");
-        right.visit(self, writer);
-        writeln!(writer, "\tpop {{r1, ip}}");
-    }
-    fn emit_fn_prologue(&mut self, writer: &mut dyn Write) -> std::io::Result<()> {
-        writeln!(writer, "\tpush {{fp, lr}}")?;
-        writeln!(writer, "\tmov fp, sp")?;
-        writeln!(writer, "\tpush {{r0, r1, r2, r3}}")
-    }
-    fn emit_fn_epilogue(&mut self, writer: &mut dyn Write) -> std::io::Result<()> {
-        writeln!(writer, "\tmov sp, fp")?;
+        left: &AST,
+        right: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        left.visit(self, writer)?;
+        writer.push(Instruction::Push(vec![R0, IP]).into());
+        right.visit(self, writer)?;
+        writer.push(Instruction::Pop(vec![R1, IP]).into());
+        Ok(())
+    }
+    fn emit_fn_prologue(&mut self, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        writer.push(Instruction::Push(vec![FP, LR]).into());
+        writer.push(Instruction::mov(FP, SP).into());
+        writer.push(Instruction::Push(vec![R0, R1, R2, R3]).into());
+        Ok(())
+    }
+    fn emit_fn_epilogue(&mut self, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        writer.push(Instruction::mov(SP, FP).into());
         // .We set r0 and thus our return value to 0.
         // This is to mimic the fact that JavaScript functions return undefined
         // when there’s no explicit return
-        writeln!(writer, "\tmov r0, #0")?;
-        writeln!(writer, "\tpop {{ fp, pc }}")
+        writer.push(Instruction::mov(R0, Operand::Immediate(0)).into());
+        writer.push(Instruction::Pop(vec![FP, PC]).into());
+        Ok(())
+    }
+    /// Evaluates the arguments of a call into r0-r3.
+    fn emit_arguments(&mut self, args: &[AST], writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        match args.len() {
+            0 => Ok(()),
+            1 => args[0].visit(self, writer),
+            2..=4 => {
+                // allocate enough stack space for up to four arguments (16 bytes)
+                // We do that by subtracting from the stack
+                // pointer since the stack grows from higher memory addresses to
+                // lower.
+                writer.push(Instruction::sub(SP, SP, Operand::Immediate(16)).into());
+                for (i, arg) in args.iter().enumerate() {
+                    arg.visit(self, writer)?;
+                    // We multiply by four to convert array indexes 0, 1, 2, 3 into
+                    // stack offsets in bytes: 0, 4, 8, 12.
+                    writer.push(Instruction::str(R0, Address::Offset(SP, 4 * i as i32)).into());
+                }
+                writer.push(Instruction::Pop(vec![R0, R1, R2, R3]).into());
+                Ok(())
+            }
+            _ => Err(CompileError::CodeGenError(
+                "More than 4 arguments are not supported in function calls".to_string(),
+            )),
+        }
+    }
+
+    /// Generates `return callee(args)` without growing the stack. A function
+    /// calling itself stores the arguments over its parameters, drops its
+    /// locals and jumps back to the start of its body. Any other call is a
+    /// jump to the callee once this frame is gone, so that the callee returns
+    /// straight to our caller.
+    fn emit_tail_call(
+        &mut self,
+        callee: &str,
+        args: &[AST],
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        self.emit_arguments(args, writer)?;
+        match &self.self_tail_call {
+            Some((name, parameters, label)) if name == callee && *parameters == args.len() => {
+                for i in 0..args.len() {
+                    let parameter = Address::Offset(FP, 4 * i as i32 - 16);
+                    writer.push(Instruction::str(Register(i as u8), parameter).into());
+                }
+                writer.push(Instruction::sub(SP, FP, Operand::Immediate(16)).into());
+                writer.push(Instruction::b(label).into());
+            }
+            _ => {
+                writer.push(Instruction::mov(SP, FP).into());
+                writer.push(Instruction::Pop(vec![FP, LR]).into());
+                writer.push(Instruction::b(callee).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Branches to `label` when `condition` is `when`. A comparison branches
+    /// on its own `cmp` and `!` flips `when`, so neither puts 0 or 1 in r0
+    /// first.
+    fn emit_branch(
+        &mut self,
+        condition: &AST,
+        when: bool,
+        label: &str,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let (left, right, holds) = match condition {
+            AST::Not(term) => return self.emit_branch(term, !when, label, writer),
+            AST::Equal { left, right } => (left, right, Condition::Eq),
+            AST::NotEqual { left, right } => (left, right, Condition::Ne),
+            AST::LessThan { left, right } => (left, right, Condition::Lt),
+            AST::GreaterThan { left, right } => (left, right, Condition::Gt),
+            AST::LessThanEqual { left, right } => (left, right, Condition::Le),
+            AST::GreaterThanEqual { left, right } => (left, right, Condition::Ge),
+            _ => {
+                condition.visit(self, writer)?;
+                writer.push(Instruction::cmp(R0, Operand::Immediate(0)).into());
+                let taken = if when { Condition::Ne } else { Condition::Eq };
+                writer.push(Instruction::b(label).when(taken).into());
+                return Ok(());
+            }
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::cmp(R1, R0).into());
+        let taken = if when { holds } else { holds.inverse() };
+        writer.push(Instruction::b(label).when(taken).into());
+        Ok(())
     }
+
     fn new_label(&mut self) -> String {
         self.label_counter += 1;
         format!(".L{}", self.label_counter)
     }
+    fn local_offset(&self, name: &str) -> Result<isize, CompileError> {
+        self.locals
+            .get(name)
+            .copied()
+            .ok_or_else(|| CompileError::CodeGenError(format!("Undefined variable: {}", name)))
+    }
+}
+
+/// Sets r0 to 1 if comparing `left` with `right` gives `condition`, and to 0
+/// otherwise.
+fn compare(left: Register, right: Operand, condition: Condition) -> [Line; 3] {
+    [
+        Instruction::cmp(left, right).into(),
+        Instruction::mov(R0, Operand::Immediate(1))
+            .when(condition)
+            .into(),
+        Instruction::mov(R0, Operand::Immediate(0))
+            .when(condition.inverse())
+            .into(),
+    ]
+}
+
+/// Loads element r0 of the array at r1 into r0, or 0 if r0 is out of
+/// bounds. Clobbers r1 and r2.
+pub(crate) fn array_load() -> [Instruction; 5] {
+    [
+        Instruction::ldr(R2, Address::Offset(R1, 0)),
+        Instruction::cmp(R0, R2),
+        Instruction::mov(R0, Operand::Immediate(0)).when(Condition::Hs),
+        Instruction::add(R1, R1, Operand::Immediate(4)).when(Condition::Lo),
+        Instruction::ldr(R0, Address::Indexed(R1, R0, 2)).when(Condition::Lo),
+    ]
+}
+
+/// Stores `value` in element `index` of the array at `array`. Elements further
+/// than `str` reaches are addressed through `ip`.
+pub(crate) fn element_store(value: Register, array: Register, index: usize) -> Vec<Instruction> {
+    let offset = 4 * (index as u32 + 1);
+    if offset <= 4095 {
+        return vec![Instruction::str(
+            value,
+            Address::Offset(array, offset as i32),
+        )];
+    }
+    vec![
+        Instruction::LoadConstant {
+            dest: IP,
+            value: offset,
+        },
+        Instruction::add(IP, array, IP),
+        Instruction::str(value, Address::Offset(IP, 0)),
+    ]
+}
+
+/// Whether the body of function `name` contains `return name(...)`, outside
+/// of the functions declared in it.
+pub(crate) fn calls_itself_in_tail_position(name: &str, body: &AST) -> bool {
+    match body {
+        AST::Return { term } => matches!(term.as_ref(), AST::Call { callee, .. } if callee == name),
+        AST::Function { .. } => false,
+        _ => nested_statements(body)
+            .into_iter()
+            .any(|statement| calls_itself_in_tail_position(name, statement)),
+    }
 }
-impl Visitor<(), &mut dyn Write> for ArmCodeGenerator {
-    fn visit_assert(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+
+/// Generates ARM code for an already parsed program.
+pub fn generate_lines(ast: &AST) -> Result<Vec<Line>, CompileError> {
+    let mut generator: ArmCodeGenerator = Default::default();
+    let mut lines = Vec::new();
+    ast.visit(&mut generator, &mut lines)?;
+    Ok(lines)
+}
+
+/// Generates ARM assembly for an already parsed program.
+pub fn generate(ast: &AST) -> Result<String, CompileError> {
+    Ok(arm::print(&generate_lines(ast)?))
+}
+
+/// Parses `source` and generates ARM assembly for it.
+pub fn compile(source: &str) -> Result<String, CompileError> {
+    generate(&parse(source)?)
+}
+impl Visitor<(), Vec<Line>> for ArmCodeGenerator {
+    fn visit_assert(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Assert(condition) = node else {
             panic!("Expected Assert node, got: {:?}", node)
         };
         condition.visit(self, writer)?;
-        write!(writer, "\t")?;
-        writeln!(
-            writer,
-            r#"cmp r0, #1
-            moveq r0, #'T'
-            movne r0, #'F'
-            bl putchar"#
-        )
+        writer.push(Instruction::cmp(R0, Operand::Immediate(1)).into());
+        let t = Instruction::mov(R0, Operand::Immediate('T' as u32));
+        writer.push(t.when(Condition::Eq).into());
+        let f = Instruction::mov(R0, Operand::Immediate('F' as u32));
+        writer.push(f.when(Condition::Ne).into());
+        writer.push(Instruction::bl("putchar").into());
+        Ok(())
     }
 
-    fn visit_print(&mut self, node: &AST, w: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_print(&mut self, node: &AST, w: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Print(value) = node else {
             panic!("Expected Print node, got: {:?}", node)
         };
@@ -79,278 +251,276 @@ impl Visitor<(), &mut dyn Write> for ArmCodeGenerator {
         value.visit(self, w)?;
 
         // Save the value to r4 (callee-saved register)
-        writeln!(w, "\tpush {{r4, ip}}")?;
-        writeln!(w, "\tmov r4, r0")?;
+        w.push(Instruction::Push(vec![R4, IP]).into());
+        w.push(Instruction::mov(R4, R0).into());
 
         // Branch around the format string data
-        writeln!(w, "\tb {}", skip_label)?;
-        writeln!(w, "\t.align 2")?;
-        writeln!(w, "{}:", fmt_label)?;
-        writeln!(w, "\t.asciz \"%d\\n\"")?;
-        writeln!(w, "\t.align 2")?;
-        writeln!(w, "{}:", skip_label)?;
+        w.push(Instruction::b(&skip_label).into());
+        w.push(Directive::Align(2).into());
+        w.push(Line::Label(fmt_label.clone()));
+        w.push(Directive::Asciz("%d\n".to_string()).into());
+        w.push(Directive::Align(2).into());
+        w.push(Line::Label(skip_label));
 
         // Load the format string address into r0 using adr (PC-relative)
-        writeln!(w, "\tadr r0, {}", fmt_label)?;
+        w.push(
+            Instruction::LoadAddress {
+                dest: R0,
+                label: fmt_label,
+            }
+            .into(),
+        );
 
         // Move the saved value to r1 (second argument for printf)
-        writeln!(w, "\tmov r1, r4")?;
+        w.push(Instruction::mov(R1, R4).into());
 
         // Call printf
-        writeln!(w, "\tbl printf")?;
+        w.push(Instruction::bl("printf").into());
 
         // Restore registers
-        writeln!(w, "\tpop {{r4, ip}}")?;
+        w.push(Instruction::Pop(vec![R4, IP]).into());
 
         Ok(())
     }
 
-    fn visit_array_length(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_array_length(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
         let AST::ArrayLength(array) = node else {
             panic!("Expected Assert node, got: {:?}", node)
         };
         array.visit(self, writer)?;
-        writeln!(writer, "\tldr r0, [r0, #0]")
+        writer.push(Instruction::ldr(R0, Address::Offset(R0, 0)).into());
+        Ok(())
     }
 
-    fn visit_array_lookup(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_array_lookup(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
         let AST::ArrayLookup { array, index } = node else {
             panic!("Expected ArrayLookup node, got: {:?}", node)
         };
         array.visit(self, writer)?;
-        writeln!(writer, "\tpush {{r0, ip}}");
+        writer.push(Instruction::Push(vec![R0, IP]).into());
         index.visit(self, writer)?;
-        writeln!(writer, "\tpop {{r1, ip}}");
-        writeln!(writer, "\tldr r2, [r1]");
-        writeln!(writer, "\tcmp r0, r2");
-        writeln!(writer, "\tmovhs r0, #0");
-        writeln!(writer, "\taddlo r1, r1, #4");
-        writeln!(writer, "\tlsllo r0, r0, #2");
-        writeln!(writer, "\tldrlo r0, [r1, r0]")
+        writer.push(Instruction::Pop(vec![R1, IP]).into());
+        writer.extend(array_load().into_iter().map(Line::from));
+        Ok(())
     }
 
-    fn visit_array_literal(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_array_literal(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
         let AST::ArrayLiteral(array_items) = node else {
             panic!("Expected ArrayLiteral node, got: {:?}", node)
         };
         let len = array_items.len();
-        writeln!(writer, "\tldr r0, ={}", 4 * (len + 1));
-        writeln!(writer, "\tbl malloc");
-        writeln!(writer, "\tpush {{r4, ip}}");
-        writeln!(writer, "\tmov r4, r0");
-        writeln!(writer, "\tldr r0, ={}", len);
-        writeln!(writer, "\tstr r0, [r4]");
+        let size = 4 * (len as u32 + 1);
+        writer.push(
+            Instruction::LoadConstant {
+                dest: R0,
+                value: size,
+            }
+            .into(),
+        );
+        writer.push(Instruction::bl("malloc").into());
+        writer.push(Instruction::Push(vec![R4, IP]).into());
+        writer.push(Instruction::mov(R4, R0).into());
+        let value = len as u32;
+        writer.push(Instruction::LoadConstant { dest: R0, value }.into());
+        writer.push(Instruction::str(R0, Address::Offset(R4, 0)).into());
         for (i, item) in array_items.iter().enumerate() {
             item.visit(self, writer)?;
-            writeln!(writer, "\tstr r0, [r4, #{}]", 4 * (i + 1));
+            writer.extend(element_store(R0, R4, i).into_iter().map(Line::from));
         }
 
-        writeln!(writer, "\tmov r0, r4");
-        writeln!(writer, "\tpop {{r4, ip}}")
+        writer.push(Instruction::mov(R0, R4).into());
+        writer.push(Instruction::Pop(vec![R4, IP]).into());
+        Ok(())
     }
 
-    fn visit_boolean(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_boolean(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Boolean(value) = node else {
             panic!("Expected ArrayLiteral node, got: {:?}", node)
         };
-        writeln!(writer, "\tmov r0, #{}", if *value { 1 } else { 0 })
+        let value = Operand::Immediate(if *value { 1 } else { 0 });
+        writer.push(Instruction::mov(R0, value).into());
+        Ok(())
     }
 
-    fn visit_number(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_number(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Number(number) = node else {
             panic!("Expected ArrayLiteral node, got: {:?}", node)
         };
-        writeln!(writer, "\tldr r0, ={}", *number)
+        let value = *number as u32;
+        writer.push(Instruction::LoadConstant { dest: R0, value }.into());
+        Ok(())
     }
 
-    fn visit_id(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_id(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Id(name) = node else {
             panic!("Expected ArrayLiteral node, got: {:?}", node)
         };
 
-        let offset = self
-            .locals
-            .get(name)
-            .unwrap_or_else(|| panic!("Undefined variable: {}", name));
-        writeln!(writer, "\tldr r0, [fp, #{}]", offset)
+        let offset = self.local_offset(name)?;
+        writer.push(Instruction::ldr(R0, Address::Offset(FP, offset as i32)).into());
+        Ok(())
     }
 
-    fn visit_not(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_not(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Not(term) = node else {
             panic!("Expected Not node, got: {:?}", node)
         };
         term.visit(self, writer)?;
-        writeln!(
-            writer,
-            r#" cmp r0, #0
-    moveq r0, #1
-    movne r0, #0"#
-        )
+        writer.extend(compare(R0, Operand::Immediate(0), Condition::Eq));
+        Ok(())
     }
 
-    fn visit_equal(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_equal(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Equal { left, right } = node else {
             panic!("Expected Not node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(
-            writer,
-            r#"cmp r0, r1
-    moveq r0, #1
-    movne r0, #0"#
-        )
+        self.visit_infix_operands(left, right, writer)?;
+        writer.extend(compare(R0, R1.into(), Condition::Eq));
+        Ok(())
     }
 
-    fn visit_not_equal(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_not_equal(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::NotEqual { left, right } = node else {
             panic!("Expected NotEqual node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(
-            writer,
-            r#"cmp r0, r1
-    moveq r0, #0
-    movne r0, #1"#
-        )
+        self.visit_infix_operands(left, right, writer)?;
+        writer.extend(compare(R0, R1.into(), Condition::Ne));
+        Ok(())
     }
 
-    fn visit_add(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_add(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Add { left, right } = node else {
             panic!("Expected NotEqual node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(writer, "add r0, r1, r0")
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::add(R0, R1, R0).into());
+        Ok(())
     }
 
-    fn visit_subtract(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_subtract(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Subtract { left, right } = node else {
             panic!("Expected NotEqual node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(writer, "sub r0, r1, r0")
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::sub(R0, R1, R0).into());
+        Ok(())
     }
 
-    fn visit_multiply(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_multiply(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Multiply { left, right } = node else {
             panic!("Expected Multiply node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(writer, "mul r0, r0, r1")
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(
+            Instruction::Multiply {
+                dest: R0,
+                left: R0,
+                right: R1,
+            }
+            .into(),
+        );
+        Ok(())
     }
 
-    fn visit_divide(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_divide(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Divide { left, right } = node else {
             panic!("Expected Divide node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(writer, "udiv r0, r1, r0")
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(
+            Instruction::Divide {
+                dest: R0,
+                left: R1,
+                right: R0,
+            }
+            .into(),
+        );
+        Ok(())
     }
 
-    fn visit_less_than(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_less_than(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::LessThan { left, right } = node else {
             panic!("Expected Divide node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(
-            writer,
-            r#"cmp r1, r0
-    movlt r0, #1
-    movge r0, #0"#
-        )
+        self.visit_infix_operands(left, right, writer)?;
+        writer.extend(compare(R1, R0.into(), Condition::Lt));
+        Ok(())
     }
 
-    fn visit_greater_than(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_greater_than(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
         let AST::GreaterThan { left, right } = node else {
             panic!("Expected Divide node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(
-            writer,
-            r#"cmp r1, r0
-    movgt r0, #1
-    movle r0, #0"#
-        )
+        self.visit_infix_operands(left, right, writer)?;
+        writer.extend(compare(R1, R0.into(), Condition::Gt));
+        Ok(())
     }
 
-    fn visit_less_than_equal(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_less_than_equal(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
         let AST::LessThanEqual { left, right } = node else {
             panic!("Expected Divide node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(
-            writer,
-            r#"cmp r1, r0
-    movle r0, #1
-    movgt r0, #0"#
-        )
+        self.visit_infix_operands(left, right, writer)?;
+        writer.extend(compare(R1, R0.into(), Condition::Le));
+        Ok(())
     }
 
     fn visit_greater_than_equal(
         &mut self,
         node: &AST,
-        writer: &mut dyn Write,
-    ) -> std::io::Result<()> {
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
         let AST::GreaterThanEqual { left, right } = node else {
             panic!("Expected Divide node, got: {:?}", node)
         };
-        self.visit_infix_operands(left, right, writer);
-        write!(writer, "\t")?;
-        writeln!(
-            writer,
-            r#"cmp r1, r0
-    movge r0, #1
-    movlt r0, #0"#
-        )
+        self.visit_infix_operands(left, right, writer)?;
+        writer.extend(compare(R1, R0.into(), Condition::Ge));
+        Ok(())
     }
 
-    fn visit_call(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_call(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Call { args, callee } = node else {
             panic!("Expected Call node, got: {:?}", node)
         };
-        let len = args.len();
-        if args.is_empty() {
-            writeln!(writer, "\tbl {}", callee)
-        } else if len == 1 {
-            args.first().unwrap().visit(self, writer)?;
-            writeln!(writer, "\tbl {}", callee)
-        } else if (2..=4).contains(&len) {
-            // allocate enough stack space for up to four arguments (16 bytes)
-            // We do that by subtracting from the stack
-            // pointer since the stack grows from higher memory addresses to
-            // lower.
-            writeln!(writer, "\tsub sp, sp, #16")?;
-            args.iter().enumerate().for_each(|(i, arg)| {
-                arg.visit(self, writer);
-                // We multiply by four to convert array indexes 0, 1, 2, 3 into
-                // stack offsets in bytes: 0, 4, 8, 12.
-                writeln!(writer, "\tstr r0, [sp, #{}]", 4 * i).expect("Write failed");
-            });
-            writeln!(writer, "\tpop {{r0, r1, r2, r3}}")?;
-            writeln!(writer, "\tbl {}", callee)
-        } else {
-            panic!("More than 4 arguments are not supported in function calls")
-        }
+        self.emit_arguments(args, writer)?;
+        writer.push(Instruction::bl(callee).into());
+        Ok(())
     }
 
-    fn visit_return(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_return(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Return { term } = node else {
             panic!("Expected Call node, got: {:?}", node)
         };
+        if let AST::Call { callee, args } = term.as_ref() {
+            return self.emit_tail_call(callee, args, writer);
+        }
         term.visit(self, writer)?;
-        writeln!(writer, "\tmov sp, fp")?;
-        writeln!(writer, "\tpop {{fp, pc}}")
+        writer.push(Instruction::mov(SP, FP).into());
+        writer.push(Instruction::Pop(vec![FP, PC]).into());
+        Ok(())
     }
 
-    fn visit_block(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_block(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Block(statements) = node else {
             panic!("Expected Call node, got: {:?}", node)
         };
@@ -360,7 +530,7 @@ impl Visitor<(), &mut dyn Write> for ArmCodeGenerator {
         Ok(())
     }
 
-    fn visit_if(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_if(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::IfNode {
             conditional,
             consequence,
@@ -371,17 +541,16 @@ impl Visitor<(), &mut dyn Write> for ArmCodeGenerator {
         };
         let if_false_label = self.new_label();
         let end_if_label = self.new_label();
-        conditional.visit(self, writer)?;
-        writeln!(writer, "\tcmp r0, #0")?;
-        writeln!(writer, "\tbeq {}", if_false_label)?;
+        self.emit_branch(conditional, false, &if_false_label, writer)?;
         consequence.visit(self, writer)?;
-        writeln!(writer, "\tb {}", end_if_label)?;
-        writeln!(writer, "{}:", if_false_label)?;
+        writer.push(Instruction::b(&end_if_label).into());
+        writer.push(Line::Label(if_false_label));
         alternative.visit(self, writer)?;
-        writeln!(writer, "{}:", end_if_label)
+        writer.push(Line::Label(end_if_label));
+        Ok(())
     }
 
-    fn visit_function(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_function(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Function {
             name,
             parameters,
@@ -391,12 +560,20 @@ impl Visitor<(), &mut dyn Write> for ArmCodeGenerator {
             panic!("Expected Call node, got: {:?}", node)
         };
         if parameters.len() > 4 {
-            panic!("More than 4 params is not supported");
+            return Err(CompileError::CodeGenError(
+                "More than 4 params is not supported".to_string(),
+            ));
         }
-        writeln!(writer)?;
-        writeln!(writer, ".global {}", name)?;
-        writeln!(writer, "{}:", name)?;
+        writer.push(Directive::Global(name.clone()).into());
+        writer.push(Line::Label(name.clone()));
         self.emit_fn_prologue(writer)?;
+        let self_tail_call = if calls_itself_in_tail_position(name, body) {
+            let label = self.new_label();
+            writer.push(Line::Label(label.clone()));
+            Some((name.clone(), parameters.len(), label))
+        } else {
+            None
+        };
 
         let mut locals: HashMap<String, isize> = HashMap::new();
         for (i, parameter) in parameters.iter().enumerate() {
@@ -406,165 +583,164 @@ impl Visitor<(), &mut dyn Write> for ArmCodeGenerator {
         //     locals,
         //     next_local_offset: -20,
         // };
+        // Labels are shared by the whole program, so the counter carries on
+        // from the enclosing scope.
         let mut code_gen_visitor = ArmCodeGenerator {
-            label_counter: 0,
+            label_counter: self.label_counter,
             locals,
             next_local_offset: -20,
+            self_tail_call,
         };
         body.visit(&mut code_gen_visitor, writer)?;
+        self.label_counter = code_gen_visitor.label_counter;
         // self.env.push_back(env);
         // self.write(body, writer)?;
         // self.env.pop_back();
-        self.emit_fn_epilogue(writer);
-        Ok(())
+        self.emit_fn_epilogue(writer)
     }
 
-    fn visit_var(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_var(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Var { name, value } = node else {
             panic!("Expected Call node, got: {:?}", node)
         };
         value.visit(self, writer)?;
-        writeln!(writer, "\tpush {{r0, ip}}");
+        writer.push(Instruction::Push(vec![R0, IP]).into());
         self.locals
             .insert(name.to_string(), self.next_local_offset - 4);
         self.next_local_offset -= 8;
         Ok(())
     }
 
-    fn visit_assign(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_assign(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Assign { name, value } = node else {
             panic!("Expected Call node, got: {:?}", node)
         };
         value.visit(self, writer)?;
-        let offset = self
-            .locals
-            .get(name)
-            .unwrap_or_else(|| panic!("Undefined variable: {}", name));
-        writeln!(writer, "\tstr r0, [fp, #{}]", offset)
+        let offset = self.local_offset(name)?;
+        writer.push(Instruction::str(R0, Address::Offset(FP, offset as i32)).into());
+        Ok(())
     }
 
-    fn visit_while(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_while(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::While { conditional, body } = node else {
             panic!("Expected Call node, got: {:?}", node)
         };
         let loop_start = self.new_label();
         let loop_end = self.new_label();
-        writeln!(writer, "{}:", loop_start)?;
-        conditional.visit(self, writer)?;
-        writeln!(writer, "\tcmp r0, #0")?;
-        writeln!(writer, "\tbeq {}", loop_end)?;
+        writer.push(Line::Label(loop_start.clone()));
+        self.emit_branch(conditional, false, &loop_end, writer)?;
         body.visit(self, writer)?;
-        writeln!(writer, "\tb {}", loop_start)?;
-        writeln!(writer, "{}:", loop_end)
+        writer.push(Instruction::b(&loop_start).into());
+        writer.push(Line::Label(loop_end));
+        Ok(())
     }
 
-    fn visit_undefined(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
-        writeln!(writer, "\tmov r0, #0")
+    fn visit_undefined(&mut self, _node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        writer.push(Instruction::mov(R0, Operand::Immediate(0)).into());
+        Ok(())
     }
 
-    fn visit_null(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
-        writeln!(writer, "\tmov r0, #0")
+    fn visit_null(&mut self, _node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        writer.push(Instruction::mov(R0, Operand::Immediate(0)).into());
+        Ok(())
     }
 
-    fn visit_main(&mut self, node: &AST, writer: &mut dyn Write) -> std::io::Result<()> {
+    fn visit_main(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
         let AST::Main(statements) = node else {
             panic!("Expected Main, got: {:?}", node)
         };
-        writeln!(writer, ".global main")?;
-        writeln!(writer, "main:")?;
-        writeln!(writer, "\tpush {{fp, lr}}")?;
+        writer.push(Directive::Global("main".to_string()).into());
+        writer.push(Line::Label("main".to_string()));
+        writer.push(Instruction::Push(vec![FP, LR]).into());
         for statement in statements {
             statement.visit(self, writer)?;
         }
-        writeln!(writer, "\tmov r0, #0")?;
-        writeln!(writer, "\tpop {{fp, pc}}")
+        writer.push(Instruction::mov(R0, Operand::Immediate(0)).into());
+        writer.push(Instruction::Pop(vec![FP, PC]).into());
+        Ok(())
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::error::CompileError;
+    use crate::generator::check_against_interpreter;
     use crate::parser::parse;
-    use rand::distributions::Alphanumeric;
-    use rand::Rng;
-    use std::fs::File;
-    use std::hash::{DefaultHasher, Hash, Hasher};
-    use std::io;
-    use std::process::Command;
+    use crate::{
+        aarch64_code_generator, aarch64_emulator, arm_emitter, emulator, native,
+        x86_64_code_generator,
+    };
 
     struct Output {
         pub stdout: Vec<u8>,
-        pub stderr: Vec<u8>,
     }
 
     fn compile_and_run(code: &str) -> Result<Output, CompileError> {
-        let s: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(7)
-            .map(char::from)
-            .collect();
-
-        let mut hasher = DefaultHasher::new();
-        code.hash(&mut hasher);
-        println!("Hash is {:x}!", hasher.finish());
-
-        let file_base_name = format!("tmp/test_{:x}", hasher.finish());
-
-        let mut generator: ArmCodeGenerator = Default::default();
         let ast = parse(code).expect("Parse error");
-        let _ = ast.visit(
-            &mut generator,
-            &mut File::create(format!("{}.s", file_base_name)).expect("Open file failed"),
-        );
-
-        println!("assembly written");
-        let compile_result = Command::new("arm-linux-gnueabihf-gcc")
-            // .arg("-g")
-            .arg("-march=armv8-a")
-            // .arg("-mcpu=cortex-m3")
-            .arg("-static")
-            .arg(format!("{}.s", file_base_name))
-            .arg("-o")
-            .arg(format!("{}.bin", file_base_name))
-            .output();
-
-        let _codegen_result: Result<(), CompileError> = match compile_result {
-            Ok(output) => {
-                io::stdout().write_all(&output.stdout).unwrap();
-                io::stderr().write_all(&output.stderr).unwrap();
-
-                if output.status.success() {
-                    println!("Compiled, executing...");
-                    Ok(())
-                } else {
-                    println!("Compile error");
-                    let errmsg = String::from_utf8_lossy(&output.stderr).into_owned();
-                    Err(CompileError::CodeGenError(errmsg))
-                }
+        compile_and_run_ast(&ast)
+    }
+
+    /// A code generator, with the way its code is run.
+    #[derive(Debug, Clone, Copy)]
+    enum Backend {
+        Arm,
+        /// ARM code generated through the IR, by `arm_emitter`.
+        ArmIr,
+        Aarch64,
+        X86_64,
+    }
+
+    impl Backend {
+        /// The backends the tests run on this machine: the ARM targets in
+        /// their emulators, and x86-64 natively on x86-64 Linux.
+        fn available() -> Vec<Backend> {
+            let mut backends = vec![Backend::Arm, Backend::ArmIr, Backend::Aarch64];
+            if native::AVAILABLE {
+                backends.push(Backend::X86_64);
             }
-            Err(e) => Err(CompileError::CodeGenError(e.to_string())),
-        };
-
-        let execution_res = match Command::new(format!("./{}.bin", file_base_name)).output() {
-            Ok(output) => {
-                io::stdout().write_all(&output.stdout).unwrap();
-                io::stderr().write_all(&output.stderr).unwrap();
-
-                if output.status.success() {
-                    Ok(Output {
-                        stdout: output.stdout.clone(),
-                        stderr: output.stdout.clone(),
-                    })
-                } else {
-                    let errmsg = String::from_utf8_lossy(&output.stderr).into_owned();
+            backends
+        }
 
-                    Err(CompileError::RuntimeError(errmsg, output.status.code()))
+        /// Runs the code, failing unless `main` returns 0.
+        fn run(self, ast: &AST) -> Result<Output, CompileError> {
+            let mut stdout = vec![];
+            let max_steps = 1_000_000_000;
+            let status = match self {
+                Backend::Arm => emulator::run(&generate_lines(ast)?, &mut stdout, max_steps)?,
+                Backend::ArmIr => {
+                    let lines = arm_emitter::generate_lines(ast)?;
+                    emulator::run(&lines, &mut stdout, max_steps)?
+                }
+                Backend::Aarch64 => {
+                    let lines = aarch64_code_generator::generate_lines(ast)?;
+                    aarch64_emulator::run(&lines, &mut stdout, max_steps)?
+                }
+                Backend::X86_64 => {
+                    native::run(&x86_64_code_generator::generate_lines(ast)?, &mut stdout)?
                 }
+            };
+            if status != 0 {
+                let message = format!("main returned {} on {:?}", status, self);
+                return Err(CompileError::RuntimeError(message, Some(status)));
             }
-            Err(e) => Err(CompileError::RuntimeError(e.to_string(), Option::None)),
-        };
+            Ok(Output { stdout })
+        }
+    }
 
-        execution_res
+    /// Runs the code with every available backend, which have to print the
+    /// same.
+    fn compile_and_run_ast(ast: &AST) -> Result<Output, CompileError> {
+        let output = Backend::Arm.run(ast)?;
+        for backend in &Backend::available()[1..] {
+            let other = backend.run(ast)?;
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&other.stdout),
+                "{:?} printed something else",
+                backend
+            );
+        }
+        Ok(output)
     }
 
     #[test]
@@ -660,11 +836,13 @@ mod tests {
 
     #[test]
     fn not() {
-        compile_and_run(
+        let result = compile_and_run(
             r#"function main() {
                 assert(!1);
             }"#,
-        );
+        )
+        .expect("Compile an run failed");
+        assert_eq!("F".to_string(), String::from_utf8(result.stdout).unwrap());
     }
 
     #[test]
@@ -1695,6 +1873,26 @@ function main() {
         assert_eq!("TTT", String::from_utf8(result.stdout).unwrap());
     }
 
+    #[test]
+    fn large_array_literal() {
+        let items: Vec<String> = (0..1500).map(|i| format!("x + {}", i)).collect();
+        let source = format!(
+            r#"
+            function main() {{
+                var x = 1;
+                var arr = [{}];
+                assert(length(arr) == 1500);
+                assert(arr[1022] == 1023);
+                assert(arr[1023] == 1024);
+                assert(arr[1499] == 1500);
+            }}
+        "#,
+            items.join(", ")
+        );
+        let result = compile_and_run(&source).expect("Compile and run failed");
+        assert_eq!("TTTT", String::from_utf8(result.stdout).unwrap());
+    }
+
     // ========== NEW TESTS: Edge Cases ==========
 
     #[test]
@@ -1768,4 +1966,179 @@ function main() {
         let output = String::from_utf8(result.stdout).unwrap();
         assert_eq!("8\nT", output);
     }
+
+    // ========== Crash regressions found by fuzzing ==========
+
+    #[test]
+    fn undefined_variable() {
+        assert!(matches!(
+            compile("function main() { print(x); }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+        assert!(matches!(
+            compile("function main() { x = 1; }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+        assert!(matches!(
+            compile("function main() { f(1, x); }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+    }
+
+    #[test]
+    fn too_many_arguments() {
+        assert!(matches!(
+            compile("function main() { f(1, 2, 3, 4, 5); }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+    }
+
+    #[test]
+    fn too_many_parameters() {
+        assert!(matches!(
+            compile("function f(a, b, c, d, e) { }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+    }
+
+    #[test]
+    fn many_labels() {
+        let body = "if (1) { } else { } ".repeat(20_000);
+        assert!(compile(&format!("function main() {{ {} }}", body)).is_ok());
+    }
+
+    #[test]
+    fn labels_are_unique_across_functions() {
+        let assembly = compile(
+            r#"function f(a) {
+                if (a) { print(1); } else { print(2); }
+                return 0;
+            }
+            function main() {
+                while (0) { print(3); }
+                if (1) { f(1); } else {}
+            }"#,
+        )
+        .expect("Compile failed");
+        let labels: Vec<&str> = assembly.lines().filter(|l| l.ends_with(':')).collect();
+        let unique: std::collections::HashSet<&&str> = labels.iter().collect();
+        assert_eq!(labels.len(), unique.len(), "{}", assembly);
+    }
+
+    #[test]
+    fn comparisons_branch_directly() {
+        let assembly = compile(
+            r#"function main() {
+                var i = 0;
+                while (i < 10) { i = i + 1; }
+                if (!(i == 10)) { print(1); } else { print(2); }
+            }"#,
+        )
+        .expect("Compile failed");
+        assert!(
+            assembly.contains("\tpop {r1, ip}\n\tcmp r1, r0\n\tbge .L2\n"),
+            "{}",
+            assembly
+        );
+        assert!(
+            assembly.contains("\tpop {r1, ip}\n\tcmp r1, r0\n\tbeq .L3\n"),
+            "{}",
+            assembly
+        );
+        assert!(!assembly.contains("movge"), "{}", assembly);
+        assert!(!assembly.contains("cmp r0, #0"), "{}", assembly);
+    }
+
+    #[test]
+    fn other_conditions_compare_with_zero() {
+        let assembly = compile("function main() { var a = 1; while (!a) { a = a - 1; } }")
+            .expect("Compile failed");
+        assert!(
+            assembly.contains("\tldr r0, [fp, #-24]\n\tcmp r0, #0\n\tbne .L2\n"),
+            "{}",
+            assembly
+        );
+    }
+
+    #[test]
+    fn self_tail_call_is_a_loop() {
+        let source = r#"function sum(n, total) {
+                if (n == 0) { return total; } else { return sum(n - 1, total + n); }
+            }
+            function main() { print(sum(100, 0)); }"#;
+        let assembly = compile(source).expect("Compile failed");
+        let body = &assembly[assembly.find("sum:").unwrap()..assembly.find("main:").unwrap()];
+        assert!(!body.contains("bl sum"), "{}", body);
+        assert!(
+            body.contains(
+                "\tstr r0, [fp, #-16]\n\tstr r1, [fp, #-12]\n\tsub sp, fp, #16\n\tb .L1\n"
+            ),
+            "{}",
+            body
+        );
+        assert!(body.contains("\tpush {r0, r1, r2, r3}\n.L1:\n"), "{}", body);
+        assert!(assembly.contains("\tbl sum\n"), "{}", assembly);
+
+        let ast = parse(source).expect("Parse error");
+        let assembly = arm_emitter::generate(&ast).expect("Compile failed");
+        let body = &assembly[assembly.find("sum:").unwrap()..assembly.find("main:").unwrap()];
+        assert!(
+            !body.contains("bl sum") && !body.contains("b sum"),
+            "{}",
+            body
+        );
+        assert!(
+            body.contains("\tsub sp, sp, #4\n.Lsum_start:\n\tmov r4, r0\n\tmov r5, r1\n"),
+            "{}",
+            body
+        );
+        assert!(body.contains("\tmov r0, r6\n\tb .Lsum_start\n"), "{}", body);
+        let output = Backend::ArmIr.run(&ast).expect("Run failed");
+        assert_eq!("5050\n", String::from_utf8(output.stdout).unwrap());
+    }
+
+    #[test]
+    fn tail_call_reuses_the_frame() {
+        let assembly =
+            compile("function f(x) { return g(x, 1); } function g(a, b) { return a + b; }")
+                .expect("Compile failed");
+        assert!(
+            assembly.contains("\tpop {r0, r1, r2, r3}\n\tmov sp, fp\n\tpop {fp, lr}\n\tb g\n"),
+            "{}",
+            assembly
+        );
+        assert!(!assembly.contains("bl g"), "{}", assembly);
+    }
+
+    #[test]
+    fn deep_tail_recursion() {
+        let result = compile_and_run(
+            r#"
+            function countdown(n) {
+                if (n == 0) {
+                    return 0;
+                } else {
+                    return countdown(n - 1);
+                }
+            }
+
+            function main() {
+                print(countdown(10000000));
+            }
+        "#,
+        )
+        .expect("Compile and run failed");
+        assert_eq!("0\n", String::from_utf8(result.stdout).unwrap());
+    }
+
+    // ========== Differential testing against the interpreter ==========
+
+    #[test]
+    fn random_programs_match_interpreter() {
+        // Only on ARM: the interpreter has 32-bit integers.
+        check_against_interpreter(0..20, |ast, out| {
+            emulator::run(&generate_lines(ast).unwrap(), out, 1_000_000_000)
+                .expect("Compile and run failed")
+        });
+    }
 }
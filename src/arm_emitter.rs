@@ -0,0 +1,711 @@
+use crate::arm::{
+    self, Address, Condition, Directive, Instruction as Arm, Line, Operand, Register, FP, IP, LR,
+    PC, R0, R1, SP,
+};
+use crate::arm_code_generator::{array_load, element_store};
+use crate::ast::AST;
+use crate::error::CompileError;
+use crate::ir::{BinaryOp, Block, BlockId, Function, Instruction, Program, Reg, Terminator};
+use crate::ir_optimize::optimize;
+use crate::lowering::lower;
+use crate::register_allocation::{allocate, Allocation, Location};
+use std::collections::HashMap;
+
+/// Lowers a program to the IR, optimizes it and generates ARM code from it.
+pub fn generate_lines(ast: &AST) -> Result<Vec<Line>, CompileError> {
+    let mut program = lower(ast)?;
+    optimize(&mut program);
+    Ok(emit_lines(&program))
+}
+
+/// Lowers a program to the IR, optimizes it and generates ARM assembly from
+/// it.
+pub fn generate(ast: &AST) -> Result<String, CompileError> {
+    Ok(arm::print(&generate_lines(ast)?))
+}
+
+/// Generates ARM code for an IR program.
+///
+/// The virtual registers of each function are kept in r4-r10 as far as
+/// they go, as decided by `register_allocation::allocate`, and in words of
+/// the frame below `fp` otherwise. Operands in the frame are loaded into
+/// r0-r3 and results computed into r0 before being stored. Calls take their
+/// arguments in r0-r3 and return in r0, as with `ArmCodeGenerator`, so the
+/// code of both can be linked together. A call whose result is returned
+/// right away is a tail call, which jumps to the callee after dropping the
+/// frame.
+///
+/// Small constants are the immediate operands of the additions,
+/// subtractions and comparisons reading them, a comparison only read by the
+/// branch after it is branched on directly, and a value only read by the
+/// call or return after it is computed into the register it's passed in.
+pub fn emit_lines(program: &Program) -> Vec<Line> {
+    let mut emitter = Emitter {
+        out: vec![],
+        label_counter: 0,
+        allocation: Allocation {
+            locations: HashMap::new(),
+            used: vec![],
+            spill_slots: 0,
+        },
+        immediates: HashMap::new(),
+        forwarded: HashMap::new(),
+    };
+    for function in &program.functions {
+        emitter.function(function);
+    }
+    emitter.out
+}
+
+/// Generates ARM assembly for an IR program, see `emit_lines`.
+pub fn emit(program: &Program) -> String {
+    arm::print(&emit_lines(program))
+}
+
+struct Emitter {
+    out: Vec<Line>,
+    label_counter: usize,
+    /// Where the registers of the function being emitted live.
+    allocation: Allocation,
+    /// The registers of constants that are only immediate operands, which
+    /// aren't emitted.
+    immediates: HashMap<Reg, u32>,
+    /// The registers computed into the machine register the next
+    /// instruction reads them from, instead of where they live.
+    forwarded: HashMap<Reg, Register>,
+}
+
+fn label(function: &Function, block: BlockId) -> String {
+    format!(".L{}_{}", function.name, block)
+}
+
+/// Where a self tail call jumps to: after the prologue, before the parameters
+/// are moved to where they live.
+fn loop_label(function: &Function) -> String {
+    format!(".L{}_start", function.name)
+}
+
+/// The callee and arguments of the call whose result a block returns.
+fn tail_call(block: &Block) -> Option<(&str, &[Reg])> {
+    match (block.instructions.last(), &block.terminator) {
+        (Some(Instruction::Call { dest, callee, args }), Terminator::Return(value))
+            if dest == value =>
+        {
+            Some((callee, args))
+        }
+        _ => None,
+    }
+}
+
+/// Whether a tail call is one to the function itself, which loops instead.
+fn is_self_tail_call(function: &Function, callee: &str, args: &[Reg]) -> bool {
+    callee == function.name && args.len() == function.parameters.len()
+}
+
+/// How many times each register is read and written in a function.
+fn counts(function: &Function) -> (HashMap<Reg, usize>, HashMap<Reg, usize>) {
+    let mut uses = HashMap::new();
+    let mut writes = HashMap::new();
+    for parameter in &function.parameters {
+        *writes.entry(*parameter).or_default() += 1;
+    }
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            for reg in instruction.uses() {
+                *uses.entry(reg).or_default() += 1;
+            }
+            if let Some(dest) = instruction.dest() {
+                *writes.entry(dest).or_default() += 1;
+            }
+        }
+        for reg in block.terminator.uses() {
+            *uses.entry(reg).or_default() += 1;
+        }
+    }
+    (uses, writes)
+}
+
+/// Whether an operation can take its right operand as an immediate.
+fn takes_immediate(op: BinaryOp) -> bool {
+    !matches!(op, BinaryOp::Mul | BinaryOp::Div)
+}
+
+/// The constants written once and only read as the right operand of an
+/// operation taking an immediate, with the values they hold.
+fn immediates(
+    function: &Function,
+    uses: &HashMap<Reg, usize>,
+    writes: &HashMap<Reg, usize>,
+) -> HashMap<Reg, u32> {
+    let instructions = || function.blocks.iter().flat_map(|block| &block.instructions);
+    let mut immediate_uses: HashMap<Reg, usize> = HashMap::new();
+    for instruction in instructions() {
+        if let Instruction::Binary {
+            op, left, right, ..
+        } = instruction
+        {
+            if takes_immediate(*op) && left != right {
+                *immediate_uses.entry(*right).or_default() += 1;
+            }
+        }
+    }
+    instructions()
+        .filter_map(|instruction| match instruction {
+            Instruction::Const { dest, value }
+                if (0..=255).contains(value)
+                    && writes[dest] == 1
+                    && immediate_uses.get(dest) == uses.get(dest) =>
+            {
+                Some((*dest, *value as u32))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The condition under which a comparison holds.
+fn condition(op: BinaryOp) -> Option<Condition> {
+    match op {
+        BinaryOp::Eq => Some(Condition::Eq),
+        BinaryOp::Ne => Some(Condition::Ne),
+        BinaryOp::Lt => Some(Condition::Lt),
+        BinaryOp::Gt => Some(Condition::Gt),
+        BinaryOp::Le => Some(Condition::Le),
+        BinaryOp::Ge => Some(Condition::Ge),
+        _ => None,
+    }
+}
+
+impl Emitter {
+    fn line(&mut self, instruction: Arm) {
+        self.out.push(Line::Instruction(instruction));
+    }
+
+    /// Offset below `fp` of a spilled register: the frame holds the saved
+    /// registers, then the spill slots.
+    fn offset(&self, slot: usize) -> usize {
+        4 * (self.allocation.used.len() + slot + 1)
+    }
+
+    /// `ldr`/`str` only reach 4095 bytes from `fp`; further slots are
+    /// addressed through `ip`.
+    fn access(&mut self, access: fn(Register, Address) -> Arm, target: Register, slot: usize) {
+        let offset = self.offset(slot);
+        if offset <= 4095 {
+            self.line(access(target, Address::Offset(FP, -(offset as i32))));
+        } else {
+            self.line(Arm::LoadConstant {
+                dest: IP,
+                value: offset as u32,
+            });
+            self.line(Arm::sub(IP, FP, IP));
+            self.line(access(target, Address::Offset(IP, 0)));
+        }
+    }
+
+    /// The machine register holding `reg`, loading it into `scratch` if it
+    /// was spilled.
+    fn operand(&mut self, reg: Reg, scratch: Register) -> Register {
+        match self.allocation.location(reg) {
+            Location::Register(register) => Register(register),
+            Location::Stack(slot) => {
+                self.access(Arm::ldr, scratch, slot);
+                scratch
+            }
+        }
+    }
+
+    /// Puts `reg` in the machine register `target`.
+    fn load(&mut self, target: Register, reg: Reg) {
+        if self.forwarded.get(&reg) == Some(&target) {
+            return;
+        }
+        let register = self.operand(reg, target);
+        if register != target {
+            self.line(Arm::mov(target, register));
+        }
+    }
+
+    /// The machine register to compute `reg` into: its own, or r0 to be
+    /// stored by `store`.
+    fn result(&self, reg: Reg) -> Register {
+        if let Some(register) = self.forwarded.get(&reg) {
+            return *register;
+        }
+        match self.allocation.location(reg) {
+            Location::Register(register) => Register(register),
+            Location::Stack(_) => R0,
+        }
+    }
+
+    /// Moves a value computed in `source` to where `reg` lives.
+    fn store(&mut self, reg: Reg, source: Register) {
+        if let Some(register) = self.forwarded.get(&reg) {
+            if *register != source {
+                self.line(Arm::mov(*register, source));
+            }
+            return;
+        }
+        match self.allocation.location(reg) {
+            Location::Register(register) => {
+                if Register(register) != source {
+                    self.line(Arm::mov(Register(register), source));
+                }
+            }
+            Location::Stack(slot) => self.access(Arm::str, source, slot),
+        }
+    }
+
+    /// Saves the registers the function uses and makes room for the spilled
+    /// ones, keeping the stack 8-byte aligned for calls.
+    fn emit_fn_prologue(&mut self) {
+        self.line(Arm::Push(vec![FP, LR]));
+        self.line(Arm::mov(FP, SP));
+        if !self.allocation.used.is_empty() {
+            self.line(Arm::Push(self.saved_registers()));
+        }
+        let frame = self.frame();
+        if frame >= 256 {
+            self.line(Arm::LoadConstant {
+                dest: IP,
+                value: frame as u32,
+            });
+            self.line(Arm::sub(SP, SP, IP));
+        } else if frame > 0 {
+            self.line(Arm::sub(SP, SP, Operand::Immediate(frame as u32)));
+        }
+    }
+
+    /// The bytes below the saved registers: the spill slots, and padding to
+    /// keep the stack aligned.
+    fn frame(&self) -> usize {
+        let saved = self.allocation.used.len();
+        (4 * (saved + self.allocation.spill_slots)).div_ceil(8) * 8 - 4 * saved
+    }
+
+    /// Restores the saved registers and returns what is in r0.
+    fn emit_fn_epilogue(&mut self) {
+        self.emit_restore_registers(PC);
+    }
+
+    /// Drops the frame and restores the saved registers, `fp`, and the saved
+    /// `lr` into `lr_target`, all with one `pop` as they are next to each
+    /// other.
+    fn emit_restore_registers(&mut self, lr_target: Register) {
+        let saved = self.allocation.used.len();
+        if self.frame() > 0 {
+            if saved > 0 {
+                self.line(Arm::sub(SP, FP, Operand::Immediate(4 * saved as u32)));
+            } else {
+                self.line(Arm::mov(SP, FP));
+            }
+        }
+        let mut registers = self.saved_registers();
+        registers.extend([FP, lr_target]);
+        self.line(Arm::Pop(registers));
+    }
+
+    /// Generates `return callee(args)` without growing the stack, like
+    /// `ArmCodeGenerator` does: the frame is dropped before jumping to the
+    /// callee, which returns to the caller. A function calling itself keeps
+    /// its frame and jumps back to after its prologue instead, where the
+    /// arguments are moved to the parameters.
+    fn emit_tail_call(&mut self, function: &Function, callee: &str, args: &[Reg]) {
+        for (i, arg) in args.iter().enumerate() {
+            self.load(Register(i as u8), *arg);
+        }
+        if is_self_tail_call(function, callee, args) {
+            self.line(Arm::b(&loop_label(function)));
+            return;
+        }
+        self.emit_restore_registers(LR);
+        self.line(Arm::b(callee));
+    }
+
+    fn saved_registers(&self) -> Vec<Register> {
+        self.allocation.used.iter().copied().map(Register).collect()
+    }
+
+    /// The right operand of an operation: an immediate if it's a small
+    /// constant.
+    fn right_operand(&mut self, reg: Reg, scratch: Register) -> Operand {
+        match self.immediates.get(&reg) {
+            Some(value) => Operand::Immediate(*value),
+            None => self.operand(reg, scratch).into(),
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.allocation = allocate(function);
+        let (uses, writes) = counts(function);
+        self.immediates = immediates(function, &uses, &writes);
+        let once = |reg: &Reg| uses.get(reg) == Some(&1) && writes.get(reg) == Some(&1);
+        self.out
+            .push(Directive::Global(function.name.clone()).into());
+        self.out.push(Line::Label(function.name.clone()));
+        self.emit_fn_prologue();
+        let loops = function
+            .blocks
+            .iter()
+            .filter_map(tail_call)
+            .any(|(callee, args)| is_self_tail_call(function, callee, args));
+        if loops {
+            self.out.push(Line::Label(loop_label(function)));
+        }
+        for (i, parameter) in function.parameters.iter().enumerate() {
+            self.store(*parameter, Register(i as u8));
+        }
+
+        for (i, block) in function.blocks.iter().enumerate() {
+            if i > 0 {
+                self.out.push(Line::Label(label(function, block.id)));
+            }
+            let tail_call = tail_call(block);
+            let branched_on = match (block.instructions.last(), &block.terminator) {
+                (
+                    Some(Instruction::Binary {
+                        op,
+                        dest,
+                        left,
+                        right,
+                    }),
+                    Terminator::Branch {
+                        condition: tested, ..
+                    },
+                ) if dest == tested && once(dest) => {
+                    condition(*op).map(|holds| (holds, *left, *right))
+                }
+                _ => None,
+            };
+            let last = tail_call.is_some() || branched_on.is_some();
+            let body = &block.instructions[..block.instructions.len() - last as usize];
+            for (j, instruction) in body.iter().enumerate() {
+                // Where the instruction after this one reads its result.
+                let forward = instruction.dest().and_then(|dest| {
+                    let register = match block.instructions.get(j + 1) {
+                        Some(Instruction::Call { args, .. }) if once(&dest) => {
+                            let position = args.iter().position(|arg| *arg == dest)?;
+                            Register(position as u8)
+                        }
+                        None if block.terminator == Terminator::Return(dest) => R0,
+                        _ => return None,
+                    };
+                    Some((dest, register))
+                });
+                self.forwarded.extend(forward);
+                self.instruction(instruction);
+                self.forwarded
+                    .retain(|reg, _| forward.is_some_and(|(dest, _)| dest == *reg));
+            }
+            if let Some((callee, args)) = tail_call {
+                self.emit_tail_call(function, callee, args);
+                self.forwarded.clear();
+                continue;
+            }
+            let next = function.blocks.get(i + 1).map(|next| next.id);
+            if let Some((holds, left, right)) = branched_on {
+                let left = self.operand(left, R0);
+                let right = self.right_operand(right, R1);
+                self.line(Arm::cmp(left, right));
+                let Terminator::Branch {
+                    then, otherwise, ..
+                } = block.terminator
+                else {
+                    unreachable!()
+                };
+                if Some(then) == next {
+                    let otherwise = label(function, otherwise);
+                    self.line(Arm::b(&otherwise).when(holds.inverse()));
+                } else {
+                    self.line(Arm::b(&label(function, then)).when(holds));
+                    if Some(otherwise) != next {
+                        self.line(Arm::b(&label(function, otherwise)));
+                    }
+                }
+                continue;
+            }
+            match &block.terminator {
+                Terminator::Jump(target) => {
+                    if Some(*target) != next {
+                        self.line(Arm::b(&label(function, *target)));
+                    }
+                }
+                Terminator::Branch {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    let condition = self.operand(*condition, R0);
+                    self.line(Arm::cmp(condition, Operand::Immediate(0)));
+                    if Some(*then) == next {
+                        let otherwise = label(function, *otherwise);
+                        self.line(Arm::b(&otherwise).when(Condition::Eq));
+                    } else {
+                        self.line(Arm::b(&label(function, *then)).when(Condition::Ne));
+                        if Some(*otherwise) != next {
+                            self.line(Arm::b(&label(function, *otherwise)));
+                        }
+                    }
+                }
+                Terminator::Return(value) => {
+                    self.load(R0, *value);
+                    self.emit_fn_epilogue();
+                }
+            }
+            self.forwarded.clear();
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Const { dest, .. } if self.immediates.contains_key(dest) => {}
+            Instruction::Const { dest, value } => {
+                let result = self.result(*dest);
+                self.line(Arm::LoadConstant {
+                    dest: result,
+                    value: *value as u32,
+                });
+                self.store(*dest, result);
+            }
+            Instruction::Copy { dest, src } => {
+                let src = self.operand(*src, R0);
+                self.store(*dest, src);
+            }
+            Instruction::Binary {
+                op,
+                dest,
+                left,
+                right,
+            } => {
+                let left = self.operand(*left, R0);
+                let result = self.result(*dest);
+                match (op, condition(*op)) {
+                    (_, Some(holds)) => {
+                        let right = self.right_operand(*right, R1);
+                        self.line(Arm::cmp(left, right));
+                        self.line(Arm::mov(result, Operand::Immediate(1)).when(holds));
+                        let fails = holds.inverse();
+                        self.line(Arm::mov(result, Operand::Immediate(0)).when(fails));
+                    }
+                    (BinaryOp::Add, _) => {
+                        let right = self.right_operand(*right, R1);
+                        self.line(Arm::add(result, left, right))
+                    }
+                    (BinaryOp::Sub, _) => {
+                        let right = self.right_operand(*right, R1);
+                        self.line(Arm::sub(result, left, right))
+                    }
+                    (BinaryOp::Mul, _) => {
+                        let right = self.operand(*right, R1);
+                        self.line(Arm::Multiply {
+                            dest: result,
+                            left,
+                            right,
+                        })
+                    }
+                    (BinaryOp::Div, _) => {
+                        let right = self.operand(*right, R1);
+                        self.line(Arm::Divide {
+                            dest: result,
+                            left,
+                            right,
+                        })
+                    }
+                    _ => unreachable!("{:?} is a comparison", op),
+                }
+                self.store(*dest, result);
+            }
+            Instruction::Not { dest, src } => {
+                let src = self.operand(*src, R0);
+                let result = self.result(*dest);
+                self.line(Arm::cmp(src, Operand::Immediate(0)));
+                self.line(Arm::mov(result, Operand::Immediate(1)).when(Condition::Eq));
+                self.line(Arm::mov(result, Operand::Immediate(0)).when(Condition::Ne));
+                self.store(*dest, result);
+            }
+            Instruction::Call { dest, callee, args } => {
+                for (i, arg) in args.iter().enumerate() {
+                    self.load(Register(i as u8), *arg);
+                }
+                self.line(Arm::bl(callee));
+                self.store(*dest, R0);
+            }
+            Instruction::Print { src } => {
+                let fmt_label = format!(".Lprint_fmt_{}", self.label_counter);
+                let skip_label = format!(".Lskip_fmt_{}", self.label_counter);
+                self.label_counter += 1;
+                self.line(Arm::b(&skip_label));
+                self.out.push(Directive::Align(2).into());
+                self.out.push(Line::Label(fmt_label.clone()));
+                self.out.push(Directive::Asciz("%d\n".to_string()).into());
+                self.out.push(Directive::Align(2).into());
+                self.out.push(Line::Label(skip_label));
+                self.line(Arm::LoadAddress {
+                    dest: R0,
+                    label: fmt_label,
+                });
+                self.load(R1, *src);
+                self.line(Arm::bl("printf"));
+            }
+            Instruction::Assert { src } => {
+                let src = self.operand(*src, R0);
+                self.line(Arm::cmp(src, Operand::Immediate(1)));
+                let t = Arm::mov(R0, Operand::Immediate('T' as u32));
+                self.line(t.when(Condition::Eq));
+                let f = Arm::mov(R0, Operand::Immediate('F' as u32));
+                self.line(f.when(Condition::Ne));
+                self.line(Arm::bl("putchar"));
+            }
+            Instruction::NewArray { dest, items } => {
+                self.line(Arm::LoadConstant {
+                    dest: R0,
+                    value: 4 * (items.len() as u32 + 1),
+                });
+                self.line(Arm::bl("malloc"));
+                self.line(Arm::LoadConstant {
+                    dest: R1,
+                    value: items.len() as u32,
+                });
+                self.line(Arm::str(R1, Address::Offset(R0, 0)));
+                for (i, item) in items.iter().enumerate() {
+                    let item = self.operand(*item, R1);
+                    for instruction in element_store(item, R0, i) {
+                        self.line(instruction);
+                    }
+                }
+                self.store(*dest, R0);
+            }
+            Instruction::Length { dest, array } => {
+                let array = self.operand(*array, R0);
+                let result = self.result(*dest);
+                self.line(Arm::ldr(result, Address::Offset(array, 0)));
+                self.store(*dest, result);
+            }
+            Instruction::Load { dest, array, index } => {
+                self.load(R1, *array);
+                self.load(R0, *index);
+                for instruction in array_load() {
+                    self.line(instruction);
+                }
+                self.store(*dest, R0);
+            }
+            Instruction::Phi { .. } => unreachable!("Phis are replaced by ssa::destruct"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator;
+    use crate::parser::parse;
+    use crate::peephole;
+
+    fn compile(source: &str) -> String {
+        emit(&lower(&parse(source).expect("Parser failed")).expect("Lowering failed"))
+    }
+
+    #[test]
+    fn function() {
+        assert_eq!(
+            "
+.global f
+f:
+	push {fp, lr}
+	mov fp, sp
+	push {r4, r5, r6}
+	sub sp, sp, #4
+	mov r4, r0
+	mov r5, r1
+	cmp r4, r5
+	bge .Lf_bb2
+.Lf_bb1:
+	mov r0, r4
+	sub sp, fp, #12
+	pop {r4, r5, r6, fp, pc}
+.Lf_bb2:
+	mov r0, r5
+	sub sp, fp, #12
+	pop {r4, r5, r6, fp, pc}
+",
+            compile("function f(a, b) { if (a < b) { return a; } else { return b; } }")
+        );
+    }
+
+    #[test]
+    fn loops_and_calls() {
+        let assembly = compile(
+            "function main() { var i = 0; while (i < 3) { i = g(i, 1); } print(i); }
+             function g(a, b) { return a + b; }",
+        );
+        for expected in [
+            "\tpush {r4, r5}\n\tldr r4, =0\n.Lmain_bb1:\n",
+            "\tcmp r4, #3\n\tbge .Lmain_bb3\n.Lmain_bb2:\n",
+            "\tldr r1, =1\n\tmov r0, r4\n\tbl g\n\tmov r5, r0\n\tmov r4, r5\n\tb .Lmain_bb1\n",
+            "\tadr r0, .Lprint_fmt_0\n\tmov r1, r4\n\tbl printf\n\tldr r0, =0\n\tpop {r4, r5, fp, pc}\n",
+            "\tmov r4, r0\n\tmov r5, r1\n\tadd r0, r4, r5\n\tpop {r4, r5, fp, pc}\n",
+        ] {
+            assert!(
+                assembly.contains(expected),
+                "{} not in\n{}",
+                expected,
+                assembly
+            );
+        }
+    }
+
+    #[test]
+    fn fibonacci_runs_fewer_instructions() {
+        let ast = parse(
+            "function fibonacci(n) {
+                if (n < 2) { return n; } else { return fibonacci(n - 1) + fibonacci(n - 2); }
+            }
+            function main() { print(fibonacci(20)); }",
+        )
+        .expect("Parser failed");
+        let assembly = generate(&ast).expect("Codegen failed");
+        assert!(!assembly.contains("[fp"), "{}", assembly);
+        // Count the instructions each way of generating code runs, after the
+        // peephole optimizer as `compile` does.
+        let steps = |mut lines: Vec<Line>| {
+            peephole::optimize(&mut lines);
+            let mut stdout = vec![];
+            let (_, steps) =
+                emulator::run_counting(&lines, &mut stdout, 100_000_000).expect("Run failed");
+            assert_eq!("6765\n", String::from_utf8_lossy(&stdout));
+            steps
+        };
+        let ir = steps(generate_lines(&ast).expect("Codegen failed"));
+        let tree = steps(crate::arm_code_generator::generate_lines(&ast).expect("Codegen failed"));
+        // 284,596 instructions against 372,158.
+        assert!(
+            ir * 10 < tree * 8,
+            "{} instructions through the IR, {} from the tree",
+            ir,
+            tree
+        );
+    }
+
+    #[test]
+    fn spills() {
+        // Every value is live until the array at the end.
+        let names: Vec<String> = (0..1100).map(|i| format!("v{}", i)).collect();
+        let body: String = names
+            .iter()
+            .map(|name| format!("var {} = a + {};", name, name.len()))
+            .collect();
+        let assembly = compile(&format!(
+            "function f(a) {{ {} var all = [{}]; return all[0]; }}",
+            body,
+            names.join(", ")
+        ));
+        assert!(assembly
+            .contains("\tpush {r4, r5, r6, r7, r8, r9, r10}\n\tldr ip, =4380\n\tsub sp, sp, ip\n"));
+        assert!(assembly.contains("\tadd r10, r4, #2\n\tstr r10, [fp, #-32]\n"));
+        assert!(assembly.contains("\tldr ip, =4096\n\tsub ip, fp, ip\n\tstr r10, [ip]\n"));
+        assert!(assembly.contains("\tldr r1, [fp, #-32]\n\tstr r1, [r0, #24]\n"));
+        assert!(
+            assembly.ends_with("\tsub sp, fp, #28\n\tpop {r4, r5, r6, r7, r8, r9, r10, fp, pc}\n")
+        );
+    }
+}
@@ -1,7 +1,7 @@
-use std::io::Write;
-use std::{fmt, writeln};
+use crate::printer;
+use std::fmt;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum AST {
     Number(u64),
     Id(String),
@@ -110,94 +110,10 @@ impl From<String> for Box<AST> {
     }
 }
 
+/// Formats the node as source text, see `printer::print`.
 impl fmt::Display for AST {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        match &self {
-            AST::Number(value) => write!(f, "{}", value),
-            AST::Id(name) => write!(f, "{}", name),
-            AST::Not(value) => write!(f, "!{}", value),
-            AST::Equal { left, right } => write!(f, "({} == {})", left, right),
-            AST::NotEqual { left, right } => write!(f, "({} != {})", left, right),
-            AST::Add { left, right } => write!(f, "({} + {})", left, right),
-            AST::Subtract { left, right } => write!(f, "({} - {})", left, right),
-            AST::Multiply { left, right } => write!(f, "({} * {})", left, right),
-            AST::Divide { left, right } => write!(f, "({} / {})", left, right),
-            AST::LessThan { left, right } => write!(f, "({} < {})", left, right),
-            AST::GreaterThan { left, right } => write!(f, "({} > {})", left, right),
-            AST::LessThanEqual { left, right } => write!(f, "({} <= {})", left, right),
-            AST::GreaterThanEqual { left, right } => write!(f, "({} >= {})", left, right),
-            AST::Boolean(value) => write!(f, "{}", value),
-
-            AST::Call { callee, args } => write!(
-                f,
-                "{} ({})",
-                callee,
-                args.iter().map(|x| x.to_string() + ",").collect::<String>()
-            ),
-            AST::Return { term } => write!(f, "return {}", term),
-            AST::Block(statements) => {
-                writeln!(f, "{{").unwrap();
-                for stmt in statements {
-                    writeln!(f, " {};", stmt).unwrap();
-                }
-                write!(f, "}}")
-            }
-            AST::IfNode {
-                conditional,
-                consequence,
-                alternative,
-            } => write!(
-                f,
-                "if ({})\n{}\n else\n{}",
-                conditional, consequence, alternative
-            ),
-            AST::Function {
-                name,
-                parameters,
-                body,
-            } => write!(
-                f,
-                "function {}({})\n{{\n{}\n}}\n",
-                name,
-                parameters.join(","),
-                body
-            ),
-            AST::Var { name, value } => write!(f, "var {} = {}", name, value),
-            AST::Assign { name, value } => write!(f, "{} = {}", name, value),
-            AST::While { conditional, body } => {
-                write!(f, "while ({})\n{}", conditional, body)
-            }
-            AST::Main(statements) => {
-                write!(f, "{:?}", statements)
-            }
-            AST::Assert(condition) => {
-                write!(f, "assert({})", condition)
-            }
-            AST::Print(condition) => {
-                write!(f, "print({})", condition)
-            }
-            AST::Undefined => {
-                write!(f, "undefined")
-            }
-            AST::Null => {
-                write!(f, "null")
-            }
-            AST::ArrayLiteral(args) => {
-                let vec_str = args.into_iter().map(|v| v.to_string()).collect::<Vec<_>>();
-
-                write!(f, "[{}]", vec_str.join(","))
-                // for v in args.iter() {
-                //     write!(f, "{}", v.to_string());
-                // }
-                // write!(f, "]\n")
-            }
-            AST::ArrayLookup { array, index } => {
-                write!(f, "{}[{}]", array, index)
-            }
-            AST::ArrayLength(array) => {
-                write!(f, "{}.length", array)
-            }
-        }
+        write!(f, "{}", printer::print(self))
     }
 }
 
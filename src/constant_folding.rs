@@ -0,0 +1,277 @@
+use crate::ast::AST;
+use crate::error::CompileError;
+use crate::fold::{self, Fold};
+use crate::pass_manager::{Diagnostics, Pass};
+use crate::visitor::AstVisitor;
+
+/// Replaces operators whose operands are all literals with their value,
+/// computed the way the generated code would: arithmetic wraps at 32 bits,
+/// comparisons are signed and division is unsigned, with `x / 0` being 0.
+/// Comparisons and `!` fold to booleans. Division by a literal zero is
+/// reported as a warning, located at the statement it is in.
+pub struct ConstantFolding;
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "constant-folding"
+    }
+
+    fn run(&mut self, ast: AST, diagnostics: &mut Diagnostics) -> Result<AST, CompileError> {
+        let mut folder = Folder {
+            diagnostics,
+            next: 0,
+            statement: 0,
+        };
+        Ok(folder.statement(ast))
+    }
+}
+
+struct Folder<'a> {
+    diagnostics: &'a mut Diagnostics,
+    /// Number of the next statement, see `parser::parse_with_locations`.
+    next: usize,
+    /// Number of the statement being folded.
+    statement: usize,
+}
+
+/// The value of a literal in generated code.
+fn constant(node: &AST) -> Option<i32> {
+    match node {
+        // `ldr r0, =N` keeps the low 32 bits.
+        AST::Number(number) => Some(*number as u32 as i32),
+        AST::Boolean(value) => Some(*value as i32),
+        AST::Undefined | AST::Null => Some(0),
+        _ => None,
+    }
+}
+
+fn number(value: i32) -> AST {
+    AST::Number(value as u32 as u64)
+}
+
+impl Folder<'_> {
+    /// Folds a statement, numbering it and the statements nested in it the
+    /// way `parser::nested_statements` walks them.
+    fn statement(&mut self, statement: AST) -> AST {
+        let number = self.next;
+        self.next += 1;
+        self.statement = number;
+        match statement {
+            AST::Block(statements) => AST::Block(self.statements(statements)),
+            AST::Main(statements) => AST::Main(self.statements(statements)),
+            AST::IfNode {
+                conditional,
+                consequence,
+                alternative,
+            } => AST::IfNode {
+                conditional: conditional.fold(self).into(),
+                consequence: self.statement(*consequence).into(),
+                alternative: self.statement(*alternative).into(),
+            },
+            AST::While { conditional, body } => AST::While {
+                conditional: conditional.fold(self).into(),
+                body: self.statement(*body).into(),
+            },
+            AST::Function {
+                name,
+                parameters,
+                body,
+            } => {
+                let body = match *body {
+                    AST::Block(statements) => AST::Block(self.statements(statements)),
+                    body => self.statement(body),
+                };
+                AST::Function {
+                    name,
+                    parameters,
+                    body: body.into(),
+                }
+            }
+            statement => statement.fold(self),
+        }
+    }
+
+    fn statements(&mut self, statements: Vec<AST>) -> Vec<AST> {
+        statements
+            .into_iter()
+            .map(|statement| self.statement(statement))
+            .collect()
+    }
+
+    /// Folds an operator whose operands have already been folded.
+    fn evaluate(&mut self, node: AST) -> AST {
+        let (left, right) = match &node {
+            AST::Not(term) => return constant(term).map_or(node, |value| AST::Boolean(value == 0)),
+            AST::Equal { left, right }
+            | AST::NotEqual { left, right }
+            | AST::Add { left, right }
+            | AST::Subtract { left, right }
+            | AST::Multiply { left, right }
+            | AST::Divide { left, right }
+            | AST::LessThan { left, right }
+            | AST::GreaterThan { left, right }
+            | AST::LessThanEqual { left, right }
+            | AST::GreaterThanEqual { left, right } => (constant(left), constant(right)),
+            _ => return node,
+        };
+        if matches!(node, AST::Divide { .. }) && right == Some(0) {
+            self.diagnostics.statement_warning(
+                self.statement,
+                format!("division by zero in `{}` always gives 0", node),
+            );
+        }
+        let (Some(l), Some(r)) = (left, right) else {
+            return node;
+        };
+        match node {
+            AST::Equal { .. } => AST::Boolean(l == r),
+            AST::NotEqual { .. } => AST::Boolean(l != r),
+            AST::Add { .. } => number(l.wrapping_add(r)),
+            AST::Subtract { .. } => number(l.wrapping_sub(r)),
+            AST::Multiply { .. } => number(l.wrapping_mul(r)),
+            AST::Divide { .. } => number((l as u32).checked_div(r as u32).unwrap_or(0) as i32),
+            AST::LessThan { .. } => AST::Boolean(l < r),
+            AST::GreaterThan { .. } => AST::Boolean(l > r),
+            AST::LessThanEqual { .. } => AST::Boolean(l <= r),
+            AST::GreaterThanEqual { .. } => AST::Boolean(l >= r),
+            _ => unreachable!("not an operator: {:?}", node),
+        }
+    }
+}
+
+impl Fold for Folder<'_> {
+    fn fold_not(&mut self, node: AST) -> AST {
+        let node = fold::fold_not(self, node);
+        self.evaluate(node)
+    }
+    fn fold_equal(&mut self, node: AST) -> AST {
+        let node = fold::fold_equal(self, node);
+        self.evaluate(node)
+    }
+    fn fold_not_equal(&mut self, node: AST) -> AST {
+        let node = fold::fold_not_equal(self, node);
+        self.evaluate(node)
+    }
+    fn fold_add(&mut self, node: AST) -> AST {
+        let node = fold::fold_add(self, node);
+        self.evaluate(node)
+    }
+    fn fold_subtract(&mut self, node: AST) -> AST {
+        let node = fold::fold_subtract(self, node);
+        self.evaluate(node)
+    }
+    fn fold_multiply(&mut self, node: AST) -> AST {
+        let node = fold::fold_multiply(self, node);
+        self.evaluate(node)
+    }
+    fn fold_divide(&mut self, node: AST) -> AST {
+        let node = fold::fold_divide(self, node);
+        self.evaluate(node)
+    }
+    fn fold_less_than(&mut self, node: AST) -> AST {
+        let node = fold::fold_less_than(self, node);
+        self.evaluate(node)
+    }
+    fn fold_greater_than(&mut self, node: AST) -> AST {
+        let node = fold::fold_greater_than(self, node);
+        self.evaluate(node)
+    }
+    fn fold_less_than_equal(&mut self, node: AST) -> AST {
+        let node = fold::fold_less_than_equal(self, node);
+        self.evaluate(node)
+    }
+    fn fold_greater_than_equal(&mut self, node: AST) -> AST {
+        let node = fold::fold_greater_than_equal(self, node);
+        self.evaluate(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::check_against_interpreter;
+    use crate::interpreter::Interpreter;
+    use crate::parser::{parse, parse_with_locations};
+    use crate::pass_manager::PassManager;
+
+    fn fold_source(source: &str) -> (AST, Vec<String>) {
+        let (ast, locations) = parse_with_locations(source).expect("Parser failed");
+        let mut passes = PassManager::new();
+        passes.add(ConstantFolding);
+        passes.locations = locations;
+        let results = passes.run(ast, &mut vec![]).expect("Folding failed");
+        let warnings = results.diagnostics.iter().map(|d| d.to_string()).collect();
+        (results.ast, warnings)
+    }
+
+    fn assert_folds(expected: &str, source: &str) {
+        let (ast, warnings) = fold_source(source);
+        assert_eq!(parse(expected).expect("Parser failed"), ast, "{}", source);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_folds("print(42);", "print(4 + 2 * (12 - 2) + 3 * (5 + 1));");
+        assert_folds("print(2);", "print(true + true);");
+        assert_folds("print(a + 3);", "print(a + (1 + 2));");
+        assert_folds("print(1 + a * 0);", "print(1 + a * (2 - 2));");
+    }
+
+    #[test]
+    fn wrapping_and_signedness() {
+        assert_folds("print(2147483648);", "print(2147483647 + 1);");
+        assert_folds("print(4294967295);", "print(0 - 1);");
+        assert_folds("print(0);", "print(4294967296 * 3);");
+        assert_folds("print(2147483647);", "print((0 - 1) / 2);");
+        assert_folds("print(true);", "print(0 - 1 < 0);");
+        assert_folds("print(false);", "print(4294967295 > 1);");
+        assert_folds("print(true);", "print(4294967296 == 0);");
+    }
+
+    #[test]
+    fn comparisons_and_not() {
+        assert_folds("assert(true);", "assert(1 <= 1);");
+        assert_folds("assert(false);", "assert(2 != 2);");
+        assert_folds("assert(true);", "assert(!0);");
+        assert_folds("assert(false);", "assert(!(3 >= 1));");
+        assert_folds("assert(true);", "assert(!undefined == !null);");
+        assert_folds("assert(!a);", "assert(!a);");
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let (ast, warnings) = fold_source("print(7 / (1 - 1)); print(a / 0);");
+        assert_eq!(
+            parse("print(0); print(a / 0);").expect("Parser failed"),
+            ast
+        );
+        assert_eq!(
+            vec![
+                "1:1: warning: division by zero in `7 / 0` always gives 0 [constant-folding]",
+                "1:21: warning: division by zero in `a / 0` always gives 0 [constant-folding]",
+            ],
+            warnings
+        );
+        let (_, warnings) = fold_source(
+            "function f(a) {\n  while (a / 0) {\n    if (1) { a = 1; } else { return a / (2 - 2); }\n  }\n}",
+        );
+        assert_eq!(
+            vec![
+                "2:3: warning: division by zero in `a / 0` always gives 0 [constant-folding]",
+                "3:30: warning: division by zero in `a / 0` always gives 0 [constant-folding]",
+            ],
+            warnings
+        );
+    }
+
+    #[test]
+    fn agrees_with_interpreter() {
+        check_against_interpreter(0..300, |ast, out| {
+            let (folded, _) = fold_source(&ast.to_string());
+            Interpreter::with_max_steps(100_000)
+                .execute(&folded, out)
+                .expect("Folded program failed")
+        });
+    }
+}
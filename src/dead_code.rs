@@ -0,0 +1,379 @@
+use crate::ast::AST;
+use crate::error::CompileError;
+use crate::parser::statement_count;
+use crate::pass_manager::{Diagnostics, Pass};
+use crate::visitor::{walk_call, AstVisitor, Visitor};
+use std::collections::{HashMap, HashSet};
+
+/// Removes code that never runs, with a warning located at it:
+///
+/// - statements following a `return` in the same block, except function
+///   declarations,
+/// - the branch of an `if` whose condition is a literal that isn't taken, and
+///   `while` loops whose condition is a literal false value,
+/// - top-level functions that `main` never calls, directly or not.
+///
+/// Conditions are only recognised once they are literals, so this runs after
+/// `ConstantFolding`. No warning is given for an empty `else {}`.
+pub struct DeadCodeElimination;
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "dead-code-elimination"
+    }
+
+    fn run(&mut self, ast: AST, diagnostics: &mut Diagnostics) -> Result<AST, CompileError> {
+        let mut eliminator = Eliminator {
+            diagnostics,
+            next: 0,
+            functions: HashMap::new(),
+        };
+        let ast = eliminator.statement(ast).unwrap_or(AST::Block(vec![]));
+        let Eliminator {
+            diagnostics,
+            functions,
+            ..
+        } = eliminator;
+        remove_uncalled_functions(ast, |name| {
+            diagnostics.statement_warning(
+                functions[name],
+                format!("function `{}` is never called", name),
+            );
+            true
+        })
+    }
+}
+
+struct Eliminator<'a> {
+    diagnostics: &'a mut Diagnostics,
+    /// Number of the next statement, see `parser::parse_with_locations`.
+    next: usize,
+    /// Number of the statement declaring each function.
+    functions: HashMap<String, usize>,
+}
+
+/// Whether a literal condition holds, the way `cmp r0, #0` tests it.
+fn constant_condition(node: &AST) -> Option<bool> {
+    match node {
+        AST::Number(number) => Some(*number as u32 != 0),
+        AST::Boolean(value) => Some(*value),
+        AST::Undefined | AST::Null => Some(false),
+        _ => None,
+    }
+}
+
+fn is_empty_block(statement: &AST) -> bool {
+    matches!(statement, AST::Block(statements) if statements.is_empty())
+}
+
+/// Whether every way through the statement ends in a `return`.
+fn always_returns(statement: &AST) -> bool {
+    match statement {
+        AST::Return { .. } => true,
+        AST::Block(statements) => statements.iter().any(always_returns),
+        AST::IfNode {
+            consequence,
+            alternative,
+            ..
+        } => always_returns(consequence) && always_returns(alternative),
+        _ => false,
+    }
+}
+
+impl Eliminator<'_> {
+    /// Skips a statement that is removed, with everything nested in it.
+    fn skip(&mut self, statement: &AST) {
+        self.next += statement_count(statement);
+    }
+
+    /// Returns what is left of a statement, or `None` if none of it runs.
+    fn statement(&mut self, statement: AST) -> Option<AST> {
+        let number = self.next;
+        self.next += 1;
+        match statement {
+            AST::Block(statements) => Some(AST::Block(self.statements(statements))),
+            AST::Main(statements) => Some(AST::Main(self.statements(statements))),
+            AST::IfNode {
+                conditional,
+                consequence,
+                alternative,
+            } => {
+                let Some(condition) = constant_condition(&conditional) else {
+                    return Some(AST::IfNode {
+                        conditional,
+                        consequence: self.branch(*consequence).into(),
+                        alternative: self.branch(*alternative).into(),
+                    });
+                };
+                let dead_number = if condition {
+                    self.next + statement_count(&consequence)
+                } else {
+                    self.next
+                };
+                let dead = if condition {
+                    &alternative
+                } else {
+                    &consequence
+                };
+                if !is_empty_block(dead) {
+                    self.diagnostics.statement_warning(
+                        dead_number,
+                        format!("unreachable code: the condition is always {}", condition),
+                    );
+                }
+                let taken = if condition {
+                    let taken = self.statement(*consequence);
+                    self.skip(&alternative);
+                    taken
+                } else {
+                    self.skip(&consequence);
+                    self.statement(*alternative)
+                };
+                taken.filter(|taken| !is_empty_block(taken))
+            }
+            AST::While { conditional, body } => {
+                if constant_condition(&conditional) == Some(false) {
+                    self.diagnostics
+                        .statement_warning(number, "unreachable code: the loop never runs");
+                    self.skip(&body);
+                    return None;
+                }
+                let body = self.branch(*body).into();
+                Some(AST::While { conditional, body })
+            }
+            AST::Function {
+                name,
+                parameters,
+                body,
+            } => {
+                self.functions.entry(name.clone()).or_insert(number);
+                let body = match *body {
+                    AST::Block(statements) => AST::Block(self.statements(statements)),
+                    body => self.branch(body),
+                };
+                Some(AST::Function {
+                    name,
+                    parameters,
+                    body: body.into(),
+                })
+            }
+            statement => Some(statement),
+        }
+    }
+
+    /// A statement that has to be kept in place, like the body of a loop.
+    fn branch(&mut self, statement: AST) -> AST {
+        self.statement(statement).unwrap_or(AST::Block(vec![]))
+    }
+
+    fn statements(&mut self, statements: Vec<AST>) -> Vec<AST> {
+        let mut live = vec![];
+        let mut statements = statements.into_iter();
+        for statement in statements.by_ref() {
+            if let Some(statement) = self.statement(statement) {
+                let returns = always_returns(&statement);
+                live.push(statement);
+                if returns {
+                    break;
+                }
+            }
+        }
+        let mut warned = false;
+        for statement in statements {
+            // Functions are declared wherever they appear.
+            if let AST::Function { .. } = statement {
+                live.extend(self.statement(statement));
+                continue;
+            }
+            if !warned {
+                self.diagnostics
+                    .statement_warning(self.next, "unreachable code after `return`");
+                warned = true;
+            }
+            self.skip(&statement);
+        }
+        live
+    }
+}
+
+/// Drops the top-level functions that aren't reachable from `main`, or from
+/// top-level statements that aren't functions. Programs without a `main`
+/// function are left alone. Of those functions, only the ones `remove` returns
+/// true for are dropped.
+pub(crate) fn remove_uncalled_functions(
+    ast: AST,
+    mut remove: impl FnMut(&str) -> bool,
+) -> Result<AST, CompileError> {
+    let top_level = match &ast {
+        AST::Block(statements) => statements.as_slice(),
+        other => std::slice::from_ref(other),
+    };
+    let mut bodies = HashMap::new();
+    let mut pending = vec![];
+    for statement in top_level {
+        match statement {
+            AST::Function { name, .. } => {
+                bodies.entry(name.as_str()).or_insert(statement);
+            }
+            other => pending.push(other),
+        }
+    }
+    let Some(&main) = bodies.get("main") else {
+        return Ok(ast);
+    };
+    pending.push(main);
+
+    let mut calls = Calls(HashSet::from(["main".to_string()]));
+    while let Some(node) = pending.pop() {
+        let before = calls.0.clone();
+        node.accept(&mut calls)?;
+        for callee in calls.0.difference(&before) {
+            pending.extend(bodies.get(callee.as_str()));
+        }
+    }
+
+    let AST::Block(statements) = ast else {
+        return Ok(ast);
+    };
+    let mut live = vec![];
+    for statement in statements {
+        match &statement {
+            AST::Function { name, .. } if !calls.0.contains(name) && remove(name) => {}
+            _ => live.push(statement),
+        }
+    }
+    // Like `parse`, which only wraps several statements in a block.
+    if live.len() == 1 {
+        return Ok(live.pop().unwrap());
+    }
+    Ok(AST::Block(live))
+}
+
+/// Collects the names of the functions called.
+struct Calls(HashSet<String>);
+
+/// Names of the functions called anywhere in `node`.
+pub(crate) fn called_functions(node: &AST) -> Result<HashSet<String>, CompileError> {
+    let mut calls = Calls(HashSet::new());
+    node.accept(&mut calls)?;
+    Ok(calls.0)
+}
+
+impl Visitor for Calls {
+    fn visit_call(&mut self, node: &AST, w: &mut ()) -> Result<(), CompileError> {
+        if let AST::Call { callee, .. } = node {
+            self.0.insert(callee.clone());
+        }
+        walk_call(self, node, w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constant_folding::ConstantFolding;
+    use crate::generator::check_against_interpreter;
+    use crate::interpreter::Interpreter;
+    use crate::parser::{parse, parse_with_locations};
+    use crate::pass_manager::PassManager;
+
+    fn eliminate(source: &str) -> (AST, Vec<String>) {
+        let (ast, locations) = parse_with_locations(source).expect("Parser failed");
+        let mut passes = PassManager::new();
+        passes.add(ConstantFolding).add(DeadCodeElimination);
+        passes.locations = locations;
+        let results = passes.run(ast, &mut vec![]).expect("Passes failed");
+        let warnings = results.diagnostics.iter().map(|d| d.to_string()).collect();
+        (results.ast, warnings)
+    }
+
+    fn assert_eliminates(expected: &str, source: &str, warnings: &[&str]) {
+        let (ast, actual) = eliminate(source);
+        assert_eq!(parse(expected).expect("Parser failed"), ast, "{}", ast);
+        assert_eq!(warnings, actual);
+    }
+
+    #[test]
+    fn after_return() {
+        assert_eliminates(
+            "function main() { print(1); return 0; }",
+            "function main() {\n  print(1);\n  return 0;\n  print(2);\n  print(3);\n}",
+            &["4:3: warning: unreachable code after `return` [dead-code-elimination]"],
+        );
+        assert_eliminates(
+            "function main() { if (a) { return 1; } else { return 2; } }",
+            "function main() { if (a) { return 1; } else { return 2; } a = 3; }",
+            &["1:59: warning: unreachable code after `return` [dead-code-elimination]"],
+        );
+        assert_eliminates(
+            "function main() { while (a) { return 1; } return 2; }",
+            "function main() { while (a) { return 1; a = 1; } return 2; }",
+            &["1:41: warning: unreachable code after `return` [dead-code-elimination]"],
+        );
+        assert_eliminates(
+            "function main() { return f(); function f() { return 1; } }",
+            "function main() { return f(); function f() { return 1; } }",
+            &[],
+        );
+    }
+
+    #[test]
+    fn constant_conditions() {
+        assert_eliminates(
+            "function main() { { print(2); } print(3); }",
+            "function main() {\n  if (1 > 2) {\n    print(1);\n  } else {\n    print(2);\n  }\n  while (false) {}\n  print(3);\n}",
+            &[
+                "2:14: warning: unreachable code: the condition is always false [dead-code-elimination]",
+                "7:3: warning: unreachable code: the loop never runs [dead-code-elimination]",
+            ],
+        );
+        assert_eliminates(
+            "function main() { { print(1); } }",
+            "function main() { if (!0) { print(1); } else {} }",
+            &[],
+        );
+        assert_eliminates(
+            "function main() { while (a) {} }",
+            "function main() { while (a) if (null) a = 1; else {} }",
+            &["1:39: warning: unreachable code: the condition is always false [dead-code-elimination]"],
+        );
+        assert_eliminates(
+            "function main() { { return 1; } }",
+            "function main() { if (true) { return 1; } else { print(2); } print(3); }",
+            &[
+                "1:48: warning: unreachable code: the condition is always true [dead-code-elimination]",
+                "1:62: warning: unreachable code after `return` [dead-code-elimination]",
+            ],
+        );
+    }
+
+    #[test]
+    fn uncalled_functions() {
+        assert_eliminates(
+            "function f() { return g(); } function g() { return 1; } function main() { return f(); }",
+            "function f() { return g(); }\nfunction g() { return 1; }\nfunction h() { return f(); }\n\
+             function main() { return f(); }\nfunction k() { if (false) { return k(); } else {} }",
+            &[
+                "5:27: warning: unreachable code: the condition is always false [dead-code-elimination]",
+                "3:1: warning: function `h` is never called [dead-code-elimination]",
+                "5:1: warning: function `k` is never called [dead-code-elimination]",
+            ],
+        );
+        // Without `main` there is nothing to start from.
+        assert_eliminates(
+            "function f() { return 1; }",
+            "function f() { return 1; }",
+            &[],
+        );
+    }
+
+    #[test]
+    fn agrees_with_interpreter() {
+        check_against_interpreter(0..300, |ast, out| {
+            let (eliminated, _) = eliminate(&ast.to_string());
+            Interpreter::with_max_steps(100_000)
+                .execute(&eliminated, out)
+                .expect("Program failed after dead code elimination")
+        });
+    }
+}
@@ -0,0 +1,282 @@
+use crate::arm::{Condition, Directive, Instruction, Line};
+use crate::arm_code_generator::generate_lines;
+use crate::ast::AST;
+use crate::dump::{fields, Field};
+use crate::error::CompileError;
+use std::collections::HashMap;
+
+/// Escapes text for a double-quoted Graphviz string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Draws the tree as a Graphviz digraph, one box per node labelled with its
+/// kind and scalar fields, and edges labelled with the field holding the
+/// child.
+pub fn ast_to_dot(ast: &AST) -> String {
+    let mut out = String::from("digraph ast {\n\tordering=out;\n\tnode [shape=box];\n");
+    ast_node(&mut out, ast, &mut 0);
+    out.push_str("}\n");
+    out
+}
+
+fn ast_node(out: &mut String, ast: &AST, next_id: &mut usize) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+    let (kind, fields) = fields(ast);
+    let mut label = kind.to_string();
+    for (_, field) in &fields {
+        match field {
+            Field::Name(name) => label.push_str(&format!(" {}", name)),
+            Field::Names(names) => label.push_str(&format!(" ({})", names.join(", "))),
+            Field::Number(value) => label.push_str(&format!(" {}", value)),
+            Field::Boolean(value) => label.push_str(&format!(" {}", value)),
+            Field::Node(_) | Field::Nodes(_) => {}
+        }
+    }
+    out.push_str(&format!("\tn{} [label=\"{}\"];\n", id, escape(&label)));
+    for (name, field) in &fields {
+        let children: Vec<(String, &AST)> = match field {
+            Field::Node(node) => vec![(name.to_string(), node)],
+            Field::Nodes(nodes) => nodes
+                .iter()
+                .enumerate()
+                .map(|(i, node)| (format!("{}[{}]", name, i), node))
+                .collect(),
+            _ => continue,
+        };
+        for (edge, child) in children {
+            let child = ast_node(out, child, next_id);
+            out.push_str(&format!("\tn{} -> n{} [label=\"{}\"];\n", id, child, edge));
+        }
+    }
+    id
+}
+
+/// Generates code for the program with `ArmCodeGenerator`, the code
+/// `compile --tree` prints, and draws the control-flow graph of every
+/// function, see `lines_cfg_to_dot`.
+pub fn cfg_to_dot(ast: &AST) -> Result<String, CompileError> {
+    Ok(lines_cfg_to_dot(&generate_lines(ast)?))
+}
+
+/// A straight run of instructions, entered only at the top.
+struct BasicBlock<'a> {
+    label: Option<&'a str>,
+    lines: Vec<&'a Line>,
+    /// Targets of the branch ending the block, with the condition it is taken
+    /// on.
+    jumps: Vec<(&'a str, Option<Condition>)>,
+    falls_through: bool,
+}
+
+impl<'a> BasicBlock<'a> {
+    fn new(label: Option<&'a str>) -> BasicBlock<'a> {
+        BasicBlock {
+            label,
+            lines: vec![],
+            jumps: vec![],
+            falls_through: false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.label.is_none() && self.lines.is_empty()
+    }
+
+    /// Blocks made only of directives are data, like the format strings
+    /// `visit_print` emits inline, and are left out of the graph.
+    fn is_data(&self) -> bool {
+        !self.lines.is_empty()
+            && self
+                .lines
+                .iter()
+                .all(|line| matches!(line, Line::Directive(_)))
+    }
+}
+
+/// Splits the code of a function into basic blocks, at labels and after
+/// branches and returns.
+fn basic_blocks<'a>(name: &'a str, lines: &[&'a Line]) -> Vec<BasicBlock<'a>> {
+    let mut blocks = vec![];
+    let mut current = BasicBlock::new(Some(name).filter(|name| !name.is_empty()));
+    for &line in lines {
+        let instruction = match line {
+            Line::Label(label) => {
+                if !current.is_empty() {
+                    current.falls_through = true;
+                    blocks.push(current);
+                }
+                current = BasicBlock::new(Some(label));
+                continue;
+            }
+            Line::Directive(_) => {
+                current.lines.push(line);
+                continue;
+            }
+            Line::Instruction(instruction) => instruction,
+        };
+        current.lines.push(line);
+        if let Instruction::Branch { condition, target } = instruction {
+            let condition = Some(*condition).filter(|c| *c != Condition::Al);
+            current.jumps.push((target, condition));
+        } else if !instruction.ends_flow() {
+            continue;
+        }
+        current.falls_through = !instruction.ends_flow();
+        blocks.push(std::mem::replace(&mut current, BasicBlock::new(None)));
+    }
+    if !current.is_empty() {
+        blocks.push(current);
+    }
+    blocks
+}
+
+/// Draws the control-flow graph of code emitted by `ArmCodeGenerator`, with a
+/// cluster of basic blocks per function.
+///
+/// Blocks are labelled with the labels in the code, `.L<n>` for the ones
+/// `visit_if` and `visit_while` create, and show their instructions. Taken
+/// branches are labelled with their condition, and fall-through edges are
+/// unlabelled. Code outside any function goes in a "top level" cluster.
+pub fn lines_cfg_to_dot(lines: &[Line]) -> String {
+    let mut functions: Vec<(&str, Vec<&Line>)> = vec![("", vec![])];
+    let mut global = None;
+    for line in lines {
+        match line {
+            Line::Directive(Directive::Global(name)) => global = Some(name.as_str()),
+            Line::Label(label) if global == Some(label.as_str()) => {
+                functions.push((global.take().unwrap(), vec![]));
+            }
+            line => functions.last_mut().unwrap().1.push(line),
+        }
+    }
+
+    let mut out = String::from("digraph cfg {\n\tnode [shape=box, fontname=\"monospace\"];\n");
+    for (name, lines) in &functions {
+        let blocks = basic_blocks(name, lines);
+        if blocks.is_empty() {
+            continue;
+        }
+        let title = if name.is_empty() { "top level" } else { name };
+        out.push_str(&format!(
+            "\tsubgraph \"cluster_{}\" {{\n\t\tlabel=\"{}\";\n",
+            escape(name),
+            escape(title)
+        ));
+        let id = |i: usize| format!("\"{}:{}\"", escape(name), i);
+        let labels: HashMap<&str, usize> = blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, block)| block.label.map(|label| (label, i)))
+            .collect();
+        for (i, block) in blocks.iter().enumerate() {
+            if block.is_data() {
+                continue;
+            }
+            let mut text = block
+                .label
+                .map_or(String::new(), |label| format!("{}:\\l", escape(label)));
+            for line in &block.lines {
+                if let Line::Instruction(instruction) = line {
+                    text.push_str(&escape(&instruction.to_string()));
+                    text.push_str("\\l");
+                }
+            }
+            out.push_str(&format!("\t\t{} [label=\"{}\"];\n", id(i), text));
+            for (target, condition) in &block.jumps {
+                if let Some(&j) = labels.get(target) {
+                    let label =
+                        condition.map_or(String::new(), |c| format!(" [label=\"{}\"]", c.suffix()));
+                    out.push_str(&format!("\t\t{} -> {}{};\n", id(i), id(j), label));
+                }
+            }
+            if block.falls_through && blocks.get(i + 1).is_some_and(|next| !next.is_data()) {
+                out.push_str(&format!("\t\t{} -> {};\n", id(i), id(i + 1)));
+            }
+        }
+        out.push_str("\t}\n");
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm;
+    use crate::parser::parse;
+
+    #[test]
+    fn ast() {
+        let ast = parse("function f(a) { return a[0] == b; }").expect("Parser failed");
+        assert_eq!(
+            r#"digraph ast {
+	ordering=out;
+	node [shape=box];
+	n0 [label="Function f (a)"];
+	n1 [label="Block"];
+	n2 [label="Return"];
+	n3 [label="Equal"];
+	n4 [label="ArrayLookup"];
+	n5 [label="Id a"];
+	n4 -> n5 [label="array"];
+	n6 [label="Number 0"];
+	n4 -> n6 [label="index"];
+	n3 -> n4 [label="left"];
+	n7 [label="Id b"];
+	n3 -> n7 [label="right"];
+	n2 -> n3 [label="term"];
+	n1 -> n2 [label="statements[0]"];
+	n0 -> n1 [label="body"];
+}
+"#,
+            ast_to_dot(&ast)
+        );
+        assert!(ast_to_dot(&AST::Id("\"x\"".to_string())).contains(r#"[label="Id \"x\""]"#));
+    }
+
+    #[test]
+    fn cfg() {
+        let ast = parse(
+            r#"function main() {
+                var a = 2;
+                while (a) {
+                    if (a == 1) { print(a); } else {}
+                    a = a - 1;
+                }
+            }"#,
+        )
+        .expect("Parser failed");
+        let dot = cfg_to_dot(&ast).expect("Code generation failed");
+        for expected in [
+            "subgraph \"cluster_main\" {",
+            "\"main:0\" [label=\"main:\\lpush {fp, lr}\\l",
+            "\"main:1\" [label=\".L1:\\l",
+            "\"main:0\" -> \"main:1\";",
+            "\"main:1\" -> \"main:9\" [label=\"eq\"];",
+            "\"main:1\" -> \"main:2\";",
+            "\"main:2\" -> \"main:7\" [label=\"ne\"];",
+            "\"main:3\" -> \"main:6\";",
+            "\"main:6\" -> \"main:8\";",
+            "\"main:7\" [label=\".L3:\\l\"];",
+            "\"main:7\" -> \"main:8\";",
+            "\"main:8\" -> \"main:1\";",
+            "\"main:9\" [label=\".L2:\\l",
+        ] {
+            assert!(dot.contains(expected), "{} not in\n{}", expected, dot);
+        }
+        assert!(!dot.contains(".asciz"), "{}", dot);
+        assert!(!dot.contains("top level"), "{}", dot);
+    }
+
+    #[test]
+    fn top_level_code() {
+        let lines = arm::parse("\tmov r0, #1\n\tbne .L1\n.L1:\n\tbx lr\n").expect("Parse failed");
+        let dot = lines_cfg_to_dot(&lines);
+        assert!(dot.contains("label=\"top level\""), "{}", dot);
+        assert!(dot.contains("\":0\" -> \":1\" [label=\"ne\"];"), "{}", dot);
+    }
+}
@@ -0,0 +1,699 @@
+use crate::ast::AST;
+use crate::error::CompileError;
+use crate::parser::{check_depth, nested_statements, MAX_NESTING};
+use peg::str::LineCol;
+use std::collections::HashMap;
+
+/// A field of a node, as written in a dump.
+pub(crate) enum Field<'a> {
+    Node(&'a AST),
+    Nodes(&'a [AST]),
+    Name(&'a str),
+    Names(&'a [String]),
+    Number(u64),
+    Boolean(bool),
+}
+
+fn binary_kind(ast: &AST) -> Option<(&'static str, &AST, &AST)> {
+    let (kind, left, right) = match ast {
+        AST::Equal { left, right } => ("Equal", left, right),
+        AST::NotEqual { left, right } => ("NotEqual", left, right),
+        AST::Add { left, right } => ("Add", left, right),
+        AST::Subtract { left, right } => ("Subtract", left, right),
+        AST::Multiply { left, right } => ("Multiply", left, right),
+        AST::Divide { left, right } => ("Divide", left, right),
+        AST::LessThan { left, right } => ("LessThan", left, right),
+        AST::GreaterThan { left, right } => ("GreaterThan", left, right),
+        AST::LessThanEqual { left, right } => ("LessThanEqual", left, right),
+        AST::GreaterThanEqual { left, right } => ("GreaterThanEqual", left, right),
+        _ => return None,
+    };
+    Some((kind, left, right))
+}
+
+type BinaryNode = fn(Box<AST>, Box<AST>) -> AST;
+
+fn binary_node(kind: &str) -> Option<BinaryNode> {
+    Some(match kind {
+        "Equal" => |left, right| AST::Equal { left, right },
+        "NotEqual" => |left, right| AST::NotEqual { left, right },
+        "Add" => |left, right| AST::Add { left, right },
+        "Subtract" => |left, right| AST::Subtract { left, right },
+        "Multiply" => |left, right| AST::Multiply { left, right },
+        "Divide" => |left, right| AST::Divide { left, right },
+        "LessThan" => |left, right| AST::LessThan { left, right },
+        "GreaterThan" => |left, right| AST::GreaterThan { left, right },
+        "LessThanEqual" => |left, right| AST::LessThanEqual { left, right },
+        "GreaterThanEqual" => |left, right| AST::GreaterThanEqual { left, right },
+        _ => return None,
+    })
+}
+
+/// The kind of a node and its fields, in declaration order.
+///
+/// The kind is the name of the `AST` variant, and fields are named as in
+/// `AST` or, for tuple variants, after what they hold: `Number`, `Boolean`
+/// and `Print` have a `value`, `Id` a `name`, `Not` a `term`, `Block` and
+/// `Main` their `statements`, `ArrayLiteral` its `items`, `ArrayLength` an
+/// `array` and `Assert` a `condition`.
+pub(crate) fn fields(ast: &AST) -> (&'static str, Vec<(&'static str, Field<'_>)>) {
+    if let Some((kind, left, right)) = binary_kind(ast) {
+        return (
+            kind,
+            vec![("left", Field::Node(left)), ("right", Field::Node(right))],
+        );
+    }
+    match ast {
+        AST::Number(value) => ("Number", vec![("value", Field::Number(*value))]),
+        AST::Id(name) => ("Id", vec![("name", Field::Name(name))]),
+        AST::Not(term) => ("Not", vec![("term", Field::Node(term))]),
+        AST::Call { callee, args } => (
+            "Call",
+            vec![
+                ("callee", Field::Name(callee)),
+                ("args", Field::Nodes(args)),
+            ],
+        ),
+        AST::Return { term } => ("Return", vec![("term", Field::Node(term))]),
+        AST::Block(statements) => ("Block", vec![("statements", Field::Nodes(statements))]),
+        AST::IfNode {
+            conditional,
+            consequence,
+            alternative,
+        } => (
+            "IfNode",
+            vec![
+                ("conditional", Field::Node(conditional)),
+                ("consequence", Field::Node(consequence)),
+                ("alternative", Field::Node(alternative)),
+            ],
+        ),
+        AST::Function {
+            name,
+            parameters,
+            body,
+        } => (
+            "Function",
+            vec![
+                ("name", Field::Name(name)),
+                ("parameters", Field::Names(parameters)),
+                ("body", Field::Node(body)),
+            ],
+        ),
+        AST::Var { name, value } => (
+            "Var",
+            vec![("name", Field::Name(name)), ("value", Field::Node(value))],
+        ),
+        AST::Assign { name, value } => (
+            "Assign",
+            vec![("name", Field::Name(name)), ("value", Field::Node(value))],
+        ),
+        AST::While { conditional, body } => (
+            "While",
+            vec![
+                ("conditional", Field::Node(conditional)),
+                ("body", Field::Node(body)),
+            ],
+        ),
+        AST::Undefined => ("Undefined", vec![]),
+        AST::Null => ("Null", vec![]),
+        AST::Boolean(value) => ("Boolean", vec![("value", Field::Boolean(*value))]),
+        AST::ArrayLiteral(items) => ("ArrayLiteral", vec![("items", Field::Nodes(items))]),
+        AST::ArrayLookup { array, index } => (
+            "ArrayLookup",
+            vec![("array", Field::Node(array)), ("index", Field::Node(index))],
+        ),
+        AST::ArrayLength(array) => ("ArrayLength", vec![("array", Field::Node(array))]),
+        AST::Main(statements) => ("Main", vec![("statements", Field::Nodes(statements))]),
+        AST::Assert(condition) => ("Assert", vec![("condition", Field::Node(condition))]),
+        AST::Print(value) => ("Print", vec![("value", Field::Node(value))]),
+        _ => unreachable!("binary nodes are handled above"),
+    }
+}
+
+/// Writes a string as a JSON string literal.
+fn quote(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn quote_names(out: &mut String, names: &[String]) {
+    for (i, name) in names.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        quote(out, name);
+    }
+}
+
+const INDENT: &str = "  ";
+
+fn new_line(out: &mut String, indent: usize) {
+    out.push('\n');
+    for _ in 0..indent {
+        out.push_str(INDENT);
+    }
+}
+
+/// Where the statements of a tree start, by node, from the locations
+/// `parser::parse_with_locations` returns for it.
+type Locations = HashMap<*const AST, LineCol>;
+
+fn statement_locations(ast: &AST, locations: &[Option<LineCol>]) -> Locations {
+    fn walk(statement: &AST, next: &mut usize, from: &[Option<LineCol>], to: &mut Locations) {
+        if let Some(Some(location)) = from.get(*next) {
+            to.insert(statement, *location);
+        }
+        *next += 1;
+        for nested in nested_statements(statement) {
+            walk(nested, next, from, to);
+        }
+    }
+    let mut found = HashMap::new();
+    if !locations.is_empty() {
+        walk(ast, &mut 0, locations, &mut found);
+    }
+    found
+}
+
+/// Dumps the tree as indented JSON, with an object per node holding its
+/// `kind` and its fields (see `fields`). `from_json` reads it back.
+pub fn to_json(ast: &AST) -> String {
+    to_json_with_locations(ast, &[])
+}
+
+/// Dumps the tree like `to_json`, adding the `line` and `column` where each
+/// statement starts, from the locations `parser::parse_with_locations`
+/// returns. `from_json` ignores them.
+pub fn to_json_with_locations(ast: &AST, locations: &[Option<LineCol>]) -> String {
+    let mut out = String::new();
+    json_node(&mut out, ast, 0, &statement_locations(ast, locations));
+    out.push('\n');
+    out
+}
+
+fn json_node(out: &mut String, ast: &AST, indent: usize, locations: &Locations) {
+    let (kind, fields) = fields(ast);
+    out.push('{');
+    new_line(out, indent + 1);
+    out.push_str("\"kind\": ");
+    quote(out, kind);
+    if let Some(location) = locations.get(&(ast as *const AST)) {
+        out.push(',');
+        new_line(out, indent + 1);
+        out.push_str(&format!("\"line\": {},", location.line));
+        new_line(out, indent + 1);
+        out.push_str(&format!("\"column\": {}", location.column));
+    }
+    for (name, field) in fields {
+        out.push(',');
+        new_line(out, indent + 1);
+        quote(out, name);
+        out.push_str(": ");
+        match field {
+            Field::Node(node) => json_node(out, node, indent + 1, locations),
+            Field::Nodes(nodes) => {
+                out.push('[');
+                for (i, node) in nodes.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    new_line(out, indent + 2);
+                    json_node(out, node, indent + 2, locations);
+                }
+                if !nodes.is_empty() {
+                    new_line(out, indent + 1);
+                }
+                out.push(']');
+            }
+            Field::Name(name) => quote(out, name),
+            Field::Names(names) => {
+                out.push('[');
+                for (i, name) in names.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    quote(out, name);
+                }
+                out.push(']');
+            }
+            Field::Number(value) => out.push_str(&value.to_string()),
+            Field::Boolean(value) => out.push_str(&value.to_string()),
+        }
+    }
+    new_line(out, indent);
+    out.push('}');
+}
+
+/// Dumps the tree as indented S-expressions.
+///
+/// Nodes whose children are all leaves are written on one line; the others
+/// have each child node on its own line. Lists of nodes are spliced into the
+/// parent, and lists of names, like parameters, are written in parentheses.
+pub fn to_sexp(ast: &AST) -> String {
+    to_sexp_with_locations(ast, &[])
+}
+
+/// Dumps the tree like `to_sexp`, writing `@line:column` after the kind of
+/// each statement, from the locations `parser::parse_with_locations` returns.
+pub fn to_sexp_with_locations(ast: &AST, locations: &[Option<LineCol>]) -> String {
+    let mut out = String::new();
+    sexp_node(&mut out, ast, 0, &statement_locations(ast, locations));
+    out.push('\n');
+    out
+}
+
+fn is_leaf(ast: &AST) -> bool {
+    fields(ast)
+        .1
+        .iter()
+        .all(|(_, field)| !matches!(field, Field::Node(_) | Field::Nodes(_)))
+}
+
+fn sexp_node(out: &mut String, ast: &AST, indent: usize, locations: &Locations) {
+    let (kind, fields) = fields(ast);
+    let children = || {
+        fields.iter().flat_map(|(_, field)| match field {
+            Field::Node(node) => std::slice::from_ref(*node),
+            Field::Nodes(nodes) => nodes,
+            _ => &[],
+        })
+    };
+    let one_line = children().all(is_leaf);
+    out.push('(');
+    out.push_str(kind);
+    if let Some(location) = locations.get(&(ast as *const AST)) {
+        out.push_str(&format!(" @{}:{}", location.line, location.column));
+    }
+    for (_, field) in &fields {
+        match field {
+            Field::Name(name) => {
+                out.push(' ');
+                quote(out, name);
+            }
+            Field::Names(names) => {
+                out.push_str(" (");
+                quote_names(out, names);
+                out.push(')');
+            }
+            Field::Number(value) => out.push_str(&format!(" {}", value)),
+            Field::Boolean(value) => out.push_str(&format!(" {}", value)),
+            Field::Node(_) | Field::Nodes(_) => {}
+        }
+    }
+    for child in children() {
+        if one_line {
+            out.push(' ');
+        } else {
+            new_line(out, indent + 1);
+        }
+        sexp_node(out, child, indent + 1, locations);
+    }
+    out.push(')');
+}
+
+/// A parsed JSON value.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Boolean(bool),
+    /// Numbers are kept as written, so that integers outside the range of a
+    /// `f64` survive.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+peg::parser! {
+  grammar json_parser() for str {
+
+    rule _ = quiet!{[' ' | '\n' | '\t' | '\r']*}
+
+    pub rule document() -> Json
+      = _ v:value() _ { v }
+
+    rule value() -> Json
+      = "null" { Json::Null }
+      / "true" { Json::Boolean(true) }
+      / "false" { Json::Boolean(false) }
+      / n:$("-"? ['0'..='9']+ ("." ['0'..='9']+)? (['e' | 'E'] ['+' | '-']? ['0'..='9']+)?) {
+            Json::Number(n.to_string())
+        }
+      / s:string() { Json::String(s) }
+      / "[" _ items:(value() ** (_ "," _)) _ "]" { Json::Array(items) }
+      / "{" _ members:(member() ** (_ "," _)) _ "}" { Json::Object(members) }
+
+    rule member() -> (String, Json)
+      = k:string() _ ":" _ v:value() { (k, v) }
+
+    rule string() -> String
+      = "\"" s:character()* "\"" { s.into_iter().collect() }
+
+    rule character() -> char
+      = [^ '"' | '\\' | '\0'..='\x1f']
+      / "\\" c:escape() { c }
+
+    rule escape() -> char
+      = "\"" { '"' }
+      / "\\" { '\\' }
+      / "/" { '/' }
+      / "b" { '\x08' }
+      / "f" { '\x0c' }
+      / "n" { '\n' }
+      / "r" { '\r' }
+      / "t" { '\t' }
+      / "u" h:$(['0'..='9' | 'a'..='f' | 'A'..='F']*<4>) {?
+            char::from_u32(u32::from_str_radix(h, 16).unwrap())
+                .ok_or("a \\u escape outside the surrogate range")
+        }
+  }
+}
+
+/// Rejects documents nested deeper than the parser would build a tree, for
+/// the same reason as `parser::MAX_NESTING`: a JSON object per node, plus an
+/// array for lists.
+fn check_nesting(input: &str) -> Result<(), CompileError> {
+    let mut depth: isize = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in input.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '[' | '{' if !in_string => depth += 1,
+            ']' | '}' if !in_string => depth -= 1,
+            _ => continue,
+        }
+        if depth > 2 * MAX_NESTING as isize {
+            return Err(CompileError::SyntaxError(format!(
+                "JSON is nested more than {} levels deep",
+                2 * MAX_NESTING
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Loads a tree written by `to_json`.
+///
+/// Fields that a node doesn't have are ignored, like the locations that
+/// `to_json_with_locations` adds. Apart from its depth, the tree isn't checked
+/// any further, so it may have shapes that `parser::parse` never produces.
+pub fn from_json(input: &str) -> Result<AST, CompileError> {
+    check_nesting(input)?;
+    let json = json_parser::document(input).map_err(CompileError::ParseError)?;
+    let ast = node(&json).map_err(CompileError::SyntaxError)?;
+    check_depth(&ast)?;
+    Ok(ast)
+}
+
+/// The fields of a JSON object describing a node of the given kind.
+struct Object<'a> {
+    kind: &'a str,
+    members: &'a [(String, Json)],
+}
+
+impl<'a> Object<'a> {
+    fn get(&self, name: &str) -> Result<&'a Json, String> {
+        self.members
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| format!("{} has no field \"{}\"", self.kind, name))
+    }
+
+    fn expected(&self, name: &str, what: &str) -> String {
+        format!("Field \"{}\" of {} should be {}", name, self.kind, what)
+    }
+
+    fn node(&self, name: &str) -> Result<Box<AST>, String> {
+        Ok(node(self.get(name)?)?.into())
+    }
+
+    fn nodes(&self, name: &str) -> Result<Vec<AST>, String> {
+        match self.get(name)? {
+            Json::Array(items) => items.iter().map(node).collect(),
+            _ => Err(self.expected(name, "an array of nodes")),
+        }
+    }
+
+    fn name(&self, name: &str) -> Result<String, String> {
+        match self.get(name)? {
+            Json::String(s) => Ok(s.clone()),
+            _ => Err(self.expected(name, "a string")),
+        }
+    }
+
+    fn names(&self, name: &str) -> Result<Vec<String>, String> {
+        match self.get(name)? {
+            Json::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    Json::String(s) => Ok(s.clone()),
+                    _ => Err(self.expected(name, "an array of strings")),
+                })
+                .collect(),
+            _ => Err(self.expected(name, "an array of strings")),
+        }
+    }
+
+    fn number(&self, name: &str) -> Result<u64, String> {
+        match self.get(name)? {
+            Json::Number(n) => n
+                .parse()
+                .map_err(|_| self.expected(name, "an integer between 0 and 2^64 - 1")),
+            _ => Err(self.expected(name, "a number")),
+        }
+    }
+
+    fn boolean(&self, name: &str) -> Result<bool, String> {
+        match self.get(name)? {
+            Json::Boolean(b) => Ok(*b),
+            _ => Err(self.expected(name, "a boolean")),
+        }
+    }
+}
+
+fn node(json: &Json) -> Result<AST, String> {
+    let Json::Object(members) = json else {
+        return Err(format!("Expected an object for a node, got: {:?}", json));
+    };
+    let kind = match members.iter().find(|(key, _)| key == "kind") {
+        Some((_, Json::String(kind))) => kind.as_str(),
+        _ => return Err("Node has no \"kind\"".to_string()),
+    };
+    let o = Object { kind, members };
+    if let Some(binary) = binary_node(kind) {
+        return Ok(binary(o.node("left")?, o.node("right")?));
+    }
+    Ok(match kind {
+        "Number" => AST::Number(o.number("value")?),
+        "Id" => AST::Id(o.name("name")?),
+        "Not" => AST::Not(o.node("term")?),
+        "Call" => AST::Call {
+            callee: o.name("callee")?,
+            args: o.nodes("args")?,
+        },
+        "Return" => AST::Return {
+            term: o.node("term")?,
+        },
+        "Block" => AST::Block(o.nodes("statements")?),
+        "IfNode" => AST::IfNode {
+            conditional: o.node("conditional")?,
+            consequence: o.node("consequence")?,
+            alternative: o.node("alternative")?,
+        },
+        "Function" => AST::Function {
+            name: o.name("name")?,
+            parameters: o.names("parameters")?,
+            body: o.node("body")?,
+        },
+        "Var" => AST::Var {
+            name: o.name("name")?,
+            value: o.node("value")?,
+        },
+        "Assign" => AST::Assign {
+            name: o.name("name")?,
+            value: o.node("value")?,
+        },
+        "While" => AST::While {
+            conditional: o.node("conditional")?,
+            body: o.node("body")?,
+        },
+        "Undefined" => AST::Undefined,
+        "Null" => AST::Null,
+        "Boolean" => AST::Boolean(o.boolean("value")?),
+        "ArrayLiteral" => AST::ArrayLiteral(o.nodes("items")?),
+        "ArrayLookup" => AST::ArrayLookup {
+            array: o.node("array")?,
+            index: o.node("index")?,
+        },
+        "ArrayLength" => AST::ArrayLength(o.node("array")?),
+        "Main" => AST::Main(o.nodes("statements")?),
+        "Assert" => AST::Assert(o.node("condition")?),
+        "Print" => AST::Print(o.node("value")?),
+        _ => return Err(format!("Unknown node kind: {}", kind)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::ProgramGenerator;
+    use crate::parser::{parse, parse_with_locations};
+
+    #[test]
+    fn json() {
+        let ast = parse("function f(a, b) { return a + 1; }").expect("Parser failed");
+        assert_eq!(
+            r#"{
+  "kind": "Function",
+  "name": "f",
+  "parameters": ["a", "b"],
+  "body": {
+    "kind": "Block",
+    "statements": [
+      {
+        "kind": "Return",
+        "term": {
+          "kind": "Add",
+          "left": {
+            "kind": "Id",
+            "name": "a"
+          },
+          "right": {
+            "kind": "Number",
+            "value": 1
+          }
+        }
+      }
+    ]
+  }
+}
+"#,
+            to_json(&ast)
+        );
+        assert_eq!(
+            "{\n  \"kind\": \"ArrayLiteral\",\n  \"items\": []\n}\n",
+            to_json(&AST::ArrayLiteral(vec![]))
+        );
+    }
+
+    #[test]
+    fn sexp() {
+        let ast = parse(
+            r#"function f(a, b) {
+                if (!a) { print(a + b * 2); } else {}
+                return [true, null, undefined];
+            }"#,
+        )
+        .expect("Parser failed");
+        assert_eq!(
+            r#"(Function "f" ("a" "b")
+  (Block
+    (IfNode
+      (Not (Id "a"))
+      (Block
+        (Print
+          (Add
+            (Id "a")
+            (Multiply (Id "b") (Number 2)))))
+      (Block))
+    (Return
+      (ArrayLiteral (Boolean true) (Null) (Undefined)))))
+"#,
+            to_sexp(&ast)
+        );
+    }
+
+    #[test]
+    fn locations() {
+        let source = "function f(a) {\n  if (a) { print(a); } else {}\n}\nprint(1);";
+        let (ast, locations) = parse_with_locations(source).expect("Parser failed");
+        assert_eq!(
+            r#"(Block
+  (Function @1:1 "f" ("a")
+    (Block
+      (IfNode @2:3
+        (Id "a")
+        (Block @2:10
+          (Print @2:12 (Id "a")))
+        (Block @2:29))))
+  (Print @4:1 (Number 1)))
+"#,
+            to_sexp_with_locations(&ast, &locations)
+        );
+        let json = to_json_with_locations(&ast, &locations);
+        assert!(
+            json.contains(
+                "{\n      \"kind\": \"Print\",\n      \"line\": 4,\n      \"column\": 1,\n      \"value\": {"
+            ),
+            "{}",
+            json
+        );
+        assert!(
+            json.starts_with("{\n  \"kind\": \"Block\",\n  \"statements\""),
+            "{}",
+            json
+        );
+        assert_eq!(ast, from_json(&json).expect("Loading failed"));
+        assert_eq!(to_json(&ast), to_json_with_locations(&ast, &[]));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for seed in 0..100 {
+            let ast = ProgramGenerator::new(seed).program();
+            assert_eq!(ast, from_json(&to_json(&ast)).expect("Loading failed"));
+        }
+        let odd = AST::Block(vec![
+            AST::Main(vec![AST::Id("with \"quotes\"\n\\ and \u{1} é".to_string())]),
+            AST::Number(u64::MAX),
+        ]);
+        assert_eq!(odd, from_json(&to_json(&odd)).expect("Loading failed"));
+    }
+
+    #[test]
+    fn loading() {
+        assert_eq!(
+            AST::Print(AST::Id("é\n".to_string()).into()),
+            from_json(
+                r#" {"value": {"name": "é\n", "kind": "Id", "span": null}, "kind": "Print"} "#
+            )
+            .expect("Loading failed")
+        );
+        assert!(matches!(
+            from_json(r#"{"kind": "Print"}"#),
+            Err(CompileError::SyntaxError(message)) if message == "Print has no field \"value\""
+        ));
+        assert!(matches!(
+            from_json(r#"{"kind": "Number", "value": -1}"#),
+            Err(CompileError::SyntaxError(_))
+        ));
+        assert!(matches!(
+            from_json(r#"{"kind": "Goto"}"#),
+            Err(CompileError::SyntaxError(_))
+        ));
+        assert!(matches!(
+            from_json(r#"{"kind": "Null""#),
+            Err(CompileError::ParseError(_))
+        ));
+        assert!(matches!(
+            from_json(&"[".repeat(100_000)),
+            Err(CompileError::SyntaxError(_))
+        ));
+    }
+}
@@ -0,0 +1,642 @@
+//! Writes 32-bit little-endian ARM ELF files.
+
+use crate::encoder::{Object, Relocation, RelocationKind, Section, Target};
+use crate::error::CompileError;
+
+pub const EM_ARM: u16 = 40;
+/// Version 5 of the ARM EABI, with floating point arguments in registers.
+pub const EF_ARM_FLAGS: u32 = 0x0500_0400;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_REL: u32 = 9;
+
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const SHF_INFO_LINK: u32 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+pub const HEADER_SIZE: u32 = 52;
+const SECTION_HEADER_SIZE: u32 = 40;
+const PROGRAM_HEADER_SIZE: u32 = 32;
+
+/// Where executables are loaded.
+pub const BASE_ADDRESS: u32 = 0x10000;
+const PAGE_SIZE: u32 = 0x1000;
+
+fn relocation_type(kind: RelocationKind) -> u32 {
+    match kind {
+        RelocationKind::Call => 28,
+        RelocationKind::Jump => 29,
+        RelocationKind::MovwPrel => 45,
+        RelocationKind::MovtPrel => 46,
+    }
+}
+
+/// Little-endian bytes, with helpers to append the fields of ELF
+/// structures.
+#[derive(Default)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Bytes {
+    pub fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn len(&self) -> u32 {
+        self.0.len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn align(&mut self, alignment: u32) {
+        while !self.len().is_multiple_of(alignment) {
+            self.u8(0);
+        }
+    }
+
+    /// The identification bytes and the fields of the file header up to
+    /// `e_entry`.
+    pub fn header_start(&mut self, kind: u16) {
+        self.0.extend_from_slice(b"\x7fELF");
+        // 32 bits, little-endian, version 1, System V ABI.
+        self.0.extend_from_slice(&[1, 1, 1, 0]);
+        self.0.extend_from_slice(&[0; 8]);
+        self.u16(kind);
+        self.u16(EM_ARM);
+        self.u32(1);
+    }
+}
+
+/// A table of zero-terminated names, starting with the empty one.
+struct Strings(Vec<u8>);
+
+impl Strings {
+    fn new() -> Strings {
+        Strings(vec![0])
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(name.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    alignment: u32,
+    entry_size: u32,
+}
+
+/// The index of a section in the section headers of an object file.
+fn index(section: Section) -> u16 {
+    match section {
+        Section::Text => 1,
+        Section::Data => 2,
+        Section::Rodata => 3,
+    }
+}
+
+/// Writes a relocatable object file, for the system linker.
+///
+/// Its sections are `.text`, `.data`, `.rodata`, the relocations of
+/// `.text`, and the symbol and string tables. The symbols are the sections,
+/// the `$a` and `$d` mapping symbols marking code and data, the `.global`
+/// functions and the undefined symbols.
+pub fn object_file(object: &Object) -> Vec<u8> {
+    let mut names = Strings::new();
+    let mut symbols = Bytes::default();
+    let mut symbol = |name: u32, value: u32, info: u8, section: u16| {
+        symbols.u32(name);
+        symbols.u32(value);
+        symbols.u32(0);
+        symbols.u8(info);
+        symbols.u8(0);
+        symbols.u16(section);
+    };
+    symbol(0, 0, 0, 0);
+    for section in [Section::Text, Section::Data, Section::Rodata] {
+        symbol(0, 0, STB_LOCAL << 4 | STT_SECTION, index(section));
+    }
+    let mut locals = 4;
+    if !object.text.is_empty() {
+        symbol(
+            names.add("$a"),
+            0,
+            STB_LOCAL << 4 | STT_NOTYPE,
+            index(Section::Text),
+        );
+        locals += 1;
+    }
+    if !object.rodata.is_empty() {
+        symbol(
+            names.add("$d"),
+            0,
+            STB_LOCAL << 4 | STT_NOTYPE,
+            index(Section::Rodata),
+        );
+        locals += 1;
+    }
+    for global in &object.symbols {
+        let name = names.add(&global.name);
+        match global.definition {
+            Some((section, value)) => {
+                let kind = if section == Section::Text {
+                    STT_FUNC
+                } else {
+                    STT_NOTYPE
+                };
+                symbol(name, value, STB_GLOBAL << 4 | kind, index(section))
+            }
+            None => symbol(name, 0, STB_GLOBAL << 4 | STT_NOTYPE, 0),
+        }
+    }
+
+    let mut relocations = Bytes::default();
+    for relocation in &object.relocations {
+        let symbol = match &relocation.target {
+            Target::Section(section) => index(*section) as u32,
+            Target::Symbol(name) => {
+                let position = object
+                    .symbols
+                    .iter()
+                    .position(|symbol| &symbol.name == name)
+                    .expect("Relocation against an unknown symbol");
+                locals + position as u32
+            }
+        };
+        relocations.u32(relocation.offset);
+        relocations.u32(symbol << 8 | relocation_type(relocation.kind));
+    }
+
+    let mut section_names = Strings::new();
+    let names_of_sections = [
+        ".text",
+        ".data",
+        ".rodata",
+        ".rel.text",
+        ".symtab",
+        ".strtab",
+        ".shstrtab",
+    ]
+    .map(|name| section_names.add(name));
+    let mut out = Bytes(vec![0; HEADER_SIZE as usize]);
+    let mut headers = vec![SectionHeader::default()];
+    let section = |kind, flags| SectionHeader {
+        kind,
+        flags,
+        alignment: if kind == SHT_STRTAB { 1 } else { 4 },
+        ..SectionHeader::default()
+    };
+    let sections: [(SectionHeader, &[u8]); 7] = [
+        (
+            section(SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR),
+            &object.text,
+        ),
+        (section(SHT_PROGBITS, SHF_ALLOC | SHF_WRITE), &object.data),
+        (section(SHT_PROGBITS, SHF_ALLOC), &object.rodata),
+        (
+            SectionHeader {
+                link: 5,
+                info: 1,
+                entry_size: 8,
+                ..section(SHT_REL, SHF_INFO_LINK)
+            },
+            &relocations.0,
+        ),
+        (
+            SectionHeader {
+                link: 6,
+                info: locals,
+                entry_size: 16,
+                ..section(SHT_SYMTAB, 0)
+            },
+            &symbols.0,
+        ),
+        (section(SHT_STRTAB, 0), &names.0),
+        (section(SHT_STRTAB, 0), &section_names.0),
+    ];
+    for (name, (header, contents)) in names_of_sections.into_iter().zip(sections) {
+        out.align(header.alignment);
+        headers.push(SectionHeader {
+            name,
+            offset: out.len(),
+            size: contents.len() as u32,
+            ..header
+        });
+        out.0.extend_from_slice(contents);
+    }
+
+    out.align(4);
+    let section_headers = out.len();
+    for header in &headers {
+        out.u32(header.name);
+        out.u32(header.kind);
+        out.u32(header.flags);
+        out.u32(0);
+        out.u32(header.offset);
+        out.u32(header.size);
+        out.u32(header.link);
+        out.u32(header.info);
+        out.u32(header.alignment);
+        out.u32(header.entry_size);
+    }
+
+    let mut header = Bytes::default();
+    header.header_start(ET_REL);
+    header.u32(0);
+    header.u32(0);
+    header.u32(section_headers);
+    header.u32(EF_ARM_FLAGS);
+    header.u16(HEADER_SIZE as u16);
+    header.u16(0);
+    header.u16(0);
+    header.u16(SECTION_HEADER_SIZE as u16);
+    header.u16(headers.len() as u16);
+    header.u16(headers.len() as u16 - 1);
+    out.0[..HEADER_SIZE as usize].copy_from_slice(&header.0);
+    out.0
+}
+
+/// Fills in a relocation at `place` in `text`, an instruction at address
+/// `address`, given the address of its target.
+fn relocate(
+    text: &mut [u8],
+    place: usize,
+    address: u32,
+    relocation: &Relocation,
+    target: u32,
+) -> Result<(), CompileError> {
+    let bytes: &mut [u8; 4] = (&mut text[place..place + 4]).try_into().unwrap();
+    let word = u32::from_le_bytes(*bytes);
+    let word = match relocation.kind {
+        RelocationKind::Call | RelocationKind::Jump => {
+            let addend = ((word << 8) as i32 >> 6) as u32;
+            let offset = target.wrapping_add(addend).wrapping_sub(address) as i32 >> 2;
+            if !(-(1 << 23)..1 << 23).contains(&offset) {
+                return Err(CompileError::CodeGenError(format!(
+                    "{:?} is out of range of a branch",
+                    relocation.target
+                )));
+            }
+            word & 0xff00_0000 | offset as u32 & 0x00ff_ffff
+        }
+        RelocationKind::MovwPrel | RelocationKind::MovtPrel => {
+            let addend = (word >> 4 & 0xf000 | word & 0xfff) as i16 as u32;
+            let mut value = target.wrapping_add(addend).wrapping_sub(address);
+            if relocation.kind == RelocationKind::MovtPrel {
+                value >>= 16;
+            }
+            word & 0xfff0_f000 | (value & 0xf000) << 4 | value & 0xfff
+        }
+    };
+    *bytes = word.to_le_bytes();
+    Ok(())
+}
+
+/// The addresses the sections of an object are loaded at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub text: u32,
+    pub data: u32,
+    pub rodata: u32,
+}
+
+impl Layout {
+    pub fn address(&self, section: Section, offset: u32) -> u32 {
+        let start = match section {
+            Section::Text => self.text,
+            Section::Data => self.data,
+            Section::Rodata => self.rodata,
+        };
+        start + offset
+    }
+
+    /// The address of a symbol the object defines.
+    pub fn symbol(&self, object: &Object, name: &str) -> Option<u32> {
+        object
+            .symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .and_then(|symbol| symbol.definition)
+            .map(|(section, offset)| self.address(section, offset))
+    }
+}
+
+/// The code of an object with its relocations filled in, to be loaded as
+/// laid out. `external` gives the addresses of the symbols it doesn't define.
+pub fn link(
+    object: &Object,
+    layout: Layout,
+    external: impl Fn(&str) -> Option<u32>,
+) -> Result<Vec<u8>, CompileError> {
+    let mut code = object.text.clone();
+    for relocation in &object.relocations {
+        let target = match &relocation.target {
+            Target::Section(section) => layout.address(*section, 0),
+            Target::Symbol(name) => layout
+                .symbol(object, name)
+                .or_else(|| external(name))
+                .ok_or_else(|| CompileError::CodeGenError(format!("Undefined symbol {}", name)))?,
+        };
+        let place = relocation.offset;
+        let address = layout.address(Section::Text, place);
+        relocate(&mut code, place as usize, address, relocation, target)?;
+    }
+    Ok(code)
+}
+
+/// Links an object into a static executable, starting at its `_start`.
+///
+/// `.text` and `.rodata` are loaded together, read-only, and `.data` into
+/// the pages after them. The object can't use any symbol it doesn't define:
+/// `runtime::lines` has the ones generated code calls.
+pub fn executable(object: &Object) -> Result<Vec<u8>, CompileError> {
+    let segments = if object.data.is_empty() { 1 } else { 2 };
+    let text = HEADER_SIZE + segments * PROGRAM_HEADER_SIZE;
+    let rodata = (text + object.text.len() as u32).next_multiple_of(4);
+    let code_size = rodata + object.rodata.len() as u32;
+    let data = code_size.next_multiple_of(PAGE_SIZE);
+    let layout = Layout {
+        text: BASE_ADDRESS + text,
+        data: BASE_ADDRESS + data,
+        rodata: BASE_ADDRESS + rodata,
+    };
+    let code = link(object, layout, |_| None)?;
+    let entry = layout
+        .symbol(object, "_start")
+        .ok_or_else(|| CompileError::CodeGenError("Undefined symbol _start".to_string()))?;
+
+    let mut out = Bytes::default();
+    out.header_start(ET_EXEC);
+    out.u32(entry);
+    out.u32(HEADER_SIZE);
+    out.u32(0);
+    out.u32(EF_ARM_FLAGS);
+    out.u16(HEADER_SIZE as u16);
+    out.u16(PROGRAM_HEADER_SIZE as u16);
+    out.u16(segments as u16);
+    out.u16(SECTION_HEADER_SIZE as u16);
+    out.u16(0);
+    out.u16(0);
+    let mut segment = |offset: u32, size: u32, flags: u32| {
+        out.u32(PT_LOAD);
+        out.u32(offset);
+        out.u32(BASE_ADDRESS + offset);
+        out.u32(BASE_ADDRESS + offset);
+        out.u32(size);
+        out.u32(size);
+        out.u32(flags);
+        out.u32(PAGE_SIZE);
+    };
+    // The first segment includes the headers, so that it starts on a page.
+    segment(0, code_size, PF_R | PF_X);
+    if segments == 2 {
+        segment(data, object.data.len() as u32, PF_R | PF_W);
+    }
+    out.0.extend_from_slice(&code);
+    out.align(4);
+    out.0.extend_from_slice(&object.rodata);
+    if segments == 2 {
+        out.align(PAGE_SIZE);
+        out.0.extend_from_slice(&object.data);
+    }
+    Ok(out.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::parse;
+    use crate::encoder::encode;
+
+    fn u16_at(bytes: &[u8], offset: u32) -> u16 {
+        let offset = offset as usize;
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], offset: u32) -> u32 {
+        let offset = offset as usize;
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn name_at(bytes: &[u8], offset: u32) -> &str {
+        let name = &bytes[offset as usize..];
+        let end = name.iter().position(|&byte| byte == 0).unwrap();
+        std::str::from_utf8(&name[..end]).unwrap()
+    }
+
+    /// The contents of the named section, with its header.
+    fn section<'a>(file: &'a [u8], name: &str) -> (&'a [u8], [u32; 10]) {
+        let headers = u32_at(file, 32);
+        let count = u16_at(file, 48) as u32;
+        let header = |index: u32| -> [u32; 10] {
+            std::array::from_fn(|field| u32_at(file, headers + index * 40 + field as u32 * 4))
+        };
+        let names = header(u16_at(file, 50) as u32)[4];
+        (1..count)
+            .map(header)
+            .find(|fields| name_at(file, names + fields[0]) == name)
+            .map(|fields| {
+                let start = fields[4] as usize;
+                (&file[start..start + fields[5] as usize], fields)
+            })
+            .unwrap()
+    }
+
+    fn object() -> Vec<u8> {
+        let lines = parse(
+            ".global main
+            main:
+            push {fp, lr}
+            adr r0, .L1
+            bl printf
+            pop {fp, pc}
+            .L1:
+            .asciz \"%d\\n\"",
+        )
+        .unwrap();
+        object_file(&encode(&lines).unwrap())
+    }
+
+    #[test]
+    fn header() {
+        let file = object();
+        assert_eq!(b"\x7fELF\x01\x01\x01", &file[..7]);
+        assert_eq!(ET_REL, u16_at(&file, 16));
+        assert_eq!(EM_ARM, u16_at(&file, 18));
+        assert_eq!(EF_ARM_FLAGS, u32_at(&file, 36));
+        assert_eq!(HEADER_SIZE as u16, u16_at(&file, 40));
+        assert_eq!(SECTION_HEADER_SIZE as u16, u16_at(&file, 46));
+        assert_eq!(8, u16_at(&file, 48));
+        assert_eq!(0, u32_at(&file, 32) % 4);
+    }
+
+    #[test]
+    fn sections() {
+        let file = object();
+        let (text, header) = section(&file, ".text");
+        assert_eq!(24, text.len());
+        assert_eq!(0xe92d_4800, u32_at(text, 0));
+        assert_eq!(SHF_ALLOC | SHF_EXECINSTR, header[2]);
+        let (rodata, header) = section(&file, ".rodata");
+        assert_eq!(b"%d\n\0", rodata);
+        assert_eq!(SHF_ALLOC, header[2]);
+        assert!(section(&file, ".data").0.is_empty());
+    }
+
+    #[test]
+    fn symbols_and_relocations() {
+        let file = object();
+        let (symbols, header) = section(&file, ".symtab");
+        let (names, _) = section(&file, ".strtab");
+        let symbol = |index: u32| {
+            let entry = index * 16;
+            (
+                name_at(names, u32_at(symbols, entry)),
+                u32_at(symbols, entry + 4),
+                symbols[entry as usize + 12],
+                u16_at(symbols, entry + 14),
+            )
+        };
+        // The null symbol, the sections and `$a` and `$d` are local.
+        assert_eq!(6, header[7]);
+        assert_eq!(("$a", 0, STT_NOTYPE, 1), symbol(4));
+        assert_eq!(("$d", 0, STT_NOTYPE, 3), symbol(5));
+        assert_eq!(("main", 0, STB_GLOBAL << 4 | STT_FUNC, 1), symbol(6));
+        assert_eq!(("printf", 0, STB_GLOBAL << 4 | STT_NOTYPE, 0), symbol(7));
+        assert_eq!(8 * 16, symbols.len());
+
+        let (relocations, _) = section(&file, ".rel.text");
+        let relocation = |index: u32| {
+            let info = u32_at(relocations, index * 8 + 4);
+            (u32_at(relocations, index * 8), info >> 8, info & 0xff)
+        };
+        assert_eq!((4, 3, 45), relocation(0));
+        assert_eq!((8, 3, 46), relocation(1));
+        assert_eq!((16, 7, 28), relocation(2));
+        assert_eq!(3 * 8, relocations.len());
+    }
+
+    fn executable_of(code: &str) -> Result<Vec<u8>, CompileError> {
+        executable(&encode(&parse(code).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn executable_header() {
+        let file = executable_of(
+            ".global _start
+            bx lr
+            _start:
+            b _start",
+        )
+        .unwrap();
+        assert_eq!(b"\x7fELF\x01\x01\x01", &file[..7]);
+        assert_eq!(ET_EXEC, u16_at(&file, 16));
+        let code = HEADER_SIZE + PROGRAM_HEADER_SIZE;
+        assert_eq!(BASE_ADDRESS + code + 4, u32_at(&file, 24));
+        assert_eq!(HEADER_SIZE, u32_at(&file, 28));
+        assert_eq!(1, u16_at(&file, 44));
+        // One segment with the headers and the code, loaded where it is in
+        // the file.
+        let segment = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| u32_at(&file, HEADER_SIZE + i * 4));
+        assert_eq!(
+            [
+                PT_LOAD,
+                0,
+                BASE_ADDRESS,
+                BASE_ADDRESS,
+                code + 8,
+                code + 8,
+                PF_R | PF_X,
+                PAGE_SIZE
+            ],
+            segment
+        );
+        assert_eq!(code as usize + 8, file.len());
+    }
+
+    #[test]
+    fn executable_relocations() {
+        let file = executable_of(
+            ".global _start
+            _start:
+            adr r0, .L1
+            bl f
+            .global f
+            f:
+            bx lr
+            .L1:
+            .asciz \"x\"",
+        )
+        .unwrap();
+        let code = HEADER_SIZE + PROGRAM_HEADER_SIZE;
+        let words: Vec<u32> = (0..5).map(|i| u32_at(&file, code + i * 4)).collect();
+        // `.L1` is right after the code, 4 bytes on from where the `add`
+        // reads `pc`.
+        assert_eq!(
+            vec![
+                0xe300_0004,
+                0xe340_0000,
+                0xe08f_0000,
+                0xebff_ffff,
+                0xe12f_ff1e
+            ],
+            words
+        );
+        assert_eq!(b"x\0", &file[code as usize + 20..]);
+    }
+
+    #[test]
+    fn calls_between_objects() {
+        let mut object = encode(&parse(".global _start\n_start:\nbl f\nbx lr").unwrap()).unwrap();
+        object.symbols[1].definition = Some((Section::Text, 4));
+        let file = executable(&object).unwrap();
+        let code = HEADER_SIZE + PROGRAM_HEADER_SIZE;
+        assert_eq!(0xebff_ffff, u32_at(&file, code));
+    }
+
+    #[test]
+    fn undefined_symbols() {
+        for code in ["_start:\nbl printf", ".global main\nmain:\nbx lr"] {
+            assert!(matches!(
+                executable_of(code),
+                Err(CompileError::CodeGenError(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,649 @@
+//! An ARMv7 user-mode emulator, to run generated code without an ARM
+//! machine or a cross toolchain.
+//!
+//! It runs the A32 machine code `encoder` produces, loaded at
+//! `elf::BASE_ADDRESS`, and only supports the instructions the encoder
+//! emits. Calls to `putchar`, `printf` and `malloc` go to shims in Rust
+//! unless the code defines them, as it does when linked with `runtime`;
+//! then the `write`, `brk` and `exit_group` system calls it makes are
+//! emulated instead. Programs start at `_start` if there is one and at
+//! `main` otherwise.
+
+use crate::arm::{Condition, Line};
+use crate::elf::{self, Layout, BASE_ADDRESS};
+use crate::encoder::encode;
+use crate::error::CompileError;
+use crate::interpreter::runtime_error;
+use std::io::Write;
+
+/// The functions with shims, at `SHIMS` and the following words.
+const SHIM_NAMES: [&str; 3] = ["putchar", "printf", "malloc"];
+const SHIMS: u32 = 0xffff_0000;
+/// Where `main` returns to, to end the program.
+const EXIT: u32 = 0xffff_fff0;
+
+const HEAP: u32 = 0x1000_0000;
+const MAX_HEAP_SIZE: u32 = 256 << 20;
+const STACK_TOP: u32 = 0x8000_0000;
+const STACK_SIZE: u32 = 8 << 20;
+
+const SP: usize = 13;
+const LR: usize = 14;
+const PC: u32 = 15;
+
+/// Encodes and runs code, writing what it prints to `out`, and returns the
+/// exit status: the value `main` returns. `max_steps` bounds the number of
+/// instructions run.
+pub fn run<W: Write + ?Sized>(
+    lines: &[Line],
+    out: &mut W,
+    max_steps: usize,
+) -> Result<i32, CompileError> {
+    Ok(run_counting(lines, out, max_steps)?.0)
+}
+
+/// Like `run`, but also returns the number of instructions run, to compare
+/// the code generated for a program in different ways.
+pub fn run_counting<W: Write + ?Sized>(
+    lines: &[Line],
+    out: &mut W,
+    max_steps: usize,
+) -> Result<(i32, usize), CompileError> {
+    let object = encode(lines)?;
+    let text = BASE_ADDRESS;
+    let rodata = (text + object.text.len() as u32).next_multiple_of(4);
+    let data = (rodata + object.rodata.len() as u32).next_multiple_of(4);
+    let layout = Layout { text, data, rodata };
+    let mut image = elf::link(&object, layout, |name| {
+        let shim = SHIM_NAMES.iter().position(|shim| *shim == name)?;
+        Some(SHIMS + 4 * shim as u32)
+    })?;
+    image.resize((rodata - BASE_ADDRESS) as usize, 0);
+    image.extend_from_slice(&object.rodata);
+    image.resize((data - BASE_ADDRESS) as usize, 0);
+    image.extend_from_slice(&object.data);
+    let entry = layout
+        .symbol(&object, "_start")
+        .or_else(|| layout.symbol(&object, "main"))
+        .ok_or_else(|| runtime_error("No _start or main to run"))?;
+
+    let mut machine = Machine {
+        registers: [0; 15],
+        pc: entry,
+        next_pc: entry,
+        n: false,
+        z: false,
+        c: false,
+        v: false,
+        image,
+        text_end: text + object.text.len() as u32,
+        heap: vec![],
+        stack: vec![0; STACK_SIZE as usize],
+        status: None,
+        steps: 0,
+        max_steps,
+    };
+    machine.registers[SP] = STACK_TOP;
+    machine.registers[LR] = EXIT;
+    let status = machine.execute(out)?;
+    Ok((status, machine.steps))
+}
+
+struct Machine {
+    /// `r0` to `lr`: `pc` is kept apart.
+    registers: [u32; 15],
+    pc: u32,
+    /// Where the instruction being run goes on to.
+    next_pc: u32,
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+    /// The code and the data after it, from `BASE_ADDRESS`.
+    image: Vec<u8>,
+    text_end: u32,
+    /// From `HEAP` to the program break.
+    heap: Vec<u8>,
+    /// The bottom of the stack, up to `STACK_TOP`.
+    stack: Vec<u8>,
+    /// Set by `exit_group`.
+    status: Option<i32>,
+    steps: usize,
+    max_steps: usize,
+}
+
+impl Machine {
+    fn execute<W: Write + ?Sized>(&mut self, out: &mut W) -> Result<i32, CompileError> {
+        loop {
+            if let Some(status) = self.status {
+                return Ok(status);
+            }
+            if self.pc == EXIT {
+                return Ok(self.registers[0] as i32);
+            }
+            if (SHIMS..SHIMS + 4 * SHIM_NAMES.len() as u32).contains(&self.pc) {
+                self.shim(SHIM_NAMES[(self.pc - SHIMS) as usize / 4], out)?;
+                self.pc = self.registers[LR];
+                continue;
+            }
+            if !(BASE_ADDRESS..self.text_end).contains(&self.pc) || !self.pc.is_multiple_of(4) {
+                return Err(runtime_error(format!("Jumped to {:#x}", self.pc)));
+            }
+            self.steps += 1;
+            if self.steps > self.max_steps {
+                return Err(runtime_error("Too many steps"));
+            }
+            let at = (self.pc - BASE_ADDRESS) as usize;
+            let word = u32::from_le_bytes(self.image[at..at + 4].try_into().unwrap());
+            self.next_pc = self.pc + 4;
+            self.step(word, out)?;
+            self.pc = self.next_pc;
+        }
+    }
+
+    fn get(&self, register: u32) -> u32 {
+        match register {
+            PC => self.pc + 8,
+            _ => self.registers[register as usize],
+        }
+    }
+
+    fn set(&mut self, register: u32, value: u32) {
+        match register {
+            PC => self.next_pc = value,
+            _ => self.registers[register as usize] = value,
+        }
+    }
+
+    fn memory(&mut self, address: u32, size: u32) -> Result<&mut [u8], CompileError> {
+        let fault = || runtime_error(format!("Segmentation fault at {:#x}", address));
+        let (start, bytes) = if address >= STACK_TOP - STACK_SIZE {
+            (STACK_TOP - STACK_SIZE, &mut self.stack)
+        } else if address >= HEAP {
+            (HEAP, &mut self.heap)
+        } else if address >= BASE_ADDRESS {
+            (BASE_ADDRESS, &mut self.image)
+        } else {
+            return Err(fault());
+        };
+        let offset = (address - start) as usize;
+        bytes
+            .get_mut(offset..offset + size as usize)
+            .ok_or_else(fault)
+    }
+
+    fn load(&mut self, address: u32) -> Result<u32, CompileError> {
+        Ok(u32::from_le_bytes(
+            self.memory(address, 4)?.try_into().unwrap(),
+        ))
+    }
+
+    fn store(&mut self, address: u32, value: u32) -> Result<(), CompileError> {
+        self.memory(address, 4)?
+            .copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    /// The zero-terminated string at `address`.
+    fn string(&mut self, mut address: u32) -> Result<Vec<u8>, CompileError> {
+        let mut string = vec![];
+        loop {
+            match self.memory(address, 1)?[0] {
+                0 => return Ok(string),
+                byte => string.push(byte),
+            }
+            address += 1;
+        }
+    }
+
+    /// Moves the program break to `end`, if the heap can end there.
+    fn brk(&mut self, end: u32) -> u32 {
+        if (HEAP..=HEAP + MAX_HEAP_SIZE).contains(&end) {
+            self.heap.resize((end - HEAP) as usize, 0);
+        }
+        HEAP + self.heap.len() as u32
+    }
+
+    fn shim<W: Write + ?Sized>(&mut self, name: &str, out: &mut W) -> Result<(), CompileError> {
+        let argument = self.registers[1];
+        let result = match name {
+            "putchar" => {
+                out.write_all(&[self.registers[0] as u8])?;
+                self.registers[0] & 0xff
+            }
+            "printf" => {
+                let format = self.string(self.registers[0])?;
+                let mut printed = vec![];
+                let mut bytes = format.into_iter().peekable();
+                while let Some(byte) = bytes.next() {
+                    if byte != b'%' {
+                        printed.push(byte);
+                    } else if bytes.next_if_eq(&b'd').is_some() {
+                        write!(printed, "{}", argument as i32)?;
+                    } else if bytes.next_if_eq(&b'%').is_some() {
+                        printed.push(b'%');
+                    } else {
+                        printed.push(byte);
+                    }
+                }
+                out.write_all(&printed)?;
+                printed.len() as u32
+            }
+            _ => {
+                let start = HEAP + self.heap.len() as u32;
+                let end = start.saturating_add(self.registers[0].next_multiple_of(8));
+                if self.brk(end) == end {
+                    start
+                } else {
+                    0
+                }
+            }
+        };
+        self.registers[0] = result;
+        Ok(())
+    }
+
+    fn unsupported(&self, word: u32) -> CompileError {
+        runtime_error(format!(
+            "Unsupported instruction {:#010x} at {:#x}",
+            word, self.pc
+        ))
+    }
+
+    /// `cmp left, right`.
+    fn compare(&mut self, left: u32, right: u32) {
+        let result = left.wrapping_sub(right);
+        self.n = (result as i32) < 0;
+        self.z = result == 0;
+        self.c = left >= right;
+        self.v = ((left ^ right) & (left ^ result)) >> 31 != 0;
+    }
+
+    fn passes(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::Eq => self.z,
+            Condition::Ne => !self.z,
+            Condition::Hs => self.c,
+            Condition::Lo => !self.c,
+            Condition::Mi => self.n,
+            Condition::Pl => !self.n,
+            Condition::Vs => self.v,
+            Condition::Vc => !self.v,
+            Condition::Hi => self.c && !self.z,
+            Condition::Ls => !self.c || self.z,
+            Condition::Ge => self.n == self.v,
+            Condition::Lt => self.n != self.v,
+            Condition::Gt => !self.z && self.n == self.v,
+            Condition::Le => self.z || self.n != self.v,
+            Condition::Al => true,
+        }
+    }
+
+    /// A register shifted by a constant, as the operand of a data-processing
+    /// instruction or the offset of a load or store.
+    fn shifted(&self, word: u32) -> Result<u32, CompileError> {
+        if word & 0x10 != 0 {
+            return Err(self.unsupported(word));
+        }
+        let value = self.get(word & 0xf);
+        let amount = word >> 7 & 0x1f;
+        Ok(match (word >> 5 & 3, amount) {
+            (0, _) => value << amount,
+            (1, 0) => 0,
+            (1, _) => value >> amount,
+            (2, 0) => (value as i32 >> 31) as u32,
+            (2, _) => (value as i32 >> amount) as u32,
+            (_, 0) => (self.c as u32) << 31 | value >> 1,
+            (_, _) => value.rotate_right(amount),
+        })
+    }
+
+    fn step<W: Write + ?Sized>(&mut self, word: u32, out: &mut W) -> Result<(), CompileError> {
+        let condition = *Condition::ALL
+            .get(word as usize >> 28)
+            .ok_or_else(|| self.unsupported(word))?;
+        if !self.passes(condition) {
+            return Ok(());
+        }
+        let rd = word >> 12 & 0xf;
+        let rn = word >> 16 & 0xf;
+        if word & 0x0fff_fff0 == 0x012f_ff10 {
+            // bx
+            self.next_pc = self.get(word & 0xf);
+        } else if word & 0x0fe0_f0f0 == 0x0000_0090 {
+            // mul, with the destination where loads have the base.
+            let product = self.get(word & 0xf).wrapping_mul(self.get(word >> 8 & 0xf));
+            self.set(rn, product);
+        } else if word & 0x0ff0_f0f0 == 0x0730_f010 {
+            // udiv, which gives 0 when dividing by 0.
+            let divisor = self.get(word >> 8 & 0xf);
+            let quotient = self.get(word & 0xf).checked_div(divisor).unwrap_or(0);
+            self.set(rn, quotient);
+        } else if word & 0x0fb0_0000 == 0x0300_0000 {
+            // movw and movt
+            let half = word >> 4 & 0xf000 | word & 0xfff;
+            let value = match word & 1 << 22 {
+                0 => half,
+                _ => half << 16 | self.get(rd) & 0xffff,
+            };
+            self.set(rd, value);
+        } else if word & 0x0c00_0000 == 0 {
+            self.data_processing(word)?;
+        } else if word & 0x0c00_0000 == 0x0400_0000 {
+            self.transfer(word)?;
+        } else if word & 0x0e40_0000 == 0x0800_0000 {
+            self.block_transfer(word)?;
+        } else if word & 0x0e00_0000 == 0x0a00_0000 {
+            // b and bl
+            if word & 1 << 24 != 0 {
+                self.registers[LR] = self.pc + 4;
+            }
+            let offset = ((word << 8) as i32 >> 6) as u32;
+            self.next_pc = self.get(PC).wrapping_add(offset);
+        } else if word & 0x0f00_0000 == 0x0f00_0000 {
+            self.system_call(word, out)?;
+        } else {
+            return Err(self.unsupported(word));
+        }
+        Ok(())
+    }
+
+    fn data_processing(&mut self, word: u32) -> Result<(), CompileError> {
+        let left = self.get(word >> 16 & 0xf);
+        let right = if word & 1 << 25 != 0 {
+            (word & 0xff).rotate_right(2 * (word >> 8 & 0xf))
+        } else {
+            self.shifted(word)?
+        };
+        let set_flags = word & 1 << 20 != 0;
+        let result = match (word >> 21 & 0xf, set_flags) {
+            (0b0000, false) => left & right,
+            (0b0001, false) => left ^ right,
+            (0b0010, false) => left.wrapping_sub(right),
+            (0b0011, false) => right.wrapping_sub(left),
+            (0b0100, false) => left.wrapping_add(right),
+            (0b1010, true) => {
+                self.compare(left, right);
+                return Ok(());
+            }
+            (0b1100, false) => left | right,
+            (0b1101, false) => right,
+            (0b1110, false) => left & !right,
+            (0b1111, false) => !right,
+            _ => return Err(self.unsupported(word)),
+        };
+        self.set(word >> 12 & 0xf, result);
+        Ok(())
+    }
+
+    /// `ldr`, `str`, `ldrb` and `strb`.
+    fn transfer(&mut self, word: u32) -> Result<(), CompileError> {
+        let load = word & 1 << 20 != 0;
+        let byte = word & 1 << 22 != 0;
+        let pre_indexed = word & 1 << 24 != 0;
+        let base_register = word >> 16 & 0xf;
+        let base = self.get(base_register);
+        let offset = if word & 1 << 25 != 0 {
+            self.shifted(word)?
+        } else {
+            word & 0xfff
+        };
+        let offset_base = if word & 1 << 23 != 0 {
+            base.wrapping_add(offset)
+        } else {
+            base.wrapping_sub(offset)
+        };
+        let address = if pre_indexed { offset_base } else { base };
+        let register = word >> 12 & 0xf;
+        let loaded = match (load, byte) {
+            (true, true) => Some(self.memory(address, 1)?[0] as u32),
+            (true, false) => Some(self.load(address)?),
+            (false, true) => {
+                self.memory(address, 1)?[0] = self.get(register) as u8;
+                None
+            }
+            (false, false) => {
+                self.store(address, self.get(register))?;
+                None
+            }
+        };
+        if !pre_indexed || word & 1 << 21 != 0 {
+            self.set(base_register, offset_base);
+        }
+        if let Some(value) = loaded {
+            self.set(register, value);
+        }
+        Ok(())
+    }
+
+    /// `ldm` and `stm`, as in `pop` and `push`.
+    fn block_transfer(&mut self, word: u32) -> Result<(), CompileError> {
+        let load = word & 1 << 20 != 0;
+        let up = word & 1 << 23 != 0;
+        let base_register = word >> 16 & 0xf;
+        let base = self.get(base_register);
+        let size = 4 * (word & 0xffff).count_ones();
+        let (lowest, end) = if up {
+            (base, base.wrapping_add(size))
+        } else {
+            (base.wrapping_sub(size), base.wrapping_sub(size))
+        };
+        // Pre-indexing goes up past the base first.
+        let mut address = match (up, word & 1 << 24 != 0) {
+            (true, true) | (false, false) => lowest.wrapping_add(4),
+            _ => lowest,
+        };
+        let loads_base = load && word & 1 << base_register != 0;
+        for register in (0..16).filter(|register| word & 1 << register != 0) {
+            if load {
+                let value = self.load(address)?;
+                self.set(register, value);
+            } else {
+                self.store(address, self.get(register))?;
+            }
+            address = address.wrapping_add(4);
+        }
+        if word & 1 << 21 != 0 && !loads_base {
+            self.set(base_register, end);
+        }
+        Ok(())
+    }
+
+    /// `svc`, with the system call number in `r7`.
+    fn system_call<W: Write + ?Sized>(
+        &mut self,
+        word: u32,
+        out: &mut W,
+    ) -> Result<(), CompileError> {
+        let [r0, r1, r2] = [0, 1, 2].map(|register| self.registers[register]);
+        self.registers[0] = match self.registers[7] {
+            // write, to stdout only.
+            4 if r0 == 1 => {
+                out.write_all(self.memory(r1, r2)?)?;
+                r2
+            }
+            // -EBADF
+            4 => -9i32 as u32,
+            45 => self.brk(r0),
+            1 | 248 => {
+                self.status = Some(r0 as i32);
+                r0
+            }
+            number => {
+                return Err(runtime_error(format!(
+                    "Unsupported system call {} by {:#010x} at {:#x}",
+                    number, word, self.pc
+                )))
+            }
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::parse;
+    use crate::ast::AST;
+    use crate::generator::check_against_interpreter;
+    use crate::{arm_code_generator, arm_emitter, peephole, runtime};
+
+    fn run_assembly(assembly: &str) -> Result<(i32, String), CompileError> {
+        let mut out = vec![];
+        let status = run(&parse(assembly).unwrap(), &mut out, 10_000)?;
+        Ok((status, String::from_utf8(out).unwrap()))
+    }
+
+    fn status(assembly: &str) -> i32 {
+        run_assembly(&format!(".global main\nmain:\n{}\nbx lr", assembly))
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(42, status("mov r0, #40\nadd r0, r0, #2"));
+        assert_eq!(-3, status("mov r1, #5\nmov r2, #8\nsub r0, r1, r2"));
+        assert_eq!(3, status("mov r1, #5\nrsb r0, r1, #8"));
+        assert_eq!(56, status("mov r1, #7\nmov r2, #8\nmul r0, r1, r2"));
+        assert_eq!(0x1234_5678, status("ldr r0, =0x12345678"));
+        assert_eq!(-2, status("mvn r0, #1"));
+        assert_eq!(6, status("mov r1, #0xe\nand r0, r1, #7"));
+        assert_eq!(12, status("mov r1, #3\nmov r0, r1, lsl #2"));
+        assert_eq!(-1, status("mvn r1, #0\nmov r0, r1, asr #4"));
+        assert_eq!(0x0fff_ffff, status("mvn r1, #0\nmov r0, r1, lsr #4"));
+    }
+
+    #[test]
+    fn division_is_unsigned_and_by_zero_gives_zero() {
+        assert_eq!(3, status("mov r1, #10\nmov r2, #3\nudiv r0, r1, r2"));
+        assert_eq!(0, status("mov r1, #10\nmov r2, #0\nudiv r0, r1, r2"));
+        assert_eq!(
+            0x7fff_fffe,
+            status("mvn r1, #3\nmov r2, #2\nudiv r0, r1, r2")
+        );
+    }
+
+    #[test]
+    fn conditions() {
+        // The conditions that hold comparing -1 with 1, and 1 with 1.
+        let holds = |left: i32, condition: Condition| {
+            let assembly = format!(
+                "ldr r1, ={}\nmov r2, #1\nmov r0, #0\ncmp r1, r2\nmov{} r0, #1",
+                left,
+                condition.suffix()
+            );
+            status(&assembly) == 1
+        };
+        use Condition::*;
+        for condition in [Ne, Lt, Le, Hi, Hs, Mi] {
+            assert!(holds(-1, condition), "{:?}", condition);
+            assert!(!holds(-1, condition.inverse()), "{:?}", condition);
+        }
+        for condition in [Eq, Ge, Le, Ls, Hs, Pl, Vc] {
+            assert!(holds(1, condition), "{:?}", condition);
+        }
+        assert!(holds(i32::MIN, Vs));
+    }
+
+    #[test]
+    fn memory_and_calls() {
+        let (status, out) = run_assembly(
+            ".global main
+            main:
+            push {r4, lr}
+            mov r0, #8
+            bl malloc
+            mov r4, r0
+            mov r1, #3
+            str r1, [r4, #4]
+            mov r2, #1
+            ldr r1, [r4, r2, lsl #2]
+            add r1, r1, #4
+            adr r0, .L1
+            bl printf
+            mov r0, #'!'
+            bl putchar
+            bl f
+            pop {r4, pc}
+            f:
+            mov r0, #7
+            bx lr
+            .L1:
+            .asciz \"%d%%\\n\"",
+        )
+        .unwrap();
+        assert_eq!((7, "7%\n!".to_string()), (status, out));
+    }
+
+    #[test]
+    fn errors() {
+        for assembly in [
+            ".global main\nmain:\nmov r0, #0\nldr r0, [r0]",
+            ".global main\nmain:\nb main",
+            ".global main\nmain:\nmov pc, #4",
+            "f:\nbx lr",
+        ] {
+            assert!(
+                matches!(run_assembly(assembly), Err(CompileError::RuntimeError(..))),
+                "{}",
+                assembly
+            );
+        }
+    }
+
+    #[test]
+    fn runtime() {
+        let mut lines = parse(
+            ".global main
+            main:
+            push {r4, lr}
+            mov r0, #8
+            bl malloc
+            mov r4, r0
+            mov r0, #12
+            bl malloc
+            sub r4, r0, r4
+            ldr r1, =-2147483648
+            adr r0, .L1
+            bl printf
+            mov r1, r4
+            adr r0, .L1
+            bl printf
+            mov r0, #'T'
+            bl putchar
+            mov r0, #3
+            pop {r4, pc}
+            .L1:
+            .asciz \"%d%%\\n\"",
+        )
+        .unwrap();
+        lines.extend(runtime::lines());
+        let mut out = vec![];
+        assert_eq!(3, run(&lines, &mut out, 10_000).unwrap());
+        assert_eq!("-2147483648%\n8%\nT", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn generated_programs_match_interpreter() {
+        let generators: [fn(&AST) -> Vec<Line>; 4] = [
+            |ast| arm_code_generator::generate_lines(ast).unwrap(),
+            |ast| arm_emitter::generate_lines(ast).unwrap(),
+            |ast| {
+                let mut lines = arm_emitter::generate_lines(ast).unwrap();
+                peephole::optimize(&mut lines);
+                lines
+            },
+            |ast| {
+                let mut lines = arm_code_generator::generate_lines(ast).unwrap();
+                lines.extend(runtime::lines());
+                lines
+            },
+        ];
+        for generate in generators {
+            check_against_interpreter(0..50, |ast, out| {
+                run(&generate(ast), out, 10_000_000).unwrap()
+            });
+        }
+    }
+}
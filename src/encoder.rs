@@ -0,0 +1,723 @@
+//! Encodes ARM code into A32 machine code, ready to be written as an ELF
+//! object file by `elf::object_file`.
+//!
+//! Code goes in `.text`, except the strings of `.asciz` directives with the
+//! labels naming them, which go in `.rodata`. An `adr` of such a label
+//! becomes a `movw`/`movt` pair and an `add` from `pc`, with relocations for
+//! the linker to fill in the distance between the sections. Calls and
+//! branches to labels that aren't defined, like `bl printf`, get
+//! relocations too. `ldr rD, =N` is encoded as a `mov` or `mvn` when the
+//! constant fits, and as `movw` and `movt` otherwise, so there is no
+//! literal pool.
+
+use crate::arm::{
+    is_immediate, Address, ArithmeticOp, Condition, Directive, Instruction, Line, Operand,
+    Register, Shift, PC,
+};
+use crate::error::CompileError;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    Text,
+    Data,
+    Rodata,
+}
+
+/// A symbol defined in the object, or used by it and defined elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// Where the symbol is defined, or `None` if it is undefined.
+    pub definition: Option<(Section, u32)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// The 24-bit offset of a `bl`.
+    Call,
+    /// The 24-bit offset of a `b`.
+    Jump,
+    /// The low half of a `pc`-relative address, in a `movw`.
+    MovwPrel,
+    /// The high half of a `pc`-relative address, in a `movt`.
+    MovtPrel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Section(Section),
+    Symbol(String),
+}
+
+/// A place in `.text` the linker patches with the address of `target`. The
+/// addend is in the instruction, as ELF `REL` relocations on ARM expect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u32,
+    pub kind: RelocationKind,
+    pub target: Target,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub rodata: Vec<u8>,
+    /// The `.global` symbols, then the undefined ones.
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+fn error(message: String) -> CompileError {
+    CompileError::CodeGenError(message)
+}
+
+/// The labels directly followed by an `.asciz`, through other labels and
+/// `.align` directives.
+fn data_labels(lines: &[Line]) -> HashSet<&str> {
+    let mut labels = HashSet::new();
+    let mut pending = vec![];
+    for line in lines {
+        match line {
+            Line::Label(label) => pending.push(label.as_str()),
+            Line::Directive(Directive::Align(_)) => {}
+            Line::Directive(Directive::Asciz(_)) => labels.extend(pending.drain(..)),
+            _ => pending.clear(),
+        }
+    }
+    labels
+}
+
+/// Number of bytes `ldr rD, =value` takes.
+fn constant_size(value: u32) -> u32 {
+    if is_immediate(value) || is_immediate(!value) || value <= 0xffff {
+        4
+    } else {
+        8
+    }
+}
+
+/// Pads `bytes` to a multiple of 2 to the power `power`. Code is padded with
+/// whole instructions, as it is always a multiple of 4 bytes long.
+fn align(bytes: &mut Vec<u8>, power: u32, padding: &[u8]) {
+    while !bytes.len().is_multiple_of(1 << power) {
+        bytes.extend_from_slice(padding);
+    }
+}
+
+/// `mov r0, r0`, to pad code.
+const NOP: u32 = 0xe1a0_0000;
+
+fn operand2(operand: Operand) -> Result<u32, CompileError> {
+    Ok(match operand {
+        Operand::Immediate(value) => {
+            let rotation = (0..16)
+                .find(|rotation| value.rotate_left(2 * rotation) <= 0xff)
+                .ok_or_else(|| error(format!("#{} is not a valid immediate", value)))?;
+            1 << 25 | rotation << 8 | value.rotate_left(2 * rotation)
+        }
+        Operand::Register(register) => register.0 as u32,
+        Operand::Shifted(register, _, 0) => register.0 as u32,
+        Operand::Shifted(register, shift, amount) => {
+            let kind = match shift {
+                Shift::Lsl => 0,
+                Shift::Lsr => 1,
+                Shift::Asr => 2,
+                Shift::Ror => 3,
+            };
+            (amount as u32) << 7 | kind << 5 | register.0 as u32
+        }
+    })
+}
+
+/// A data-processing instruction.
+fn data(
+    condition: Condition,
+    opcode: u32,
+    set_flags: bool,
+    left: Register,
+    dest: Register,
+    right: Operand,
+) -> Result<u32, CompileError> {
+    Ok((condition as u32) << 28
+        | opcode << 21
+        | (set_flags as u32) << 20
+        | (left.0 as u32) << 16
+        | (dest.0 as u32) << 12
+        | operand2(right)?)
+}
+
+fn opcode(op: ArithmeticOp) -> u32 {
+    match op {
+        ArithmeticOp::And => 0b0000,
+        ArithmeticOp::Eor => 0b0001,
+        ArithmeticOp::Sub => 0b0010,
+        ArithmeticOp::Rsb => 0b0011,
+        ArithmeticOp::Add => 0b0100,
+        ArithmeticOp::Orr => 0b1100,
+    }
+}
+
+const MOV: u32 = 0b1101;
+const MVN: u32 = 0b1111;
+const CMP: u32 = 0b1010;
+
+/// `movw`, or `movt` if `top`, of a 16-bit value.
+fn move_half(dest: Register, value: u32, top: bool) -> u32 {
+    0xe300_0000
+        | (top as u32) << 22
+        | (value >> 12 & 0xf) << 16
+        | (dest.0 as u32) << 12
+        | value & 0xfff
+}
+
+fn transfer(
+    condition: Condition,
+    load: bool,
+    register: Register,
+    address: Address,
+) -> Result<u32, CompileError> {
+    let base = (condition as u32) << 28 | 0x0500_0000 | (load as u32) << 20;
+    let register = (register.0 as u32) << 12;
+    Ok(match address {
+        Address::Offset(base_register, offset) => {
+            if offset.unsigned_abs() > 4095 {
+                return Err(error(format!("Offset {} is out of range", offset)));
+            }
+            base | ((offset >= 0) as u32) << 23
+                | (base_register.0 as u32) << 16
+                | register
+                | offset.unsigned_abs()
+        }
+        Address::Indexed(base_register, index, shift) => {
+            base | 1 << 25
+                | 1 << 23
+                | (base_register.0 as u32) << 16
+                | register
+                | (shift as u32) << 7
+                | index.0 as u32
+        }
+    })
+}
+
+fn register_mask(registers: &[Register]) -> u32 {
+    registers
+        .iter()
+        .fold(0, |mask, register| mask | 1 << register.0)
+}
+
+struct Encoder<'a> {
+    object: Object,
+    labels: HashMap<&'a str, (Section, u32)>,
+}
+
+impl Encoder<'_> {
+    fn word(&mut self, word: u32) {
+        self.object.text.extend_from_slice(&word.to_le_bytes());
+    }
+
+    fn here(&self) -> u32 {
+        self.object.text.len() as u32
+    }
+
+    fn relocate(&mut self, kind: RelocationKind, target: Target) {
+        let offset = self.here();
+        self.object.relocations.push(Relocation {
+            offset,
+            kind,
+            target,
+        });
+    }
+
+    /// A `b` or `bl` to `target`, relocated if it isn't defined here.
+    fn branch(
+        &mut self,
+        condition: Condition,
+        link: bool,
+        target: &str,
+    ) -> Result<(), CompileError> {
+        let base = (condition as u32) << 28 | 0x0a00_0000 | (link as u32) << 24;
+        let offset = match self.labels.get(target) {
+            Some((Section::Text, address)) => {
+                let offset = (*address as i32 - self.here() as i32 - 8) >> 2;
+                if !(-(1 << 23)..1 << 23).contains(&offset) {
+                    return Err(error(format!("{} is out of range", target)));
+                }
+                offset
+            }
+            Some(_) => return Err(error(format!("Can't branch to data at {}", target))),
+            None => {
+                let kind = if link {
+                    RelocationKind::Call
+                } else {
+                    RelocationKind::Jump
+                };
+                self.relocate(kind, Target::Symbol(target.to_string()));
+                if !self
+                    .object
+                    .symbols
+                    .iter()
+                    .any(|symbol| symbol.name == target)
+                {
+                    self.object.symbols.push(Symbol {
+                        name: target.to_string(),
+                        definition: None,
+                    });
+                }
+                // The linker adds the offset to the symbol, and `pc` is 8
+                // bytes ahead.
+                -2
+            }
+        };
+        self.word(base | offset as u32 & 0x00ff_ffff);
+        Ok(())
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), CompileError> {
+        let word = match instruction {
+            Instruction::Move {
+                condition,
+                dest,
+                src,
+            } => data(*condition, MOV, false, Register(0), *dest, *src)?,
+            Instruction::MoveNot {
+                condition,
+                dest,
+                src,
+            } => data(*condition, MVN, false, Register(0), *dest, *src)?,
+            Instruction::Arithmetic {
+                op,
+                condition,
+                dest,
+                left,
+                right,
+            } => data(*condition, opcode(*op), false, *left, *dest, *right)?,
+            Instruction::Multiply { dest, left, right } => {
+                0xe000_0090 | (dest.0 as u32) << 16 | (right.0 as u32) << 8 | left.0 as u32
+            }
+            Instruction::Divide { dest, left, right } => {
+                0xe730_f010 | (dest.0 as u32) << 16 | (right.0 as u32) << 8 | left.0 as u32
+            }
+            Instruction::Compare { left, right } => {
+                data(Condition::Al, CMP, true, *left, Register(0), *right)?
+            }
+            Instruction::Load {
+                condition,
+                dest,
+                address,
+            } => transfer(*condition, true, *dest, *address)?,
+            Instruction::Store {
+                condition,
+                src,
+                address,
+            } => transfer(*condition, false, *src, *address)?,
+            Instruction::LoadByte { dest, address } => {
+                transfer(Condition::Al, true, *dest, *address)? | 1 << 22
+            }
+            Instruction::LoadConstant { dest, value } => {
+                let value = *value;
+                if is_immediate(value) {
+                    data(
+                        Condition::Al,
+                        MOV,
+                        false,
+                        Register(0),
+                        *dest,
+                        Operand::Immediate(value),
+                    )?
+                } else if is_immediate(!value) {
+                    data(
+                        Condition::Al,
+                        MVN,
+                        false,
+                        Register(0),
+                        *dest,
+                        Operand::Immediate(!value),
+                    )?
+                } else {
+                    self.word(move_half(*dest, value & 0xffff, false));
+                    if value <= 0xffff {
+                        return Ok(());
+                    }
+                    move_half(*dest, value >> 16, true)
+                }
+            }
+            Instruction::LoadAddress { dest, label } => {
+                match self.labels.get(label.as_str()) {
+                    Some((Section::Text, address)) => {
+                        let offset = *address as i32 - self.here() as i32 - 8;
+                        let (op, distance) = if offset >= 0 {
+                            (ArithmeticOp::Add, offset as u32)
+                        } else {
+                            (ArithmeticOp::Sub, offset.unsigned_abs())
+                        };
+                        let distance = Operand::Immediate(distance);
+                        data(Condition::Al, opcode(op), false, PC, *dest, distance)?
+                    }
+                    Some((section, address)) => {
+                        let section = *section;
+                        // The `add` reads `pc` 16 bytes after the `movw` and
+                        // 12 after the `movt`, which the relocations are
+                        // relative to.
+                        let low = i16::try_from(*address as i32 - 16)
+                            .map_err(|_| error(format!("{} is out of range", label)))?;
+                        let high = *address as i32 - 12;
+                        self.relocate(RelocationKind::MovwPrel, Target::Section(section));
+                        self.word(move_half(*dest, low as u16 as u32, false));
+                        self.relocate(RelocationKind::MovtPrel, Target::Section(section));
+                        self.word(move_half(*dest, high as u16 as u32, true));
+                        data(
+                            Condition::Al,
+                            opcode(ArithmeticOp::Add),
+                            false,
+                            PC,
+                            *dest,
+                            (*dest).into(),
+                        )?
+                    }
+                    None => return Err(error(format!("Undefined label {}", label))),
+                }
+            }
+            Instruction::Push(registers) => match registers[..] {
+                // `str rN, [sp, #-4]!`, the preferred form for one register.
+                [register] => 0xe52d_0004 | (register.0 as u32) << 12,
+                _ => 0xe92d_0000 | register_mask(registers),
+            },
+            Instruction::Pop(registers) => match registers[..] {
+                // `ldr rN, [sp], #4`.
+                [register] => 0xe49d_0004 | (register.0 as u32) << 12,
+                _ => 0xe8bd_0000 | register_mask(registers),
+            },
+            Instruction::Branch { condition, target } => {
+                return self.branch(*condition, false, target);
+            }
+            Instruction::BranchLink(target) => return self.branch(Condition::Al, true, target),
+            Instruction::BranchExchange(register) => 0xe12f_ff10 | register.0 as u32,
+            Instruction::SupervisorCall(number) if *number <= 0xff_ffff => 0xef00_0000 | number,
+            Instruction::SupervisorCall(number) => {
+                return Err(error(format!("svc #{} is out of range", number)))
+            }
+        };
+        self.word(word);
+        Ok(())
+    }
+}
+
+/// Encodes code into the sections of an object file.
+pub fn encode(lines: &[Line]) -> Result<Object, CompileError> {
+    let data = data_labels(lines);
+    let section = |label: &str| {
+        if data.contains(label) {
+            Section::Rodata
+        } else {
+            Section::Text
+        }
+    };
+
+    // Where everything goes, with the size of every instruction known.
+    let mut labels = HashMap::new();
+    let mut text: u32 = 0;
+    let mut rodata: u32 = 0;
+    let mut alignment = 0;
+    for line in lines {
+        match line {
+            Line::Label(label) => {
+                let offset = match section(label) {
+                    Section::Rodata => {
+                        rodata = rodata.next_multiple_of(1 << alignment);
+                        rodata
+                    }
+                    _ => {
+                        text = text.next_multiple_of(1 << alignment);
+                        text
+                    }
+                };
+                alignment = 0;
+                if labels
+                    .insert(label.as_str(), (section(label), offset))
+                    .is_some()
+                {
+                    return Err(error(format!("Label {} is defined twice", label)));
+                }
+            }
+            Line::Directive(Directive::Align(power)) => alignment = *power,
+            Line::Directive(Directive::Asciz(string)) => {
+                rodata = rodata.next_multiple_of(1 << alignment) + string.len() as u32 + 1;
+                alignment = 0;
+            }
+            Line::Directive(Directive::Global(_)) => {}
+            Line::Instruction(instruction) => {
+                text = text.next_multiple_of(1 << alignment);
+                alignment = 0;
+                text += match instruction {
+                    Instruction::LoadConstant { value, .. } => constant_size(*value),
+                    Instruction::LoadAddress { label, .. } if data.contains(label.as_str()) => 12,
+                    _ => 4,
+                };
+            }
+        }
+    }
+
+    let mut encoder = Encoder {
+        object: Object::default(),
+        labels,
+    };
+    for line in lines {
+        if let Line::Directive(Directive::Global(name)) = line {
+            let definition = encoder.labels.get(name.as_str()).copied();
+            if definition.is_none() {
+                return Err(error(format!("Undefined global {}", name)));
+            }
+            encoder.object.symbols.push(Symbol {
+                name: name.clone(),
+                definition,
+            });
+        }
+    }
+    let nop = NOP.to_le_bytes();
+    let mut alignment = 0;
+    for line in lines {
+        match line {
+            Line::Label(label) => {
+                match section(label) {
+                    Section::Rodata => align(&mut encoder.object.rodata, alignment, &[0]),
+                    _ => align(&mut encoder.object.text, alignment, &nop),
+                }
+                alignment = 0;
+            }
+            Line::Directive(Directive::Align(power)) => alignment = *power,
+            Line::Directive(Directive::Asciz(string)) => {
+                let rodata = &mut encoder.object.rodata;
+                align(rodata, alignment, &[0]);
+                rodata.extend_from_slice(string.as_bytes());
+                rodata.push(0);
+                alignment = 0;
+            }
+            Line::Directive(Directive::Global(_)) => {}
+            Line::Instruction(instruction) => {
+                align(&mut encoder.object.text, alignment, &nop);
+                alignment = 0;
+                encoder.instruction(instruction)?;
+            }
+        }
+    }
+    Ok(encoder.object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::parse;
+    use crate::generator::ProgramGenerator;
+    use crate::{arm_code_generator, arm_emitter};
+
+    fn encoded(code: &str) -> Object {
+        encode(&parse(code).unwrap()).unwrap()
+    }
+
+    fn words(bytes: &[u8]) -> Vec<u32> {
+        bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn instructions() {
+        // Checked against the GNU assembler.
+        let cases = [
+            ("mov r0, #0", 0xe3a0_0000),
+            ("mov fp, sp", 0xe1a0_b00d),
+            ("mov r0, r1, lsl #2", 0xe1a0_0101),
+            ("moveq r0, #84", 0x03a0_0054),
+            ("mov r0, #0xff000000", 0xe3a0_04ff),
+            ("mvn r0, #0", 0xe3e0_0000),
+            ("add r0, r1, r0", 0xe081_0000),
+            ("addlo r1, r1, #4", 0x3281_1004),
+            ("sub sp, sp, #16", 0xe24d_d010),
+            ("rsb r0, r0, #0", 0xe260_0000),
+            ("and r0, r1, r0", 0xe001_0000),
+            ("orr r0, r1, r0", 0xe181_0000),
+            ("eor r0, r0, #1", 0xe220_0001),
+            ("mul r0, r0, r1", 0xe000_0190),
+            ("udiv r0, r1, r0", 0xe730_f011),
+            ("cmp r1, r0", 0xe151_0000),
+            ("cmp r0, #0", 0xe350_0000),
+            ("ldr r0, [fp, #-16]", 0xe51b_0010),
+            ("ldr r2, [r1]", 0xe591_2000),
+            ("ldrlo r0, [r1, r0, lsl #2]", 0x3791_0100),
+            ("str r0, [sp, #4]", 0xe58d_0004),
+            ("push {fp, lr}", 0xe92d_4800),
+            ("push {r4}", 0xe52d_4004),
+            ("pop {fp, pc}", 0xe8bd_8800),
+            ("pop {r4}", 0xe49d_4004),
+            ("bx lr", 0xe12f_ff1e),
+            ("ldrb r0, [r4]", 0xe5d4_0000),
+            ("ldrb r0, [r1, #3]", 0xe5d1_0003),
+            ("svc #0", 0xef00_0000),
+        ];
+        for (code, word) in cases {
+            assert_eq!(vec![word], words(&encoded(code).text), "{}", code);
+        }
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(vec![0xe3a0_0054], words(&encoded("ldr r0, =84").text));
+        assert_eq!(vec![0xe3e0_0004], words(&encoded("ldr r0, =-5").text));
+        assert_eq!(vec![0xe301_011c], words(&encoded("ldr r0, =4380").text));
+        assert_eq!(
+            vec![0xe305_c678, 0xe341_c234],
+            words(&encoded("ldr ip, =0x12345678").text)
+        );
+    }
+
+    #[test]
+    fn branches() {
+        let object = encoded(
+            ".L1:
+            b .L1
+            bne .L2
+            ldr r0, =0x12345678
+            .L2:
+            bl .L1
+            adr r0, .L1",
+        );
+        assert_eq!(
+            vec![
+                0xeaff_fffe,
+                0x1a00_0001,
+                0xe305_0678,
+                0xe341_0234,
+                0xebff_fffa,
+                0xe24f_001c,
+            ],
+            words(&object.text)
+        );
+        assert!(object.relocations.is_empty());
+    }
+
+    #[test]
+    fn calls_are_relocated() {
+        let object = encoded(
+            ".global main
+            main:
+            bl printf
+            bl malloc
+            b printf",
+        );
+        assert_eq!(
+            vec![0xebff_fffe, 0xebff_fffe, 0xeaff_fffe],
+            words(&object.text)
+        );
+        let symbol = |name: &str, definition| Symbol {
+            name: name.to_string(),
+            definition,
+        };
+        assert_eq!(
+            vec![
+                symbol("main", Some((Section::Text, 0))),
+                symbol("printf", None),
+                symbol("malloc", None),
+            ],
+            object.symbols
+        );
+        let relocation = |offset, kind, name: &str| Relocation {
+            offset,
+            kind,
+            target: Target::Symbol(name.to_string()),
+        };
+        assert_eq!(
+            vec![
+                relocation(0, RelocationKind::Call, "printf"),
+                relocation(4, RelocationKind::Call, "malloc"),
+                relocation(8, RelocationKind::Jump, "printf"),
+            ],
+            object.relocations
+        );
+    }
+
+    #[test]
+    fn strings_go_in_rodata() {
+        let object = encoded(
+            "adr r0, .L2
+            bx lr
+            .L1:
+            .asciz \"%d\\n\"
+            .align 2
+            .L2:
+            .asciz \"T\"",
+        );
+        assert_eq!(b"%d\n\0T\0", &object.rodata[..]);
+        // The addends are the address of `.L2` less the distance to `pc`.
+        assert_eq!(
+            vec![0xe30f_0ff4, 0xe34f_0ff8, 0xe08f_0000, 0xe12f_ff1e],
+            words(&object.text)
+        );
+        let relocation = |offset, kind| Relocation {
+            offset,
+            kind,
+            target: Target::Section(Section::Rodata),
+        };
+        assert_eq!(
+            vec![
+                relocation(0, RelocationKind::MovwPrel),
+                relocation(4, RelocationKind::MovtPrel),
+            ],
+            object.relocations
+        );
+    }
+
+    #[test]
+    fn alignment_pads_code_with_nops() {
+        let object = encoded(
+            "bx lr
+            .align 3
+            .L1:
+            bx lr",
+        );
+        assert_eq!(vec![0xe12f_ff1e, NOP, 0xe12f_ff1e], words(&object.text));
+    }
+
+    #[test]
+    fn errors() {
+        for code in [
+            "mov r0, #257",
+            "ldr r0, [fp, #-4096]",
+            "svc #0x1000000",
+            "adr r0, .L1",
+            ".global main",
+            ".L1:\n.L1:",
+            ".L1:\n.asciz \"x\"\nb .L1",
+        ] {
+            let lines = parse(code).unwrap();
+            assert!(
+                matches!(encode(&lines), Err(CompileError::CodeGenError(_))),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn generated_programs() {
+        for seed in 0..50 {
+            let ast = ProgramGenerator::new(seed).program();
+            for lines in [
+                arm_code_generator::generate_lines(&ast),
+                arm_emitter::generate_lines(&ast),
+            ] {
+                let object = encode(&lines.unwrap()).unwrap();
+                assert!(object.symbols.iter().any(|symbol| symbol.name == "main"));
+                let calls = object
+                    .symbols
+                    .iter()
+                    .filter(|symbol| symbol.definition.is_none());
+                for symbol in calls {
+                    assert!(["malloc", "printf", "putchar"].contains(&symbol.name.as_str()));
+                }
+            }
+        }
+    }
+}
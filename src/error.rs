@@ -1,5 +1,6 @@
 use peg::error::ParseError;
 use peg::str::LineCol;
+use std::fmt;
 
 pub struct CodeGenError {}
 #[derive(Debug)]
@@ -8,5 +9,47 @@ pub enum CompileError {
     IOError(std::io::Error),
     CodeGenError(String),
     SyntaxError(String),
+    /// Command-line arguments or options that aren't accepted, as opposed to
+    /// bad source.
+    UsageError(String),
     RuntimeError(String, Option<i32>),
 }
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::ParseError(e) => write!(f, "{}", e),
+            CompileError::IOError(e) => write!(f, "{}", e),
+            CompileError::CodeGenError(message)
+            | CompileError::SyntaxError(message)
+            | CompileError::UsageError(message) => write!(f, "{}", message),
+            CompileError::RuntimeError(message, _) => write!(f, "Runtime error: {}", message),
+        }
+    }
+}
+
+impl From<std::io::Error> for CompileError {
+    fn from(e: std::io::Error) -> CompileError {
+        CompileError::IOError(e)
+    }
+}
+
+/// A warning reported by a compiler pass. Unlike a `CompileError`, it doesn't
+/// stop compilation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Name of the pass that reported it.
+    pub pass: &'static str,
+    /// Where the code it is about starts, when known.
+    pub location: Option<LineCol>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(location) = self.location {
+            write!(f, "{}: ", location)?;
+        }
+        write!(f, "warning: {} [{}]", self.message, self.pass)
+    }
+}
@@ -0,0 +1,417 @@
+use crate::ast::AST;
+use crate::visitor::AstVisitor;
+
+/// A pass that rebuilds the `AST`, called back by `AstVisitor::fold` with
+/// every node of the kind it is named after, and returning the node that
+/// replaces it.
+///
+/// Every method defaults to the `fold_*` function of the same name, which
+/// folds the children of the node in source order and rebuilds it around
+/// them. A pass only overrides the nodes it transforms, typically calling the
+/// `fold_*` function first to get a node whose children are already folded.
+pub trait Fold {
+    fn fold_assert(&mut self, node: AST) -> AST {
+        fold_assert(self, node)
+    }
+    fn fold_print(&mut self, node: AST) -> AST {
+        fold_print(self, node)
+    }
+    fn fold_array_length(&mut self, node: AST) -> AST {
+        fold_array_length(self, node)
+    }
+    fn fold_array_lookup(&mut self, node: AST) -> AST {
+        fold_array_lookup(self, node)
+    }
+    fn fold_array_literal(&mut self, node: AST) -> AST {
+        fold_array_literal(self, node)
+    }
+    fn fold_boolean(&mut self, node: AST) -> AST {
+        fold_boolean(self, node)
+    }
+    fn fold_number(&mut self, node: AST) -> AST {
+        fold_number(self, node)
+    }
+    fn fold_id(&mut self, node: AST) -> AST {
+        fold_id(self, node)
+    }
+    fn fold_not(&mut self, node: AST) -> AST {
+        fold_not(self, node)
+    }
+    fn fold_equal(&mut self, node: AST) -> AST {
+        fold_equal(self, node)
+    }
+    fn fold_not_equal(&mut self, node: AST) -> AST {
+        fold_not_equal(self, node)
+    }
+    fn fold_add(&mut self, node: AST) -> AST {
+        fold_add(self, node)
+    }
+    fn fold_subtract(&mut self, node: AST) -> AST {
+        fold_subtract(self, node)
+    }
+    fn fold_multiply(&mut self, node: AST) -> AST {
+        fold_multiply(self, node)
+    }
+    fn fold_divide(&mut self, node: AST) -> AST {
+        fold_divide(self, node)
+    }
+    fn fold_less_than(&mut self, node: AST) -> AST {
+        fold_less_than(self, node)
+    }
+    fn fold_greater_than(&mut self, node: AST) -> AST {
+        fold_greater_than(self, node)
+    }
+    fn fold_less_than_equal(&mut self, node: AST) -> AST {
+        fold_less_than_equal(self, node)
+    }
+    fn fold_greater_than_equal(&mut self, node: AST) -> AST {
+        fold_greater_than_equal(self, node)
+    }
+    fn fold_call(&mut self, node: AST) -> AST {
+        fold_call(self, node)
+    }
+    fn fold_return(&mut self, node: AST) -> AST {
+        fold_return(self, node)
+    }
+    fn fold_block(&mut self, node: AST) -> AST {
+        fold_block(self, node)
+    }
+    fn fold_if(&mut self, node: AST) -> AST {
+        fold_if(self, node)
+    }
+    fn fold_function(&mut self, node: AST) -> AST {
+        fold_function(self, node)
+    }
+    fn fold_var(&mut self, node: AST) -> AST {
+        fold_var(self, node)
+    }
+    fn fold_assign(&mut self, node: AST) -> AST {
+        fold_assign(self, node)
+    }
+    fn fold_while(&mut self, node: AST) -> AST {
+        fold_while(self, node)
+    }
+    fn fold_undefined(&mut self, node: AST) -> AST {
+        fold_undefined(self, node)
+    }
+    fn fold_null(&mut self, node: AST) -> AST {
+        fold_null(self, node)
+    }
+    fn fold_main(&mut self, node: AST) -> AST {
+        fold_main(self, node)
+    }
+}
+
+fn fold_all<F: Fold + ?Sized>(f: &mut F, nodes: Vec<AST>) -> Vec<AST> {
+    nodes.into_iter().map(|node| node.fold(f)).collect()
+}
+
+/// Folds a boxed child, reusing its allocation.
+fn fold_box<F: Fold + ?Sized>(f: &mut F, mut node: Box<AST>) -> Box<AST> {
+    *node = std::mem::replace(&mut *node, AST::Null).fold(f);
+    node
+}
+
+pub fn fold_assert<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Assert(condition) = node else {
+        panic!("Expected Assert node, got: {:?}", node)
+    };
+    AST::Assert(fold_box(f, condition))
+}
+
+pub fn fold_print<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Print(value) = node else {
+        panic!("Expected Print node, got: {:?}", node)
+    };
+    AST::Print(fold_box(f, value))
+}
+
+pub fn fold_array_length<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::ArrayLength(array) = node else {
+        panic!("Expected ArrayLength node, got: {:?}", node)
+    };
+    AST::ArrayLength(fold_box(f, array))
+}
+
+pub fn fold_array_lookup<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::ArrayLookup { array, index } = node else {
+        panic!("Expected ArrayLookup node, got: {:?}", node)
+    };
+    AST::ArrayLookup {
+        array: fold_box(f, array),
+        index: fold_box(f, index),
+    }
+}
+
+pub fn fold_array_literal<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::ArrayLiteral(items) = node else {
+        panic!("Expected ArrayLiteral node, got: {:?}", node)
+    };
+    AST::ArrayLiteral(fold_all(f, items))
+}
+
+pub fn fold_boolean<F: Fold + ?Sized>(_f: &mut F, node: AST) -> AST {
+    node
+}
+
+pub fn fold_number<F: Fold + ?Sized>(_f: &mut F, node: AST) -> AST {
+    node
+}
+
+pub fn fold_id<F: Fold + ?Sized>(_f: &mut F, node: AST) -> AST {
+    node
+}
+
+pub fn fold_not<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Not(term) = node else {
+        panic!("Expected Not node, got: {:?}", node)
+    };
+    AST::Not(fold_box(f, term))
+}
+
+pub fn fold_equal<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Equal { left, right } = node else {
+        panic!("Expected Equal node, got: {:?}", node)
+    };
+    AST::Equal {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_not_equal<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::NotEqual { left, right } = node else {
+        panic!("Expected NotEqual node, got: {:?}", node)
+    };
+    AST::NotEqual {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_add<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Add { left, right } = node else {
+        panic!("Expected Add node, got: {:?}", node)
+    };
+    AST::Add {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_subtract<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Subtract { left, right } = node else {
+        panic!("Expected Subtract node, got: {:?}", node)
+    };
+    AST::Subtract {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_multiply<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Multiply { left, right } = node else {
+        panic!("Expected Multiply node, got: {:?}", node)
+    };
+    AST::Multiply {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_divide<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Divide { left, right } = node else {
+        panic!("Expected Divide node, got: {:?}", node)
+    };
+    AST::Divide {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_less_than<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::LessThan { left, right } = node else {
+        panic!("Expected LessThan node, got: {:?}", node)
+    };
+    AST::LessThan {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_greater_than<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::GreaterThan { left, right } = node else {
+        panic!("Expected GreaterThan node, got: {:?}", node)
+    };
+    AST::GreaterThan {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_less_than_equal<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::LessThanEqual { left, right } = node else {
+        panic!("Expected LessThanEqual node, got: {:?}", node)
+    };
+    AST::LessThanEqual {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_greater_than_equal<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::GreaterThanEqual { left, right } = node else {
+        panic!("Expected GreaterThanEqual node, got: {:?}", node)
+    };
+    AST::GreaterThanEqual {
+        left: fold_box(f, left),
+        right: fold_box(f, right),
+    }
+}
+
+pub fn fold_call<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Call { callee, args } = node else {
+        panic!("Expected Call node, got: {:?}", node)
+    };
+    AST::Call {
+        callee,
+        args: fold_all(f, args),
+    }
+}
+
+pub fn fold_return<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Return { term } = node else {
+        panic!("Expected Return node, got: {:?}", node)
+    };
+    AST::Return {
+        term: fold_box(f, term),
+    }
+}
+
+pub fn fold_block<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Block(statements) = node else {
+        panic!("Expected Block node, got: {:?}", node)
+    };
+    AST::Block(fold_all(f, statements))
+}
+
+pub fn fold_if<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::IfNode {
+        conditional,
+        consequence,
+        alternative,
+    } = node
+    else {
+        panic!("Expected IfNode node, got: {:?}", node)
+    };
+    AST::IfNode {
+        conditional: fold_box(f, conditional),
+        consequence: fold_box(f, consequence),
+        alternative: fold_box(f, alternative),
+    }
+}
+
+pub fn fold_function<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Function {
+        name,
+        parameters,
+        body,
+    } = node
+    else {
+        panic!("Expected Function node, got: {:?}", node)
+    };
+    AST::Function {
+        name,
+        parameters,
+        body: fold_box(f, body),
+    }
+}
+
+pub fn fold_var<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Var { name, value } = node else {
+        panic!("Expected Var node, got: {:?}", node)
+    };
+    AST::Var {
+        name,
+        value: fold_box(f, value),
+    }
+}
+
+pub fn fold_assign<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Assign { name, value } = node else {
+        panic!("Expected Assign node, got: {:?}", node)
+    };
+    AST::Assign {
+        name,
+        value: fold_box(f, value),
+    }
+}
+
+pub fn fold_while<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::While { conditional, body } = node else {
+        panic!("Expected While node, got: {:?}", node)
+    };
+    AST::While {
+        conditional: fold_box(f, conditional),
+        body: fold_box(f, body),
+    }
+}
+
+pub fn fold_undefined<F: Fold + ?Sized>(_f: &mut F, node: AST) -> AST {
+    node
+}
+
+pub fn fold_null<F: Fold + ?Sized>(_f: &mut F, node: AST) -> AST {
+    node
+}
+
+pub fn fold_main<F: Fold + ?Sized>(f: &mut F, node: AST) -> AST {
+    let AST::Main(statements) = node else {
+        panic!("Expected Main node, got: {:?}", node)
+    };
+    AST::Main(fold_all(f, statements))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::ProgramGenerator;
+    use crate::parser::parse;
+
+    /// Adds up numbers, working bottom-up on already folded children.
+    struct AddNumbers;
+
+    impl Fold for AddNumbers {
+        fn fold_add(&mut self, node: AST) -> AST {
+            match fold_add(self, node) {
+                AST::Add { left, right } => match (*left, *right) {
+                    (AST::Number(a), AST::Number(b)) => AST::Number(a + b),
+                    (left, right) => AST::Add {
+                        left: left.into(),
+                        right: right.into(),
+                    },
+                },
+                other => other,
+            }
+        }
+    }
+
+    #[test]
+    fn rebuild_bottom_up() {
+        let ast = parse("function f() { return (1 + 2) + (3 + 4) + x; }").expect("Parser failed");
+        assert_eq!(
+            parse("function f() { return 10 + x; }").expect("Parser failed"),
+            ast.fold(&mut AddNumbers)
+        );
+    }
+
+    #[test]
+    fn default_methods_change_nothing() {
+        struct Nothing;
+        impl Fold for Nothing {}
+        for seed in 0..50 {
+            let ast = ProgramGenerator::new(seed).program();
+            assert_eq!(ast.clone(), ast.fold(&mut Nothing));
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use crate::error::CompileError;
+use crate::parser::parse;
+
+/// Reformats source text, keeping its comments.
+///
+/// Unlike `printer::print_program`, which works on the `AST`, this works on
+/// the tokens of the source, so comments and redundant parentheses survive.
+/// The layout is the printer's: four-space indentation, one statement per
+/// line, spaces around binary operators and after commas, `} else {` on one
+/// line and a blank line around top-level functions. Other blank lines are
+/// kept, but runs of them are collapsed into one.
+///
+/// Formatting is idempotent, and the result always parses to the same tree as
+/// the input. Source that doesn't parse is rejected with the parse error.
+pub fn format_source(source: &str) -> Result<String, CompileError> {
+    let ast = parse(source)?;
+    let tokens = tokenize(source)?;
+    let formatted = Formatter::new(&tokens).format();
+    if parse(&formatted)? != ast {
+        return Err(CompileError::SyntaxError(
+            "Formatting changed the meaning of the program".to_string(),
+        ));
+    }
+    Ok(formatted)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Word,
+    Number,
+    Symbol,
+    Comment,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    kind: Kind,
+    text: &'a str,
+    /// The token is the first one on its line.
+    new_line: bool,
+    /// There is at least one empty line between this token and the previous.
+    blank_line: bool,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token<'_>>, CompileError> {
+    let bytes = source.as_bytes();
+    let mut tokens: Vec<Token> = vec![];
+    let mut newlines = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let kind = match bytes[i] {
+            b'\n' => {
+                newlines += 1;
+                i += 1;
+                continue;
+            }
+            b' ' | b'\t' | b'\r' => {
+                i += 1;
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                i = source[i..].find('\n').map_or(bytes.len(), |end| i + end);
+                Kind::Comment
+            }
+            b'a'..=b'z' | b'A'..=b'Z' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                Kind::Word
+            }
+            b'0'..=b'9' => {
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                Kind::Number
+            }
+            c => {
+                if matches!(source.get(i..i + 2), Some("==" | "!=" | "<=" | ">=")) {
+                    i += 2;
+                } else if b"=<>+-*/!(){}[],;".contains(&c) {
+                    i += 1;
+                } else {
+                    let c = source[i..].chars().next().unwrap();
+                    return Err(CompileError::SyntaxError(format!(
+                        "Unexpected character {:?}",
+                        c
+                    )));
+                }
+                Kind::Symbol
+            }
+        };
+        tokens.push(Token {
+            kind,
+            text: source[start..i].trim_end(),
+            new_line: newlines > 0 || tokens.is_empty(),
+            blank_line: newlines > 1,
+        });
+        newlines = 0;
+    }
+    Ok(tokens)
+}
+
+/// What has to be written before the next token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Break {
+    None,
+    Line,
+    BlankLine,
+}
+
+struct Formatter<'a> {
+    tokens: &'a [Token<'a>],
+    out: String,
+    indent: usize,
+    /// For every open brace, whether it is the body of a function.
+    blocks: Vec<bool>,
+    function_header: bool,
+    in_statement: bool,
+    pending: Break,
+    previous: Option<Token<'a>>,
+    own_line_comment: bool,
+}
+
+const INDENT: &str = "    ";
+
+const KEYWORDS: [&str; 6] = ["if", "while", "else", "return", "var", "function"];
+
+/// Whether two tokens on the same line are separated by a space.
+fn space_between(previous: &str, next: &str) -> bool {
+    match (previous, next) {
+        (_, ")" | "]" | "," | ";") | ("(" | "[" | "!", _) | ("{", "}") => false,
+        (_, "(" | "[") => {
+            KEYWORDS.contains(&previous)
+                || !(previous.starts_with(|c: char| c.is_ascii_alphanumeric())
+                    || previous == ")"
+                    || previous == "]")
+        }
+        _ => true,
+    }
+}
+
+impl<'a> Formatter<'a> {
+    fn new(tokens: &'a [Token<'a>]) -> Formatter<'a> {
+        Formatter {
+            tokens,
+            out: String::new(),
+            indent: 0,
+            blocks: vec![],
+            function_header: false,
+            in_statement: false,
+            pending: Break::None,
+            previous: None,
+            own_line_comment: false,
+        }
+    }
+
+    fn format(mut self) -> String {
+        for (i, token) in self.tokens.iter().enumerate() {
+            let next = self.tokens[i + 1..]
+                .iter()
+                .find(|t| t.kind != Kind::Comment)
+                .map(|t| t.text);
+            if token.kind == Kind::Comment {
+                self.comment(token, next);
+            } else {
+                self.token(token, next);
+            }
+        }
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        self.out
+    }
+
+    fn comment(&mut self, token: &Token, next: Option<&str>) {
+        if token.new_line || self.out.is_empty() {
+            self.pending = self.pending.max(Break::Line);
+            self.line_break(token, next == Some("function"));
+            self.own_line_comment = true;
+        } else {
+            self.out.push(' ');
+            self.own_line_comment = false;
+        }
+        self.out.push_str(token.text);
+        self.pending = self.pending.max(Break::Line);
+    }
+
+    fn token(&mut self, token: &Token<'a>, next: Option<&str>) {
+        let previous = self.previous.map(|t| t.text);
+        if token.text == "}" {
+            self.indent -= 1;
+            if previous != Some("{") {
+                self.pending = self.pending.max(Break::Line);
+            }
+        }
+        if self.pending != Break::None {
+            self.line_break(token, token.text == "function");
+        } else if previous.is_some_and(|previous| space_between(previous, token.text)) {
+            self.out.push(' ');
+        }
+        self.own_line_comment = false;
+
+        if token.kind == Kind::Number {
+            let digits = token.text.trim_start_matches('0');
+            self.out
+                .push_str(if digits.is_empty() { "0" } else { digits });
+        } else {
+            self.out.push_str(token.text);
+        }
+
+        match token.text {
+            "function" => {
+                self.function_header = true;
+                self.in_statement = true;
+            }
+            "{" => {
+                self.indent += 1;
+                self.blocks.push(self.function_header);
+                self.function_header = false;
+                self.in_statement = false;
+                if next != Some("}") {
+                    self.pending = Break::Line;
+                }
+            }
+            "}" => {
+                let function = self.blocks.pop().unwrap_or(false);
+                self.in_statement = false;
+                self.pending = match next {
+                    Some("else") => Break::None,
+                    _ if function && self.indent == 0 => Break::BlankLine,
+                    _ => Break::Line,
+                };
+            }
+            ";" => {
+                self.in_statement = false;
+                if next != Some("else") {
+                    self.pending = Break::Line;
+                }
+            }
+            _ => self.in_statement = true,
+        }
+        self.previous = Some(*token);
+    }
+
+    /// Writes the pending line break before `token`, and indents the new line.
+    fn line_break(&mut self, token: &Token, function: bool) {
+        let mut pending = std::mem::replace(&mut self.pending, Break::None);
+        if self.out.is_empty() {
+            return;
+        }
+        let previous = self.previous.map(|t| t.text);
+        let block_start = previous == Some("{") && !self.own_line_comment;
+        if token.blank_line && !block_start && token.text != "}" {
+            pending = pending.max(Break::BlankLine);
+        }
+        if function && self.indent == 0 && !self.in_statement && !self.own_line_comment {
+            pending = Break::BlankLine;
+        }
+        self.out.push('\n');
+        if pending == Break::BlankLine {
+            self.out.push('\n');
+        }
+        for _ in 0..self.indent + self.in_statement as usize {
+            self.out.push_str(INDENT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::ProgramGenerator;
+    use crate::printer::print_program;
+
+    fn assert_formats(expected: &str, source: &str) {
+        assert_eq!(expected, format_source(source).expect("Formatting failed"));
+        assert_eq!(
+            expected,
+            format_source(expected).expect("Formatting failed")
+        );
+    }
+
+    #[test]
+    fn layout() {
+        assert_formats(
+            r#"function f(a, b) {
+    var x = [1, 2, a];
+    if (!(a <= b)) {
+        return x[0] * (a + 1);
+    } else if (a == 3) {
+        print(length(x));
+    } else {}
+    while (a) a = a - 1;
+    return 0;
+}
+
+function main() {
+    assert(f(1, 2));
+}
+"#,
+            "function f(a,b){var x=[1,2,a];if(!(a<=b)){return x[0]*(a+1);}\n\n\n\
+             else if(a==3){print(length(x));}else{}while(a)a=a-1;return 00;}\n\
+             function main(){assert(f(1,2));}",
+        );
+    }
+
+    #[test]
+    fn top_level_statements() {
+        assert_formats("print(1);\n", "  print( 1 ) ;  ");
+        assert_formats(
+            "var x = 1;\nprint(x);\n\nfunction f() {}\n\nprint(f());\n",
+            "var x = 1; print(x); function f() {} print(f());",
+        );
+        assert_formats(
+            "if (x) y = 1; else y = 2;\n",
+            "if (x)\n  y = 1;\nelse\n  y = 2;",
+        );
+        assert_formats("", "  \n");
+    }
+
+    #[test]
+    fn comments() {
+        assert_formats(
+            r#"// Adds two numbers.
+function add(a, b) { // trailing
+    // Own line.
+
+    return a + // in an expression
+        b;
+
+    // After a blank line.
+} // After the function.
+
+// Before main.
+function main() {
+    // Alone in a block.
+}
+"#,
+            "// Adds two numbers.\nfunction add(a, b) { // trailing\n// Own line.\n\n\
+             return a + // in an expression\nb;\n\n\n    // After a blank line.\n\n} \
+             // After the function.\n// Before main.\nfunction main() {\n  // Alone in a block.\n}",
+        );
+    }
+
+    #[test]
+    fn invalid_source() {
+        assert!(matches!(
+            format_source("print(1"),
+            Err(CompileError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn agrees_with_printer() {
+        for seed in 0..300 {
+            let printed = print_program(&ProgramGenerator::new(seed).program());
+            assert_eq!(
+                printed,
+                format_source(&printed).expect("Formatting failed"),
+                "seed {}",
+                seed
+            );
+        }
+    }
+}
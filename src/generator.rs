@@ -0,0 +1,331 @@
+use crate::ast::AST;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Generates random, well-formed programs for fuzzing the compiler.
+///
+/// Every program is a list of functions ending with `main`. They always
+/// terminate and never index out of bounds:
+///
+/// * a function only calls functions defined before it, so there is no
+///   recursion;
+/// * every `while` loop runs a dedicated counter up to a small constant bound
+///   and nothing else assigns to that counter;
+/// * arrays are never reassigned and are only indexed with constants smaller
+///   than their length.
+///
+/// `var` statements only appear at the top of function bodies, because the
+/// code generator assigns stack slots to locals at compile time and a `var`
+/// that runs conditionally (or repeatedly, in a loop) shifts every slot
+/// allocated after it.
+///
+/// Identifiers, literals and array lookups are restricted to what
+/// `lang_parser` accepts, so printing a program with `Display` yields source
+/// text that can be fed back to the parser.
+pub struct ProgramGenerator {
+    rng: StdRng,
+    functions: Vec<(String, usize)>,
+    ints: Vec<String>,
+    arrays: Vec<(String, usize)>,
+    counters: Vec<String>,
+    active_counters: Vec<String>,
+    next_counter: usize,
+}
+
+const MAX_EXPRESSION_DEPTH: usize = 4;
+const MAX_STATEMENT_DEPTH: usize = 3;
+const MAX_LOOP_BOUND: u64 = 5;
+
+impl ProgramGenerator {
+    pub fn new(seed: u64) -> ProgramGenerator {
+        ProgramGenerator {
+            rng: StdRng::seed_from_u64(seed),
+            functions: vec![],
+            ints: vec![],
+            arrays: vec![],
+            counters: vec![],
+            active_counters: vec![],
+            next_counter: 0,
+        }
+    }
+
+    /// Generates a whole program: a few helper functions followed by `main`.
+    pub fn program(&mut self) -> AST {
+        self.functions.clear();
+        let helpers = self.rng.gen_range(0..=3);
+        let mut program = vec![];
+        for i in 0..helpers {
+            let arity = self.rng.gen_range(0..=4);
+            let function = self.function(format!("f{}", i), arity, true);
+            program.push(function);
+            self.functions.push((format!("f{}", i), arity));
+        }
+        program.push(self.function("main".to_string(), 0, false));
+        AST::Block(program)
+    }
+
+    fn function(&mut self, name: String, arity: usize, returns: bool) -> AST {
+        let parameters: Vec<String> = (0..arity).map(|i| format!("p{}", i)).collect();
+        self.ints = parameters.clone();
+        self.arrays.clear();
+        self.counters.clear();
+        self.active_counters.clear();
+        self.next_counter = 0;
+
+        let mut declarations = vec![];
+        for i in 0..self.rng.gen_range(0..=3) {
+            let value = self.expression(2);
+            declarations.push(AST::Var {
+                name: format!("v{}", i),
+                value: value.into(),
+            });
+            self.ints.push(format!("v{}", i));
+        }
+        for i in 0..self.rng.gen_range(0..=2) {
+            let len = self.rng.gen_range(0..=4);
+            let items = (0..len).map(|_| self.expression(2)).collect();
+            declarations.push(AST::Var {
+                name: format!("a{}", i),
+                value: AST::ArrayLiteral(items).into(),
+            });
+            self.arrays.push((format!("a{}", i), len));
+        }
+
+        let mut statements = vec![];
+        for _ in 0..self.rng.gen_range(1..=5) {
+            statements.push(self.statement(0));
+        }
+        if returns {
+            let value = self.expression(0);
+            statements.push(AST::Return { term: value.into() });
+        }
+
+        // Loop counters are only known once the body has been generated.
+        for counter in &self.counters {
+            declarations.push(AST::Var {
+                name: counter.clone(),
+                value: AST::Number(0).into(),
+            });
+        }
+        declarations.extend(statements);
+        AST::Function {
+            name,
+            parameters,
+            body: AST::Block(declarations).into(),
+        }
+    }
+
+    fn statement(&mut self, depth: usize) -> AST {
+        let choices = if depth >= MAX_STATEMENT_DEPTH { 3 } else { 5 };
+        match self.rng.gen_range(0..choices) {
+            0 => AST::Print(self.expression(0).into()),
+            1 => AST::Assert(self.expression(0).into()),
+            2 => match self.assignable() {
+                Some(name) => AST::Assign {
+                    name,
+                    value: self.expression(0).into(),
+                },
+                None => AST::Print(self.expression(0).into()),
+            },
+            3 => AST::IfNode {
+                conditional: self.expression(0).into(),
+                consequence: self.block(depth + 1).into(),
+                alternative: self.block(depth + 1).into(),
+            },
+            _ => self.while_loop(depth),
+        }
+    }
+
+    fn block(&mut self, depth: usize) -> AST {
+        let statements = (0..self.rng.gen_range(0..=3))
+            .map(|_| self.statement(depth))
+            .collect();
+        AST::Block(statements)
+    }
+
+    /// `{ cN = 0; while (cN < bound) { ...; cN = cN + 1; } }`
+    fn while_loop(&mut self, depth: usize) -> AST {
+        let counter = format!("c{}", self.next_counter);
+        self.next_counter += 1;
+        self.counters.push(counter.clone());
+        let bound = self.rng.gen_range(0..=MAX_LOOP_BOUND);
+
+        self.active_counters.push(counter.clone());
+        let AST::Block(mut body) = self.block(depth + 1) else {
+            unreachable!()
+        };
+        self.active_counters.pop();
+        body.push(AST::Assign {
+            name: counter.clone(),
+            value: AST::Add {
+                left: counter.clone().into(),
+                right: AST::Number(1).into(),
+            }
+            .into(),
+        });
+
+        AST::Block(vec![
+            AST::Assign {
+                name: counter.clone(),
+                value: AST::Number(0).into(),
+            },
+            AST::While {
+                conditional: AST::LessThan {
+                    left: counter.into(),
+                    right: AST::Number(bound).into(),
+                }
+                .into(),
+                body: AST::Block(body).into(),
+            },
+        ])
+    }
+
+    fn assignable(&mut self) -> Option<String> {
+        if self.ints.is_empty() {
+            None
+        } else {
+            let i = self.rng.gen_range(0..self.ints.len());
+            Some(self.ints[i].clone())
+        }
+    }
+
+    /// Generates an integer-valued expression.
+    pub fn expression(&mut self, depth: usize) -> AST {
+        if depth >= MAX_EXPRESSION_DEPTH || self.rng.gen_bool(0.3) {
+            return self.leaf();
+        }
+        let binary = |generator: &mut Self, f: fn(Box<AST>, Box<AST>) -> AST| {
+            let left = generator.expression(depth + 1);
+            let right = generator.expression(depth + 1);
+            f(left.into(), right.into())
+        };
+        match self.rng.gen_range(0..14) {
+            0 => binary(self, |left, right| AST::Add { left, right }),
+            1 => binary(self, |left, right| AST::Subtract { left, right }),
+            2 => binary(self, |left, right| AST::Multiply { left, right }),
+            3 => binary(self, |left, right| AST::Divide { left, right }),
+            4 => binary(self, |left, right| AST::Equal { left, right }),
+            5 => binary(self, |left, right| AST::NotEqual { left, right }),
+            6 => binary(self, |left, right| AST::LessThan { left, right }),
+            7 => binary(self, |left, right| AST::GreaterThan { left, right }),
+            8 => binary(self, |left, right| AST::LessThanEqual { left, right }),
+            9 => binary(self, |left, right| AST::GreaterThanEqual { left, right }),
+            10 => AST::Not(self.expression(depth + 1).into()),
+            11 if !self.functions.is_empty() => {
+                let i = self.rng.gen_range(0..self.functions.len());
+                let (callee, arity) = self.functions[i].clone();
+                let args = (0..arity).map(|_| self.expression(depth + 1)).collect();
+                AST::Call { callee, args }
+            }
+            12 if !self.arrays.is_empty() => {
+                let i = self.rng.gen_range(0..self.arrays.len());
+                let (array, len) = self.arrays[i].clone();
+                if len == 0 {
+                    AST::ArrayLength(array.into())
+                } else {
+                    AST::ArrayLookup {
+                        array: array.into(),
+                        index: AST::Number(self.rng.gen_range(0..len) as u64).into(),
+                    }
+                }
+            }
+            13 if !self.arrays.is_empty() => {
+                let i = self.rng.gen_range(0..self.arrays.len());
+                AST::ArrayLength(self.arrays[i].0.clone().into())
+            }
+            _ => self.leaf(),
+        }
+    }
+
+    fn leaf(&mut self) -> AST {
+        match self.rng.gen_range(0..10) {
+            0 => AST::Boolean(self.rng.gen()),
+            1 => AST::Null,
+            2 => AST::Undefined,
+            3..=5 if !self.ints.is_empty() => {
+                let i = self.rng.gen_range(0..self.ints.len());
+                AST::Id(self.ints[i].clone())
+            }
+            6 if !self.active_counters.is_empty() => {
+                let i = self.rng.gen_range(0..self.active_counters.len());
+                AST::Id(self.active_counters[i].clone())
+            }
+            _ => AST::Number(self.rng.gen_range(0..1000)),
+        }
+    }
+}
+
+/// Checks a way of running programs against the interpreter on the programs
+/// generated from `seeds`. `run` runs a program, writing what it prints to
+/// the buffer, and returns what `main` returned, which must be what the
+/// interpreter prints and returns.
+#[cfg(test)]
+pub fn check_against_interpreter(
+    seeds: std::ops::Range<u64>,
+    mut run: impl FnMut(&AST, &mut Vec<u8>) -> i32,
+) {
+    for seed in seeds {
+        let program = ProgramGenerator::new(seed).program();
+        let mut expected = vec![];
+        let result = crate::interpreter::Interpreter::with_max_steps(100_000)
+            .execute(&program, &mut expected)
+            .unwrap_or_else(|e| panic!("seed {}: {}\n{}", seed, e, program));
+        let mut out = vec![];
+        let other = run(&program, &mut out);
+        assert_eq!(
+            (result, String::from_utf8_lossy(&expected)),
+            (other, String::from_utf8_lossy(&out)),
+            "seed {}:\n{}",
+            seed,
+            program
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::parser::parse;
+
+    #[test]
+    fn same_seed_same_program() {
+        assert_eq!(
+            ProgramGenerator::new(7).program(),
+            ProgramGenerator::new(7).program()
+        );
+    }
+
+    #[test]
+    fn programs_terminate() {
+        for seed in 0..500 {
+            let program = ProgramGenerator::new(seed).program();
+            let mut interpreter = Interpreter::with_max_steps(100_000);
+            interpreter
+                .execute(&program, &mut Vec::new())
+                .unwrap_or_else(|e| panic!("seed {}: {}\n{}", seed, e, program));
+        }
+    }
+
+    #[test]
+    fn main_is_last() {
+        let AST::Block(functions) = ProgramGenerator::new(3).program() else {
+            panic!("Expected a block of functions")
+        };
+        let Some(AST::Function { name, .. }) = functions.last() else {
+            panic!("Expected a function")
+        };
+        assert_eq!("main", name);
+    }
+
+    #[test]
+    fn printed_programs_parse_back() {
+        for seed in 0..50 {
+            let program = ProgramGenerator::new(seed).program();
+            let source = program.to_string();
+            let parsed =
+                parse(&source).unwrap_or_else(|e| panic!("seed {}: {}\n{}", seed, e, source));
+            assert_eq!(program, parsed, "seed {}", seed);
+        }
+    }
+}
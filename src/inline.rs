@@ -0,0 +1,541 @@
+use crate::ast::AST;
+use crate::dead_code::{called_functions, remove_uncalled_functions};
+use crate::dump::{fields, Field};
+use crate::error::CompileError;
+use crate::fold::{self, Fold};
+use crate::pass_manager::{Diagnostics, Pass};
+use crate::visitor::AstVisitor;
+use crate::visitor_mut::{walk_assign_mut, walk_var_mut, VisitorMut};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+/// Largest function body, in nodes, that `Inlining` copies by default.
+pub const DEFAULT_INLINE_THRESHOLD: usize = 12;
+
+/// Replaces calls to small functions with their bodies.
+///
+/// A function is inlined if it isn't `main`, can't call itself, directly or
+/// not, has a body of at most `threshold` nodes, only uses its parameters and
+/// its own variables, and only returns at its end. A function whose body is a
+/// single `return` is inlined into any expression, with the arguments
+/// substituted for the parameters, as long as that neither drops nor repeats
+/// side effects or repeats work. Other functions are inlined at the statements
+/// of a function body that call them for their value: `f(...);`,
+/// `var x = f(...);`, `x = f(...);` and `return f(...);`. Their parameters
+/// become variables initialised with the arguments, and all their variables are
+/// renamed so they don't clash with the caller's.
+///
+/// Only the calls in the program as written are inlined, not the ones in the
+/// bodies copied. Afterwards, the functions inlined that `main` no longer calls
+/// are removed, without the warning `DeadCodeElimination` gives.
+pub struct Inlining {
+    pub threshold: usize,
+}
+
+impl Pass for Inlining {
+    fn name(&self) -> &'static str {
+        "inlining"
+    }
+
+    fn run(&mut self, ast: AST, _diagnostics: &mut Diagnostics) -> Result<AST, CompileError> {
+        let functions = top_level_functions(&ast);
+        let mut calls = HashMap::new();
+        for (name, (_, body)) in &functions {
+            calls.insert(*name, called_functions(body)?);
+        }
+        let mut candidates = HashMap::new();
+        for (&name, (parameters, body)) in &functions {
+            if name == "main" || calls_itself(name, &calls) {
+                continue;
+            }
+            if let Some(candidate) = Candidate::new(parameters, body, self.threshold) {
+                candidates.insert(name.to_string(), candidate);
+            }
+        }
+        if candidates.is_empty() {
+            return Ok(ast);
+        }
+        let mut names = HashSet::new();
+        collect_names(&ast, &mut names);
+        let mut inliner = Inliner {
+            candidates,
+            names,
+            inlined: HashSet::new(),
+        };
+        let ast = ast.fold(&mut inliner);
+        // Functions inlined everywhere are left without callers.
+        remove_uncalled_functions(ast, |name| inliner.inlined.contains(name))
+    }
+}
+
+/// The parameters and body of every function declared at the top level.
+fn top_level_functions(ast: &AST) -> HashMap<&str, (&[String], &AST)> {
+    let statements = match ast {
+        AST::Block(statements) => statements.as_slice(),
+        other => std::slice::from_ref(other),
+    };
+    let mut functions = HashMap::new();
+    for statement in statements {
+        if let AST::Function {
+            name,
+            parameters,
+            body,
+        } = statement
+        {
+            functions
+                .entry(name.as_str())
+                .or_insert((parameters.as_slice(), body.as_ref()));
+        }
+    }
+    functions
+}
+
+fn calls_itself(name: &str, calls: &HashMap<&str, HashSet<String>>) -> bool {
+    let mut seen = HashSet::new();
+    let mut pending: Vec<&String> = calls[name].iter().collect();
+    while let Some(callee) = pending.pop() {
+        if callee == name {
+            return true;
+        }
+        if seen.insert(callee) {
+            pending.extend(calls.get(callee.as_str()).into_iter().flatten());
+        }
+    }
+    false
+}
+
+/// Every node of the tree, parents before their children.
+fn nodes<'a>(ast: &'a AST, out: &mut Vec<&'a AST>) {
+    out.push(ast);
+    for (_, field) in fields(ast).1 {
+        match field {
+            Field::Node(node) => nodes(node, out),
+            Field::Nodes(items) => items.iter().for_each(|node| nodes(node, out)),
+            _ => {}
+        }
+    }
+}
+
+fn all_nodes(ast: &AST) -> Vec<&AST> {
+    let mut out = vec![];
+    nodes(ast, &mut out);
+    out
+}
+
+/// Every name a variable, parameter or function is known by.
+fn collect_names(ast: &AST, names: &mut HashSet<String>) {
+    for node in all_nodes(ast) {
+        match node {
+            AST::Id(name) | AST::Var { name, .. } | AST::Assign { name, .. } => {
+                names.insert(name.clone());
+            }
+            AST::Function {
+                name, parameters, ..
+            } => {
+                names.insert(name.clone());
+                names.extend(parameters.iter().cloned());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// An expression that can be evaluated any number of times, or not at all,
+/// without changing what the program does.
+fn is_pure(node: &AST) -> bool {
+    all_nodes(node)
+        .iter()
+        .all(|node| !matches!(node, AST::Call { .. } | AST::Print(_) | AST::Assert(_)))
+}
+
+/// An expression that costs no more to repeat than a variable.
+fn is_trivial(node: &AST) -> bool {
+    matches!(
+        node,
+        AST::Id(_) | AST::Number(_) | AST::Boolean(_) | AST::Undefined | AST::Null
+    )
+}
+
+enum Candidate {
+    /// A function whose body is `return term;`.
+    Expression { parameters: Vec<String>, term: AST },
+    /// A function whose body ends with its only `return`.
+    Statements {
+        parameters: Vec<String>,
+        statements: Vec<AST>,
+        term: AST,
+    },
+}
+
+impl Candidate {
+    fn new(parameters: &[String], body: &AST, threshold: usize) -> Option<Candidate> {
+        let statements = match body {
+            AST::Block(statements) => statements.as_slice(),
+            body => std::slice::from_ref(body),
+        };
+        let nodes: Vec<&AST> = statements.iter().flat_map(all_nodes).collect();
+        if nodes.len() > threshold {
+            return None;
+        }
+        let mut declared: HashSet<&str> = parameters.iter().map(String::as_str).collect();
+        let mut returns = 0;
+        for node in &nodes {
+            match node {
+                AST::Var { name, .. } => {
+                    declared.insert(name);
+                }
+                AST::Return { .. } => returns += 1,
+                AST::Function { .. } => return None,
+                _ => {}
+            }
+        }
+        let free = nodes.iter().any(|node| match node {
+            AST::Id(name) | AST::Assign { name, .. } => !declared.contains(name.as_str()),
+            _ => false,
+        });
+        let (Some(AST::Return { term }), 1) = (statements.last(), returns) else {
+            return None;
+        };
+        if free {
+            return None;
+        }
+        let parameters = parameters.to_vec();
+        let term = term.as_ref().clone();
+        if statements.len() == 1 {
+            Some(Candidate::Expression { parameters, term })
+        } else {
+            let statements = statements[..statements.len() - 1].to_vec();
+            Some(Candidate::Statements {
+                parameters,
+                statements,
+                term,
+            })
+        }
+    }
+}
+
+/// Replaces variables, wherever they are declared, assigned or read.
+struct Substitute(HashMap<String, AST>);
+
+impl Substitute {
+    fn rename_target(&self, node: &mut AST) {
+        if let AST::Var { name, .. } | AST::Assign { name, .. } = node {
+            if let Some(AST::Id(new_name)) = self.0.get(name) {
+                *name = new_name.clone();
+            }
+        }
+    }
+}
+
+impl VisitorMut for Substitute {
+    fn visit_id_mut(&mut self, node: &mut AST) {
+        if let AST::Id(name) = node {
+            if let Some(value) = self.0.get(name) {
+                *node = value.clone();
+            }
+        }
+    }
+
+    fn visit_var_mut(&mut self, node: &mut AST) {
+        self.rename_target(node);
+        walk_var_mut(self, node)
+    }
+
+    fn visit_assign_mut(&mut self, node: &mut AST) {
+        self.rename_target(node);
+        walk_assign_mut(self, node)
+    }
+}
+
+struct Inliner {
+    candidates: HashMap<String, Candidate>,
+    /// Names in use, which new variables must not take.
+    names: HashSet<String>,
+    /// Functions a call was inlined to.
+    inlined: HashSet<String>,
+}
+
+impl Inliner {
+    fn fresh_name(&mut self, function: &str, name: &str) -> String {
+        let mut n = 1;
+        loop {
+            let fresh = format!("{}_{}_{}", function, name, n);
+            if self.names.insert(fresh.clone()) {
+                return fresh;
+            }
+            n += 1;
+        }
+    }
+
+    /// Inlines the calls made by a statement for their value, replacing it
+    /// with several statements.
+    fn inline_statements(&mut self, statements: Vec<AST>) -> Vec<AST> {
+        let mut inlined = vec![];
+        for statement in statements {
+            let call = match &statement {
+                AST::Call { .. } => &statement,
+                AST::Var { value, .. } | AST::Assign { value, .. } => value.as_ref(),
+                AST::Return { term } => term.as_ref(),
+                _ => &statement,
+            };
+            let AST::Call { callee, args } = call else {
+                inlined.push(statement);
+                continue;
+            };
+            let Some(Candidate::Statements {
+                parameters,
+                statements: body,
+                term,
+            }) = self.candidates.get(callee)
+            else {
+                inlined.push(statement);
+                continue;
+            };
+            if parameters.len() != args.len() {
+                inlined.push(statement);
+                continue;
+            }
+            let (parameters, body, term) = (parameters.clone(), body.clone(), term.clone());
+            let callee = callee.clone();
+            self.inlined.insert(callee.clone());
+            let args = args.clone();
+
+            let mut declared = parameters.clone();
+            for statement in &body {
+                for node in all_nodes(statement) {
+                    if let AST::Var { name, .. } = node {
+                        declared.push(name.clone());
+                    }
+                }
+            }
+            let mut renames = HashMap::new();
+            for name in declared {
+                if let Entry::Vacant(entry) = renames.entry(name) {
+                    let fresh = self.fresh_name(&callee, entry.key());
+                    entry.insert(AST::Id(fresh));
+                }
+            }
+            let mut substitute = Substitute(renames);
+            for (parameter, value) in parameters.iter().zip(args) {
+                let AST::Id(name) = &substitute.0[parameter] else {
+                    unreachable!()
+                };
+                inlined.push(AST::Var {
+                    name: name.clone(),
+                    value: value.into(),
+                });
+            }
+            for mut statement in body {
+                statement.visit_mut(&mut substitute);
+                inlined.push(statement);
+            }
+            let mut term = term;
+            term.visit_mut(&mut substitute);
+            inlined.push(match statement {
+                AST::Var { name, .. } => AST::Var {
+                    name,
+                    value: term.into(),
+                },
+                AST::Assign { name, .. } => AST::Assign {
+                    name,
+                    value: term.into(),
+                },
+                AST::Return { .. } => AST::Return { term: term.into() },
+                _ => term,
+            });
+        }
+        inlined
+    }
+}
+
+impl Fold for Inliner {
+    fn fold_function(&mut self, node: AST) -> AST {
+        let AST::Function {
+            name,
+            parameters,
+            body,
+        } = node
+        else {
+            panic!("Expected Function node, got: {:?}", node)
+        };
+        let body = match *body {
+            AST::Block(statements) => AST::Block(self.inline_statements(statements)),
+            body => body,
+        };
+        let node = AST::Function {
+            name,
+            parameters,
+            body: body.into(),
+        };
+        fold::fold_function(self, node)
+    }
+
+    fn fold_main(&mut self, node: AST) -> AST {
+        let AST::Main(statements) = node else {
+            panic!("Expected Main node, got: {:?}", node)
+        };
+        let node = AST::Main(self.inline_statements(statements));
+        fold::fold_main(self, node)
+    }
+
+    fn fold_call(&mut self, node: AST) -> AST {
+        let node = fold::fold_call(self, node);
+        let AST::Call { callee, args } = &node else {
+            return node;
+        };
+        let Some(Candidate::Expression { parameters, term }) = self.candidates.get(callee) else {
+            return node;
+        };
+        if parameters.len() != args.len() || !args.iter().all(is_pure) {
+            return node;
+        }
+        let uses = all_nodes(term);
+        let repeated = parameters.iter().zip(args).any(|(parameter, arg)| {
+            let count = uses
+                .iter()
+                .filter(|node| matches!(node, AST::Id(name) if name == parameter))
+                .count();
+            count > 1 && !is_trivial(arg)
+        });
+        if repeated {
+            return node;
+        }
+        let mut substitute = Substitute(
+            parameters
+                .iter()
+                .cloned()
+                .zip(args.iter().cloned())
+                .collect(),
+        );
+        let mut term = term.clone();
+        term.visit_mut(&mut substitute);
+        self.inlined.insert(callee.clone());
+        term
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::check_against_interpreter;
+    use crate::interpreter::Interpreter;
+    use crate::parser::parse;
+    use crate::pass_manager::PassManager;
+
+    fn inline(source: &str, threshold: usize) -> AST {
+        let mut passes = PassManager::new();
+        passes.add(Inlining { threshold });
+        passes
+            .run(parse(source).expect("Parser failed"), &mut vec![])
+            .expect("Inlining failed")
+            .ast
+    }
+
+    fn assert_inlines(expected: &str, source: &str) {
+        let ast = inline(source, DEFAULT_INLINE_THRESHOLD);
+        assert_eq!(parse(expected).expect("Parser failed"), ast, "{}", ast);
+    }
+
+    #[test]
+    fn expressions() {
+        assert_inlines(
+            "function main() { var a = 3; print(a * 2 + (a + 1) * 2); }",
+            "function helper(x) { return x * 2; }
+             function main() { var a = 3; print(helper(a) + helper(a + 1)); }",
+        );
+        // Arguments aren't evaluated twice, nor dropped if they have effects.
+        let source = "function twice(x) { return x + x; }
+             function zero(x) { return 0; }
+             function main() { print(twice(a + 1) + zero(f())); }";
+        assert_inlines(source, source);
+        assert_inlines(
+            "function main() { return 2 + 2; }",
+            "function twice(x) { return x + x; } function main() { return twice(2); }",
+        );
+    }
+
+    #[test]
+    fn statements() {
+        assert_inlines(
+            "function main() {
+                 var z = 1;
+                 var f_x_1 = 2;
+                 var f_x_2 = z;
+                 var f_y_1 = g(f_x_1);
+                 var f_z_1 = f_x_2 + f_y_1;
+                 f_z_1 = f_z_1 * f_x_2;
+                 var a = f_z_1;
+                 var f_x_3 = a;
+                 var f_y_2 = 1;
+                 var f_z_2 = f_x_3 + f_y_2;
+                 f_z_2 = f_z_2 * f_x_3;
+                 return f_z_2;
+             }",
+            "function f(x, y) { var z = x + y; z = z * x; return z; }
+             function main() {
+                 var z = 1;
+                 var f_x_1 = 2;
+                 var a = f(z, g(f_x_1));
+                 return f(a, 1);
+             }",
+        );
+        // Only the statements of the function body are expanded.
+        let source = "function f(x) { print(x); return x; }
+             function main() { if (a) { f(1); } else {} return 1 + f(2); }";
+        assert_inlines(source, source);
+    }
+
+    #[test]
+    fn not_inlined() {
+        for source in [
+            // Recursive, directly or not.
+            "function f(x) { return f(x); } function main() { return f(1); }",
+            "function f(x) { return g(x); } function g(x) { return f(x); }
+             function main() { return f(1); }",
+            // Returns early.
+            "function f(x) { if (x) { return 1; } else {} return 2; }
+             function main() { return f(1); }",
+            // Uses a variable it doesn't declare.
+            "function f(x) { return x + y; } function main() { var y = 1; return f(1); }",
+            // Called with the wrong number of arguments.
+            "function f(x) { return x; } function main() { return f(1, 2); }",
+            "function main() { return 1; } function f() { return main(); }",
+        ] {
+            assert_inlines(source, source);
+        }
+        let source = "function f(x) { return x * x * x * x; } function main() { return f(2); }";
+        assert_inlines("function main() { return 2 * 2 * 2 * 2; }", source);
+        assert_eq!(parse(source).expect("Parser failed"), inline(source, 7));
+        assert_eq!(parse(source).expect("Parser failed"), inline(source, 0));
+    }
+
+    #[test]
+    fn removes_functions_inlined_everywhere() {
+        // Functions never called are left to `DeadCodeElimination`.
+        assert_inlines(
+            "function g(x) { return x; }
+             function main() { var f_x_1 = 1; print(f_x_1); var a = f_x_1; return a + 1 + 1; }",
+            "function f(x) { print(x); return x; } function g(x) { return x; }
+             function main() { var a = f(1); return h(a + 1); }
+             function h(x) { return x + 1; }",
+        );
+        // Still called where it couldn't be inlined.
+        let source = "function f(x) { print(x); return x; }
+             function main() { f(1); return 1 + f(2); }";
+        assert_inlines(
+            "function f(x) { print(x); return x; }
+             function main() { var f_x_1 = 1; print(f_x_1); f_x_1; return 1 + f(2); }",
+            source,
+        );
+    }
+
+    #[test]
+    fn agrees_with_interpreter() {
+        check_against_interpreter(0..300, |ast, out| {
+            let inlined = inline(&ast.to_string(), 50);
+            Interpreter::with_max_steps(100_000)
+                .execute(&inlined, out)
+                .expect("Program failed after inlining")
+        });
+    }
+}
@@ -0,0 +1,572 @@
+use crate::ast::AST;
+use crate::error::CompileError;
+use crate::visitor::{AstVisitor, Visitor};
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Array(Rc<Vec<Value>>),
+}
+
+/// The value of statements, `undefined` and `null`, which are all 0 in
+/// generated code.
+impl Default for Value {
+    fn default() -> Value {
+        Value::Int(0)
+    }
+}
+
+impl Value {
+    pub(crate) fn as_int(&self) -> Result<i32, CompileError> {
+        match self {
+            Value::Int(value) => Ok(*value),
+            Value::Array(_) => Err(runtime_error("expected an integer, got an array")),
+        }
+    }
+}
+
+const MAX_CALL_DEPTH: usize = 1000;
+
+/// Reference evaluator for the language.
+///
+/// It mirrors the semantics of the code emitted by `ArmCodeGenerator` rather
+/// than those of JavaScript: values are 32-bit words, arithmetic wraps,
+/// division is unsigned (`udiv`, dividing by zero yields 0), comparisons are
+/// signed, `assert` prints `T` only when its argument is exactly 1 and
+/// out-of-bounds array reads yield 0.
+pub struct Interpreter {
+    functions: HashMap<String, Rc<AST>>,
+    frames: Vec<HashMap<String, Value>>,
+    returning: Option<Value>,
+    steps: usize,
+    max_steps: usize,
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter {
+            functions: Default::default(),
+            frames: vec![Default::default()],
+            returning: None,
+            steps: 0,
+            max_steps: 10_000_000,
+        }
+    }
+}
+
+pub(crate) fn runtime_error(message: impl Into<String>) -> CompileError {
+    CompileError::RuntimeError(message.into(), None)
+}
+
+/// Runs `main` (or the statements of an `AST::Main`) writing everything the
+/// program prints to `out`, and returns the value `main` returned.
+pub fn run(ast: &AST, out: &mut dyn Write) -> Result<i32, CompileError> {
+    let mut interpreter: Interpreter = Default::default();
+    interpreter.execute(ast, out)
+}
+
+impl Interpreter {
+    pub fn with_max_steps(max_steps: usize) -> Interpreter {
+        Interpreter {
+            max_steps,
+            ..Default::default()
+        }
+    }
+
+    pub fn execute<W: Write + ?Sized>(
+        &mut self,
+        ast: &AST,
+        out: &mut W,
+    ) -> Result<i32, CompileError> {
+        if let AST::Main(_) = ast {
+            return ast.visit(self, out)?.as_int();
+        }
+        let statements = match ast {
+            AST::Block(statements) => statements.as_slice(),
+            other => std::slice::from_ref(other),
+        };
+        for statement in statements {
+            let AST::Function { name, .. } = statement else {
+                return Err(runtime_error(format!(
+                    "only functions are allowed at the top level, got: {}",
+                    statement
+                )));
+            };
+            self.functions
+                .insert(name.clone(), Rc::new(statement.clone()));
+        }
+        self.call("main", vec![], out)?.as_int()
+    }
+
+    fn call<W: Write + ?Sized>(
+        &mut self,
+        callee: &str,
+        args: Vec<Value>,
+        out: &mut W,
+    ) -> Result<Value, CompileError> {
+        if callee == "putchar" && args.len() == 1 {
+            out.write_all(&[args[0].as_int()? as u8])?;
+            return Ok(Value::Int(args[0].as_int()? & 0xff));
+        }
+        let function = self
+            .functions
+            .get(callee)
+            .cloned()
+            .ok_or_else(|| runtime_error(format!("Undefined function: {}", callee)))?;
+        let AST::Function {
+            parameters, body, ..
+        } = function.as_ref()
+        else {
+            unreachable!()
+        };
+        if parameters.len() != args.len() {
+            return Err(runtime_error(format!(
+                "{} expects {} arguments, got {}",
+                callee,
+                parameters.len(),
+                args.len()
+            )));
+        }
+        if self.frames.len() > MAX_CALL_DEPTH {
+            return Err(runtime_error("Maximum call depth exceeded"));
+        }
+        self.frames
+            .push(parameters.iter().cloned().zip(args).collect());
+        let result = body.visit(self, out);
+        self.frames.pop();
+        result?;
+        // Functions without an explicit return yield 0, like the epilogue
+        // emitted by the code generator.
+        Ok(self.returning.take().unwrap_or(Value::Int(0)))
+    }
+
+    fn tick(&mut self) -> Result<(), CompileError> {
+        self.steps += 1;
+        if self.steps > self.max_steps {
+            return Err(runtime_error("Step limit exceeded"));
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self) -> &mut HashMap<String, Value> {
+        self.frames.last_mut().expect("No stack frame")
+    }
+
+    fn int<W: Write + ?Sized>(&mut self, node: &AST, out: &mut W) -> Result<i32, CompileError> {
+        node.visit(self, out)?.as_int()
+    }
+
+    fn binary<W: Write + ?Sized>(
+        &mut self,
+        left: &AST,
+        right: &AST,
+        out: &mut W,
+        op: fn(i32, i32) -> i32,
+    ) -> Result<Value, CompileError> {
+        let left = self.int(left, out)?;
+        let right = self.int(right, out)?;
+        Ok(Value::Int(op(left, right)))
+    }
+}
+
+impl<W: Write + ?Sized> Visitor<Value, W> for Interpreter {
+    fn visit_assert(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Assert(condition) = node else {
+            panic!("Expected Assert node, got: {:?}", node)
+        };
+        let passed = condition.visit(self, out)? == Value::Int(1);
+        out.write_all(if passed { b"T" } else { b"F" })?;
+        Ok(Value::Int(0))
+    }
+
+    fn visit_print(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Print(value) = node else {
+            panic!("Expected Print node, got: {:?}", node)
+        };
+        let value = self.int(value, out)?;
+        writeln!(out, "{}", value)?;
+        Ok(Value::Int(0))
+    }
+
+    fn visit_array_length(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::ArrayLength(array) = node else {
+            panic!("Expected ArrayLength node, got: {:?}", node)
+        };
+        match array.visit(self, out)? {
+            Value::Array(items) => Ok(Value::Int(items.len() as i32)),
+            Value::Int(_) => Err(runtime_error("length() expects an array")),
+        }
+    }
+
+    fn visit_array_lookup(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::ArrayLookup { array, index } = node else {
+            panic!("Expected ArrayLookup node, got: {:?}", node)
+        };
+        let Value::Array(items) = array.visit(self, out)? else {
+            return Err(runtime_error("Indexing a value that is not an array"));
+        };
+        // The generated code compares the index unsigned against the length
+        // and yields 0 when it is out of bounds.
+        let index = self.int(index, out)? as u32 as usize;
+        Ok(items.get(index).cloned().unwrap_or(Value::Int(0)))
+    }
+
+    fn visit_array_literal(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::ArrayLiteral(array_items) = node else {
+            panic!("Expected ArrayLiteral node, got: {:?}", node)
+        };
+        let mut items = Vec::with_capacity(array_items.len());
+        for item in array_items {
+            items.push(item.visit(self, out)?);
+        }
+        Ok(Value::Array(Rc::new(items)))
+    }
+
+    fn visit_boolean(&mut self, node: &AST, _out: &mut W) -> Result<Value, CompileError> {
+        let AST::Boolean(value) = node else {
+            panic!("Expected Boolean node, got: {:?}", node)
+        };
+        Ok(Value::Int(*value as i32))
+    }
+
+    fn visit_number(&mut self, node: &AST, _out: &mut W) -> Result<Value, CompileError> {
+        let AST::Number(number) = node else {
+            panic!("Expected Number node, got: {:?}", node)
+        };
+        // `ldr r0, =N` keeps the low 32 bits.
+        Ok(Value::Int(*number as u32 as i32))
+    }
+
+    fn visit_id(&mut self, node: &AST, _out: &mut W) -> Result<Value, CompileError> {
+        let AST::Id(name) = node else {
+            panic!("Expected Id node, got: {:?}", node)
+        };
+        self.frame()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| runtime_error(format!("Undefined variable: {}", name)))
+    }
+
+    fn visit_not(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Not(term) = node else {
+            panic!("Expected Not node, got: {:?}", node)
+        };
+        Ok(Value::Int((self.int(term, out)? == 0) as i32))
+    }
+
+    fn visit_equal(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Equal { left, right } = node else {
+            panic!("Expected Equal node, got: {:?}", node)
+        };
+        self.binary(left, right, out, |l, r| (l == r) as i32)
+    }
+
+    fn visit_not_equal(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::NotEqual { left, right } = node else {
+            panic!("Expected NotEqual node, got: {:?}", node)
+        };
+        self.binary(left, right, out, |l, r| (l != r) as i32)
+    }
+
+    fn visit_add(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Add { left, right } = node else {
+            panic!("Expected Add node, got: {:?}", node)
+        };
+        self.binary(left, right, out, i32::wrapping_add)
+    }
+
+    fn visit_subtract(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Subtract { left, right } = node else {
+            panic!("Expected Subtract node, got: {:?}", node)
+        };
+        self.binary(left, right, out, i32::wrapping_sub)
+    }
+
+    fn visit_multiply(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Multiply { left, right } = node else {
+            panic!("Expected Multiply node, got: {:?}", node)
+        };
+        self.binary(left, right, out, i32::wrapping_mul)
+    }
+
+    fn visit_divide(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Divide { left, right } = node else {
+            panic!("Expected Divide node, got: {:?}", node)
+        };
+        self.binary(left, right, out, |l, r| {
+            (l as u32).checked_div(r as u32).unwrap_or(0) as i32
+        })
+    }
+
+    fn visit_less_than(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::LessThan { left, right } = node else {
+            panic!("Expected LessThan node, got: {:?}", node)
+        };
+        self.binary(left, right, out, |l, r| (l < r) as i32)
+    }
+
+    fn visit_greater_than(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::GreaterThan { left, right } = node else {
+            panic!("Expected GreaterThan node, got: {:?}", node)
+        };
+        self.binary(left, right, out, |l, r| (l > r) as i32)
+    }
+
+    fn visit_less_than_equal(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::LessThanEqual { left, right } = node else {
+            panic!("Expected LessThanEqual node, got: {:?}", node)
+        };
+        self.binary(left, right, out, |l, r| (l <= r) as i32)
+    }
+
+    fn visit_greater_than_equal(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::GreaterThanEqual { left, right } = node else {
+            panic!("Expected GreaterThanEqual node, got: {:?}", node)
+        };
+        self.binary(left, right, out, |l, r| (l >= r) as i32)
+    }
+
+    fn visit_call(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Call { args, callee } = node else {
+            panic!("Expected Call node, got: {:?}", node)
+        };
+        if args.len() > 4 {
+            return Err(runtime_error(
+                "More than 4 arguments are not supported in function calls",
+            ));
+        }
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(arg.visit(self, out)?);
+        }
+        self.call(callee, values, out)
+    }
+
+    fn visit_return(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Return { term } = node else {
+            panic!("Expected Return node, got: {:?}", node)
+        };
+        let value = term.visit(self, out)?;
+        self.returning = Some(value);
+        Ok(Value::Int(0))
+    }
+
+    fn visit_block(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Block(statements) = node else {
+            panic!("Expected Block node, got: {:?}", node)
+        };
+        for statement in statements {
+            self.tick()?;
+            statement.visit(self, out)?;
+            if self.returning.is_some() {
+                break;
+            }
+        }
+        Ok(Value::Int(0))
+    }
+
+    fn visit_if(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::IfNode {
+            conditional,
+            consequence,
+            alternative,
+        } = node
+        else {
+            panic!("Expected IfNode node, got: {:?}", node)
+        };
+        if self.int(conditional, out)? != 0 {
+            consequence.visit(self, out)
+        } else {
+            alternative.visit(self, out)
+        }
+    }
+
+    fn visit_function(&mut self, node: &AST, _out: &mut W) -> Result<Value, CompileError> {
+        Err(runtime_error(format!(
+            "Nested function definitions are not supported: {}",
+            node
+        )))
+    }
+
+    fn visit_var(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Var { name, value } = node else {
+            panic!("Expected Var node, got: {:?}", node)
+        };
+        let value = value.visit(self, out)?;
+        self.frame().insert(name.clone(), value);
+        Ok(Value::Int(0))
+    }
+
+    fn visit_assign(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Assign { name, value } = node else {
+            panic!("Expected Assign node, got: {:?}", node)
+        };
+        let value = value.visit(self, out)?;
+        let slot = self
+            .frame()
+            .get_mut(name)
+            .ok_or_else(|| runtime_error(format!("Undefined variable: {}", name)))?;
+        *slot = value;
+        Ok(Value::Int(0))
+    }
+
+    fn visit_while(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::While { conditional, body } = node else {
+            panic!("Expected While node, got: {:?}", node)
+        };
+        while self.int(conditional, out)? != 0 {
+            self.tick()?;
+            body.visit(self, out)?;
+            if self.returning.is_some() {
+                break;
+            }
+        }
+        Ok(Value::Int(0))
+    }
+
+    fn visit_undefined(&mut self, _node: &AST, _out: &mut W) -> Result<Value, CompileError> {
+        Ok(Value::Int(0))
+    }
+
+    fn visit_null(&mut self, _node: &AST, _out: &mut W) -> Result<Value, CompileError> {
+        Ok(Value::Int(0))
+    }
+
+    fn visit_main(&mut self, node: &AST, out: &mut W) -> Result<Value, CompileError> {
+        let AST::Main(statements) = node else {
+            panic!("Expected Main, got: {:?}", node)
+        };
+        for statement in statements {
+            statement.visit(self, out)?;
+            if self.returning.is_some() {
+                break;
+            }
+        }
+        Ok(self.returning.take().unwrap_or(Value::Int(0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn run_source(code: &str) -> Result<String, CompileError> {
+        let ast = parse(code)?;
+        let mut out = Vec::new();
+        run(&ast, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn assert_and_print() {
+        let output = run_source(
+            r#"function main() {
+                print(42);
+                assert(1);
+                assert(2);
+            }"#,
+        )
+        .expect("Run failed");
+        assert_eq!("42\nTF", output);
+    }
+
+    #[test]
+    fn infix() {
+        let output = run_source(
+            r#"function main() {
+                assert(42 == 4 + 2 * (12 - 2) + 3 * (5 + 1));
+                assert(6 == 4 + (3-1) );
+                assert( ((2 + 3) * 4 )/ 2 + 1 == 11);
+            }"#,
+        )
+        .expect("Run failed");
+        assert_eq!("TTT", output);
+    }
+
+    #[test]
+    fn factorial() {
+        let output = run_source(
+            r#"function factorial(n) {
+                if (n == 0) {
+                    return 1;
+                } else {
+                    return n * factorial(n - 1);
+                }
+            }
+
+            function main() {
+                print(factorial(6));
+                assert(720 == factorial(6));
+            }"#,
+        )
+        .expect("Run failed");
+        assert_eq!("720\nT", output);
+    }
+
+    #[test]
+    fn arrays() {
+        let output = run_source(
+            r#"function main() {
+                var arr = [1, 2, 3, 4, 5];
+                var sum = 0;
+                var i = 0;
+                while (i < length(arr)) {
+                    sum = sum + arr[i];
+                    i = i + 1;
+                }
+                print(sum);
+                print(arr[5]);
+            }"#,
+        )
+        .expect("Run failed");
+        assert_eq!("15\n0\n", output);
+    }
+
+    #[test]
+    fn machine_semantics() {
+        let output = run_source(
+            r#"function main() {
+                print(0 - 1);
+                print((0 - 1) / 2);
+                print(7 / 0);
+                print(4294967297);
+                assert(0 - 1 < 0);
+            }"#,
+        )
+        .expect("Run failed");
+        assert_eq!("-1\n2147483647\n0\n1\nT", output);
+    }
+
+    #[test]
+    fn missing_return_yields_zero() {
+        let output = run_source(
+            r#"function nothing() {
+                var x = 1;
+            }
+            function main() {
+                print(nothing());
+            }"#,
+        )
+        .expect("Run failed");
+        assert_eq!("0\n", output);
+    }
+
+    #[test]
+    fn undefined_variable() {
+        assert!(matches!(
+            run_source("function main() { print(x); }"),
+            Err(CompileError::RuntimeError(_, None))
+        ));
+    }
+
+    #[test]
+    fn infinite_loop_hits_step_limit() {
+        let ast = parse("function main() { while (1) { } }").unwrap();
+        let mut interpreter = Interpreter::with_max_steps(1000);
+        assert!(interpreter.execute(&ast, &mut Vec::new()).is_err());
+    }
+}
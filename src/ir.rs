@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+use std::fmt;
+
+/// A virtual register, holding values of the `Type` its function gives it.
+/// A function has as many as it needs; the emitter decides where each one
+/// lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Reg(pub usize);
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+/// The type of the values a register holds.
+///
+/// The language has integers, which booleans, `null` and `undefined` are
+/// too, and arrays. It is dynamically typed, so the type of some values, like
+/// parameters and the results of calls, isn't known: they are `Word`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Type {
+    /// A 32-bit integer.
+    Int,
+    /// A pointer to an array.
+    Array,
+    /// An integer or an array.
+    Word,
+}
+
+impl Type {
+    pub fn name(self) -> &'static str {
+        match self {
+            Type::Int => "int",
+            Type::Array => "array",
+            Type::Word => "word",
+        }
+    }
+
+    /// The type of a register holding values of both types.
+    pub fn join(self, other: Type) -> Type {
+        if self == other {
+            self
+        } else {
+            Type::Word
+        }
+    }
+
+    /// Whether a register of this type can hold the values of `other`.
+    pub fn contains(self, other: Type) -> bool {
+        self == other || self == Type::Word
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Identifies a basic block within its function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    /// Unsigned division, where dividing by zero yields 0.
+    Div,
+    Eq,
+    Ne,
+    /// The comparisons are signed and yield 0 or 1.
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl BinaryOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Eq => "eq",
+            BinaryOp::Ne => "ne",
+            BinaryOp::Lt => "lt",
+            BinaryOp::Gt => "gt",
+            BinaryOp::Le => "le",
+            BinaryOp::Ge => "ge",
+        }
+    }
+
+    /// Computes the operation the way the generated code does.
+    pub fn apply(self, left: i32, right: i32) -> i32 {
+        match self {
+            BinaryOp::Add => left.wrapping_add(right),
+            BinaryOp::Sub => left.wrapping_sub(right),
+            BinaryOp::Mul => left.wrapping_mul(right),
+            BinaryOp::Div => (left as u32).checked_div(right as u32).unwrap_or(0) as i32,
+            BinaryOp::Eq => (left == right) as i32,
+            BinaryOp::Ne => (left != right) as i32,
+            BinaryOp::Lt => (left < right) as i32,
+            BinaryOp::Gt => (left > right) as i32,
+            BinaryOp::Le => (left <= right) as i32,
+            BinaryOp::Ge => (left >= right) as i32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    Const {
+        dest: Reg,
+        value: i32,
+    },
+    Copy {
+        dest: Reg,
+        src: Reg,
+    },
+    Binary {
+        op: BinaryOp,
+        dest: Reg,
+        left: Reg,
+        right: Reg,
+    },
+    /// 1 if `src` is 0, and 0 otherwise.
+    Not {
+        dest: Reg,
+        src: Reg,
+    },
+    Call {
+        dest: Reg,
+        callee: String,
+        args: Vec<Reg>,
+    },
+    Print {
+        src: Reg,
+    },
+    /// Prints `T` if `src` is 1, and `F` otherwise.
+    Assert {
+        src: Reg,
+    },
+    NewArray {
+        dest: Reg,
+        items: Vec<Reg>,
+    },
+    Length {
+        dest: Reg,
+        array: Reg,
+    },
+    /// Reads an item of an array, or 0 if `index` is out of bounds.
+    Load {
+        dest: Reg,
+        array: Reg,
+        index: Reg,
+    },
+    /// Only in SSA form, at the start of a block: the value of the source for
+    /// the predecessor control came from.
+    Phi {
+        dest: Reg,
+        sources: Vec<(BlockId, Reg)>,
+    },
+}
+
+impl Instruction {
+    /// The register the instruction writes, if any.
+    pub fn dest(&self) -> Option<Reg> {
+        match self {
+            Instruction::Const { dest, .. }
+            | Instruction::Copy { dest, .. }
+            | Instruction::Binary { dest, .. }
+            | Instruction::Not { dest, .. }
+            | Instruction::Call { dest, .. }
+            | Instruction::NewArray { dest, .. }
+            | Instruction::Length { dest, .. }
+            | Instruction::Load { dest, .. }
+            | Instruction::Phi { dest, .. } => Some(*dest),
+            Instruction::Print { .. } | Instruction::Assert { .. } => None,
+        }
+    }
+
+    /// The registers the instruction reads, in order.
+    pub fn uses(&self) -> Vec<Reg> {
+        match self {
+            Instruction::Const { .. } => vec![],
+            Instruction::Copy { src, .. }
+            | Instruction::Not { src, .. }
+            | Instruction::Print { src }
+            | Instruction::Assert { src } => vec![*src],
+            Instruction::Binary { left, right, .. } => vec![*left, *right],
+            Instruction::Call { args, .. } => args.clone(),
+            Instruction::NewArray { items, .. } => items.clone(),
+            Instruction::Length { array, .. } => vec![*array],
+            Instruction::Load { array, index, .. } => vec![*array, *index],
+            Instruction::Phi { sources, .. } => sources.iter().map(|(_, reg)| *reg).collect(),
+        }
+    }
+
+    /// The registers the instruction reads, to be replaced.
+    pub fn uses_mut(&mut self) -> Vec<&mut Reg> {
+        match self {
+            Instruction::Const { .. } => vec![],
+            Instruction::Copy { src, .. }
+            | Instruction::Not { src, .. }
+            | Instruction::Print { src }
+            | Instruction::Assert { src } => vec![src],
+            Instruction::Binary { left, right, .. } => vec![left, right],
+            Instruction::Call { args, .. } => args.iter_mut().collect(),
+            Instruction::NewArray { items, .. } => items.iter_mut().collect(),
+            Instruction::Length { array, .. } => vec![array],
+            Instruction::Load { array, index, .. } => vec![array, index],
+            Instruction::Phi { sources, .. } => sources.iter_mut().map(|(_, reg)| reg).collect(),
+        }
+    }
+
+    pub fn dest_mut(&mut self) -> Option<&mut Reg> {
+        match self {
+            Instruction::Const { dest, .. }
+            | Instruction::Copy { dest, .. }
+            | Instruction::Binary { dest, .. }
+            | Instruction::Not { dest, .. }
+            | Instruction::Call { dest, .. }
+            | Instruction::NewArray { dest, .. }
+            | Instruction::Length { dest, .. }
+            | Instruction::Load { dest, .. }
+            | Instruction::Phi { dest, .. } => Some(dest),
+            Instruction::Print { .. } | Instruction::Assert { .. } => None,
+        }
+    }
+
+    /// The type of the value the instruction writes, given the types of the
+    /// registers it reads, or None if it depends on a register of unknown
+    /// type.
+    fn result_type(&self, type_of: impl Fn(Reg) -> Option<Type>) -> Option<Type> {
+        match self {
+            Instruction::Const { .. }
+            | Instruction::Binary { .. }
+            | Instruction::Not { .. }
+            | Instruction::Length { .. } => Some(Type::Int),
+            Instruction::NewArray { .. } => Some(Type::Array),
+            Instruction::Call { .. } | Instruction::Load { .. } => Some(Type::Word),
+            Instruction::Copy { src, .. } => type_of(*src),
+            Instruction::Phi { sources, .. } => sources
+                .iter()
+                .filter_map(|(_, reg)| type_of(*reg))
+                .reduce(Type::join),
+            Instruction::Print { .. } | Instruction::Assert { .. } => None,
+        }
+    }
+
+    /// Whether the instruction does anything besides writing `dest`.
+    pub fn has_side_effects(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Call { .. } | Instruction::Print { .. } | Instruction::Assert { .. }
+        )
+    }
+}
+
+fn registers(regs: &[Reg]) -> String {
+    regs.iter()
+        .map(Reg::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Instruction {
+    /// Formats what the instruction does, without the register it writes.
+    fn fmt_operation(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Const { value, .. } => write!(f, "const {}", value),
+            Instruction::Copy { src, .. } => write!(f, "copy {}", src),
+            Instruction::Binary {
+                op, left, right, ..
+            } => write!(f, "{} {}, {}", op.name(), left, right),
+            Instruction::Not { src, .. } => write!(f, "not {}", src),
+            Instruction::Call { callee, args, .. } => {
+                write!(f, "call {}({})", callee, registers(args))
+            }
+            Instruction::Print { src } => write!(f, "print {}", src),
+            Instruction::Assert { src } => write!(f, "assert {}", src),
+            Instruction::NewArray { items, .. } => write!(f, "array [{}]", registers(items)),
+            Instruction::Length { array, .. } => write!(f, "length {}", array),
+            Instruction::Load { array, index, .. } => write!(f, "load {}[{}]", array, index),
+            Instruction::Phi { sources, .. } => {
+                let sources: Vec<String> = sources
+                    .iter()
+                    .map(|(block, reg)| format!("{}: {}", block, reg))
+                    .collect();
+                write!(f, "phi [{}]", sources.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(dest) = self.dest() {
+            write!(f, "{} = ", dest)?;
+        }
+        self.fmt_operation(f)
+    }
+}
+
+/// How a basic block ends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Goes to `then` if `condition` isn't 0, and to `otherwise` if it is.
+    Branch {
+        condition: Reg,
+        then: BlockId,
+        otherwise: BlockId,
+    },
+    Return(Reg),
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch {
+                then, otherwise, ..
+            } => vec![*then, *otherwise],
+            Terminator::Return(_) => vec![],
+        }
+    }
+
+    pub fn uses(&self) -> Vec<Reg> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { condition, .. } => vec![*condition],
+            Terminator::Return(value) => vec![*value],
+        }
+    }
+
+    pub fn uses_mut(&mut self) -> Vec<&mut Reg> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { condition, .. } => vec![condition],
+            Terminator::Return(value) => vec![value],
+        }
+    }
+
+    /// Replaces the jumps to `from` with jumps to `to`.
+    pub fn retarget(&mut self, from: BlockId, to: BlockId) {
+        match self {
+            Terminator::Jump(target) if *target == from => *target = to,
+            Terminator::Branch {
+                then, otherwise, ..
+            } => {
+                if *then == from {
+                    *then = to;
+                }
+                if *otherwise == from {
+                    *otherwise = to;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Terminator::Jump(target) => write!(f, "jump {}", target),
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            } => write!(f, "branch {}, {}, {}", condition, then, otherwise),
+            Terminator::Return(value) => write!(f, "return {}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub id: BlockId,
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+/// A function as a list of basic blocks, the first of which is the entry.
+/// The blocks are in the order they are emitted in, so a jump to the next one
+/// costs nothing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    /// The parameters, which are `Word`s.
+    pub parameters: Vec<Reg>,
+    pub blocks: Vec<Block>,
+    /// The type of every register, which are `%0` to `%{types.len() - 1}`.
+    pub types: Vec<Type>,
+}
+
+impl Function {
+    pub fn block(&self, id: BlockId) -> &Block {
+        self.blocks
+            .iter()
+            .find(|block| block.id == id)
+            .expect("No such block")
+    }
+
+    pub fn block_mut(&mut self, id: BlockId) -> &mut Block {
+        self.blocks
+            .iter_mut()
+            .find(|block| block.id == id)
+            .expect("No such block")
+    }
+
+    pub fn new_reg(&mut self, ty: Type) -> Reg {
+        self.types.push(ty);
+        Reg(self.types.len() - 1)
+    }
+
+    pub fn type_of(&self, reg: Reg) -> Type {
+        self.types[reg.0]
+    }
+
+    /// Gives every register the type of the values written to it: the join
+    /// of the types of its instructions' results, and `Word` for the
+    /// parameters and the registers never written.
+    pub fn infer_types(&mut self) {
+        let mut types: Vec<Option<Type>> = vec![None; self.types.len()];
+        for parameter in &self.parameters {
+            types[parameter.0] = Some(Type::Word);
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for instruction in self.blocks.iter().flat_map(|block| &block.instructions) {
+                let Some(dest) = instruction.dest() else {
+                    continue;
+                };
+                let Some(result) = instruction.result_type(|reg| types[reg.0]) else {
+                    continue;
+                };
+                let joined = types[dest.0].map_or(result, |ty| ty.join(result));
+                if types[dest.0] != Some(joined) {
+                    types[dest.0] = Some(joined);
+                    changed = true;
+                }
+            }
+        }
+        self.types = types
+            .into_iter()
+            .map(|ty| ty.unwrap_or(Type::Word))
+            .collect();
+    }
+
+    /// Checks that every register can hold what is written to it, for the
+    /// passes to keep the types right.
+    pub fn check_types(&self) -> Result<(), String> {
+        for parameter in &self.parameters {
+            if self.type_of(*parameter) != Type::Word {
+                return Err(format!("Parameter {} isn't a word", parameter));
+            }
+        }
+        for instruction in self.blocks.iter().flat_map(|block| &block.instructions) {
+            let (Some(dest), Some(result)) = (
+                instruction.dest(),
+                instruction.result_type(|reg| Some(self.type_of(reg))),
+            ) else {
+                continue;
+            };
+            if !self.type_of(dest).contains(result) {
+                return Err(format!(
+                    "{} is {}, but `{}` writes {}",
+                    dest,
+                    self.type_of(dest),
+                    instruction,
+                    result
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// An id no block has yet.
+    pub fn new_block_id(&self) -> BlockId {
+        BlockId(
+            self.blocks
+                .iter()
+                .map(|block| block.id.0 + 1)
+                .max()
+                .unwrap_or(0),
+        )
+    }
+
+    /// The blocks jumping to each block, in the order of `blocks`.
+    pub fn predecessors(&self) -> HashMap<BlockId, Vec<BlockId>> {
+        let mut predecessors: HashMap<BlockId, Vec<BlockId>> =
+            self.blocks.iter().map(|block| (block.id, vec![])).collect();
+        for block in &self.blocks {
+            for successor in block.terminator.successors() {
+                let list = predecessors.entry(successor).or_default();
+                if !list.contains(&block.id) {
+                    list.push(block.id);
+                }
+            }
+        }
+        predecessors
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parameters: Vec<String> = self
+            .parameters
+            .iter()
+            .map(|reg| format!("{}: {}", reg, self.type_of(*reg)))
+            .collect();
+        writeln!(f, "function {}({}) {{", self.name, parameters.join(", "))?;
+        for block in &self.blocks {
+            writeln!(f, "{}:", block.id)?;
+            for instruction in &block.instructions {
+                write!(f, "    ")?;
+                if let Some(dest) = instruction.dest() {
+                    write!(f, "{}: {} = ", dest, self.type_of(dest))?;
+                }
+                instruction.fmt_operation(f)?;
+                writeln!(f)?;
+            }
+            writeln!(f, "    {}", block.terminator)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Program {
+    pub functions: Vec<Function>,
+}
+
+/// The textual dump, one function after the other, separated by blank lines.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", function)?;
+        }
+        Ok(())
+    }
+}
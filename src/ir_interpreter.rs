@@ -0,0 +1,197 @@
+use crate::error::CompileError;
+use crate::interpreter::{runtime_error, Value};
+use crate::ir::{Function, Instruction, Program, Terminator, Type};
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+const MAX_CALL_DEPTH: usize = 1000;
+
+/// Runs `main` of an IR program, writing what it prints to `out`, and returns
+/// the value `main` returned.
+///
+/// It has the semantics of `interpreter::Interpreter`, so the two can be
+/// compared to test the lowering and the passes over the IR. Writing a value
+/// to a register of another type is an error. `max_steps`
+/// bounds the number of instructions run.
+pub fn run<W: Write + ?Sized>(
+    program: &Program,
+    out: &mut W,
+    max_steps: usize,
+) -> Result<i32, CompileError> {
+    let mut machine = Machine {
+        functions: program
+            .functions
+            .iter()
+            .map(|function| (function.name.as_str(), function))
+            .collect(),
+        depth: 0,
+        steps: 0,
+        max_steps,
+    };
+    machine.call("main", vec![], out)?.as_int()
+}
+
+struct Machine<'a> {
+    functions: HashMap<&'a str, &'a Function>,
+    depth: usize,
+    steps: usize,
+    max_steps: usize,
+}
+
+impl Machine<'_> {
+    fn call<W: Write + ?Sized>(
+        &mut self,
+        callee: &str,
+        args: Vec<Value>,
+        out: &mut W,
+    ) -> Result<Value, CompileError> {
+        if callee == "putchar" && args.len() == 1 {
+            out.write_all(&[args[0].as_int()? as u8])?;
+            return Ok(Value::Int(args[0].as_int()? & 0xff));
+        }
+        let function = *self
+            .functions
+            .get(callee)
+            .ok_or_else(|| runtime_error(format!("Undefined function: {}", callee)))?;
+        if function.parameters.len() != args.len() {
+            return Err(runtime_error(format!(
+                "{} expects {} arguments, got {}",
+                callee,
+                function.parameters.len(),
+                args.len()
+            )));
+        }
+        if self.depth > MAX_CALL_DEPTH {
+            return Err(runtime_error("Maximum call depth exceeded"));
+        }
+        self.depth += 1;
+        let result = self.execute(function, args, out);
+        self.depth -= 1;
+        result
+    }
+
+    fn execute<W: Write + ?Sized>(
+        &mut self,
+        function: &Function,
+        args: Vec<Value>,
+        out: &mut W,
+    ) -> Result<Value, CompileError> {
+        let mut registers: Vec<Option<Value>> = vec![None; function.types.len()];
+        for (reg, arg) in function.parameters.iter().zip(args) {
+            registers[reg.0] = Some(arg);
+        }
+        let read = |registers: &[Option<Value>], reg: crate::ir::Reg| {
+            registers[reg.0]
+                .clone()
+                .ok_or_else(|| runtime_error(format!("{} read before it is written", reg)))
+        };
+        let mut block = &function.blocks[0];
+        let mut previous = None;
+        loop {
+            // The phis of a block all read the registers as they were when
+            // control left the predecessor.
+            let incoming = block
+                .instructions
+                .iter()
+                .map(|instruction| match instruction {
+                    Instruction::Phi { sources, .. } => {
+                        let (_, reg) = sources
+                            .iter()
+                            .find(|(source, _)| Some(*source) == previous)
+                            .ok_or_else(|| {
+                                runtime_error(format!("{} has no phi source", block.id))
+                            })?;
+                        read(&registers, *reg).map(Some)
+                    }
+                    _ => Ok(None),
+                })
+                .collect::<Result<Vec<_>, CompileError>>()?;
+            for (instruction, incoming) in block.instructions.iter().zip(incoming) {
+                self.steps += 1;
+                if self.steps > self.max_steps {
+                    return Err(runtime_error("Step limit exceeded"));
+                }
+                let value = match instruction {
+                    Instruction::Const { value, .. } => Value::Int(*value),
+                    Instruction::Copy { src, .. } => read(&registers, *src)?,
+                    Instruction::Binary {
+                        op, left, right, ..
+                    } => {
+                        let left = read(&registers, *left)?.as_int()?;
+                        let right = read(&registers, *right)?.as_int()?;
+                        Value::Int(op.apply(left, right))
+                    }
+                    Instruction::Not { src, .. } => {
+                        Value::Int((read(&registers, *src)?.as_int()? == 0) as i32)
+                    }
+                    Instruction::Call { callee, args, .. } => {
+                        let args = args
+                            .iter()
+                            .map(|arg| read(&registers, *arg))
+                            .collect::<Result<_, _>>()?;
+                        self.call(callee, args, out)?
+                    }
+                    Instruction::Print { src } => {
+                        writeln!(out, "{}", read(&registers, *src)?.as_int()?)?;
+                        continue;
+                    }
+                    Instruction::Assert { src } => {
+                        let passed = read(&registers, *src)? == Value::Int(1);
+                        out.write_all(if passed { b"T" } else { b"F" })?;
+                        continue;
+                    }
+                    Instruction::NewArray { items, .. } => Value::Array(Rc::new(
+                        items
+                            .iter()
+                            .map(|item| read(&registers, *item))
+                            .collect::<Result<_, _>>()?,
+                    )),
+                    Instruction::Length { array, .. } => match read(&registers, *array)? {
+                        Value::Array(items) => Value::Int(items.len() as i32),
+                        Value::Int(_) => return Err(runtime_error("length() expects an array")),
+                    },
+                    Instruction::Load { array, index, .. } => {
+                        let Value::Array(items) = read(&registers, *array)? else {
+                            return Err(runtime_error("Indexing a value that is not an array"));
+                        };
+                        let index = read(&registers, *index)?.as_int()? as u32 as usize;
+                        items.get(index).cloned().unwrap_or(Value::Int(0))
+                    }
+                    Instruction::Phi { .. } => incoming.expect("Phi without a value"),
+                };
+                let dest = instruction.dest().expect("Instruction without a result");
+                let ty = match value {
+                    Value::Int(_) => Type::Int,
+                    Value::Array(_) => Type::Array,
+                };
+                if !function.type_of(dest).contains(ty) {
+                    return Err(runtime_error(format!(
+                        "{} is {}, but is written {}",
+                        dest,
+                        function.type_of(dest),
+                        ty
+                    )));
+                }
+                registers[dest.0] = Some(value);
+            }
+            let next = match &block.terminator {
+                Terminator::Jump(target) => *target,
+                Terminator::Branch {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    if read(&registers, *condition)?.as_int()? != 0 {
+                        *then
+                    } else {
+                        *otherwise
+                    }
+                }
+                Terminator::Return(value) => return read(&registers, *value),
+            };
+            previous = Some(block.id);
+            block = function.block(next);
+        }
+    }
+}
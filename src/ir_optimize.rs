@@ -0,0 +1,565 @@
+use crate::ir::{BinaryOp, BlockId, Function, Instruction, Program, Reg, Terminator};
+use crate::ssa::{self, DominatorTree};
+use std::collections::{HashMap, HashSet};
+
+/// Optimizes every function of a program in SSA form, then takes it out of
+/// SSA form again.
+pub fn optimize(program: &mut Program) {
+    for function in &mut program.functions {
+        ssa::construct(function);
+        optimize_ssa(function);
+        ssa::destruct(function);
+    }
+}
+
+/// Runs the optimizations over a function in SSA form until they find
+/// nothing more to do.
+pub fn optimize_ssa(function: &mut Function) {
+    loop {
+        let mut changed = constant_propagation(function);
+        changed |= copy_propagation(function);
+        changed |= common_subexpressions(function);
+        changed |= dead_stores(function);
+        changed |= merge_blocks(function);
+        if !changed {
+            return;
+        }
+    }
+}
+
+/// What is known about the value of a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lattice {
+    /// Nothing yet: the register hasn't been seen written.
+    Unknown,
+    Constant(i32),
+    Varying,
+}
+
+impl Lattice {
+    fn meet(self, other: Lattice) -> Lattice {
+        match (self, other) {
+            (Lattice::Unknown, value) | (value, Lattice::Unknown) => value,
+            (Lattice::Constant(a), Lattice::Constant(b)) if a == b => self,
+            _ => Lattice::Varying,
+        }
+    }
+}
+
+/// Sparse conditional constant propagation: finds the registers that always
+/// hold the same number, assuming branches on them only go one way, and
+/// replaces their instructions with constants. Branches on constants become
+/// jumps, and the blocks no longer reached are removed.
+pub fn constant_propagation(function: &mut Function) -> bool {
+    let mut values: HashMap<Reg, Lattice> = function
+        .parameters
+        .iter()
+        .map(|parameter| (*parameter, Lattice::Varying))
+        .collect();
+    let value = |values: &HashMap<Reg, Lattice>, reg: &Reg| {
+        values.get(reg).copied().unwrap_or(Lattice::Unknown)
+    };
+    let entry = function.blocks[0].id;
+    let mut executable = HashSet::from([entry]);
+    let mut edges: HashSet<(BlockId, BlockId)> = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &function.blocks {
+            if !executable.contains(&block.id) {
+                continue;
+            }
+            for instruction in &block.instructions {
+                let Some(dest) = instruction.dest() else {
+                    continue;
+                };
+                let computed = match instruction {
+                    Instruction::Const { value, .. } => Lattice::Constant(*value),
+                    Instruction::Copy { src, .. } => value(&values, src),
+                    Instruction::Binary {
+                        op, left, right, ..
+                    } => match (value(&values, left), value(&values, right)) {
+                        (Lattice::Constant(left), Lattice::Constant(right)) => {
+                            Lattice::Constant(op.apply(left, right))
+                        }
+                        (Lattice::Varying, _) | (_, Lattice::Varying) => Lattice::Varying,
+                        _ => Lattice::Unknown,
+                    },
+                    Instruction::Not { src, .. } => match value(&values, src) {
+                        Lattice::Constant(value) => Lattice::Constant((value == 0) as i32),
+                        other => other,
+                    },
+                    Instruction::Phi { sources, .. } => sources
+                        .iter()
+                        .filter(|(source, _)| edges.contains(&(*source, block.id)))
+                        .fold(Lattice::Unknown, |known, (_, reg)| {
+                            known.meet(value(&values, reg))
+                        }),
+                    _ => Lattice::Varying,
+                };
+                let known = value(&values, &dest);
+                if known.meet(computed) != known {
+                    values.insert(dest, known.meet(computed));
+                    changed = true;
+                }
+            }
+            let successors = match &block.terminator {
+                Terminator::Branch {
+                    condition,
+                    then,
+                    otherwise,
+                } => match value(&values, condition) {
+                    Lattice::Unknown => vec![],
+                    Lattice::Constant(0) => vec![*otherwise],
+                    Lattice::Constant(_) => vec![*then],
+                    Lattice::Varying => vec![*then, *otherwise],
+                },
+                terminator => terminator.successors(),
+            };
+            for successor in successors {
+                if edges.insert((block.id, successor)) {
+                    executable.insert(successor);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut rewritten = false;
+    let before = function.blocks.len();
+    function
+        .blocks
+        .retain(|block| executable.contains(&block.id));
+    rewritten |= function.blocks.len() != before;
+    for block in &mut function.blocks {
+        for instruction in &mut block.instructions {
+            let Some(dest) = instruction.dest() else {
+                continue;
+            };
+            if let Some(Lattice::Constant(value)) = values.get(&dest) {
+                if !instruction.has_side_effects()
+                    && !matches!(instruction, Instruction::Const { .. })
+                {
+                    *instruction = Instruction::Const {
+                        dest,
+                        value: *value,
+                    };
+                    rewritten = true;
+                }
+            }
+        }
+        if let Terminator::Branch {
+            condition,
+            then,
+            otherwise,
+        } = block.terminator
+        {
+            if let Some(Lattice::Constant(value)) = values.get(&condition) {
+                let target = if *value != 0 { then } else { otherwise };
+                block.terminator = Terminator::Jump(target);
+                rewritten = true;
+            }
+        }
+    }
+    if rewritten {
+        remove_dead_phi_sources(function);
+    }
+    rewritten
+}
+
+/// Drops the phi sources for edges that no longer exist, and turns phis left
+/// with a single source into copies.
+fn remove_dead_phi_sources(function: &mut Function) {
+    let predecessors = function.predecessors();
+    for block in &mut function.blocks {
+        let incoming = &predecessors[&block.id];
+        for instruction in &mut block.instructions {
+            if let Instruction::Phi { dest, sources } = instruction {
+                sources.retain(|(source, _)| incoming.contains(source));
+                if let [(_, src)] = sources.as_slice() {
+                    *instruction = Instruction::Copy {
+                        dest: *dest,
+                        src: *src,
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Replaces every use of a register with what it is replaced by, following
+/// chains.
+fn replace_uses(function: &mut Function, replacements: &HashMap<Reg, Reg>) {
+    let find = |mut reg: Reg| {
+        while let Some(replacement) = replacements.get(&reg) {
+            reg = *replacement;
+        }
+        reg
+    };
+    for block in &mut function.blocks {
+        for instruction in &mut block.instructions {
+            for reg in instruction.uses_mut() {
+                *reg = find(*reg);
+            }
+        }
+        for reg in block.terminator.uses_mut() {
+            *reg = find(*reg);
+        }
+    }
+}
+
+/// Reads the source of a copy wherever the copy is read, and drops the copy.
+/// Phis merging a single register, besides themselves, are copies too.
+pub fn copy_propagation(function: &mut Function) -> bool {
+    let mut replacements: HashMap<Reg, Reg> = HashMap::new();
+    let find = |replacements: &HashMap<Reg, Reg>, mut reg: Reg| {
+        while let Some(replacement) = replacements.get(&reg) {
+            reg = *replacement;
+        }
+        reg
+    };
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            let (dest, src) = match instruction {
+                Instruction::Copy { dest, src } => (*dest, *src),
+                Instruction::Phi { dest, sources } => {
+                    let mut merged = sources
+                        .iter()
+                        .map(|(_, reg)| find(&replacements, *reg))
+                        .filter(|reg| reg != dest);
+                    let Some(first) = merged.next() else {
+                        continue;
+                    };
+                    if !merged.all(|reg| reg == first) {
+                        continue;
+                    }
+                    (*dest, first)
+                }
+                _ => continue,
+            };
+            // A cycle of phis only merging each other can't be resolved.
+            if find(&replacements, src) != dest {
+                replacements.insert(dest, src);
+            }
+        }
+    }
+    if replacements.is_empty() {
+        return false;
+    }
+    replace_uses(function, &replacements);
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| {
+            !instruction
+                .dest()
+                .is_some_and(|dest| replacements.contains_key(&dest))
+        });
+    }
+    true
+}
+
+/// An instruction computing a value from its operands alone. Arrays can't be
+/// changed, so reading them is one too.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Expression {
+    Const(i32),
+    Binary(BinaryOp, Reg, Reg),
+    Not(Reg),
+    Length(Reg),
+    Load(Reg, Reg),
+}
+
+impl Expression {
+    fn of(instruction: &Instruction) -> Option<Expression> {
+        Some(match instruction {
+            Instruction::Const { value, .. } => Expression::Const(*value),
+            Instruction::Binary {
+                op, left, right, ..
+            } => match op {
+                BinaryOp::Add | BinaryOp::Mul | BinaryOp::Eq | BinaryOp::Ne => {
+                    Expression::Binary(*op, *left.min(right), *left.max(right))
+                }
+                _ => Expression::Binary(*op, *left, *right),
+            },
+            Instruction::Not { src, .. } => Expression::Not(*src),
+            Instruction::Length { array, .. } => Expression::Length(*array),
+            Instruction::Load { array, index, .. } => Expression::Load(*array, *index),
+            _ => return None,
+        })
+    }
+}
+
+/// Reuses the value of an instruction computing the same thing as one in a
+/// dominating block, instead of computing it again.
+pub fn common_subexpressions(function: &mut Function) -> bool {
+    let dominators = DominatorTree::new(function);
+    let mut replacements = HashMap::new();
+    let mut available = HashMap::new();
+    number_values(
+        function,
+        &dominators,
+        function.blocks[0].id,
+        &mut available,
+        &mut replacements,
+    );
+    if replacements.is_empty() {
+        return false;
+    }
+    replace_uses(function, &replacements);
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| {
+            !instruction
+                .dest()
+                .is_some_and(|dest| replacements.contains_key(&dest))
+        });
+    }
+    true
+}
+
+fn number_values(
+    function: &Function,
+    dominators: &DominatorTree,
+    id: BlockId,
+    available: &mut HashMap<Expression, Reg>,
+    replacements: &mut HashMap<Reg, Reg>,
+) {
+    let mut added = vec![];
+    for instruction in &function.block(id).instructions {
+        let mut instruction = instruction.clone();
+        // The operands were all seen before, in this block or a dominator.
+        for reg in instruction.uses_mut() {
+            if let Some(replacement) = replacements.get(reg) {
+                *reg = *replacement;
+            }
+        }
+        let (Some(expression), Some(dest)) = (Expression::of(&instruction), instruction.dest())
+        else {
+            continue;
+        };
+        match available.get(&expression) {
+            Some(reg) => {
+                replacements.insert(dest, *reg);
+            }
+            None => {
+                available.insert(expression.clone(), dest);
+                added.push(expression);
+            }
+        }
+    }
+    for &child in dominators.children(id) {
+        number_values(function, dominators, child, available, replacements);
+    }
+    for expression in added {
+        available.remove(&expression);
+    }
+}
+
+/// Appends the blocks only reached by a jump from one other block to that
+/// block, like those left by branches on constants.
+pub fn merge_blocks(function: &mut Function) -> bool {
+    let mut merged = false;
+    loop {
+        let predecessors = function.predecessors();
+        let entry = function.blocks[0].id;
+        let Some((first, second)) =
+            function
+                .blocks
+                .iter()
+                .find_map(|block| match block.terminator {
+                    Terminator::Jump(next)
+                        if next != block.id
+                            && next != entry
+                            && predecessors[&next] == [block.id] =>
+                    {
+                        Some((block.id, next))
+                    }
+                    _ => None,
+                })
+        else {
+            return merged;
+        };
+        let position = function
+            .blocks
+            .iter()
+            .position(|block| block.id == second)
+            .unwrap();
+        let mut removed = function.blocks.remove(position);
+        for instruction in &mut removed.instructions {
+            if let Instruction::Phi { dest, sources } = instruction {
+                *instruction = Instruction::Copy {
+                    dest: *dest,
+                    src: sources[0].1,
+                };
+            }
+        }
+        for successor in removed.terminator.successors() {
+            for instruction in &mut function.block_mut(successor).instructions {
+                if let Instruction::Phi { sources, .. } = instruction {
+                    for (source, _) in sources {
+                        if *source == second {
+                            *source = first;
+                        }
+                    }
+                }
+            }
+        }
+        let block = function.block_mut(first);
+        block.instructions.append(&mut removed.instructions);
+        block.terminator = removed.terminator;
+        merged = true;
+    }
+}
+
+/// Removes the instructions whose value is never used, including phis only
+/// used by each other, like the variable of a loop that is never read.
+pub fn dead_stores(function: &mut Function) -> bool {
+    let mut definitions = HashMap::new();
+    let mut pending = vec![];
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            if let Some(dest) = instruction.dest() {
+                definitions.insert(dest, instruction);
+            }
+            if instruction.has_side_effects() {
+                pending.extend(instruction.uses());
+            }
+        }
+        pending.extend(block.terminator.uses());
+    }
+    let mut live = HashSet::new();
+    while let Some(reg) = pending.pop() {
+        if live.insert(reg) {
+            if let Some(instruction) = definitions.get(&reg) {
+                pending.extend(instruction.uses());
+            }
+        }
+    }
+
+    let mut removed = false;
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| {
+            let dead = !instruction.has_side_effects()
+                && instruction.dest().is_some_and(|dest| !live.contains(&dest));
+            removed |= dead;
+            !dead
+        });
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm_emitter::emit;
+    use crate::generator::check_against_interpreter;
+    use crate::ir_interpreter;
+    use crate::lowering::lower;
+    use crate::parser::parse;
+
+    /// The first function of a program, optimized in SSA form.
+    fn optimized(source: &str) -> Function {
+        let mut program = lower(&parse(source).expect("Parser failed")).expect("Lowering failed");
+        let function = &mut program.functions[0];
+        ssa::construct(function);
+        optimize_ssa(function);
+        program.functions.swap_remove(0)
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(
+            "function main() {
+bb0:
+    %2: int = const 0
+    %17: int = const 3
+    print %17
+    return %2
+}
+",
+            optimized(
+                "function main() {
+                    var x = 1;
+                    var y = 0;
+                    if (x == 1) { y = 2; } else { y = 3; }
+                    while (y == 0) { y = y + 1; }
+                    print(y + x);
+                }"
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn copies_and_common_subexpressions() {
+        assert_eq!(
+            "function f(%0: word, %1: word) {
+bb0:
+    %2: int = add %0, %1
+    %7: int = mul %2, %2
+    return %7
+}
+",
+            optimized(
+                "function f(a, b) { var c = a + b; var d = b + a; var e = d; return c * e; }"
+            )
+            .to_string()
+        );
+        // Only in a dominating block.
+        let f = optimized(
+            "function f(a, b) {
+                if (a < b) { print(a - b); } else { print(a - b); }
+                return a - b;
+            }",
+        );
+        assert_eq!(3, f.to_string().matches("sub").count(), "{}", f);
+    }
+
+    #[test]
+    fn dead_stores_in_loops() {
+        let f = optimized(
+            "function f(a) {
+                var i = 0;
+                var unused = 0;
+                while (i < a) { unused = unused + i; i = i + 1; }
+                return i;
+            }",
+        );
+        assert_eq!(1, f.to_string().matches("phi").count(), "{}", f);
+        assert_eq!(1, f.to_string().matches("add").count(), "{}", f);
+    }
+
+    #[test]
+    fn fewer_loads_in_loops() {
+        let source = "function main() {
+            var i = 0;
+            var j = 0;
+            var count = 0;
+            while (i < 3) {
+                j = 0;
+                while (j < 4) {
+                    count = count + 1;
+                    j = j + 1;
+                }
+                i = i + 1;
+            }
+            print(count);
+            assert(count == 12);
+        }";
+        let mut program = lower(&parse(source).expect("Parser failed")).expect("Lowering failed");
+        let loads = |program: &Program| emit(program).matches("\tldr").count();
+        let before = loads(&program);
+        optimize(&mut program);
+        assert!(loads(&program) < before, "{}", program);
+    }
+
+    #[test]
+    fn agrees_with_interpreter() {
+        check_against_interpreter(0..300, |ast, out| {
+            let mut program = lower(ast).expect("Lowering failed");
+            optimize(&mut program);
+            for function in &program.functions {
+                function.check_types().expect("Badly typed IR");
+            }
+            ir_interpreter::run(&program, out, 1_000_000).expect("IR program failed")
+        });
+    }
+}
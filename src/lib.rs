@@ -0,0 +1,36 @@
+pub mod aarch64;
+pub mod aarch64_code_generator;
+pub mod aarch64_emulator;
+pub mod arm;
+pub mod arm_code_generator;
+pub mod arm_emitter;
+pub mod ast;
+pub mod constant_folding;
+pub mod dead_code;
+pub mod dot;
+pub mod dump;
+pub mod elf;
+pub mod emulator;
+pub mod encoder;
+pub mod error;
+pub mod fold;
+pub mod formatter;
+pub mod generator;
+pub mod inline;
+pub mod interpreter;
+pub mod ir;
+pub mod ir_interpreter;
+pub mod ir_optimize;
+pub mod lowering;
+pub mod native;
+pub mod parser;
+pub mod pass_manager;
+pub mod peephole;
+pub mod printer;
+pub mod register_allocation;
+pub mod runtime;
+pub mod ssa;
+pub mod visitor;
+pub mod visitor_mut;
+pub mod x86_64;
+pub mod x86_64_code_generator;
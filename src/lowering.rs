@@ -0,0 +1,599 @@
+use crate::ast::AST;
+use crate::error::CompileError;
+use crate::ir::{BinaryOp, Block, BlockId, Function, Instruction, Program, Reg, Terminator, Type};
+use crate::visitor::{AstVisitor, Visitor};
+use std::collections::{HashMap, HashSet};
+
+/// Translates a program made of functions, or an `AST::Main`, to the IR.
+///
+/// Every variable lives in a register of its own for the whole function, and
+/// expressions put their value in a new register. Functions declared inside
+/// others become separate functions. Unreachable blocks, like the code after a
+/// `return`, are dropped.
+pub fn lower(ast: &AST) -> Result<Program, CompileError> {
+    let statements = match ast {
+        AST::Block(statements) => statements.as_slice(),
+        other => std::slice::from_ref(other),
+    };
+    let mut lowering = Lowering {
+        functions: vec![],
+        function: FunctionBuilder::new("", &[]),
+    };
+    for statement in statements {
+        match statement {
+            AST::Function { .. } | AST::Main(_) => {
+                statement.accept(&mut lowering)?;
+            }
+            other => {
+                return Err(CompileError::CodeGenError(format!(
+                    "Only functions are allowed at the top level, got: {}",
+                    other
+                )))
+            }
+        }
+    }
+    Ok(Program {
+        functions: lowering.functions,
+    })
+}
+
+/// The function being lowered.
+struct FunctionBuilder {
+    function: Function,
+    variables: HashMap<String, Reg>,
+    /// The block instructions are added to.
+    current: BlockId,
+    instructions: Vec<Instruction>,
+    next_block: usize,
+}
+
+impl FunctionBuilder {
+    fn new(name: &str, parameters: &[String]) -> FunctionBuilder {
+        let mut function = Function {
+            name: name.to_string(),
+            parameters: vec![],
+            blocks: vec![],
+            types: vec![],
+        };
+        let mut variables = HashMap::new();
+        for parameter in parameters {
+            let reg = function.new_reg(Type::Word);
+            function.parameters.push(reg);
+            variables.insert(parameter.clone(), reg);
+        }
+        FunctionBuilder {
+            function,
+            variables,
+            current: BlockId(0),
+            instructions: vec![],
+            next_block: 1,
+        }
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.next_block += 1;
+        BlockId(self.next_block - 1)
+    }
+
+    /// Ends the current block and starts adding to `next`.
+    fn finish(&mut self, terminator: Terminator, next: BlockId) {
+        self.function.blocks.push(Block {
+            id: self.current,
+            instructions: std::mem::take(&mut self.instructions),
+            terminator,
+        });
+        self.current = next;
+    }
+
+    /// Returns 0 at the end of the body, like the epilogue emitted by
+    /// `ArmCodeGenerator`, drops the blocks that can't be reached and types
+    /// the registers.
+    fn build(mut self) -> Function {
+        let zero = self.function.new_reg(Type::Int);
+        self.instructions.push(Instruction::Const {
+            dest: zero,
+            value: 0,
+        });
+        let next = self.new_block();
+        self.finish(Terminator::Return(zero), next);
+
+        let mut reachable = HashSet::from([BlockId(0)]);
+        let mut pending = vec![BlockId(0)];
+        while let Some(id) = pending.pop() {
+            for successor in self.function.block(id).terminator.successors() {
+                if reachable.insert(successor) {
+                    pending.push(successor);
+                }
+            }
+        }
+        self.function
+            .blocks
+            .retain(|block| reachable.contains(&block.id));
+        self.function.infer_types();
+        self.function
+    }
+}
+
+struct Lowering {
+    functions: Vec<Function>,
+    function: FunctionBuilder,
+}
+
+impl Lowering {
+    fn emit(&mut self, instruction: Instruction) {
+        self.function.instructions.push(instruction);
+    }
+
+    /// A register for one of the values of the function. Variables are
+    /// written more than once, so its type is only settled when the function
+    /// is built.
+    fn new_reg(&mut self) -> Reg {
+        self.function.function.new_reg(Type::Word)
+    }
+
+    fn constant(&mut self, value: i32) -> Reg {
+        let dest = self.new_reg();
+        self.emit(Instruction::Const { dest, value });
+        dest
+    }
+
+    /// Lowers an expression, returning the register holding its value.
+    /// Statements used as values, like `print`, are 0.
+    fn expression(&mut self, node: &AST) -> Result<Reg, CompileError> {
+        match node.accept(self)? {
+            Some(reg) => Ok(reg),
+            None => Ok(self.constant(0)),
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, node: &AST) -> Result<Option<Reg>, CompileError> {
+        let (left, right) = match node {
+            AST::Equal { left, right }
+            | AST::NotEqual { left, right }
+            | AST::Add { left, right }
+            | AST::Subtract { left, right }
+            | AST::Multiply { left, right }
+            | AST::Divide { left, right }
+            | AST::LessThan { left, right }
+            | AST::GreaterThan { left, right }
+            | AST::LessThanEqual { left, right }
+            | AST::GreaterThanEqual { left, right } => (left, right),
+            _ => panic!("Expected binary operator, got: {:?}", node),
+        };
+        let left = self.expression(left)?;
+        let right = self.expression(right)?;
+        let dest = self.new_reg();
+        self.emit(Instruction::Binary {
+            op,
+            dest,
+            left,
+            right,
+        });
+        Ok(Some(dest))
+    }
+
+    fn variable(&self, name: &str) -> Result<Reg, CompileError> {
+        self.function
+            .variables
+            .get(name)
+            .copied()
+            .ok_or_else(|| CompileError::CodeGenError(format!("Undefined variable: {}", name)))
+    }
+
+    fn lower_function(
+        &mut self,
+        name: &str,
+        parameters: &[String],
+        statements: &[AST],
+    ) -> Result<(), CompileError> {
+        let outer = std::mem::replace(&mut self.function, FunctionBuilder::new(name, parameters));
+        let result = statements
+            .iter()
+            .try_for_each(|statement| statement.accept(self).map(|_| ()));
+        let function = std::mem::replace(&mut self.function, outer);
+        result?;
+        self.functions.push(function.build());
+        Ok(())
+    }
+}
+
+impl Visitor<Option<Reg>> for Lowering {
+    fn visit_assert(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Assert(condition) = node else {
+            panic!("Expected Assert node, got: {:?}", node)
+        };
+        let src = self.expression(condition)?;
+        self.emit(Instruction::Assert { src });
+        Ok(None)
+    }
+
+    fn visit_print(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Print(value) = node else {
+            panic!("Expected Print node, got: {:?}", node)
+        };
+        let src = self.expression(value)?;
+        self.emit(Instruction::Print { src });
+        Ok(None)
+    }
+
+    fn visit_array_length(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::ArrayLength(array) = node else {
+            panic!("Expected ArrayLength node, got: {:?}", node)
+        };
+        let array = self.expression(array)?;
+        let dest = self.new_reg();
+        self.emit(Instruction::Length { dest, array });
+        Ok(Some(dest))
+    }
+
+    fn visit_array_lookup(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::ArrayLookup { array, index } = node else {
+            panic!("Expected ArrayLookup node, got: {:?}", node)
+        };
+        let array = self.expression(array)?;
+        let index = self.expression(index)?;
+        let dest = self.new_reg();
+        self.emit(Instruction::Load { dest, array, index });
+        Ok(Some(dest))
+    }
+
+    fn visit_array_literal(
+        &mut self,
+        node: &AST,
+        _w: &mut (),
+    ) -> Result<Option<Reg>, CompileError> {
+        let AST::ArrayLiteral(array_items) = node else {
+            panic!("Expected ArrayLiteral node, got: {:?}", node)
+        };
+        let items = array_items
+            .iter()
+            .map(|item| self.expression(item))
+            .collect::<Result<_, _>>()?;
+        let dest = self.new_reg();
+        self.emit(Instruction::NewArray { dest, items });
+        Ok(Some(dest))
+    }
+
+    fn visit_boolean(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Boolean(value) = node else {
+            panic!("Expected Boolean node, got: {:?}", node)
+        };
+        Ok(Some(self.constant(*value as i32)))
+    }
+
+    fn visit_number(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Number(number) = node else {
+            panic!("Expected Number node, got: {:?}", node)
+        };
+        // `ldr r0, =N` keeps the low 32 bits.
+        Ok(Some(self.constant(*number as u32 as i32)))
+    }
+
+    fn visit_id(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Id(name) = node else {
+            panic!("Expected Id node, got: {:?}", node)
+        };
+        // Expressions can't assign variables, so the variable's own register
+        // can be read directly.
+        self.variable(name).map(Some)
+    }
+
+    fn visit_not(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Not(term) = node else {
+            panic!("Expected Not node, got: {:?}", node)
+        };
+        let src = self.expression(term)?;
+        let dest = self.new_reg();
+        self.emit(Instruction::Not { dest, src });
+        Ok(Some(dest))
+    }
+
+    fn visit_equal(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Eq, node)
+    }
+
+    fn visit_not_equal(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Ne, node)
+    }
+
+    fn visit_add(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Add, node)
+    }
+
+    fn visit_subtract(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Sub, node)
+    }
+
+    fn visit_multiply(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Mul, node)
+    }
+
+    fn visit_divide(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Div, node)
+    }
+
+    fn visit_less_than(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Lt, node)
+    }
+
+    fn visit_greater_than(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Gt, node)
+    }
+
+    fn visit_less_than_equal(
+        &mut self,
+        node: &AST,
+        _w: &mut (),
+    ) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Le, node)
+    }
+
+    fn visit_greater_than_equal(
+        &mut self,
+        node: &AST,
+        _w: &mut (),
+    ) -> Result<Option<Reg>, CompileError> {
+        self.binary(BinaryOp::Ge, node)
+    }
+
+    fn visit_call(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Call { callee, args } = node else {
+            panic!("Expected Call node, got: {:?}", node)
+        };
+        if args.len() > 4 {
+            return Err(CompileError::CodeGenError(
+                "More than 4 arguments are not supported in function calls".to_string(),
+            ));
+        }
+        let args = args
+            .iter()
+            .map(|arg| self.expression(arg))
+            .collect::<Result<_, _>>()?;
+        let dest = self.new_reg();
+        self.emit(Instruction::Call {
+            dest,
+            callee: callee.clone(),
+            args,
+        });
+        Ok(Some(dest))
+    }
+
+    fn visit_return(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Return { term } = node else {
+            panic!("Expected Return node, got: {:?}", node)
+        };
+        let value = self.expression(term)?;
+        // Whatever follows is unreachable, and dropped by `build`.
+        let next = self.function.new_block();
+        self.function.finish(Terminator::Return(value), next);
+        Ok(None)
+    }
+
+    fn visit_if(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::IfNode {
+            conditional,
+            consequence,
+            alternative,
+        } = node
+        else {
+            panic!("Expected IfNode node, got: {:?}", node)
+        };
+        let condition = self.expression(conditional)?;
+        let then = self.function.new_block();
+        let otherwise = self.function.new_block();
+        let end = self.function.new_block();
+        self.function.finish(
+            Terminator::Branch {
+                condition,
+                then,
+                otherwise,
+            },
+            then,
+        );
+        consequence.accept(self)?;
+        self.function.finish(Terminator::Jump(end), otherwise);
+        alternative.accept(self)?;
+        self.function.finish(Terminator::Jump(end), end);
+        Ok(None)
+    }
+
+    fn visit_function(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Function {
+            name,
+            parameters,
+            body,
+        } = node
+        else {
+            panic!("Expected Function node, got: {:?}", node)
+        };
+        if parameters.len() > 4 {
+            return Err(CompileError::CodeGenError(
+                "More than 4 params is not supported".to_string(),
+            ));
+        }
+        self.lower_function(name, parameters, std::slice::from_ref(body))?;
+        Ok(None)
+    }
+
+    fn visit_var(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Var { name, value } = node else {
+            panic!("Expected Var node, got: {:?}", node)
+        };
+        let src = self.expression(value)?;
+        let dest = match self.function.variables.get(name) {
+            Some(&reg) => reg,
+            None => {
+                let reg = self.new_reg();
+                self.function.variables.insert(name.clone(), reg);
+                reg
+            }
+        };
+        self.emit(Instruction::Copy { dest, src });
+        Ok(None)
+    }
+
+    fn visit_assign(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Assign { name, value } = node else {
+            panic!("Expected Assign node, got: {:?}", node)
+        };
+        let src = self.expression(value)?;
+        let dest = self.variable(name)?;
+        self.emit(Instruction::Copy { dest, src });
+        Ok(None)
+    }
+
+    fn visit_while(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::While { conditional, body } = node else {
+            panic!("Expected While node, got: {:?}", node)
+        };
+        let head = self.function.new_block();
+        self.function.finish(Terminator::Jump(head), head);
+        let condition = self.expression(conditional)?;
+        let loop_body = self.function.new_block();
+        let end = self.function.new_block();
+        self.function.finish(
+            Terminator::Branch {
+                condition,
+                then: loop_body,
+                otherwise: end,
+            },
+            loop_body,
+        );
+        body.accept(self)?;
+        self.function.finish(Terminator::Jump(head), end);
+        Ok(None)
+    }
+
+    fn visit_undefined(&mut self, _node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        Ok(Some(self.constant(0)))
+    }
+
+    fn visit_null(&mut self, _node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        Ok(Some(self.constant(0)))
+    }
+
+    fn visit_main(&mut self, node: &AST, _w: &mut ()) -> Result<Option<Reg>, CompileError> {
+        let AST::Main(statements) = node else {
+            panic!("Expected Main node, got: {:?}", node)
+        };
+        self.lower_function("main", &[], statements)?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::check_against_interpreter;
+    use crate::ir_interpreter;
+    use crate::parser::parse;
+
+    fn lower_source(source: &str) -> Program {
+        lower(&parse(source).expect("Parser failed")).expect("Lowering failed")
+    }
+
+    #[test]
+    fn dump() {
+        let program = lower_source(
+            r#"function f(n) {
+                var total = 0;
+                while (n > 0) {
+                    if (n == 2) { print(n); } else { total = total + n; }
+                    n = n - 1;
+                }
+                var a = [total];
+                return a[0];
+                print(1);
+            }
+            function main() { assert(f(3) == 4); }"#,
+        );
+        assert_eq!(
+            "function f(%0: word) {
+bb0:
+    %1: int = const 0
+    %2: int = copy %1
+    jump bb1
+bb1:
+    %3: int = const 0
+    %4: int = gt %0, %3
+    branch %4, bb2, bb3
+bb2:
+    %5: int = const 2
+    %6: int = eq %0, %5
+    branch %6, bb4, bb5
+bb4:
+    print %0
+    jump bb6
+bb5:
+    %7: int = add %2, %0
+    %2: int = copy %7
+    jump bb6
+bb6:
+    %8: int = const 1
+    %9: int = sub %0, %8
+    %0: word = copy %9
+    jump bb1
+bb3:
+    %10: array = array [%2]
+    %11: array = copy %10
+    %12: int = const 0
+    %13: word = load %11[%12]
+    return %13
+}
+
+function main() {
+bb0:
+    %0: int = const 3
+    %1: word = call f(%0)
+    %2: int = const 4
+    %3: int = eq %1, %2
+    assert %3
+    %4: int = const 0
+    return %4
+}
+",
+            program.to_string()
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for source in [
+            "print(1);",
+            "function main() { return x; }",
+            "function main() { y = 1; }",
+            "function f(a, b, c, d, e) { }",
+            "function main() { f(1, 2, 3, 4, 5); }",
+        ] {
+            assert!(
+                matches!(
+                    lower(&parse(source).expect("Parser failed")),
+                    Err(CompileError::CodeGenError(_))
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn nested_functions_and_main() {
+        let program = lower_source("function main() { function g() { return 1; } return g(); }");
+        let names: Vec<&str> = program.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(vec!["g", "main"], names);
+        let program =
+            lower(&AST::Main(vec![AST::Print(AST::Number(1).into())])).expect("Lowering failed");
+        assert_eq!("main", program.functions[0].name);
+    }
+
+    #[test]
+    fn agrees_with_interpreter() {
+        check_against_interpreter(0..300, |ast, out| {
+            let program = lower(ast).expect("Lowering failed");
+            for function in &program.functions {
+                function.check_types().expect("Badly typed IR");
+            }
+            ir_interpreter::run(&program, out, 1_000_000).expect("IR program failed")
+        });
+    }
+}
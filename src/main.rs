@@ -1,13 +1,284 @@
-extern crate core;
+use arm_compile::aarch64;
+use arm_compile::aarch64_code_generator;
+use arm_compile::arm;
+use arm_compile::arm_code_generator::generate_lines;
+use arm_compile::arm_emitter;
+use arm_compile::ast::AST;
+use arm_compile::dot::{ast_to_dot, cfg_to_dot};
+use arm_compile::dump::{from_json, to_json_with_locations, to_sexp_with_locations};
+use arm_compile::elf;
+use arm_compile::encoder::encode;
+use arm_compile::error::CompileError;
+use arm_compile::formatter::format_source;
+use arm_compile::ir_optimize::optimize_ssa;
+use arm_compile::lowering::lower;
+use arm_compile::parser::{parse, parse_with_locations};
+use arm_compile::pass_manager::{PassManager, PassOptions};
+use arm_compile::peephole;
+use arm_compile::printer::print_program;
+use arm_compile::runtime;
+use arm_compile::ssa;
+use arm_compile::x86_64;
+use arm_compile::x86_64_code_generator;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::process::ExitCode;
 
-// mod arm;
-mod ast;
+const USAGE: &str = "Usage:
+    ArmCompile compile [--json] [--tree] [--no-peephole] [--stop-after=<pass>]
+                     [--print-after=<pass>]... [--time-passes] [--inline-threshold=<n>]
+                     [--target=(arm | aarch64 | x86_64)] <file> [-o <output>]
+    ArmCompile compile (--object | --executable) [<compile options>] <file> -o <output>
+    ArmCompile dot [--cfg] <file>
+    ArmCompile dump [--json | --sexp | --ir | --ssa] <file>
+    ArmCompile fmt [--check] <file>...
 
-mod arm_code_generator;
-mod error;
-mod parser;
-mod visitor;
+`dot --cfg` draws the control-flow graph of the code `compile --tree` prints.";
 
-fn main() {
-    println!("Hello, world!");
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("compile") => report(compile(&args[1..])),
+        Some("dot") => report(dot(&args[1..])),
+        Some("dump") => report(dump(&args[1..])),
+        Some("fmt") => fmt(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn report(result: Result<(), CompileError>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage_error() -> CompileError {
+    CompileError::UsageError(USAGE.to_string())
+}
+
+fn read(file: &str) -> Result<String, CompileError> {
+    Ok(fs::read_to_string(file)?)
+}
+
+/// The architecture `compile` generates code for.
+#[derive(PartialEq)]
+enum Target {
+    Arm,
+    Aarch64,
+    X86_64,
+}
+
+/// Compiles a source file, or with `--json` a tree written by `dump --json`,
+/// to assembly on stdout or in the `-o` file. ARM code is generated through
+/// the IR, or from the tree directly with `--tree`, and `--no-peephole`
+/// leaves it as generated. With `--stop-after`, the program is written as
+/// source after that pass instead. With `--object`, the code is encoded into
+/// an ELF object file for the system linker, and with `--executable` linked
+/// with `runtime` into a static executable. `--target=aarch64` and
+/// `--target=x86_64` generate AArch64 or x86-64 assembly instead of ARM,
+/// from the tree only. Diagnostics, the
+/// `--print-after` dumps and the `--time-passes` timings go to stderr.
+fn compile(args: &[String]) -> Result<(), CompileError> {
+    let mut options = PassOptions::default();
+    let mut stop_after = None;
+    let mut print_after = vec![];
+    let mut time_passes = false;
+    let mut json = false;
+    let mut tree = false;
+    let mut peephole = true;
+    let mut object = false;
+    let mut executable = false;
+    let mut target = Target::Arm;
+    let mut input = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--tree" => tree = true,
+            "--no-peephole" => peephole = false,
+            "--object" => object = true,
+            "--executable" => executable = true,
+            "--time-passes" => time_passes = true,
+            _ if arg.starts_with("--target=") => {
+                target = match &arg["--target=".len()..] {
+                    "arm" => Target::Arm,
+                    "aarch64" => Target::Aarch64,
+                    "x86_64" => Target::X86_64,
+                    _ => return Err(usage_error()),
+                }
+            }
+            _ if arg.starts_with("--stop-after=") => {
+                stop_after = Some(arg["--stop-after=".len()..].to_string())
+            }
+            _ if arg.starts_with("--print-after=") => {
+                print_after.push(arg["--print-after=".len()..].to_string())
+            }
+            _ if arg.starts_with("--inline-threshold=") => {
+                options.inline_threshold = arg["--inline-threshold=".len()..]
+                    .parse()
+                    .map_err(|_| usage_error())?
+            }
+            "-o" => output = Some(args.next().ok_or_else(usage_error)?),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(usage_error()),
+        }
+    }
+    let mut passes = PassManager::standard(&options);
+    passes.stop_after = stop_after;
+    passes.print_after = print_after;
+    passes
+        .check_pass_names()
+        .map_err(|e| CompileError::UsageError(format!("{}\n{}", e, USAGE)))?;
+    let source = read(input.ok_or_else(usage_error)?)?;
+    if (object || executable) && (output.is_none() || passes.stop_after.is_some())
+        || object && executable
+        || target != Target::Arm && (tree || object || executable)
+    {
+        return Err(usage_error());
+    }
+    let ast: AST = if json {
+        from_json(&source)?
+    } else {
+        let (ast, locations) = parse_with_locations(&source)?;
+        passes.locations = locations;
+        ast
+    };
+    let results = passes.run(ast, &mut std::io::stderr())?;
+    for diagnostic in &results.diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    if time_passes {
+        for (name, time) in &results.timings {
+            eprintln!("{:>10.3}ms  {}", time.as_secs_f64() * 1000.0, name);
+        }
+    }
+    let assembly = if results.stopped {
+        print_program(&results.ast).into_bytes()
+    } else if target == Target::Aarch64 {
+        let lines = aarch64_code_generator::generate_lines(&results.ast)?;
+        aarch64::print(&lines).into_bytes()
+    } else if target == Target::X86_64 {
+        let lines = x86_64_code_generator::generate_lines(&results.ast)?;
+        x86_64::print(&lines).into_bytes()
+    } else {
+        let mut lines = if tree {
+            generate_lines(&results.ast)?
+        } else {
+            arm_emitter::generate_lines(&results.ast)?
+        };
+        if peephole {
+            peephole::optimize(&mut lines);
+        }
+        if executable {
+            lines.extend(runtime::lines());
+            elf::executable(&encode(&lines)?)?
+        } else if object {
+            elf::object_file(&encode(&lines)?)
+        } else {
+            arm::print(&lines).into_bytes()
+        }
+    };
+    match output {
+        Some(file) => {
+            fs::write(file, assembly)?;
+            if executable {
+                fs::set_permissions(file, fs::Permissions::from_mode(0o755))?;
+            }
+            Ok(())
+        }
+        None => Ok(std::io::stdout().write_all(&assembly)?),
+    }
+}
+
+/// Prints the tree of a source file as a Graphviz graph, or with `--cfg` the
+/// control-flow graph of the code `compile --tree` generates for it.
+fn dot(args: &[String]) -> Result<(), CompileError> {
+    let graph = match args {
+        [file] => ast_to_dot(&parse(&read(file)?)?),
+        [flag, file] if flag == "--cfg" => cfg_to_dot(&parse(&read(file)?)?)?,
+        _ => return Err(usage_error()),
+    };
+    print!("{}", graph);
+    Ok(())
+}
+
+/// Prints the tree of a source file as S-expressions, or with `--json` as
+/// JSON, with where its statements start. With `--ir`, prints the IR it is
+/// lowered to instead, and with `--ssa` the optimized IR in SSA form.
+fn dump(args: &[String]) -> Result<(), CompileError> {
+    let dumped = match args {
+        [file] => {
+            let (ast, locations) = parse_with_locations(&read(file)?)?;
+            to_sexp_with_locations(&ast, &locations)
+        }
+        [flag, file] if flag == "--sexp" => {
+            let (ast, locations) = parse_with_locations(&read(file)?)?;
+            to_sexp_with_locations(&ast, &locations)
+        }
+        [flag, file] if flag == "--json" => {
+            let (ast, locations) = parse_with_locations(&read(file)?)?;
+            to_json_with_locations(&ast, &locations)
+        }
+        [flag, file] if flag == "--ir" => lower(&parse(&read(file)?)?)?.to_string(),
+        [flag, file] if flag == "--ssa" => {
+            let mut program = lower(&parse(&read(file)?)?)?;
+            for function in &mut program.functions {
+                ssa::construct(function);
+                optimize_ssa(function);
+            }
+            program.to_string()
+        }
+        _ => return Err(usage_error()),
+    };
+    print!("{}", dumped);
+    Ok(())
+}
+
+/// Formats files in place. With `--check`, only lists the files that aren't
+/// formatted and fails if there are any.
+fn fmt(args: &[String]) -> ExitCode {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if files.is_empty() {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for file in files {
+        match fmt_file(file, check) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("{} is not formatted", file);
+                status = ExitCode::FAILURE;
+            }
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+    status
+}
+
+/// Returns false if `check` is set and the file isn't formatted.
+fn fmt_file(file: &str, check: bool) -> Result<bool, CompileError> {
+    let source = read(file)?;
+    let formatted = format_source(&source)?;
+    if formatted == source {
+        return Ok(true);
+    }
+    if !check {
+        fs::write(file, formatted)?;
+    }
+    Ok(!check)
 }
@@ -0,0 +1,56 @@
+//! Runs x86-64 code on the machine itself: the assembly is built into an
+//! executable with the host's `cc`, against its C library, and run in a
+//! temporary directory.
+
+use crate::error::CompileError;
+use crate::interpreter::runtime_error;
+use crate::x86_64::{self, Line};
+use std::fs;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Whether this machine can run x86-64 code built by `cc`.
+pub const AVAILABLE: bool = cfg!(all(target_arch = "x86_64", target_os = "linux"));
+
+/// Numbers the builds of this process, which may run at the same time.
+static BUILDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Builds and runs code, writing what it prints to `out`, and returns the
+/// exit status: the value `main` returns, truncated to a byte.
+pub fn run<W: Write + ?Sized>(lines: &[Line], out: &mut W) -> Result<i32, CompileError> {
+    let build = BUILDS.fetch_add(1, Ordering::Relaxed);
+    let directory =
+        std::env::temp_dir().join(format!("arm_compile_{}_{}", std::process::id(), build));
+    fs::create_dir_all(&directory)?;
+    let result = build_and_run(lines, &directory, out);
+    fs::remove_dir_all(&directory)?;
+    result
+}
+
+fn build_and_run<W: Write + ?Sized>(
+    lines: &[Line],
+    directory: &std::path::Path,
+    out: &mut W,
+) -> Result<i32, CompileError> {
+    let source = directory.join("program.s");
+    let executable = directory.join("program");
+    fs::write(&source, x86_64::print(lines))?;
+    let built = Command::new("cc")
+        .arg(&source)
+        .arg("-o")
+        .arg(&executable)
+        .output()?;
+    if !built.status.success() {
+        let message = String::from_utf8_lossy(&built.stderr).into_owned();
+        return Err(CompileError::CodeGenError(message));
+    }
+    let ran = Command::new(&executable).output()?;
+    out.write_all(&ran.stdout)?;
+    match (ran.status.code(), ran.status.signal()) {
+        (Some(status), _) => Ok(status),
+        (None, Some(signal)) => Err(runtime_error(format!("Killed by signal {}", signal))),
+        (None, None) => Err(runtime_error("Stopped without a status")),
+    }
+}
@@ -1,95 +1,128 @@
 use crate::ast::AST;
 use crate::error::CompileError;
+use peg::str::LineCol;
+use peg::Parse;
+use std::cell::Cell;
 
 peg::parser! {
   pub grammar lang_parser() for str {
 
-    rule _ = quiet!{[' ' | '\n' | '\t']*}
+    rule _ = quiet!{([' ' | '\n' | '\t' | '\r'] / comment())*}
+
+    /// A `//` comment running to the end of the line.
+    rule comment() = "//" [^ '\n']*
 
 
     pub rule Number() -> AST
-      = n:$(['0'..='9']+) { AST::Number(n.parse().unwrap()) }
+      = n:$(['0'..='9']+) {? n.parse().map(AST::Number).or(Err("a number that fits in 64 bits")) }
 
     pub rule Id() -> AST
       = n:$([ 'a'..='z' | 'A'..='Z']['a'..='z' | '_' |  'A'..='Z' | '0'..='9' ]*) { AST::Id(n.to_string()) }
 
     pub rule True() -> AST
-      = "true" { AST::Boolean(true) }
+      = "true" !ident_char() { AST::Boolean(true) }
 
     pub rule False() -> AST
-      = "false" { AST::Boolean(false) }
+      = "false" !ident_char() { AST::Boolean(false) }
 
     pub rule Null() -> AST
-      = "null" { AST::Null }
+      = "null" !ident_char() { AST::Null }
 
     pub rule Undefined() -> AST
-      = "undefined" { AST::Undefined }
+      = "undefined" !ident_char() { AST::Undefined }
 
     pub rule args() -> Vec<AST>
-      = expression() ** (_ "," _)
+      = a:measured_args() { a.0 }
 
-    pub rule ArrayLiteral() -> AST
-      =   "[" _ a: args() _ "]" { AST::ArrayLiteral(a) }
+    /// The arguments, with the depth of the deepest.
+    rule measured_args() -> (Vec<AST>, usize)
+      = a:measured_expression() ** (_ "," _) {
+            let depth = a.iter().map(|(_, depth)| *depth).max().unwrap_or(0);
+            (a.into_iter().map(|(arg, _)| arg).collect(), depth)
+        }
 
-    pub rule ArrayLookup() -> AST
-        = id:Id() _ "[" _ e:expression() _ "]" { AST::ArrayLookup {array: Box::new(id),index: Box::new(e)} }
+    pub rule ArrayLiteral() -> Measured
+      =   "[" _ a: measured_args() _ "]" {? measure(a.1 + 1).map(|depth| (AST::ArrayLiteral(a.0), depth)) }
 
-    ///
-    ///
-    pub rule call() -> AST
-      = callee:Id() _ "(" _ a:args() _ ")" {
-            if callee.to_string() == "assert" {
+    pub rule ArrayLookup() -> Measured
+        = id:Id() _ "[" _ e:measured_expression() _ "]" {?
+            measure(e.1.max(1) + 1).map(|depth| (AST::ArrayLookup {array: Box::new(id),index: Box::new(e.0)}, depth))
+        }
+
+    pub rule call() -> Measured
+      = callee:Id() _ "(" _ a:measured_args() _ ")" {?
+            let depth = measure(a.1 + 1)?;
+            let a = a.0;
+            let call = if callee.to_string() == "assert" {
                 let mut iter = a.into_iter().take(1);
-                let ast: AST = iter.next().unwrap();
-                AST::Assert(Box::new(ast))
+                let ast: AST = iter.next().ok_or("an argument for assert")?;
+                Ok(AST::Assert(Box::new(ast)))
             } else if callee.to_string() == "length" {
                 let mut iter = a.into_iter().take(1);
-                let ast: AST = iter.next().unwrap();
-                AST::ArrayLength(Box::new(ast))
+                let ast: AST = iter.next().ok_or("an argument for length")?;
+                Ok(AST::ArrayLength(Box::new(ast)))
             }else if callee.to_string() == "print" {
                 let mut iter = a.into_iter().take(1);
-                let ast: AST = iter.next().unwrap();
-                AST::Print(Box::new(ast))
+                let ast: AST = iter.next().ok_or("an argument for print")?;
+                Ok(AST::Print(Box::new(ast)))
             } else {
-              AST::Call {
+              Ok(AST::Call {
                 callee: callee.to_string(),
                 args:a.to_vec()
-              }
-            }
+              })
+            };
+            call.map(|call| (call, depth))
     }
 
-    pub rule atom() -> AST
-      = call() / ArrayLiteral() / ArrayLookup() /  True() / False() / Null() / Undefined() / Id() / Number()
+    pub rule atom() -> Measured
+      = call() / ArrayLiteral() / ArrayLookup()
+      / a:(True() / False() / Null() / Undefined() / Id() / Number()) { (a, 1) }
 
     /// allow whitespaces before after
-    pub rule expression() -> AST = _ e:expressionPrecedence() _ {e }
+    pub rule expression() -> AST = e:measured_expression() { e.0 }
 
-    pub rule expressionPrecedence() -> AST = precedence!{
-        x:(@) _ "==" _ y:@ { AST::Equal{left:x.into(), right:y.into()} }
-        x:(@) _ "!=" _ y:@ { AST::NotEqual{left:x.into(), right:y.into()} }
+    /// An expression, with the depth of its tree.
+    ///
+    /// Cached, because the alternatives of `statement` and `atom` parse the
+    /// same expression again after backtracking, which takes exponential time
+    /// on nested brackets that fail to parse.
+    #[cache]
+    rule measured_expression() -> Measured = nested(<_ e:expressionPrecedence() _ {? e.ok_or("") }>)
+
+    /// Chains of operators and of `!`s build trees as deep as they are long
+    /// without recursing, so every node checks its depth before it is built,
+    /// and is None if it is too deep.
+    pub rule expressionPrecedence() -> Option<Measured> = precedence!{
+        x:(@) _ "==" _ y:@ { binary(x, y, |left, right| AST::Equal{left, right}) }
+        x:(@) _ "!=" _ y:@ { binary(x, y, |left, right| AST::NotEqual{left, right}) }
          --
-        x:(@) _ ">=" _ y:@ { AST::GreaterThanEqual{left:x.into(), right:y.into()} }
-        x:(@) _ ">" _ y:@ { AST::GreaterThan{left:x.into(), right:y.into()} }
-        x:(@) _ "<=" _ y:@ { AST::LessThanEqual{left:x.into(), right:y.into()} }
-        x:(@) _ "<" _ y:@ { AST::LessThan{left:x.into(), right:y.into()} }
+        x:(@) _ ">=" _ y:@ { binary(x, y, |left, right| AST::GreaterThanEqual{left, right}) }
+        x:(@) _ ">" _ y:@ { binary(x, y, |left, right| AST::GreaterThan{left, right}) }
+        x:(@) _ "<=" _ y:@ { binary(x, y, |left, right| AST::LessThanEqual{left, right}) }
+        x:(@) _ "<" _ y:@ { binary(x, y, |left, right| AST::LessThan{left, right}) }
         --
-        x:(@) _ "+" _ y:@ { AST::Add{left:x.into(), right:y.into()} }
-        x:(@) _ "-" _ y:@ { AST::Subtract{left:x.into(), right:y.into()} }
+        x:(@) _ "+" _ y:@ { binary(x, y, |left, right| AST::Add{left, right}) }
+        x:(@) _ "-" _ y:@ { binary(x, y, |left, right| AST::Subtract{left, right}) }
         --
-        x:(@) _ "*" _ y:@ { AST::Multiply{left:x.into(), right:y.into()} }
-        x:(@) _ "/" _ y:@ { AST::Divide{left:x.into(), right:y.into()} }
+        x:(@) _ "*" _ y:@ { binary(x, y, |left, right| AST::Multiply{left, right}) }
+        x:(@) _ "/" _ y:@ { binary(x, y, |left, right| AST::Divide{left, right}) }
         --
-       "!" _ x:@ { AST::Not(x.into()) }
+       // All the `!`s at once, rather than recursing for every one.
+       nots:("!" _)+ x:@ {
+            let (x, depth) = x?;
+            let depth = measure(depth + nots.len()).ok()?;
+            Some((nots.iter().fold(x, |x, _| AST::Not(x.into())), depth))
+        }
         --
-        "(" _ v:expression() _ ")" { v }
-        n :atom() {n}
+        "(" _ v:measured_expression() _ ")" { Some(v) }
+        n :atom() { Some(n) }
     }
 
     ///
     /// Statements
     ///
     pub rule returnStmt() -> AST
-            = RETURN()  _ e:expression() _ ";" {
+            = RETURN()  _ e:expression() _ ";" _ {
             AST::Return {
                 term: e.into()
             }
@@ -98,22 +131,22 @@ peg::parser! {
    pub rule exprStmt() -> AST
             = e:expression() _ ";" _  { e }
 
-   pub  rule ifStmt() -> AST
-            = "if"  _ "(" _ conditional: expression()  _ ")"  _ consequence: statement() _ ELSE()  _ alternative: statement() _ {
-            AST::IfNode {
+   pub  rule ifStmt() -> Located
+            = "if"  _ "(" _ conditional: expression()  _ ")"  _ consequence: located_statement() _ ELSE()  _ alternative: located_statement() _ {
+            (AST::IfNode {
                 conditional: conditional.into(),
-                consequence: consequence.into(),
-                alternative: alternative.into()
-            }
+                consequence: consequence.0.into(),
+                alternative: alternative.0.into()
+            }, [consequence.1, alternative.1].concat())
         }
 
 
-   pub rule whileStmt() -> AST
-            = WHILE()   "(" _ conditional: expression()  _ ")"  _   body: statement() _ {
-            AST::While {
+   pub rule whileStmt() -> Located
+            = WHILE()   "(" _ conditional: expression()  _ ")"  _   body: located_statement() _ {
+            (AST::While {
                 conditional: conditional.into(),
-                body: body.into()
-            }
+                body: body.0.into()
+            }, body.1)
         }
 
   pub rule varStmt() -> AST
@@ -138,9 +171,10 @@ peg::parser! {
                 unreachable!()
             }
         }
-   pub rule blockStmt() -> AST
-            =  "{" _  statements:statement()* _ "}" {
-            AST::Block(statements.to_vec())
+   pub rule blockStmt() -> Located
+            =  "{" _  statements:located_statement()* _ "}" _ {
+            let (statements, starts): (Vec<AST>, Vec<Vec<usize>>) = statements.into_iter().unzip();
+            (AST::Block(statements), starts.concat())
         }
     pub rule parameters() -> Vec<String>
       = ids:Id() ** (_ "," _) {
@@ -153,7 +187,7 @@ peg::parser! {
             }).collect()
         }
 
-   pub rule functionStmt() -> AST
+   pub rule functionStmt() -> Located
             =  FUNCTION() _ id: Id() _ "(" _ p: parameters() _ ")" _ body:blockStmt() _ {
             if let AST::Id(name) = id {
 
@@ -165,11 +199,11 @@ peg::parser! {
                 //    }
                 // } else {
 
-                    AST::Function {
+                    (AST::Function {
                         name,
                         parameters: p,
-                        body: body.into()
-                    }
+                        body: body.0.into()
+                    }, body.1)
                 //}
 
 
@@ -179,32 +213,69 @@ peg::parser! {
     }
 
    pub rule statement() -> AST
-        = returnStmt() / ifStmt() / whileStmt() / varStmt() / assignmentStmt() / blockStmt() / functionStmt() / exprStmt()
+        = s:located_statement() { s.0 }
+
+    /// A statement, with where it and the statements nested in it start, in
+    /// the order `nested_statements` lists them.
+    rule located_statement() -> Located
+        = start:position!() s:nested(<
+            ifStmt() / whileStmt() / blockStmt() / functionStmt()
+            / s:(returnStmt() / varStmt() / assignmentStmt() / exprStmt()) { (s, vec![]) }
+        >) {
+            (s.0, [vec![start], s.1].concat())
+        }
 
    pub rule parser() -> AST
-        = _ s:statement() ** _ {
+        = p:program() { p.0 }
+
+    /// The program, with where its statements start, as numbered by
+    /// `parse_with_locations`.
+    pub rule program() -> (AST, Vec<Option<usize>>)
+        = _ s:located_statement() ** _ _ {
             if s.len() == 1 {
 
-                    return s.first().unwrap().clone();
+                    let (statement, starts) = s.into_iter().next().unwrap();
+                    return (statement, starts.into_iter().map(Some).collect());
 
             }
-                AST::Block(s)
+                let (statements, starts): (Vec<AST>, Vec<Vec<usize>>) = s.into_iter().unzip();
+                let starts = std::iter::once(None).chain(starts.concat().into_iter().map(Some));
+                (AST::Block(statements), starts.collect())
         }
 
 
+    /// Parses `r` one level deeper, failing once the input is nested more
+    /// than `MAX_NESTING` levels deep. `statement` and `expression` are the
+    /// rules the grammar recurses through.
+    ///
+    /// The second alternative only undoes `enter` and always fails. It
+    /// matches a character before failing, so that peg doesn't take it for
+    /// one that could match nothing.
+    rule nested<T>(r: rule<T>) -> T
+        = enter() v:r() leave() { v }
+        / quiet!{ leave() [_] {? Err("") } }
+
+    rule enter() = quiet!{ {? if enter_nesting() { Ok(()) } else { Err("") } } }
+
+    rule leave() = { leave_nesting() }
+
     ///
     /// keywords
     ///
     ///
     pub rule ASSIGN() = _ "=" _
 
-    pub rule FUNCTION() = "function"
+    /// keywords must not be followed by identifier characters, so that
+    /// `nullable` or `returned` are parsed as identifiers
+    rule ident_char() = ['a'..='z' | '_' | 'A'..='Z' | '0'..='9']
+
+    pub rule FUNCTION() = "function" !ident_char()
 
     pub rule IF() = "if"
 
-    pub rule ELSE() = "else"
+    pub rule ELSE() = "else" !ident_char()
 
-    pub rule RETURN() = "return"
+    pub rule RETURN() = "return" !ident_char()
 
     pub rule VAR() = "var" " "
 
@@ -215,13 +286,212 @@ peg::parser! {
   }
 }
 
+/// Deepest nesting accepted by `parse`.
+///
+/// The parser recurses once for every statement and bracketed expression
+/// nested in another, and the code generator, the passes and `Drop` for `AST`
+/// once for every level of the tree, so deep enough input overflows the stack.
+/// A long chain of operators, like `1+1+1+...`, builds a tree as deep as it
+/// is long and counts as nested too.
+pub const MAX_NESTING: usize = 1000;
+
+thread_local! {
+    /// How many `nested` rules the parser is in, and whether it went deeper
+    /// than `MAX_NESTING` since `parse` started.
+    static NESTING: Cell<(usize, bool)> = const { Cell::new((0, false)) };
+}
+
+/// Enters a `nested` rule, returning false if that is too deep. Once it was,
+/// every rule fails, so that the parser gives up quickly.
+fn enter_nesting() -> bool {
+    NESTING.with(|nesting| {
+        let (depth, too_deep) = nesting.get();
+        let too_deep = too_deep || depth >= MAX_NESTING;
+        nesting.set((depth + 1, too_deep));
+        !too_deep
+    })
+}
+
+fn leave_nesting() {
+    NESTING.with(|nesting| {
+        let (depth, too_deep) = nesting.get();
+        nesting.set((depth - 1, too_deep));
+    })
+}
+
+/// An expression, with the depth of its tree: 1 for a leaf.
+pub type Measured = (AST, usize);
+
+/// Returns `depth` if a tree that deep is accepted, and otherwise fails like
+/// a `nested` rule going too deep, so that the parser gives up before
+/// building it.
+fn measure(depth: usize) -> Result<usize, &'static str> {
+    if depth <= MAX_NESTING {
+        return Ok(depth);
+    }
+    NESTING.with(|nesting| {
+        let (depth, _) = nesting.get();
+        nesting.set((depth, true));
+    });
+    Err("")
+}
+
+/// Builds a binary operation, unless an operand or the operation is too deep.
+fn binary(
+    left: Option<Measured>,
+    right: Option<Measured>,
+    node: fn(Box<AST>, Box<AST>) -> AST,
+) -> Option<Measured> {
+    let ((left, left_depth), (right, right_depth)) = (left?, right?);
+    let depth = measure(left_depth.max(right_depth) + 1).ok()?;
+    Some((node(left.into(), right.into()), depth))
+}
+
+fn too_deep() -> CompileError {
+    CompileError::SyntaxError(format!(
+        "Program is nested more than {} levels deep",
+        MAX_NESTING
+    ))
+}
+
+/// Rejects trees deeper than `MAX_NESTING`, which the parser builds from long
+/// chains of operators without recursing. Gives up as soon as the tree gets
+/// too deep, so it doesn't recurse further than that itself.
+pub fn check_depth(ast: &AST) -> Result<(), CompileError> {
+    fn check(node: &AST, depth: usize) -> Result<(), CompileError> {
+        if depth > MAX_NESTING {
+            return Err(too_deep());
+        }
+        let children: Vec<&AST> = match node {
+            AST::Number(_) | AST::Id(_) | AST::Undefined | AST::Null | AST::Boolean(_) => vec![],
+            AST::Not(term)
+            | AST::Return { term }
+            | AST::ArrayLength(term)
+            | AST::Assert(term)
+            | AST::Print(term)
+            | AST::Function { body: term, .. }
+            | AST::Var { value: term, .. }
+            | AST::Assign { value: term, .. } => vec![term],
+            AST::Equal { left, right }
+            | AST::NotEqual { left, right }
+            | AST::Add { left, right }
+            | AST::Subtract { left, right }
+            | AST::Multiply { left, right }
+            | AST::Divide { left, right }
+            | AST::LessThan { left, right }
+            | AST::GreaterThan { left, right }
+            | AST::LessThanEqual { left, right }
+            | AST::GreaterThanEqual { left, right }
+            | AST::While {
+                conditional: left,
+                body: right,
+            }
+            | AST::ArrayLookup {
+                array: left,
+                index: right,
+            } => vec![left, right],
+            AST::IfNode {
+                conditional,
+                consequence,
+                alternative,
+            } => vec![conditional, consequence, alternative],
+            AST::Call { args: nodes, .. }
+            | AST::Block(nodes)
+            | AST::ArrayLiteral(nodes)
+            | AST::Main(nodes) => nodes.iter().collect(),
+        };
+        children
+            .into_iter()
+            .try_for_each(|child| check(child, depth + 1))
+    }
+    check(ast, 1)
+}
+
+/// A statement, with where it and the statements nested in it start.
+pub type Located = (AST, Vec<usize>);
+
+/// Parses a program, with where its statements start, numbered as
+/// `parse_with_locations` describes.
+///
+/// The grammar runs on its own thread, since it takes a few kilobytes of stack
+/// for every level of nesting in an unoptimized build, more than a spawned
+/// thread's 2 MiB hold at `MAX_NESTING`.
+fn parse_program(input: &str) -> Result<(AST, Vec<Option<usize>>), CompileError> {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(PARSER_STACK_SIZE)
+            .spawn_scoped(scope, || parse_program_here(input))
+            .expect("failed to start the parser thread")
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Stack for the parser thread, enough for `MAX_NESTING` levels in an
+/// unoptimized build with room to spare.
+const PARSER_STACK_SIZE: usize = 64 << 20;
+
+fn parse_program_here(input: &str) -> Result<(AST, Vec<Option<usize>>), CompileError> {
+    NESTING.with(|nesting| nesting.set((0, false)));
+    let program = lang_parser::program(input);
+    if NESTING.with(|nesting| nesting.get().1) {
+        return Err(too_deep());
+    }
+    let program = program.map_err(CompileError::ParseError)?;
+    check_depth(&program.0)?;
+    Ok(program)
+}
+
 pub fn parse(input: &str) -> Result<AST, CompileError> {
-    lang_parser::parser(input).map_err(CompileError::ParseError)
+    parse_program(input).map(|(ast, _)| ast)
+}
+
+/// The statements directly inside a statement, in source order. The body of a
+/// function counts as its statements, not as a block statement.
+pub fn nested_statements(statement: &AST) -> Vec<&AST> {
+    match statement {
+        AST::Block(statements) | AST::Main(statements) => statements.iter().collect(),
+        AST::IfNode {
+            consequence,
+            alternative,
+            ..
+        } => vec![consequence, alternative],
+        AST::While { body, .. } => vec![body],
+        AST::Function { body, .. } => match body.as_ref() {
+            AST::Block(statements) => statements.iter().collect(),
+            body => vec![body],
+        },
+        _ => vec![],
+    }
+}
+
+/// Counts a statement and all the statements nested in it.
+pub fn statement_count(statement: &AST) -> usize {
+    1 + nested_statements(statement)
+        .into_iter()
+        .map(statement_count)
+        .sum::<usize>()
+}
+
+/// Parses like `parse`, and also returns where every statement starts.
+///
+/// Statements are numbered from 0 in the order they start in the source,
+/// which is the order a pre-order walk over `nested_statements` visits them
+/// in, starting at the root. The block `parse` wraps around several top-level
+/// statements isn't in the source and has no location.
+pub fn parse_with_locations(input: &str) -> Result<(AST, Vec<Option<LineCol>>), CompileError> {
+    let (ast, starts) = parse_program(input)?;
+    let locations = starts
+        .into_iter()
+        .map(|start| start.map(|start| input.position_repr(start)))
+        .collect();
+    Ok((ast, locations))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::generator::ProgramGenerator;
 
     #[test]
     fn number() {
@@ -417,8 +687,6 @@ mod tests {
         // 42 == (4+2*(12-2) + 3*(5+1)
     }
 
-    ///
-    ///
     #[test]
     fn comparison() {
         let expected_ast = AST::NotEqual {
@@ -482,8 +750,6 @@ mod tests {
             lang_parser::expression("1<=2").expect("Parser failed")
         )
     }
-    ///
-    ///
     #[test]
     fn comparison_expr() {
         let expected_ast = AST::Equal {
@@ -1046,8 +1312,7 @@ mod tests {
             body: AST::Block(vec![AST::Var {
                 name: "x".to_string(),
                 value: AST::Number(1).into(),
-            }
-            .into()])
+            }])
             .into(),
             parameters: vec![],
         };
@@ -1063,6 +1328,7 @@ var x = 1;
 }"#,
         )
         .expect("Parser failed");
+        assert_eq!(expected_ast, ast);
 
         println!("{}", expected_ast);
         assert_eq!(
@@ -2226,4 +2492,198 @@ var x = 1;
             assert_eq!(statements.len(), 2, "Should have two functions");
         }
     }
+
+    // ===== Crash regressions found by fuzzing =====
+
+    #[test]
+    fn overflowing_number() {
+        assert!(matches!(
+            parse("99999999999999999999;"),
+            Err(CompileError::ParseError(_))
+        ));
+        assert_eq!(
+            AST::Number(u64::MAX),
+            lang_parser::expression("18446744073709551615").expect("Parser failed")
+        );
+    }
+
+    #[test]
+    fn builtins_without_arguments() {
+        assert!(parse("assert();").is_err());
+        assert!(parse("length();").is_err());
+        assert!(parse("print();").is_err());
+    }
+
+    #[test]
+    fn deeply_nested_expressions() {
+        for source in [
+            format!("{}1{};", "(".repeat(10_000), ")".repeat(10_000)),
+            format!("{}1;", "!".repeat(10_000)),
+            format!("1{};", "+1".repeat(10_000)),
+            format!("{}{}", "{".repeat(10_000), "}".repeat(10_000)),
+            format!("x = {}1{};", "[".repeat(10_000), "]".repeat(10_000)),
+            format!(
+                "if (1) {{ }} {} else {{ }}",
+                "else if (1) { } ".repeat(10_000)
+            ),
+        ] {
+            assert!(matches!(parse(&source), Err(CompileError::SyntaxError(_))));
+        }
+    }
+
+    #[test]
+    fn nesting_below_the_limit() {
+        let depth = MAX_NESTING - 10;
+        assert!(parse(&format!("{}1{};", "(".repeat(depth), ")".repeat(depth))).is_ok());
+        assert!(parse(&format!("1{};", "+1".repeat(depth))).is_ok());
+        let statements = "if (1) { } else { } ".repeat(1_000);
+        assert!(parse(&format!("function main() {{ {} }}", statements)).is_ok());
+    }
+
+    #[test]
+    fn long_flat_expression() {
+        let source = format!("x = 1{};", " + 2 * 3 - 4 / 5 < 6".repeat(150));
+        assert!(parse(&source).is_ok());
+        let source = format!("x = f({});", vec!["1 + 2"; 5_000].join(", "));
+        assert!(parse(&source).is_ok());
+    }
+
+    #[test]
+    fn long_else_if_chain() {
+        let source = format!(
+            "function main() {{ if (x == 0) {{ }} {} else {{ }} }}",
+            "else if (x == 1) { x = 2; } ".repeat(300)
+        );
+        assert!(parse(&source).is_ok());
+    }
+
+    #[test]
+    fn nesting_over_the_limit() {
+        let depth = MAX_NESTING + 1;
+        for source in [
+            format!("x = {}1{};", "(".repeat(depth), ")".repeat(depth)),
+            format!("x = 1{};", "+1".repeat(depth)),
+            format!("x = {}1;", "!".repeat(depth)),
+            format!("{}{}", "{".repeat(depth), "}".repeat(depth)),
+        ] {
+            assert!(matches!(parse(&source), Err(CompileError::SyntaxError(_))));
+        }
+    }
+
+    #[test]
+    fn identifiers_starting_with_keywords() {
+        for name in ["trueish", "falsey", "nullable", "undefined_x", "returned"] {
+            assert_eq!(
+                AST::Id(name.to_string()),
+                lang_parser::expression(name).expect("Parser failed")
+            );
+        }
+        assert_eq!(
+            AST::Assign {
+                name: "returned".to_string(),
+                value: AST::Id("nullable".to_string()).into(),
+            },
+            lang_parser::statement("returned = nullable;").expect("Parser failed")
+        );
+        assert_eq!(
+            AST::Call {
+                callee: "functional".to_string(),
+                args: vec![],
+            },
+            lang_parser::statement("functional();").expect("Parser failed")
+        );
+    }
+
+    #[test]
+    fn statements_after_blocks_and_returns() {
+        let expected_ast = AST::Block(vec![
+            AST::Block(vec![]),
+            AST::Return {
+                term: AST::Number(1).into(),
+            },
+            AST::Assign {
+                name: "x".to_string(),
+                value: AST::Number(2).into(),
+            },
+        ]);
+        assert_eq!(
+            expected_ast,
+            lang_parser::statement("{ {} return 1; x = 2; }").expect("Parser failed")
+        );
+        assert_eq!(
+            AST::Block(vec![]),
+            lang_parser::parser("{}\n").expect("Parser failed")
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let expected_ast = AST::Block(vec![
+            AST::Var {
+                name: "x".to_string(),
+                value: AST::Divide {
+                    left: AST::Number(4).into(),
+                    right: AST::Number(2).into(),
+                }
+                .into(),
+            },
+            AST::Print(AST::Id("x".to_string()).into()),
+        ]);
+        assert_eq!(
+            expected_ast,
+            parse("// leading\nvar x = 4 / // halved\n 2;\r\nprint(x); // trailing")
+                .expect("Parser failed")
+        );
+        assert!(parse(&format!("// {}\nprint(1);", "(".repeat(10_000))).is_ok());
+    }
+
+    #[test]
+    fn backtracking_over_nested_brackets() {
+        // Took time exponential in the number of brackets before `expression`
+        // was cached.
+        let source = format!("x = {};", "[e==B/".repeat(40));
+        assert!(matches!(parse(&source), Err(CompileError::ParseError(_))));
+    }
+
+    #[test]
+    fn statement_locations() {
+        let source = "function f(a) {\n    if (a) { return 1; } else {}\n    while (a) a = 0;\n}\nprint(f(2));";
+        let (ast, locations) = parse_with_locations(source).expect("Parser failed");
+        assert_eq!(parse(source).expect("Parser failed"), ast);
+        let locations: Vec<Option<String>> = locations
+            .iter()
+            .map(|location| location.map(|l| l.to_string()))
+            .collect();
+        let at = |location: &str| Some(location.to_string());
+        assert_eq!(
+            vec![
+                None,
+                at("1:1"),
+                at("2:5"),
+                at("2:12"),
+                at("2:14"),
+                at("2:31"),
+                at("3:5"),
+                at("3:15"),
+                at("5:1"),
+            ],
+            locations
+        );
+        assert_eq!(locations.len(), statement_count(&ast));
+
+        let (_, locations) = parse_with_locations("{ print(1); }").expect("Parser failed");
+        assert_eq!(2, locations.len());
+        assert!(locations.iter().all(Option::is_some));
+        assert!(parse_with_locations("print(1").is_err());
+    }
+
+    #[test]
+    fn every_statement_has_a_location() {
+        for seed in 0..50 {
+            let source = ProgramGenerator::new(seed).program().to_string();
+            let (ast, locations) = parse_with_locations(&source).expect("Parser failed");
+            assert_eq!(statement_count(&ast), locations.len(), "seed {}", seed);
+            assert!(locations[1..].iter().all(Option::is_some), "seed {}", seed);
+        }
+    }
 }
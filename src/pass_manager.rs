@@ -0,0 +1,343 @@
+use crate::ast::AST;
+use crate::constant_folding::ConstantFolding;
+use crate::dead_code::DeadCodeElimination;
+use crate::error::{CompileError, Diagnostic};
+use crate::inline::{Inlining, DEFAULT_INLINE_THRESHOLD};
+use crate::printer::print_program;
+use peg::str::LineCol;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// A transformation or analysis of the whole program, run by a `PassManager`
+/// between `parser::parse` and code generation.
+pub trait Pass {
+    /// Identifies the pass in diagnostics and in `stop_after`/`print_after`.
+    fn name(&self) -> &'static str;
+
+    /// Returns the transformed program, or the same one for an analysis.
+    /// Problems that shouldn't stop compilation are reported to
+    /// `diagnostics`.
+    fn run(&mut self, ast: AST, diagnostics: &mut Diagnostics) -> Result<AST, CompileError>;
+}
+
+/// Collects the diagnostics reported by passes, labelled with the pass that
+/// reported them.
+pub struct Diagnostics {
+    pass: &'static str,
+    reported: Vec<Diagnostic>,
+    locations: Vec<Option<LineCol>>,
+}
+
+impl Diagnostics {
+    pub fn warning(&mut self, message: impl Into<String>) {
+        self.report(None, message.into());
+    }
+
+    /// Reports a warning located at a statement, numbered as in
+    /// `parser::parse_with_locations`. The numbers only hold until a pass
+    /// adds, removes or moves statements.
+    pub fn statement_warning(&mut self, statement: usize, message: impl Into<String>) {
+        let location = self.locations.get(statement).copied().flatten();
+        self.report(location, message.into());
+    }
+
+    fn report(&mut self, location: Option<LineCol>, message: String) {
+        self.reported.push(Diagnostic {
+            pass: self.pass,
+            location,
+            message,
+        });
+    }
+}
+
+/// What running the passes produced.
+#[derive(Debug)]
+pub struct PassResults {
+    pub ast: AST,
+    pub diagnostics: Vec<Diagnostic>,
+    /// How long each pass that ran took, in order.
+    pub timings: Vec<(&'static str, Duration)>,
+    /// Whether the pipeline stopped early because of `stop_after`.
+    pub stopped: bool,
+}
+
+/// Settings of the passes in the standard pipeline.
+#[derive(Debug, Clone, Copy)]
+pub struct PassOptions {
+    /// Largest function body, in nodes, that `Inlining` copies to its call
+    /// sites. 0 turns inlining off.
+    pub inline_threshold: usize,
+}
+
+impl Default for PassOptions {
+    fn default() -> PassOptions {
+        PassOptions {
+            inline_threshold: DEFAULT_INLINE_THRESHOLD,
+        }
+    }
+}
+
+/// Runs an ordered sequence of passes over the program.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    /// Name of the pass after which to stop, skipping the following ones.
+    pub stop_after: Option<String>,
+    /// Names of the passes after which the program is printed, or `all`.
+    pub print_after: Vec<String>,
+    /// Where the statements of the program start, from
+    /// `parser::parse_with_locations`, for `Diagnostics::statement_warning`.
+    pub locations: Vec<Option<LineCol>>,
+}
+
+impl PassManager {
+    pub fn new() -> PassManager {
+        Default::default()
+    }
+
+    /// The passes `compile` runs between parsing and code generation.
+    ///
+    /// Dead code is eliminated before inlining, which moves statements, so
+    /// that its warnings can be located. Inlining removes the functions it
+    /// leaves without callers itself.
+    pub fn standard(options: &PassOptions) -> PassManager {
+        let mut passes = PassManager::new();
+        passes
+            .add(ConstantFolding)
+            .add(DeadCodeElimination)
+            .add(Inlining {
+                threshold: options.inline_threshold,
+            });
+        passes
+    }
+
+    /// Appends a pass to the pipeline.
+    pub fn add(&mut self, pass: impl Pass + 'static) -> &mut PassManager {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    pub fn pass_names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    fn check_pass_name(&self, name: &str) -> Result<(), CompileError> {
+        if self.passes.iter().any(|pass| pass.name() == name) {
+            Ok(())
+        } else {
+            Err(CompileError::UsageError(format!(
+                "Unknown pass: {} (expected one of: {})",
+                name,
+                self.pass_names().join(", ")
+            )))
+        }
+    }
+
+    /// Checks that `stop_after` and `print_after` name passes of the
+    /// pipeline, as `run` does before running any.
+    pub fn check_pass_names(&self) -> Result<(), CompileError> {
+        if let Some(name) = &self.stop_after {
+            self.check_pass_name(name)?;
+        }
+        for name in self.print_after.iter().filter(|name| *name != "all") {
+            self.check_pass_name(name)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the passes in order. The program is printed to `dumps`, as
+    /// source text headed by a comment naming the pass, after every pass
+    /// listed in `print_after`.
+    pub fn run(
+        &mut self,
+        mut ast: AST,
+        dumps: &mut dyn Write,
+    ) -> Result<PassResults, CompileError> {
+        self.check_pass_names()?;
+
+        let mut diagnostics = Diagnostics {
+            pass: "",
+            reported: vec![],
+            locations: self.locations.clone(),
+        };
+        let mut timings = vec![];
+        let mut stopped = false;
+        for pass in self.passes.iter_mut() {
+            let name = pass.name();
+            diagnostics.pass = name;
+            let start = Instant::now();
+            ast = pass.run(ast, &mut diagnostics)?;
+            timings.push((name, start.elapsed()));
+
+            if self.print_after.iter().any(|p| p == name || p == "all") {
+                writeln!(dumps, "// After {}", name)?;
+                write!(dumps, "{}", print_program(&ast))?;
+            }
+            if self.stop_after.as_deref() == Some(name) {
+                stopped = true;
+                break;
+            }
+        }
+        Ok(PassResults {
+            ast,
+            diagnostics: diagnostics.reported,
+            timings,
+            stopped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::visitor_mut::VisitorMut;
+
+    /// Replaces every number with another.
+    struct Replace(&'static str, u64);
+
+    impl VisitorMut for Replace {
+        fn visit_number_mut(&mut self, node: &mut AST) {
+            *node = AST::Number(self.1);
+        }
+    }
+
+    impl Pass for Replace {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn run(
+            &mut self,
+            mut ast: AST,
+            diagnostics: &mut Diagnostics,
+        ) -> Result<AST, CompileError> {
+            use crate::visitor::AstVisitor;
+            ast.visit_mut(self);
+            diagnostics.warning(format!("replaced numbers with {}", self.1));
+            Ok(ast)
+        }
+    }
+
+    struct Fail;
+
+    impl Pass for Fail {
+        fn name(&self) -> &'static str {
+            "fail"
+        }
+
+        fn run(&mut self, _ast: AST, _diagnostics: &mut Diagnostics) -> Result<AST, CompileError> {
+            Err(CompileError::CodeGenError("failed".to_string()))
+        }
+    }
+
+    fn pipeline() -> PassManager {
+        let mut manager = PassManager::new();
+        manager.add(Replace("one", 1)).add(Replace("two", 2));
+        manager
+    }
+
+    fn run(manager: &mut PassManager, dumps: &mut Vec<u8>) -> Result<PassResults, CompileError> {
+        manager.run(parse("print(7);").expect("Parser failed"), dumps)
+    }
+
+    #[test]
+    fn runs_passes_in_order() {
+        let results = run(&mut pipeline(), &mut vec![]).expect("Passes failed");
+        assert_eq!(parse("print(2);").expect("Parser failed"), results.ast);
+        assert_eq!(
+            vec![
+                Diagnostic {
+                    pass: "one",
+                    location: None,
+                    message: "replaced numbers with 1".to_string()
+                },
+                Diagnostic {
+                    pass: "two",
+                    location: None,
+                    message: "replaced numbers with 2".to_string()
+                },
+            ],
+            results.diagnostics
+        );
+        assert_eq!(
+            vec!["one", "two"],
+            results
+                .timings
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+        );
+        assert!(!results.stopped);
+    }
+
+    #[test]
+    fn stop_after() {
+        let mut manager = pipeline();
+        manager.stop_after = Some("one".to_string());
+        let results = run(&mut manager, &mut vec![]).expect("Passes failed");
+        assert_eq!(parse("print(1);").expect("Parser failed"), results.ast);
+        assert_eq!(1, results.timings.len());
+        assert!(results.stopped);
+    }
+
+    #[test]
+    fn print_after() {
+        let mut manager = pipeline();
+        manager.print_after = vec!["two".to_string()];
+        let mut dumps = vec![];
+        run(&mut manager, &mut dumps).expect("Passes failed");
+        assert_eq!(
+            "// After two\nprint(2);\n",
+            String::from_utf8(dumps).unwrap()
+        );
+
+        manager.print_after = vec!["all".to_string()];
+        let mut dumps = vec![];
+        run(&mut manager, &mut dumps).expect("Passes failed");
+        assert_eq!(
+            "// After one\nprint(1);\n// After two\nprint(2);\n",
+            String::from_utf8(dumps).unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_pass_names() {
+        let mut manager = pipeline();
+        manager.stop_after = Some("three".to_string());
+        assert!(matches!(
+            run(&mut manager, &mut vec![]),
+            Err(CompileError::UsageError(message)) if message == "Unknown pass: three (expected one of: one, two)"
+        ));
+        let mut manager = pipeline();
+        manager.print_after = vec!["three".to_string()];
+        assert!(run(&mut manager, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn standard_removes_helpers_inlined_everywhere() {
+        let source = "function helper(x) { return x * 2; }
+            function main() { print(helper(3)); return 0; }";
+        let mut manager = PassManager::standard(&PassOptions::default());
+        let results = manager
+            .run(parse(source).expect("Parser failed"), &mut vec![])
+            .expect("Passes failed");
+        assert_eq!(
+            parse("function main() { print(3 * 2); return 0; }").expect("Parser failed"),
+            results.ast
+        );
+        assert!(results.diagnostics.is_empty());
+        let assembly = crate::arm_emitter::generate(&results.ast).expect("Codegen failed");
+        assert!(!assembly.contains("helper:"), "{}", assembly);
+    }
+
+    #[test]
+    fn errors_stop_the_pipeline() {
+        let mut manager = pipeline();
+        manager.add(Fail).add(Replace("three", 3));
+        assert!(matches!(
+            run(&mut manager, &mut vec![]),
+            Err(CompileError::CodeGenError(_))
+        ));
+    }
+}
@@ -0,0 +1,414 @@
+//! Peephole optimization of the ARM code the code generators produce.
+//!
+//! The rules below are applied to windows of consecutive lines until none
+//! applies any more:
+//!
+//! 1. `push {rA, ip}` directly followed by `pop {rB, ip}` becomes
+//!    `mov rB, rA`, or nothing if they are the same register.
+//! 2. `push {rA, ip}`, one `mov` or `ldr` of a constant or an `fp` slot into
+//!    another register than rB, then `pop {rB, ip}` becomes `mov rB, rA`
+//!    followed by that instruction.
+//! 3. `ldr rD, =N` becomes `mov rD, #N`, or `mvn rD, #~N`, when the constant
+//!    fits in an immediate operand.
+//! 4. `cmp`, `mov<c> rD, #1`, `mov<!c> rD, #0`, `cmp rD, #0` and `beq L` (or
+//!    `bne L`) branch on the first comparison instead: the second `cmp`
+//!    goes, and the branch becomes `b<!c> L` (or `b<c> L`). rD is still set.
+//! 5. `mov rX, rX` goes, and so does a `mov` repeating the one before it.
+//! 6. A `b L` directly followed by the label `L` goes.
+//! 7. Instructions after an unconditional branch or a `pop` into `pc`, up to
+//!    the next label, are never run and go.
+//! 8. `ldr rY, [M]` directly after `str rX, [M]` becomes `mov rY, rX`, or
+//!    goes if rY is rX.
+
+use crate::arm::{is_immediate, Address, Condition, Instruction, Line, Operand, Register, FP, IP};
+
+/// A rule looks at the lines from some point on, and gives the number of
+/// lines it replaces there and what it replaces them with.
+type Rule = fn(&[Line]) -> Option<(usize, Vec<Line>)>;
+
+/// Applies the rules until none applies.
+pub fn optimize(lines: &mut Vec<Line>) {
+    let rules: [Rule; 8] = [
+        push_pop,
+        push_load_pop,
+        literal_loads,
+        compare_and_branch,
+        redundant_moves,
+        branch_to_next,
+        unreachable,
+        store_and_load,
+    ];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut i = 0;
+        while i < lines.len() {
+            match rules.iter().find_map(|rule| rule(&lines[i..])) {
+                Some((replaced, replacement)) => {
+                    lines.splice(i..i + replaced, replacement);
+                    changed = true;
+                }
+                None => i += 1,
+            }
+        }
+    }
+}
+
+fn instruction(line: Option<&Line>) -> Option<&Instruction> {
+    match line? {
+        Line::Instruction(instruction) => Some(instruction),
+        _ => None,
+    }
+}
+
+/// The register pushed or popped with `ip` to keep the stack aligned.
+fn with_ip(registers: &[Register]) -> Option<Register> {
+    match registers {
+        [register, IP] => Some(*register),
+        _ => None,
+    }
+}
+
+fn mov(dest: Register, src: Register) -> Vec<Line> {
+    if dest == src {
+        vec![]
+    } else {
+        vec![Instruction::mov(dest, src).into()]
+    }
+}
+
+/// Rule 1.
+fn push_pop(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let (Instruction::Push(pushed), Instruction::Pop(popped)) =
+        (instruction(lines.first())?, instruction(lines.get(1))?)
+    else {
+        return None;
+    };
+    Some((2, mov(with_ip(popped)?, with_ip(pushed)?)))
+}
+
+/// Rule 2.
+fn push_load_pop(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let (Instruction::Push(pushed), load, Instruction::Pop(popped)) = (
+        instruction(lines.first())?,
+        instruction(lines.get(1))?,
+        instruction(lines.get(2))?,
+    ) else {
+        return None;
+    };
+    let (pushed, popped) = (with_ip(pushed)?, with_ip(popped)?);
+    let dest = match load {
+        Instruction::Move {
+            condition: Condition::Al,
+            dest,
+            src: Operand::Immediate(_),
+        }
+        | Instruction::MoveNot {
+            condition: Condition::Al,
+            dest,
+            src: Operand::Immediate(_),
+        }
+        | Instruction::LoadConstant { dest, .. }
+        | Instruction::Load {
+            condition: Condition::Al,
+            dest,
+            address: Address::Offset(FP, _),
+        } => *dest,
+        _ => return None,
+    };
+    if dest == popped || dest.0 >= IP.0 {
+        return None;
+    }
+    let mut replacement = mov(popped, pushed);
+    replacement.push(load.clone().into());
+    Some((3, replacement))
+}
+
+/// Rule 3.
+fn literal_loads(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let Instruction::LoadConstant { dest, value } = instruction(lines.first())? else {
+        return None;
+    };
+    if is_immediate(*value) {
+        Some((
+            1,
+            vec![Instruction::mov(*dest, Operand::Immediate(*value)).into()],
+        ))
+    } else if is_immediate(!value) {
+        let mvn = Instruction::MoveNot {
+            condition: Condition::Al,
+            dest: *dest,
+            src: Operand::Immediate(!value),
+        };
+        Some((1, vec![mvn.into()]))
+    } else {
+        None
+    }
+}
+
+/// Rule 4.
+fn compare_and_branch(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let [first, holds, fails, test, branch, ..] = lines else {
+        return None;
+    };
+    let Instruction::Compare { .. } = instruction(Some(first))? else {
+        return None;
+    };
+    let (
+        Instruction::Move {
+            condition,
+            dest,
+            src: Operand::Immediate(1),
+        },
+        Instruction::Move {
+            condition: inverse,
+            dest: cleared,
+            src: Operand::Immediate(0),
+        },
+        Instruction::Compare {
+            left: tested,
+            right: Operand::Immediate(0),
+        },
+        Instruction::Branch {
+            condition: on,
+            target,
+        },
+    ) = (
+        instruction(Some(holds))?,
+        instruction(Some(fails))?,
+        instruction(Some(test))?,
+        instruction(Some(branch))?,
+    )
+    else {
+        return None;
+    };
+    if *condition == Condition::Al
+        || *inverse != condition.inverse()
+        || cleared != dest
+        || tested != dest
+    {
+        return None;
+    }
+    let taken = match on {
+        Condition::Eq => condition.inverse(),
+        Condition::Ne => *condition,
+        _ => return None,
+    };
+    Some((
+        5,
+        vec![
+            first.clone(),
+            holds.clone(),
+            fails.clone(),
+            Instruction::b(target).when(taken).into(),
+        ],
+    ))
+}
+
+/// Rule 5.
+fn redundant_moves(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let first = instruction(lines.first())?;
+    let Instruction::Move {
+        condition: Condition::Al,
+        dest,
+        src,
+    } = first
+    else {
+        return None;
+    };
+    if *src == Operand::Register(*dest) {
+        return Some((1, vec![]));
+    }
+    if instruction(lines.get(1)) == Some(first) {
+        return Some((2, vec![first.clone().into()]));
+    }
+    None
+}
+
+/// Rule 6.
+fn branch_to_next(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let Instruction::Branch {
+        condition: Condition::Al,
+        target,
+    } = instruction(lines.first())?
+    else {
+        return None;
+    };
+    match lines.get(1)? {
+        Line::Label(label) if label == target => Some((1, vec![])),
+        _ => None,
+    }
+}
+
+/// Rule 7.
+fn unreachable(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    if !instruction(lines.first())?.ends_flow() {
+        return None;
+    }
+    let dead = lines[1..]
+        .iter()
+        .take_while(|line| !matches!(line, Line::Label(_)))
+        .position(|line| matches!(line, Line::Instruction(_)))?;
+    let mut kept = lines[..=dead + 1].to_vec();
+    kept.pop();
+    Some((dead + 2, kept))
+}
+
+/// Rule 8.
+fn store_and_load(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let (
+        Instruction::Store {
+            condition: Condition::Al,
+            src,
+            address,
+        },
+        Instruction::Load {
+            condition: Condition::Al,
+            dest,
+            address: loaded,
+        },
+    ) = (instruction(lines.first())?, instruction(lines.get(1))?)
+    else {
+        return None;
+    };
+    if address != loaded {
+        return None;
+    }
+    let mut replacement = vec![lines[0].clone()];
+    replacement.extend(mov(*dest, *src));
+    Some((2, replacement))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm;
+    use crate::arm_code_generator;
+    use crate::parser;
+
+    fn optimized(assembly: &str) -> String {
+        let mut lines = arm::parse(assembly).expect("Invalid assembly");
+        optimize(&mut lines);
+        arm::print(&lines)
+    }
+
+    #[test]
+    fn push_pop() {
+        assert_eq!("\tmov r1, r0\n", optimized("push {r0, ip}\npop {r1, ip}"));
+        assert_eq!("", optimized("push {r0, ip}\npop {r0, ip}"));
+        let saved = "\tpush {r0, ip}\n\tbl f\n\tpop {r1, ip}\n";
+        assert_eq!(saved, optimized(saved));
+    }
+
+    #[test]
+    fn push_load_pop() {
+        assert_eq!(
+            "\tmov r1, r0\n\tmov r0, #3\n",
+            optimized("push {r0, ip}\nmov r0, #3\npop {r1, ip}")
+        );
+        assert_eq!(
+            "\tmov r1, r0\n\tldr r0, [fp, #-8]\n",
+            optimized("push {r0, ip}\nldr r0, [fp, #-8]\npop {r1, ip}")
+        );
+        for kept in [
+            "\tpush {r0, ip}\n\tldr r0, [sp]\n\tpop {r1, ip}\n",
+            "\tpush {r0, ip}\n\tmov r1, #3\n\tpop {r1, ip}\n",
+            "\tpush {r0, ip}\n\tmov r0, r2\n\tpop {r1, ip}\n",
+        ] {
+            assert_eq!(kept, optimized(kept));
+        }
+    }
+
+    #[test]
+    fn literal_loads() {
+        assert_eq!("\tmov r0, #255\n", optimized("ldr r0, =255"));
+        assert_eq!("\tmov r0, #1020\n", optimized("ldr r0, =1020"));
+        assert_eq!("\tmov r0, #4278190080\n", optimized("ldr r0, =4278190080"));
+        assert_eq!("\tmvn r0, #0\n", optimized("ldr r0, =-1"));
+        assert_eq!("\tmvn r0, #255\n", optimized("ldr r0, =-256"));
+        assert_eq!("\tldr r0, =4380\n", optimized("ldr r0, =4380"));
+    }
+
+    #[test]
+    fn compare_and_branch() {
+        assert_eq!(
+            "\tcmp r1, r0\n\tmovlt r0, #1\n\tmovge r0, #0\n\tbge .L1\n",
+            optimized("cmp r1, r0\nmovlt r0, #1\nmovge r0, #0\ncmp r0, #0\nbeq .L1")
+        );
+        assert_eq!(
+            "\tcmp r1, r0\n\tmoveq r0, #1\n\tmovne r0, #0\n\tbeq .L1\n",
+            optimized("cmp r1, r0\nmoveq r0, #1\nmovne r0, #0\ncmp r0, #0\nbne .L1")
+        );
+        let other_register =
+            "\tcmp r1, r0\n\tmovlt r0, #1\n\tmovge r0, #0\n\tcmp r2, #0\n\tbeq .L1\n";
+        assert_eq!(other_register, optimized(other_register));
+    }
+
+    #[test]
+    fn redundant_moves() {
+        assert_eq!("", optimized("mov r0, r0"));
+        assert_eq!("\tmov r0, r1\n", optimized("mov r0, r1\nmov r0, r1"));
+        let swap = "\tmov r0, r1\n\tmov r1, r0\n";
+        assert_eq!(swap, optimized(swap));
+    }
+
+    #[test]
+    fn branch_to_next() {
+        assert_eq!(".L1:\n", optimized("b .L1\n.L1:"));
+        assert_eq!("\tbeq .L1\n.L1:\n", optimized("beq .L1\n.L1:"));
+        let other = "\tb .L2\n.L1:\n";
+        assert_eq!(other, optimized(other));
+    }
+
+    #[test]
+    fn unreachable() {
+        assert_eq!(
+            "\tb .L2\n\t.align 2\n.L1:\n\tmov r0, #1\n",
+            optimized("b .L2\nmov r0, #2\n.align 2\nbl f\n.L1:\nmov r0, #1")
+        );
+        assert_eq!(
+            "\tpop {fp, pc}\n.L1:\n",
+            optimized("pop {fp, pc}\nb .L1\n.L1:")
+        );
+        assert_eq!("\tbx lr\n", optimized("bx lr\nmov r0, r1"));
+        let conditional = "\tbeq .L1\n\tmov r0, #2\n";
+        assert_eq!(conditional, optimized(conditional));
+    }
+
+    #[test]
+    fn store_and_load() {
+        assert_eq!(
+            "\tstr r0, [fp, #-8]\n\tmov r1, r0\n",
+            optimized("str r0, [fp, #-8]\nldr r1, [fp, #-8]")
+        );
+        assert_eq!(
+            "\tstr r0, [fp, #-8]\n",
+            optimized("str r0, [fp, #-8]\nldr r0, [fp, #-8]")
+        );
+        let other = "\tstr r0, [fp, #-8]\n\tldr r1, [fp, #-12]\n";
+        assert_eq!(other, optimized(other));
+        let conditional = "\tstreq r0, [fp, #-8]\n\tldr r1, [fp, #-8]\n";
+        assert_eq!(conditional, optimized(conditional));
+    }
+
+    #[test]
+    fn fewer_instructions() {
+        let ast = parser::parse(
+            "function fib(n) { if (n < 2) { return n; } else { return fib(n - 1) + fib(n - 2); } }",
+        )
+        .expect("Parser failed");
+        let assembly = arm_code_generator::generate(&ast).expect("Code generation failed");
+        let optimized = optimized(&assembly);
+        let count = |assembly: &str| {
+            arm::parse(assembly)
+                .expect("Invalid assembly")
+                .iter()
+                .filter(|line| matches!(line, Line::Instruction { .. }))
+                .count()
+        };
+        assert!(count(&optimized) < count(&assembly));
+        // Only the push and pop saving fib(n - 1) across the second call stay.
+        assert_eq!(1, optimized.matches("pop {r1, ip}").count());
+        assert!(!optimized.contains("cmp r0, #0"));
+    }
+}
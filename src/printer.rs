@@ -0,0 +1,507 @@
+use crate::ast::AST;
+
+/// Source formatter for the `AST`.
+///
+/// The output is valid source text with four-space indentation, one statement
+/// per line and only the parentheses that precedence requires, so that
+/// `parse(&print_program(&ast)) == Ok(ast)` for any tree that `parser::parse`
+/// can produce. Trees that no source text parses to (an `AST::Main`, a
+/// function whose body isn't a block, a lookup on something other than an
+/// identifier, identifiers that are keywords...) are still printed, but do not
+/// round-trip.
+pub struct Printer {
+    out: String,
+    indent: usize,
+}
+
+const INDENT: &str = "    ";
+
+/// Prints a whole program, as returned by `parser::parse`.
+///
+/// A program with several top-level statements parses to an `AST::Block`,
+/// which is printed without the surrounding braces.
+pub fn print_program(ast: &AST) -> String {
+    let mut printer = Printer::new();
+    match ast {
+        AST::Block(statements) if statements.len() != 1 => {
+            for (i, statement) in statements.iter().enumerate() {
+                if i > 0 && is_function(statement) {
+                    printer.out.push('\n');
+                }
+                printer.statement(statement);
+                printer.out.push('\n');
+                if is_function(statement) && statements.get(i + 1).is_some_and(|s| !is_function(s))
+                {
+                    printer.out.push('\n');
+                }
+            }
+        }
+        _ => {
+            printer.statement(ast);
+            printer.out.push('\n');
+        }
+    }
+    printer.out
+}
+
+/// Prints a single node: expressions without a trailing `;`, statements as
+/// they would appear in a block.
+pub fn print(ast: &AST) -> String {
+    let mut printer = Printer::new();
+    if is_expression(ast) {
+        printer.expression(ast);
+    } else {
+        printer.statement(ast);
+    }
+    printer.out
+}
+
+fn is_function(ast: &AST) -> bool {
+    matches!(ast, AST::Function { .. })
+}
+
+fn is_expression(ast: &AST) -> bool {
+    !matches!(
+        ast,
+        AST::Return { .. }
+            | AST::Block(_)
+            | AST::IfNode { .. }
+            | AST::Function { .. }
+            | AST::Var { .. }
+            | AST::Assign { .. }
+            | AST::While { .. }
+            | AST::Main(_)
+    )
+}
+
+/// Binding strength of an expression, following the levels of
+/// `expressionPrecedence` in the grammar.
+fn precedence(ast: &AST) -> u8 {
+    match ast {
+        AST::Equal { .. } | AST::NotEqual { .. } => 1,
+        AST::LessThan { .. }
+        | AST::GreaterThan { .. }
+        | AST::LessThanEqual { .. }
+        | AST::GreaterThanEqual { .. } => 2,
+        AST::Add { .. } | AST::Subtract { .. } => 3,
+        AST::Multiply { .. } | AST::Divide { .. } => 4,
+        AST::Not(_) => 5,
+        _ => 6,
+    }
+}
+
+fn binary_operator(ast: &AST) -> Option<(&AST, &'static str, &AST)> {
+    let (left, operator, right) = match ast {
+        AST::Equal { left, right } => (left, "==", right),
+        AST::NotEqual { left, right } => (left, "!=", right),
+        AST::LessThan { left, right } => (left, "<", right),
+        AST::GreaterThan { left, right } => (left, ">", right),
+        AST::LessThanEqual { left, right } => (left, "<=", right),
+        AST::GreaterThanEqual { left, right } => (left, ">=", right),
+        AST::Add { left, right } => (left, "+", right),
+        AST::Subtract { left, right } => (left, "-", right),
+        AST::Multiply { left, right } => (left, "*", right),
+        AST::Divide { left, right } => (left, "/", right),
+        _ => return None,
+    };
+    Some((left, operator, right))
+}
+
+impl Default for Printer {
+    fn default() -> Printer {
+        Printer::new()
+    }
+}
+
+impl Printer {
+    pub fn new() -> Printer {
+        Printer {
+            out: String::new(),
+            indent: 0,
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.out.push('\n');
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    /// Writes a statement starting at the current position, without a
+    /// trailing newline.
+    fn statement(&mut self, ast: &AST) {
+        match ast {
+            AST::Return { term } => {
+                self.out.push_str("return ");
+                self.expression(term);
+                self.out.push(';');
+            }
+            AST::Block(statements) => self.block(statements),
+            AST::IfNode {
+                conditional,
+                consequence,
+                alternative,
+            } => {
+                self.out.push_str("if (");
+                self.expression(conditional);
+                self.out.push_str(") ");
+                self.statement(consequence);
+                self.out.push_str(" else ");
+                self.statement(alternative);
+            }
+            AST::Function {
+                name,
+                parameters,
+                body,
+            } => {
+                self.out
+                    .push_str(&format!("function {}({}) ", name, parameters.join(", ")));
+                match body.as_ref() {
+                    AST::Block(statements) => self.block(statements),
+                    other => self.block(std::slice::from_ref(other)),
+                }
+            }
+            AST::Var { name, value } => {
+                self.out.push_str(&format!("var {} = ", name));
+                self.expression(value);
+                self.out.push(';');
+            }
+            AST::Assign { name, value } => {
+                self.out.push_str(&format!("{} = ", name));
+                self.expression(value);
+                self.out.push(';');
+            }
+            AST::While { conditional, body } => {
+                self.out.push_str("while (");
+                self.expression(conditional);
+                self.out.push_str(") ");
+                self.statement(body);
+            }
+            AST::Main(statements) => {
+                self.out.push_str("function main() ");
+                self.block(statements);
+            }
+            expression => {
+                self.expression(expression);
+                self.out.push(';');
+            }
+        }
+    }
+
+    fn block(&mut self, statements: &[AST]) {
+        if statements.is_empty() {
+            self.out.push_str("{}");
+            return;
+        }
+        self.out.push('{');
+        self.indent += 1;
+        for statement in statements {
+            self.new_line();
+            self.statement(statement);
+        }
+        self.indent -= 1;
+        self.new_line();
+        self.out.push('}');
+    }
+
+    fn expression(&mut self, ast: &AST) {
+        if let Some((left, operator, right)) = binary_operator(ast) {
+            // Every binary operator is left-associative, so the right operand
+            // needs parentheses even at the same precedence.
+            let level = precedence(ast);
+            self.operand(left, precedence(left) < level);
+            self.out.push_str(&format!(" {} ", operator));
+            self.operand(right, precedence(right) <= level);
+            return;
+        }
+        match ast {
+            AST::Number(value) => self.out.push_str(&value.to_string()),
+            AST::Id(name) => self.out.push_str(name),
+            AST::Boolean(value) => self.out.push_str(&value.to_string()),
+            AST::Undefined => self.out.push_str("undefined"),
+            AST::Null => self.out.push_str("null"),
+            AST::Not(term) => {
+                self.out.push('!');
+                self.operand(term, precedence(term) < precedence(ast));
+            }
+            AST::Call { callee, args } => {
+                self.out.push_str(callee);
+                self.list("(", args, ")");
+            }
+            AST::ArrayLiteral(items) => self.list("[", items, "]"),
+            AST::ArrayLookup { array, index } => {
+                self.operand(array, precedence(array) < 6);
+                self.out.push('[');
+                self.expression(index);
+                self.out.push(']');
+            }
+            AST::ArrayLength(array) => self.list("length(", std::slice::from_ref(array), ")"),
+            AST::Assert(condition) => self.list("assert(", std::slice::from_ref(condition), ")"),
+            AST::Print(value) => self.list("print(", std::slice::from_ref(value), ")"),
+            statement => self.statement(statement),
+        }
+    }
+
+    fn operand(&mut self, ast: &AST, parenthesize: bool) {
+        if parenthesize {
+            self.out.push('(');
+            self.expression(ast);
+            self.out.push(')');
+        } else {
+            self.expression(ast);
+        }
+    }
+
+    fn list(&mut self, open: &str, items: &[AST], close: &str) {
+        self.out.push_str(open);
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.expression(item);
+        }
+        self.out.push_str(close);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::ProgramGenerator;
+    use crate::parser::parse;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn assert_round_trip(ast: &AST) {
+        let source = print_program(ast);
+        match parse(&source) {
+            Ok(parsed) => assert_eq!(*ast, parsed, "source:\n{}", source),
+            Err(e) => panic!("{:?}\nsource:\n{}", e, source),
+        }
+    }
+
+    #[test]
+    fn minimal_parentheses() {
+        let ast = parse("x = ((a - (b - c)) * (d + e)) / f / (g / h);").unwrap();
+        assert_eq!("x = (a - (b - c)) * (d + e) / f / (g / h);", print(&ast));
+        let ast = parse("y = !(a == b) == (c < d);").unwrap();
+        assert_eq!("y = !(a == b) == c < d;", print(&ast));
+    }
+
+    #[test]
+    fn calls_and_builtins() {
+        let ast = parse("print(foo(1, 2) + length(xs) + ys[0]);").unwrap();
+        assert_eq!("print(foo(1, 2) + length(xs) + ys[0])", print(&ast));
+        assert_eq!("[]", print(&AST::ArrayLiteral(vec![])));
+    }
+
+    #[test]
+    fn program_layout() {
+        let source = "function helper(x) { return x * 2; }
+function main() { var a = [1, 2]; if (a[0] == 1) { print(helper(a[1])); } else if (1) { } else { assert(0); } while (0) { } }";
+        let expected = "function helper(x) {
+    return x * 2;
+}
+
+function main() {
+    var a = [1, 2];
+    if (a[0] == 1) {
+        print(helper(a[1]));
+    } else if (1) {} else {
+        assert(0);
+    }
+    while (0) {}
+}
+";
+        let ast = parse(source).unwrap();
+        assert_eq!(expected, print_program(&ast));
+    }
+
+    #[test]
+    fn single_statement_programs() {
+        assert_round_trip(&AST::Block(vec![]));
+        assert_round_trip(&AST::Number(1));
+        assert_round_trip(&AST::Block(vec![AST::Number(1)]));
+        assert_round_trip(&AST::Block(vec![AST::Block(vec![])]));
+    }
+
+    #[test]
+    fn generated_programs_round_trip() {
+        for seed in 0..300 {
+            assert_round_trip(&ProgramGenerator::new(seed).program());
+        }
+    }
+
+    /// Generates syntactically valid trees that use every construct the
+    /// grammar accepts, regardless of whether they would compile.
+    struct SyntaxGenerator {
+        rng: StdRng,
+    }
+
+    impl SyntaxGenerator {
+        fn name(&mut self) -> String {
+            const NAMES: [&str; 10] = [
+                "a",
+                "b",
+                "foo",
+                "x1",
+                "my_var",
+                "f_2",
+                "nullable",
+                "trueish",
+                "returned",
+                "elsewhere",
+            ];
+            NAMES[self.rng.gen_range(0..NAMES.len())].to_string()
+        }
+
+        fn expression(&mut self, depth: usize) -> Box<AST> {
+            let kind = if depth == 0 {
+                self.rng.gen_range(0..6)
+            } else {
+                self.rng.gen_range(0..22)
+            };
+            let d = depth.saturating_sub(1);
+            Box::new(match kind {
+                0 => {
+                    let bits = self.rng.gen_range(0..64);
+                    AST::Number(self.rng.gen_range(0..=u64::MAX >> bits))
+                }
+                1 => AST::Id(self.name()),
+                2 => AST::Boolean(self.rng.gen()),
+                3 => AST::Null,
+                4 => AST::Undefined,
+                5 => AST::ArrayLiteral(vec![]),
+                6 => AST::Not(self.expression(d)),
+                7 => AST::Equal {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                8 => AST::NotEqual {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                9 => AST::Add {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                10 => AST::Subtract {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                11 => AST::Multiply {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                12 => AST::Divide {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                13 => AST::LessThan {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                14 => AST::GreaterThan {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                15 => AST::LessThanEqual {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                16 => AST::GreaterThanEqual {
+                    left: self.expression(d),
+                    right: self.expression(d),
+                },
+                17 => AST::Call {
+                    callee: self.name(),
+                    args: (0..self.rng.gen_range(0..4))
+                        .map(|_| *self.expression(d))
+                        .collect(),
+                },
+                18 => AST::ArrayLiteral(
+                    (0..self.rng.gen_range(1..4))
+                        .map(|_| *self.expression(d))
+                        .collect(),
+                ),
+                19 => AST::ArrayLookup {
+                    array: AST::Id(self.name()).into(),
+                    index: self.expression(d),
+                },
+                20 => AST::ArrayLength(self.expression(d)),
+                _ => {
+                    if self.rng.gen() {
+                        AST::Assert(self.expression(d))
+                    } else {
+                        AST::Print(self.expression(d))
+                    }
+                }
+            })
+        }
+
+        fn block(&mut self, depth: usize) -> AST {
+            AST::Block(
+                (0..self.rng.gen_range(0..4))
+                    .map(|_| self.statement(depth))
+                    .collect(),
+            )
+        }
+
+        fn statement(&mut self, depth: usize) -> AST {
+            let kind = if depth == 0 {
+                self.rng.gen_range(0..4)
+            } else {
+                self.rng.gen_range(0..8)
+            };
+            let d = depth.saturating_sub(1);
+            match kind {
+                0 => *self.expression(3),
+                1 => AST::Return {
+                    term: self.expression(3),
+                },
+                2 => AST::Var {
+                    name: self.name(),
+                    value: self.expression(3),
+                },
+                3 => AST::Assign {
+                    name: self.name(),
+                    value: self.expression(3),
+                },
+                4 => self.block(d),
+                5 => AST::IfNode {
+                    conditional: self.expression(3),
+                    consequence: self.statement(d).into(),
+                    alternative: self.statement(d).into(),
+                },
+                6 => AST::While {
+                    conditional: self.expression(3),
+                    body: self.statement(d).into(),
+                },
+                _ => AST::Function {
+                    name: self.name(),
+                    parameters: (0..self.rng.gen_range(0..5)).map(|_| self.name()).collect(),
+                    body: self.block(d).into(),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn arbitrary_syntax_round_trips() {
+        for seed in 0..2000 {
+            let mut generator = SyntaxGenerator {
+                rng: StdRng::seed_from_u64(seed),
+            };
+            let program = match generator.rng.gen_range(0..3) {
+                0 => generator.statement(3),
+                _ => AST::Block(
+                    (0..generator.rng.gen_range(0..5))
+                        .map(|_| generator.statement(3))
+                        .collect(),
+                ),
+            };
+            assert_round_trip(&program);
+        }
+    }
+}
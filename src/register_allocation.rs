@@ -0,0 +1,335 @@
+use crate::ir::{Function, Instruction, Reg};
+use std::collections::{HashMap, HashSet};
+
+/// The registers values are kept in. They are callee-saved, so values stay
+/// in them across calls, and r0-r3 and ip are left for passing arguments and
+/// computing.
+pub const ALLOCATABLE: [u8; 7] = [4, 5, 6, 7, 8, 9, 10];
+
+/// Where the value of a virtual register lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    Register(u8),
+    /// A word of the frame, numbered from 0.
+    Stack(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    pub locations: HashMap<Reg, Location>,
+    /// The allocatable registers used, which the function must save.
+    pub used: Vec<u8>,
+    /// Number of frame words holding spilled registers.
+    pub spill_slots: usize,
+}
+
+impl Allocation {
+    pub fn location(&self, reg: Reg) -> Location {
+        self.locations[&reg]
+    }
+}
+
+/// The registers live on entry to each block, by position in `blocks`.
+pub fn live_in(function: &Function) -> Vec<HashSet<Reg>> {
+    let index: HashMap<_, _> = function
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.id, i))
+        .collect();
+    // What each block reads before writing it, and what it writes.
+    let (reads, writes): (Vec<HashSet<Reg>>, Vec<HashSet<Reg>>) = function
+        .blocks
+        .iter()
+        .map(|block| {
+            let mut reads = HashSet::new();
+            let mut writes = HashSet::new();
+            for instruction in &block.instructions {
+                reads.extend(
+                    instruction
+                        .uses()
+                        .into_iter()
+                        .filter(|reg| !writes.contains(reg)),
+                );
+                writes.extend(instruction.dest());
+            }
+            reads.extend(
+                block
+                    .terminator
+                    .uses()
+                    .into_iter()
+                    .filter(|reg| !writes.contains(reg)),
+            );
+            (reads, writes)
+        })
+        .unzip();
+
+    let mut live_in = reads.clone();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, block) in function.blocks.iter().enumerate().rev() {
+            let mut live = reads[i].clone();
+            for successor in block.terminator.successors() {
+                live.extend(
+                    live_in[index[&successor]]
+                        .iter()
+                        .filter(|reg| !writes[i].contains(reg)),
+                );
+            }
+            if live.len() != live_in[i].len() {
+                live_in[i] = live;
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+/// The first and last position a register is live at, numbering the
+/// instructions and terminators in the order of the blocks. An instruction
+/// reads its operands at an even position and writes its result at the next
+/// one, so the result can take the register of an operand read for the last
+/// time. The parameters are written at position 1.
+fn intervals(function: &Function) -> Vec<(Reg, usize, usize)> {
+    let live_in = live_in(function);
+    let index: HashMap<_, _> = function
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.id, i))
+        .collect();
+    let mut intervals: HashMap<Reg, (usize, usize)> = HashMap::new();
+    let mut extend = |reg: Reg, position: usize| {
+        let interval = intervals.entry(reg).or_insert((position, position));
+        interval.0 = interval.0.min(position);
+        interval.1 = interval.1.max(position);
+    };
+    for parameter in &function.parameters {
+        extend(*parameter, 1);
+    }
+    let mut position = 2;
+    for (i, block) in function.blocks.iter().enumerate() {
+        let start = position;
+        for reg in &live_in[i] {
+            extend(*reg, start);
+        }
+        for instruction in &block.instructions {
+            for reg in instruction.uses() {
+                extend(reg, position);
+            }
+            if let Some(dest) = instruction.dest() {
+                extend(dest, position + 1);
+            }
+            position += 2;
+        }
+        for reg in block.terminator.uses() {
+            extend(reg, position);
+        }
+        for successor in block.terminator.successors() {
+            for reg in &live_in[index[&successor]] {
+                extend(*reg, position);
+            }
+        }
+        position += 2;
+    }
+    let mut intervals: Vec<(Reg, usize, usize)> = intervals
+        .into_iter()
+        .map(|(reg, (start, end))| (reg, start, end))
+        .collect();
+    intervals.sort_by_key(|&(reg, start, _)| (start, reg));
+    intervals
+}
+
+/// Assigns the registers of a function to `ALLOCATABLE` registers by linear
+/// scan over their live intervals, spilling the ones live the furthest when
+/// there are too many.
+pub fn allocate(function: &Function) -> Allocation {
+    allocate_with(function, &ALLOCATABLE)
+}
+
+pub fn allocate_with(function: &Function, registers: &[u8]) -> Allocation {
+    // Copies between registers cost nothing when both get the same one.
+    let mut hints: HashMap<Reg, Reg> = HashMap::new();
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            if let Instruction::Copy { dest, src } = instruction {
+                hints.entry(*dest).or_insert(*src);
+            }
+        }
+    }
+
+    let mut locations = HashMap::new();
+    let mut free: Vec<u8> = registers.iter().rev().copied().collect();
+    let mut used = HashSet::new();
+    // The intervals holding a register, with where they end.
+    let mut active: Vec<(Reg, usize, u8)> = vec![];
+    let mut spill_slots = 0;
+    for (reg, start, end) in intervals(function) {
+        active.retain(|&(_, active_end, register)| {
+            if active_end < start {
+                free.push(register);
+                false
+            } else {
+                true
+            }
+        });
+        let hinted = hints.get(&reg).and_then(|src| match locations.get(src) {
+            Some(Location::Register(register)) => free.iter().position(|r| r == register),
+            _ => None,
+        });
+        let register = match hinted {
+            Some(i) => Some(free.remove(i)),
+            None => free.pop(),
+        };
+        let register = match register {
+            Some(register) => register,
+            None => {
+                let furthest = active
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, (_, end, _))| *end)
+                    .map(|(i, &(_, end, _))| (i, end));
+                match furthest {
+                    Some((i, furthest_end)) if furthest_end > end => {
+                        let (spilled, _, register) = active.remove(i);
+                        locations.insert(spilled, Location::Stack(spill_slots));
+                        spill_slots += 1;
+                        register
+                    }
+                    _ => {
+                        locations.insert(reg, Location::Stack(spill_slots));
+                        spill_slots += 1;
+                        continue;
+                    }
+                }
+            }
+        };
+        used.insert(register);
+        locations.insert(reg, Location::Register(register));
+        active.push((reg, end, register));
+    }
+    let mut used: Vec<u8> = used.into_iter().collect();
+    used.sort();
+    Allocation {
+        locations,
+        used,
+        spill_slots,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::ProgramGenerator;
+    use crate::ir::BlockId;
+    use crate::ir_optimize::optimize;
+    use crate::lowering::lower;
+    use crate::parser::parse;
+
+    fn lower_source(source: &str) -> crate::ir::Program {
+        lower(&parse(source).expect("Parser failed")).expect("Lowering failed")
+    }
+
+    /// Fails if two registers live at the same time share a machine
+    /// register.
+    fn check(function: &Function, allocation: &Allocation) {
+        let live_in = live_in(function);
+        let index: HashMap<BlockId, usize> = function
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.id, i))
+            .collect();
+        let check_live = |live: &HashSet<Reg>| {
+            let mut holders = HashMap::new();
+            for reg in live {
+                if let Location::Register(register) = allocation.location(*reg) {
+                    if let Some(other) = holders.insert(register, *reg) {
+                        panic!("{} and {} share r{} in\n{}", reg, other, register, function);
+                    }
+                }
+            }
+        };
+        let mut entry: HashSet<Reg> = function.parameters.iter().copied().collect();
+        entry.extend(&live_in[0]);
+        check_live(&entry);
+        for block in &function.blocks {
+            let mut live: HashSet<Reg> = block.terminator.uses().into_iter().collect();
+            for successor in block.terminator.successors() {
+                live.extend(&live_in[index[&successor]]);
+            }
+            check_live(&live);
+            for instruction in block.instructions.iter().rev() {
+                // The result can't share with what is live after it.
+                live.extend(instruction.dest());
+                check_live(&live);
+                if let Some(dest) = instruction.dest() {
+                    live.remove(&dest);
+                }
+                live.extend(instruction.uses());
+                check_live(&live);
+            }
+        }
+    }
+
+    #[test]
+    fn liveness() {
+        let program = lower_source(
+            "function f(a) { var i = 0; var j = 1; while (i < a) { i = i + j; } return i; }",
+        );
+        let f = &program.functions[0];
+        let live_in = live_in(f);
+        assert_eq!(HashSet::from([Reg(0)]), live_in[0]);
+        // In the loop: a, i and j.
+        assert_eq!(HashSet::from([Reg(0), Reg(2), Reg(4)]), live_in[1]);
+        assert_eq!(HashSet::from([Reg(0), Reg(2), Reg(4)]), live_in[2]);
+        assert_eq!(HashSet::from([Reg(2)]), live_in[3]);
+    }
+
+    #[test]
+    fn copies_share_registers() {
+        let mut program = lower_source("function f(a) { var b = a; var c = b + 1; return c; }");
+        let f = &mut program.functions[0];
+        let allocation = allocate(f);
+        check(f, &allocation);
+        assert_eq!(vec![4, 5], allocation.used);
+        assert_eq!(0, allocation.spill_slots);
+        assert_eq!(Location::Register(4), allocation.location(Reg(0)));
+        assert_eq!(Location::Register(4), allocation.location(Reg(1)));
+    }
+
+    #[test]
+    fn spills() {
+        let program = lower_source(
+            "function f(a, b) {
+                var c = a + b; var d = a - b; var e = a * b; var g = c + d;
+                return a + b + c + d + e + g;
+            }",
+        );
+        let f = &program.functions[0];
+        let allocation = allocate_with(f, &[4, 5, 6]);
+        check(f, &allocation);
+        assert_eq!(vec![4, 5, 6], allocation.used);
+        assert!(allocation.spill_slots > 0);
+        let allocation = allocate(f);
+        check(f, &allocation);
+        assert_eq!(0, allocation.spill_slots);
+    }
+
+    #[test]
+    fn no_interference() {
+        for seed in 0..300 {
+            let ast = ProgramGenerator::new(seed).program();
+            let mut program = lower(&ast).expect("Lowering failed");
+            for function in &program.functions {
+                check(function, &allocate_with(function, &[4, 5]));
+            }
+            optimize(&mut program);
+            for function in &program.functions {
+                check(function, &allocate(function));
+                check(function, &allocate_with(function, &[4]));
+            }
+        }
+    }
+}
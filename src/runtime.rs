@@ -0,0 +1,151 @@
+//! The little of the C library generated code needs, for executables that
+//! don't link against one: the `_start` entry point, `exit`, and the
+//! `putchar`, `printf` and `malloc` the code calls, on top of Linux system
+//! calls.
+
+use crate::arm::{self, Line};
+
+const RUNTIME: &str = "
+.global _start
+_start:
+    bl main
+.global exit
+exit:
+    mov r7, #248        @ exit_group, with main's result as the status
+    svc #0
+
+.global putchar
+putchar:
+    push {r0, r7}
+    mov r0, #1          @ stdout
+    mov r1, sp          @ the character, the low byte of the pushed r0
+    mov r2, #1
+    mov r7, #4          @ write
+    svc #0
+    pop {r0, r7}
+    bx lr
+
+@ Only understands `%d`, for r1, and `%%`.
+.global printf
+printf:
+    push {r4, r5, lr}
+    mov r4, r0
+    mov r5, r1
+.Lprintf_next:
+    ldrb r0, [r4]
+    add r4, r4, #1
+    cmp r0, #0
+    beq .Lprintf_end
+    cmp r0, #'%'
+    bne .Lprintf_char
+    ldrb r0, [r4]
+    cmp r0, #0
+    beq .Lprintf_end
+    add r4, r4, #1
+    cmp r0, #'d'
+    bne .Lprintf_char
+    mov r0, r5
+    bl .Lprint_signed
+    b .Lprintf_next
+.Lprintf_char:
+    bl putchar
+    b .Lprintf_next
+.Lprintf_end:
+    pop {r4, r5, pc}
+
+@ Prints r0 in decimal.
+.Lprint_signed:
+    cmp r0, #0
+    bge .Lprint_unsigned
+    push {r0, lr}
+    mov r0, #'-'
+    bl putchar
+    pop {r0, lr}
+    rsb r0, r0, #0
+@ Prints r0 in decimal as an unsigned number, the digits of r0 / 10 first.
+.Lprint_unsigned:
+    push {r4, lr}
+    mov r1, #10
+    udiv r2, r0, r1
+    mul r1, r2, r1
+    sub r4, r0, r1
+    mov r0, r2
+    cmp r0, #0
+    beq .Lprint_digit
+    bl .Lprint_unsigned
+.Lprint_digit:
+    add r0, r4, #'0'
+    bl putchar
+    pop {r4, pc}
+
+@ Moves the program break up by the size rounded up to 8 bytes, and never
+@ frees. Returns 0 if the break can't move.
+.global malloc
+malloc:
+    push {r4, r7}
+    add r4, r0, #7
+    lsr r4, r4, #3
+    lsl r4, r4, #3
+    mov r7, #45         @ brk
+    mov r0, #0
+    svc #0              @ returns the current break
+    mov r1, r0
+    add r4, r0, r4
+    mov r0, r4
+    svc #0
+    cmp r0, r4
+    movlo r1, #0
+    mov r0, r1
+    pop {r4, r7}
+    bx lr
+";
+
+/// The runtime, to add to the code of a program before encoding it.
+pub fn lines() -> Vec<Line> {
+    arm::parse(RUNTIME).expect("Invalid runtime")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arm::Directive;
+    use crate::elf;
+    use crate::encoder::encode;
+    use crate::generator::ProgramGenerator;
+    use crate::{arm_code_generator, arm_emitter};
+
+    #[test]
+    fn globals() {
+        let globals: Vec<String> = lines()
+            .into_iter()
+            .filter_map(|line| match line {
+                Line::Directive(Directive::Global(name)) => Some(name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vec!["_start", "exit", "putchar", "printf", "malloc"],
+            globals
+        );
+    }
+
+    #[test]
+    fn links_with_generated_code() {
+        for seed in 0..50 {
+            let ast = ProgramGenerator::new(seed).program();
+            for lines in [
+                arm_code_generator::generate_lines(&ast),
+                arm_emitter::generate_lines(&ast),
+            ] {
+                let mut lines = lines.unwrap();
+                lines.extend(super::lines());
+                let object = encode(&lines).unwrap();
+                assert!(object
+                    .symbols
+                    .iter()
+                    .all(|symbol| symbol.definition.is_some()));
+                elf::executable(&object).unwrap();
+            }
+        }
+    }
+}
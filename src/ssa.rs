@@ -0,0 +1,672 @@
+use crate::ir::{Block, BlockId, Function, Instruction, Reg, Terminator, Type};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The dominator tree of the reachable blocks of a function, computed with
+/// the iterative algorithm of Cooper, Harvey and Kennedy.
+pub struct DominatorTree {
+    /// The reachable blocks in reverse postorder, starting with the entry.
+    order: Vec<BlockId>,
+    /// The immediate dominator of every block but the entry.
+    idom: HashMap<BlockId, BlockId>,
+    children: HashMap<BlockId, Vec<BlockId>>,
+}
+
+fn reverse_postorder(function: &Function) -> Vec<BlockId> {
+    let entry = function.blocks[0].id;
+    let mut visited = HashSet::from([entry]);
+    let mut postorder = vec![];
+    let mut stack = vec![(entry, 0)];
+    while let Some((id, next)) = stack.pop() {
+        let successors = function.block(id).terminator.successors();
+        match successors.get(next) {
+            Some(&successor) => {
+                stack.push((id, next + 1));
+                if visited.insert(successor) {
+                    stack.push((successor, 0));
+                }
+            }
+            None => postorder.push(id),
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+impl DominatorTree {
+    pub fn new(function: &Function) -> DominatorTree {
+        let order = reverse_postorder(function);
+        let index: HashMap<BlockId, usize> =
+            order.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        let predecessors = function.predecessors();
+        let entry = order[0];
+        // The entry is its own dominator while computing.
+        let mut idom = HashMap::from([(entry, entry)]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &id in &order[1..] {
+                let mut new_idom = None;
+                for predecessor in &predecessors[&id] {
+                    if !idom.contains_key(predecessor) {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => *predecessor,
+                        Some(other) => {
+                            let (mut a, mut b) = (*predecessor, other);
+                            while a != b {
+                                while index[&a] > index[&b] {
+                                    a = idom[&a];
+                                }
+                                while index[&b] > index[&a] {
+                                    b = idom[&b];
+                                }
+                            }
+                            a
+                        }
+                    });
+                }
+                let new_idom = new_idom.expect("A predecessor comes first in reverse postorder");
+                if idom.insert(id, new_idom) != Some(new_idom) {
+                    changed = true;
+                }
+            }
+        }
+        idom.remove(&entry);
+
+        let mut children: HashMap<BlockId, Vec<BlockId>> = HashMap::new();
+        for &id in &order[1..] {
+            children.entry(idom[&id]).or_default().push(id);
+        }
+        DominatorTree {
+            order,
+            idom,
+            children,
+        }
+    }
+
+    /// The reachable blocks in reverse postorder, so every block comes after
+    /// its dominators.
+    pub fn order(&self) -> &[BlockId] {
+        &self.order
+    }
+
+    pub fn idom(&self, id: BlockId) -> Option<BlockId> {
+        self.idom.get(&id).copied()
+    }
+
+    pub fn children(&self, id: BlockId) -> &[BlockId] {
+        self.children.get(&id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether every path from the entry to `b` goes through `a`.
+    pub fn dominates(&self, a: BlockId, mut b: BlockId) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom(b) {
+                Some(parent) => b = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// The dominance frontier of every reachable block: the blocks where its
+    /// dominance ends, and where the values it defines meet others.
+    pub fn frontiers(&self, function: &Function) -> HashMap<BlockId, HashSet<BlockId>> {
+        let mut frontiers: HashMap<BlockId, HashSet<BlockId>> =
+            self.order.iter().map(|id| (*id, HashSet::new())).collect();
+        let predecessors = function.predecessors();
+        for &id in &self.order {
+            let reachable: Vec<BlockId> = predecessors[&id]
+                .iter()
+                .copied()
+                .filter(|predecessor| frontiers.contains_key(predecessor))
+                .collect();
+            if reachable.len() < 2 {
+                continue;
+            }
+            let idom = self.idom[&id];
+            for mut runner in reachable {
+                while runner != idom {
+                    frontiers.get_mut(&runner).unwrap().insert(id);
+                    runner = self.idom[&runner];
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+/// Puts a function in SSA form: every register is written by exactly one
+/// instruction, and a register written in several blocks becomes several
+/// registers merged by phis where the blocks' paths join.
+///
+/// Phis are only placed for the registers read in a block other than the
+/// one writing them. A register read where it may not have been written
+/// reads 0. The types are inferred again, so a variable holding an integer
+/// in one place and an array in another is split into an `int` and an
+/// `array` register.
+pub fn construct(function: &mut Function) {
+    let dominators = DominatorTree::new(function);
+    let reachable: HashSet<BlockId> = dominators.order().iter().copied().collect();
+    function
+        .blocks
+        .retain(|block| reachable.contains(&block.id));
+    let order: Vec<BlockId> = function.blocks.iter().map(|block| block.id).collect();
+    let frontiers = dominators.frontiers(function);
+
+    let entry = function.blocks[0].id;
+    let mut globals = HashSet::new();
+    let mut definitions: HashMap<Reg, HashSet<BlockId>> = HashMap::new();
+    for parameter in &function.parameters {
+        definitions.entry(*parameter).or_default().insert(entry);
+    }
+    for block in &function.blocks {
+        let mut written = HashSet::new();
+        for instruction in &block.instructions {
+            for reg in instruction.uses() {
+                if !written.contains(&reg) {
+                    globals.insert(reg);
+                }
+            }
+            if let Some(dest) = instruction.dest() {
+                written.insert(dest);
+                definitions.entry(dest).or_default().insert(block.id);
+            }
+        }
+        for reg in block.terminator.uses() {
+            if !written.contains(&reg) {
+                globals.insert(reg);
+            }
+        }
+    }
+
+    // The register each phi is for, by block.
+    let mut phis: HashMap<BlockId, Vec<Reg>> = HashMap::new();
+    let mut globals: Vec<Reg> = globals.into_iter().collect();
+    globals.sort();
+    for reg in globals {
+        let mut pending: Vec<BlockId> = definitions
+            .get(&reg)
+            .map(|blocks| blocks.iter().copied().collect())
+            .unwrap_or_default();
+        let mut placed = HashSet::new();
+        while let Some(id) = pending.pop() {
+            for &frontier in &frontiers[&id] {
+                if placed.insert(frontier) {
+                    phis.entry(frontier).or_default().push(reg);
+                    pending.push(frontier);
+                }
+            }
+        }
+    }
+    for block in &mut function.blocks {
+        if let Some(regs) = phis.get(&block.id) {
+            let inserted = regs.iter().map(|reg| Instruction::Phi {
+                dest: *reg,
+                sources: vec![],
+            });
+            block.instructions.splice(0..0, inserted);
+        }
+    }
+
+    let mut renamer = Renamer {
+        blocks: std::mem::take(&mut function.blocks)
+            .into_iter()
+            .map(|block| (block.id, block))
+            .collect(),
+        phis,
+        stacks: HashMap::new(),
+        types: function.types.clone(),
+        undefined: None,
+    };
+    for parameter in &mut function.parameters {
+        let renamed = renamer.define(*parameter);
+        *parameter = renamed;
+    }
+    renamer.rename(entry, &dominators);
+    function.blocks = order
+        .iter()
+        .map(|id| renamer.blocks.remove(id).unwrap())
+        .collect();
+    function.types = renamer.types;
+    if let Some(undefined) = renamer.undefined {
+        function.blocks[0].instructions.insert(
+            0,
+            Instruction::Const {
+                dest: undefined,
+                value: 0,
+            },
+        );
+    }
+    renumber(function);
+    function.infer_types();
+}
+
+struct Renamer {
+    blocks: HashMap<BlockId, Block>,
+    phis: HashMap<BlockId, Vec<Reg>>,
+    /// The registers currently holding the value of each original register.
+    stacks: HashMap<Reg, Vec<Reg>>,
+    types: Vec<Type>,
+    /// Read where an original register hasn't been written.
+    undefined: Option<Reg>,
+}
+
+impl Renamer {
+    fn fresh(&mut self, ty: Type) -> Reg {
+        self.types.push(ty);
+        Reg(self.types.len() - 1)
+    }
+
+    fn define(&mut self, reg: Reg) -> Reg {
+        let renamed = self.fresh(self.types[reg.0]);
+        self.stacks.entry(reg).or_default().push(renamed);
+        renamed
+    }
+
+    fn current(&mut self, reg: Reg) -> Reg {
+        match self.stacks.get(&reg).and_then(|stack| stack.last()) {
+            Some(renamed) => *renamed,
+            None => match self.undefined {
+                Some(undefined) => undefined,
+                None => {
+                    let undefined = self.fresh(Type::Int);
+                    self.undefined = Some(undefined);
+                    undefined
+                }
+            },
+        }
+    }
+
+    fn rename(&mut self, id: BlockId, dominators: &DominatorTree) {
+        let mut defined = vec![];
+        let mut block = self.blocks.remove(&id).unwrap();
+        for instruction in &mut block.instructions {
+            if !matches!(instruction, Instruction::Phi { .. }) {
+                for reg in instruction.uses_mut() {
+                    *reg = self.current(*reg);
+                }
+            }
+            if let Some(dest) = instruction.dest_mut() {
+                defined.push(*dest);
+                *dest = self.define(*dest);
+            }
+        }
+        for reg in block.terminator.uses_mut() {
+            *reg = self.current(*reg);
+        }
+        let mut successors = block.terminator.successors();
+        successors.dedup();
+        self.blocks.insert(id, block);
+
+        for successor in successors {
+            let Some(regs) = self.phis.get(&successor).cloned() else {
+                continue;
+            };
+            let sources: Vec<Reg> = regs.iter().map(|reg| self.current(*reg)).collect();
+            let block = self.blocks.get_mut(&successor).unwrap();
+            for (instruction, source) in block.instructions.iter_mut().zip(sources) {
+                let Instruction::Phi { sources, .. } = instruction else {
+                    panic!("Expected Phi, got: {}", instruction)
+                };
+                sources.push((id, source));
+            }
+        }
+        for &child in dominators.children(id) {
+            self.rename(child, dominators);
+        }
+        for reg in defined {
+            self.stacks.get_mut(&reg).unwrap().pop();
+        }
+    }
+}
+
+/// Takes a function out of SSA form, replacing every phi by copies at the
+/// end of its predecessors.
+///
+/// The copies into the phis of a block are ordered so they all still read
+/// the values from before any of them is written. Edges from a block with
+/// several successors to one with phis get a block of their own for the
+/// copies.
+pub fn destruct(function: &mut Function) {
+    let predecessors = function.predecessors();
+    let with_phis: Vec<BlockId> = function
+        .blocks
+        .iter()
+        .filter(|block| matches!(block.instructions.first(), Some(Instruction::Phi { .. })))
+        .map(|block| block.id)
+        .collect();
+
+    for id in with_phis {
+        for &predecessor in &predecessors[&id] {
+            let mut successors = function.block(predecessor).terminator.successors();
+            successors.dedup();
+            if successors.len() < 2 {
+                continue;
+            }
+            let split = function.new_block_id();
+            function
+                .block_mut(predecessor)
+                .terminator
+                .retarget(id, split);
+            for instruction in &mut function.block_mut(id).instructions {
+                if let Instruction::Phi { sources, .. } = instruction {
+                    for (source, _) in sources {
+                        if *source == predecessor {
+                            *source = split;
+                        }
+                    }
+                }
+            }
+            let position = function
+                .blocks
+                .iter()
+                .position(|block| block.id == id)
+                .unwrap();
+            function.blocks.insert(
+                position,
+                Block {
+                    id: split,
+                    instructions: vec![],
+                    terminator: Terminator::Jump(id),
+                },
+            );
+        }
+
+        let mut copies: BTreeMap<BlockId, Vec<(Reg, Reg)>> = BTreeMap::new();
+        let block = function.block_mut(id);
+        let phis = block
+            .instructions
+            .iter()
+            .take_while(|instruction| matches!(instruction, Instruction::Phi { .. }))
+            .count();
+        for phi in block.instructions.drain(..phis) {
+            let Instruction::Phi { dest, sources } = phi else {
+                unreachable!()
+            };
+            for (source, src) in sources {
+                copies.entry(source).or_default().push((dest, src));
+            }
+        }
+        for (source, copies) in copies {
+            let sequential =
+                sequentialize(copies, |saved| function.new_reg(function.type_of(saved)));
+            function.block_mut(source).instructions.extend(sequential);
+        }
+    }
+    renumber(function);
+}
+
+/// Orders copies meant to happen all at once so none overwrites a register
+/// another still has to read, going through a new register to break cycles
+/// like swaps.
+fn sequentialize(
+    mut copies: Vec<(Reg, Reg)>,
+    mut new_reg: impl FnMut(Reg) -> Reg,
+) -> Vec<Instruction> {
+    copies.retain(|(dest, src)| dest != src);
+    let mut sequential = vec![];
+    while !copies.is_empty() {
+        let ready = copies
+            .iter()
+            .position(|(dest, _)| copies.iter().all(|(_, src)| src != dest));
+        match ready {
+            Some(i) => {
+                let (dest, src) = copies.remove(i);
+                sequential.push(Instruction::Copy { dest, src });
+            }
+            None => {
+                // Every destination is still to be read: save one.
+                let saved = copies[0].0;
+                let temporary = new_reg(saved);
+                sequential.push(Instruction::Copy {
+                    dest: temporary,
+                    src: saved,
+                });
+                for (_, src) in &mut copies {
+                    if *src == saved {
+                        *src = temporary;
+                    }
+                }
+            }
+        }
+    }
+    sequential
+}
+
+/// Numbers the registers in use from 0, in the order they appear.
+fn renumber(function: &mut Function) {
+    let mut numbers: HashMap<Reg, Reg> = HashMap::new();
+    let mut number = |reg: &mut Reg| {
+        let next = Reg(numbers.len());
+        *reg = *numbers.entry(*reg).or_insert(next);
+    };
+    for parameter in &mut function.parameters {
+        number(parameter);
+    }
+    for block in &mut function.blocks {
+        for instruction in &mut block.instructions {
+            for reg in instruction.uses_mut() {
+                number(reg);
+            }
+            if let Some(dest) = instruction.dest_mut() {
+                number(dest);
+            }
+        }
+        for reg in block.terminator.uses_mut() {
+            number(reg);
+        }
+    }
+    let mut types = vec![Type::Word; numbers.len()];
+    for (old, new) in numbers {
+        types[new.0] = function.type_of(old);
+    }
+    function.types = types;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::AST;
+    use crate::generator::check_against_interpreter;
+    use crate::ir::Program;
+    use crate::ir_interpreter;
+    use crate::ir_optimize::copy_propagation;
+    use crate::lowering::lower;
+    use crate::parser::parse;
+
+    const SWAP: &str = r#"
+        function f(a, b) {
+            var t = 0;
+            while (a < 10) {
+                t = a;
+                a = b;
+                b = t + 3;
+                if (a == 7) { return b; } else { }
+            }
+            return a + b;
+        }
+        function main() { print(f(1, 2)); }"#;
+
+    fn lower_source(source: &str) -> Program {
+        lower(&parse(source).expect("Parser failed")).expect("Lowering failed")
+    }
+
+    fn run(program: &Program) -> String {
+        let mut out = vec![];
+        ir_interpreter::run(program, &mut out, 100_000).expect("IR program failed");
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn dominators() {
+        let program = lower_source(SWAP);
+        let f = &program.functions[0];
+        let dominators = DominatorTree::new(f);
+        assert_eq!(
+            vec![None, Some(0), Some(1), Some(1), Some(2), Some(2), Some(5)],
+            (0..7)
+                .map(|id| dominators.idom(BlockId(id)).map(|idom| idom.0))
+                .collect::<Vec<_>>()
+        );
+        assert!(dominators.dominates(BlockId(1), BlockId(6)));
+        assert!(!dominators.dominates(BlockId(2), BlockId(3)));
+        let frontiers = dominators.frontiers(f);
+        for id in [1, 2, 5, 6] {
+            assert_eq!(HashSet::from([BlockId(1)]), frontiers[&BlockId(id)]);
+        }
+        for id in [0, 3, 4] {
+            assert!(frontiers[&BlockId(id)].is_empty());
+        }
+    }
+
+    #[test]
+    fn phis() {
+        let mut program = lower_source(SWAP);
+        construct(&mut program.functions[0]);
+        assert_eq!(
+            "function f(%0: word, %1: word) {
+bb0:
+    %2: int = const 0
+    %3: int = copy %2
+    jump bb1
+bb1:
+    %5: word = phi [bb0: %0, bb6: %4]
+    %7: word = phi [bb0: %1, bb6: %6]
+    %8: int = const 10
+    %9: int = lt %5, %8
+    branch %9, bb2, bb3
+bb2:
+    %10: word = copy %5
+    %4: word = copy %7
+    %11: int = const 3
+    %12: int = add %10, %11
+    %6: int = copy %12
+    %13: int = const 7
+    %14: int = eq %4, %13
+    branch %14, bb4, bb5
+bb4:
+    return %6
+bb5:
+    jump bb6
+bb6:
+    jump bb1
+bb3:
+    %15: int = add %5, %7
+    return %15
+}
+",
+            program.functions[0].to_string()
+        );
+    }
+
+    #[test]
+    fn swapping_phis() {
+        let mut program = lower_source(SWAP);
+        assert_eq!("8\n", run(&program));
+        for function in &mut program.functions {
+            construct(function);
+            copy_propagation(function);
+        }
+        // The phis of the loop read each other.
+        assert!(program.functions[0].to_string().contains(
+            "    %5: word = phi [bb0: %0, bb6: %7]\n    %7: word = phi [bb0: %1, bb6: %12]\n"
+        ));
+        assert_eq!("8\n", run(&program));
+        for function in &mut program.functions {
+            destruct(function);
+        }
+        assert_eq!("8\n", run(&program));
+    }
+
+    #[test]
+    fn parallel_copies() {
+        let copy = |dest, src| Instruction::Copy {
+            dest: Reg(dest),
+            src: Reg(src),
+        };
+        // c = a must read a before a = b writes it, and a = b, b = a is a
+        // swap.
+        assert_eq!(
+            vec![copy(2, 0), copy(3, 0), copy(0, 1), copy(1, 3)],
+            sequentialize(
+                vec![
+                    (Reg(0), Reg(1)),
+                    (Reg(1), Reg(0)),
+                    (Reg(2), Reg(0)),
+                    (Reg(4), Reg(4))
+                ],
+                |_| Reg(3)
+            )
+        );
+    }
+
+    #[test]
+    fn critical_edges() {
+        let mut program =
+            lower_source("function main() { var i = 0; while (i < 3) { i = i + 1; } print(i); }");
+        let main = &mut program.functions[0];
+        construct(main);
+        // The loop's head branches to its exit and its body, the body jumps
+        // back: the exit needs no phi, and the edge needs no block.
+        destruct(main);
+        assert_eq!(4, main.blocks.len());
+
+        let mut program = lower_source(
+            "function f(a) { var x = 1; if (a < 2) { x = 2; } else { } return x; }
+             function main() { print(f(1)); print(f(3)); }",
+        );
+        let f = &mut program.functions[0];
+        construct(f);
+        assert!(f.to_string().contains("phi"), "{}", f);
+        let blocks = f.blocks.len();
+        destruct(f);
+        assert_eq!(blocks, f.blocks.len());
+        assert_eq!("2\n1\n", run(&program));
+    }
+
+    #[test]
+    fn splitting_types() {
+        let mut program =
+            lower_source("function main() { var x = 1; print(x); x = [x, 2]; print(length(x)); }");
+        let main = &mut program.functions[0];
+        assert!(main.to_string().contains("%1: word = copy"), "{}", main);
+        construct(main);
+        main.check_types().expect("Badly typed SSA");
+        let types: Vec<Type> = main
+            .blocks
+            .iter()
+            .flat_map(|block| &block.instructions)
+            .filter(|instruction| matches!(instruction, Instruction::Copy { .. }))
+            .map(|instruction| main.type_of(instruction.dest().unwrap()))
+            .collect();
+        assert_eq!(vec![Type::Int, Type::Array], types);
+        assert_eq!("1\n2\n", run(&program));
+    }
+
+    #[test]
+    fn agrees_with_interpreter() {
+        let ssa = |ast: &AST| {
+            let mut program = lower(ast).expect("Lowering failed");
+            for function in &mut program.functions {
+                construct(function);
+                function.check_types().expect("Badly typed SSA");
+            }
+            program
+        };
+        check_against_interpreter(0..300, |ast, out| {
+            ir_interpreter::run(&ssa(ast), out, 1_000_000).expect("SSA program failed")
+        });
+        check_against_interpreter(0..300, |ast, out| {
+            let mut program = ssa(ast);
+            for function in &mut program.functions {
+                destruct(function);
+                function.check_types().expect("Badly typed IR");
+            }
+            ir_interpreter::run(&program, out, 1_000_000).expect("IR program failed")
+        });
+    }
+}
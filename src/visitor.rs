@@ -1,44 +1,162 @@
 use crate::ast::AST;
-pub trait Visitor<T, W> {
-    fn visit_assert(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_print(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_array_length(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_array_lookup(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_array_literal(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_boolean(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_number(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_id(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_not(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_equal(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_not_equal(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_add(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_subtract(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_multiply(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_divide(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_less_than(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_greater_than(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_less_than_equal(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_greater_than_equal(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_call(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_return(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_block(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_if(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_function(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_var(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_assign(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_while(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_undefined(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_null(&mut self, node: &AST, w: W) -> std::io::Result<T>;
-    fn visit_main(&mut self, node: &AST, w: W) -> std::io::Result<T>;
+use crate::error::CompileError;
+use crate::fold::Fold;
+use crate::visitor_mut::VisitorMut;
+
+/// A pass over the `AST`, called back by `AstVisitor::visit` with every node
+/// of the kind it is named after.
+///
+/// Every method defaults to the `walk_*` function of the same name, which
+/// visits the children of the node in source order and returns
+/// `T::default()`. A visitor therefore only overrides the nodes it cares
+/// about, and calls the `walk_*` function from an override to keep going into
+/// the children:
+///
+/// ```
+/// use arm_compile::ast::AST;
+/// use arm_compile::error::CompileError;
+/// use arm_compile::parser::parse;
+/// use arm_compile::visitor::{walk_call, AstVisitor, Visitor};
+///
+/// struct Calls(Vec<String>);
+///
+/// impl Visitor for Calls {
+///     fn visit_call(&mut self, node: &AST, w: &mut ()) -> Result<(), CompileError> {
+///         if let AST::Call { callee, .. } = node {
+///             self.0.push(callee.clone());
+///         }
+///         walk_call(self, node, w)
+///     }
+/// }
+///
+/// let mut calls = Calls(vec![]);
+/// parse("f(g(1), h());").unwrap().accept(&mut calls).unwrap();
+/// assert_eq!(vec!["f", "g", "h"], calls.0);
+/// ```
+///
+/// `T` is what every method returns, for visitors that compute a value, and
+/// `W` is what the visitor writes to, passed along to every method, like the
+/// `Vec<arm::Line>` the code generator emits instructions into. Both default
+/// to `()`, and `AstVisitor::accept` visits without a writer.
+pub trait Visitor<T: Default = (), W: ?Sized = ()> {
+    fn visit_assert(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_assert(self, node, w)
+    }
+    fn visit_print(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_print(self, node, w)
+    }
+    fn visit_array_length(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_array_length(self, node, w)
+    }
+    fn visit_array_lookup(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_array_lookup(self, node, w)
+    }
+    fn visit_array_literal(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_array_literal(self, node, w)
+    }
+    fn visit_boolean(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_boolean(self, node, w)
+    }
+    fn visit_number(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_number(self, node, w)
+    }
+    fn visit_id(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_id(self, node, w)
+    }
+    fn visit_not(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_not(self, node, w)
+    }
+    fn visit_equal(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_equal(self, node, w)
+    }
+    fn visit_not_equal(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_not_equal(self, node, w)
+    }
+    fn visit_add(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_add(self, node, w)
+    }
+    fn visit_subtract(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_subtract(self, node, w)
+    }
+    fn visit_multiply(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_multiply(self, node, w)
+    }
+    fn visit_divide(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_divide(self, node, w)
+    }
+    fn visit_less_than(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_less_than(self, node, w)
+    }
+    fn visit_greater_than(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_greater_than(self, node, w)
+    }
+    fn visit_less_than_equal(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_less_than_equal(self, node, w)
+    }
+    fn visit_greater_than_equal(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_greater_than_equal(self, node, w)
+    }
+    fn visit_call(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_call(self, node, w)
+    }
+    fn visit_return(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_return(self, node, w)
+    }
+    fn visit_block(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_block(self, node, w)
+    }
+    fn visit_if(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_if(self, node, w)
+    }
+    fn visit_function(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_function(self, node, w)
+    }
+    fn visit_var(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_var(self, node, w)
+    }
+    fn visit_assign(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_assign(self, node, w)
+    }
+    fn visit_while(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_while(self, node, w)
+    }
+    fn visit_undefined(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_undefined(self, node, w)
+    }
+    fn visit_null(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_null(self, node, w)
+    }
+    fn visit_main(&mut self, node: &AST, w: &mut W) -> Result<T, CompileError> {
+        walk_main(self, node, w)
+    }
 }
 
 pub trait AstVisitor {
-    fn visit<T, W>(&self, v: &mut dyn Visitor<T, W>, w: W) -> std::io::Result<T>;
+    fn visit<T, W, V>(&self, v: &mut V, w: &mut W) -> Result<T, CompileError>
+    where
+        T: Default,
+        W: ?Sized,
+        V: Visitor<T, W> + ?Sized;
+    /// Visits with a visitor that doesn't write anything.
+    fn accept<T, V>(&self, v: &mut V) -> Result<T, CompileError>
+    where
+        T: Default,
+        V: Visitor<T> + ?Sized,
+    {
+        self.visit(v, &mut ())
+    }
+    fn visit_mut<V: VisitorMut + ?Sized>(&mut self, v: &mut V);
+    fn fold<F: Fold + ?Sized>(self, f: &mut F) -> AST;
     fn equal(&self, node: &AST) -> bool;
 }
 
 impl AstVisitor for AST {
-    fn visit<T, W>(&self, v: &mut dyn Visitor<T, W>, w: W) -> std::io::Result<T> {
+    fn visit<T, W, V>(&self, v: &mut V, w: &mut W) -> Result<T, CompileError>
+    where
+        T: Default,
+        W: ?Sized,
+        V: Visitor<T, W> + ?Sized,
+    {
         match self {
             AST::Number(_) => v.visit_number(self, w),
             AST::Id(_) => v.visit_id(self, w),
@@ -73,8 +191,532 @@ impl AstVisitor for AST {
         }
     }
 
+    fn visit_mut<V: VisitorMut + ?Sized>(&mut self, v: &mut V) {
+        match self {
+            AST::Number(_) => v.visit_number_mut(self),
+            AST::Id(_) => v.visit_id_mut(self),
+            AST::Not(_) => v.visit_not_mut(self),
+            AST::Equal { .. } => v.visit_equal_mut(self),
+            AST::NotEqual { .. } => v.visit_not_equal_mut(self),
+            AST::Add { .. } => v.visit_add_mut(self),
+            AST::Subtract { .. } => v.visit_subtract_mut(self),
+            AST::Multiply { .. } => v.visit_multiply_mut(self),
+            AST::Divide { .. } => v.visit_divide_mut(self),
+            AST::LessThan { .. } => v.visit_less_than_mut(self),
+            AST::GreaterThan { .. } => v.visit_greater_than_mut(self),
+            AST::LessThanEqual { .. } => v.visit_less_than_equal_mut(self),
+            AST::GreaterThanEqual { .. } => v.visit_greater_than_equal_mut(self),
+            AST::Call { .. } => v.visit_call_mut(self),
+            AST::Return { .. } => v.visit_return_mut(self),
+            AST::Block(_) => v.visit_block_mut(self),
+            AST::IfNode { .. } => v.visit_if_mut(self),
+            AST::Function { .. } => v.visit_function_mut(self),
+            AST::Var { .. } => v.visit_var_mut(self),
+            AST::Assign { .. } => v.visit_assign_mut(self),
+            AST::While { .. } => v.visit_while_mut(self),
+            AST::Undefined => v.visit_undefined_mut(self),
+            AST::Null => v.visit_null_mut(self),
+            AST::Boolean(_) => v.visit_boolean_mut(self),
+            AST::ArrayLiteral(_) => v.visit_array_literal_mut(self),
+            AST::ArrayLookup { .. } => v.visit_array_lookup_mut(self),
+            AST::ArrayLength(_) => v.visit_array_length_mut(self),
+            AST::Main(_) => v.visit_main_mut(self),
+            AST::Assert(_) => v.visit_assert_mut(self),
+            AST::Print(_) => v.visit_print_mut(self),
+        }
+    }
+
+    fn fold<F: Fold + ?Sized>(self, f: &mut F) -> AST {
+        match self {
+            AST::Number(_) => f.fold_number(self),
+            AST::Id(_) => f.fold_id(self),
+            AST::Not(_) => f.fold_not(self),
+            AST::Equal { .. } => f.fold_equal(self),
+            AST::NotEqual { .. } => f.fold_not_equal(self),
+            AST::Add { .. } => f.fold_add(self),
+            AST::Subtract { .. } => f.fold_subtract(self),
+            AST::Multiply { .. } => f.fold_multiply(self),
+            AST::Divide { .. } => f.fold_divide(self),
+            AST::LessThan { .. } => f.fold_less_than(self),
+            AST::GreaterThan { .. } => f.fold_greater_than(self),
+            AST::LessThanEqual { .. } => f.fold_less_than_equal(self),
+            AST::GreaterThanEqual { .. } => f.fold_greater_than_equal(self),
+            AST::Call { .. } => f.fold_call(self),
+            AST::Return { .. } => f.fold_return(self),
+            AST::Block(_) => f.fold_block(self),
+            AST::IfNode { .. } => f.fold_if(self),
+            AST::Function { .. } => f.fold_function(self),
+            AST::Var { .. } => f.fold_var(self),
+            AST::Assign { .. } => f.fold_assign(self),
+            AST::While { .. } => f.fold_while(self),
+            AST::Undefined => f.fold_undefined(self),
+            AST::Null => f.fold_null(self),
+            AST::Boolean(_) => f.fold_boolean(self),
+            AST::ArrayLiteral(_) => f.fold_array_literal(self),
+            AST::ArrayLookup { .. } => f.fold_array_lookup(self),
+            AST::ArrayLength(_) => f.fold_array_length(self),
+            AST::Main(_) => f.fold_main(self),
+            AST::Assert(_) => f.fold_assert(self),
+            AST::Print(_) => f.fold_print(self),
+        }
+    }
+
     fn equal(&self, node: &AST) -> bool {
         // Implement equality comparison based on your AST definition
         std::mem::discriminant(self) == std::mem::discriminant(node)
     }
 }
+
+pub fn walk_assert<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Assert(condition) = node else {
+        panic!("Expected Assert node, got: {:?}", node)
+    };
+    condition.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_print<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Print(value) = node else {
+        panic!("Expected Print node, got: {:?}", node)
+    };
+    value.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_array_length<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::ArrayLength(array) = node else {
+        panic!("Expected ArrayLength node, got: {:?}", node)
+    };
+    array.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_array_lookup<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::ArrayLookup { array, index } = node else {
+        panic!("Expected ArrayLookup node, got: {:?}", node)
+    };
+    array.visit(v, w)?;
+    index.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_array_literal<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::ArrayLiteral(items) = node else {
+        panic!("Expected ArrayLiteral node, got: {:?}", node)
+    };
+    for child in items {
+        child.visit(v, w)?;
+    }
+    Ok(T::default())
+}
+
+pub fn walk_boolean<T, W, V>(_v: &mut V, _node: &AST, _w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    Ok(T::default())
+}
+
+pub fn walk_number<T, W, V>(_v: &mut V, _node: &AST, _w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    Ok(T::default())
+}
+
+pub fn walk_id<T, W, V>(_v: &mut V, _node: &AST, _w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    Ok(T::default())
+}
+
+pub fn walk_not<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Not(term) = node else {
+        panic!("Expected Not node, got: {:?}", node)
+    };
+    term.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_equal<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Equal { left, right } = node else {
+        panic!("Expected Equal node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_not_equal<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::NotEqual { left, right } = node else {
+        panic!("Expected NotEqual node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_add<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Add { left, right } = node else {
+        panic!("Expected Add node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_subtract<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Subtract { left, right } = node else {
+        panic!("Expected Subtract node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_multiply<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Multiply { left, right } = node else {
+        panic!("Expected Multiply node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_divide<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Divide { left, right } = node else {
+        panic!("Expected Divide node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_less_than<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::LessThan { left, right } = node else {
+        panic!("Expected LessThan node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_greater_than<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::GreaterThan { left, right } = node else {
+        panic!("Expected GreaterThan node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_less_than_equal<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::LessThanEqual { left, right } = node else {
+        panic!("Expected LessThanEqual node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_greater_than_equal<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::GreaterThanEqual { left, right } = node else {
+        panic!("Expected GreaterThanEqual node, got: {:?}", node)
+    };
+    left.visit(v, w)?;
+    right.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_call<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Call { args, .. } = node else {
+        panic!("Expected Call node, got: {:?}", node)
+    };
+    for child in args {
+        child.visit(v, w)?;
+    }
+    Ok(T::default())
+}
+
+pub fn walk_return<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Return { term } = node else {
+        panic!("Expected Return node, got: {:?}", node)
+    };
+    term.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_block<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Block(statements) = node else {
+        panic!("Expected Block node, got: {:?}", node)
+    };
+    for child in statements {
+        child.visit(v, w)?;
+    }
+    Ok(T::default())
+}
+
+pub fn walk_if<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::IfNode {
+        conditional,
+        consequence,
+        alternative,
+    } = node
+    else {
+        panic!("Expected IfNode node, got: {:?}", node)
+    };
+    conditional.visit(v, w)?;
+    consequence.visit(v, w)?;
+    alternative.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_function<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Function { body, .. } = node else {
+        panic!("Expected Function node, got: {:?}", node)
+    };
+    body.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_var<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Var { value, .. } = node else {
+        panic!("Expected Var node, got: {:?}", node)
+    };
+    value.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_assign<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Assign { value, .. } = node else {
+        panic!("Expected Assign node, got: {:?}", node)
+    };
+    value.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_while<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::While { conditional, body } = node else {
+        panic!("Expected While node, got: {:?}", node)
+    };
+    conditional.visit(v, w)?;
+    body.visit(v, w)?;
+    Ok(T::default())
+}
+
+pub fn walk_undefined<T, W, V>(_v: &mut V, _node: &AST, _w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    Ok(T::default())
+}
+
+pub fn walk_null<T, W, V>(_v: &mut V, _node: &AST, _w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    Ok(T::default())
+}
+
+pub fn walk_main<T, W, V>(v: &mut V, node: &AST, w: &mut W) -> Result<T, CompileError>
+where
+    T: Default,
+    W: ?Sized,
+    V: Visitor<T, W> + ?Sized,
+{
+    let AST::Main(statements) = node else {
+        panic!("Expected Main node, got: {:?}", node)
+    };
+    for child in statements {
+        child.visit(v, w)?;
+    }
+    Ok(T::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    /// Records the leaves it sees, relying on the defaults for everything else.
+    struct Leaves(Vec<String>);
+
+    impl Visitor for Leaves {
+        fn visit_number(&mut self, node: &AST, _w: &mut ()) -> Result<(), CompileError> {
+            self.0.push(node.to_string());
+            Ok(())
+        }
+
+        fn visit_id(&mut self, node: &AST, _w: &mut ()) -> Result<(), CompileError> {
+            self.0.push(node.to_string());
+            Ok(())
+        }
+
+        fn visit_function(&mut self, node: &AST, w: &mut ()) -> Result<(), CompileError> {
+            self.0.push("function".to_string());
+            walk_function(self, node, w)
+        }
+    }
+
+    #[test]
+    fn default_methods_walk_every_child_in_order() {
+        let ast = parse(
+            r#"function f(a) {
+                var b = [a, k[1]];
+                if (!(a == 3)) { b = length(c) + d * e; } else { return f(g, 4); }
+                while (h < 5) { assert(i); print(j - 6); }
+            }"#,
+        )
+        .expect("Parser failed");
+        let mut leaves = Leaves(vec![]);
+        ast.accept(&mut leaves).expect("Visit failed");
+        assert_eq!(
+            vec![
+                "function", "a", "k", "1", "a", "3", "c", "d", "e", "g", "4", "h", "5", "i", "j",
+                "6"
+            ],
+            leaves.0
+        );
+    }
+
+    #[test]
+    fn visiting_through_a_trait_object() {
+        let mut leaves = Leaves(vec![]);
+        let visitor: &mut dyn Visitor = &mut leaves;
+        AST::Print(AST::Number(7).into())
+            .visit(visitor, &mut ())
+            .expect("Visit failed");
+        assert_eq!(vec!["7"], leaves.0);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::ast::AST;
+use crate::visitor::AstVisitor;
+
+/// A pass that may rewrite the `AST` in place, called back by
+/// `AstVisitor::visit_mut` with every node of the kind it is named after.
+///
+/// Like `Visitor`, every method defaults to the `walk_*_mut` function of the
+/// same name, which visits the children of the node in source order, so a
+/// pass only overrides the nodes it rewrites. An override can replace the
+/// whole node through `node`, and calls `walk_*_mut` to go into the children
+/// before or after doing so.
+pub trait VisitorMut {
+    fn visit_assert_mut(&mut self, node: &mut AST) {
+        walk_assert_mut(self, node)
+    }
+    fn visit_print_mut(&mut self, node: &mut AST) {
+        walk_print_mut(self, node)
+    }
+    fn visit_array_length_mut(&mut self, node: &mut AST) {
+        walk_array_length_mut(self, node)
+    }
+    fn visit_array_lookup_mut(&mut self, node: &mut AST) {
+        walk_array_lookup_mut(self, node)
+    }
+    fn visit_array_literal_mut(&mut self, node: &mut AST) {
+        walk_array_literal_mut(self, node)
+    }
+    fn visit_boolean_mut(&mut self, node: &mut AST) {
+        walk_boolean_mut(self, node)
+    }
+    fn visit_number_mut(&mut self, node: &mut AST) {
+        walk_number_mut(self, node)
+    }
+    fn visit_id_mut(&mut self, node: &mut AST) {
+        walk_id_mut(self, node)
+    }
+    fn visit_not_mut(&mut self, node: &mut AST) {
+        walk_not_mut(self, node)
+    }
+    fn visit_equal_mut(&mut self, node: &mut AST) {
+        walk_equal_mut(self, node)
+    }
+    fn visit_not_equal_mut(&mut self, node: &mut AST) {
+        walk_not_equal_mut(self, node)
+    }
+    fn visit_add_mut(&mut self, node: &mut AST) {
+        walk_add_mut(self, node)
+    }
+    fn visit_subtract_mut(&mut self, node: &mut AST) {
+        walk_subtract_mut(self, node)
+    }
+    fn visit_multiply_mut(&mut self, node: &mut AST) {
+        walk_multiply_mut(self, node)
+    }
+    fn visit_divide_mut(&mut self, node: &mut AST) {
+        walk_divide_mut(self, node)
+    }
+    fn visit_less_than_mut(&mut self, node: &mut AST) {
+        walk_less_than_mut(self, node)
+    }
+    fn visit_greater_than_mut(&mut self, node: &mut AST) {
+        walk_greater_than_mut(self, node)
+    }
+    fn visit_less_than_equal_mut(&mut self, node: &mut AST) {
+        walk_less_than_equal_mut(self, node)
+    }
+    fn visit_greater_than_equal_mut(&mut self, node: &mut AST) {
+        walk_greater_than_equal_mut(self, node)
+    }
+    fn visit_call_mut(&mut self, node: &mut AST) {
+        walk_call_mut(self, node)
+    }
+    fn visit_return_mut(&mut self, node: &mut AST) {
+        walk_return_mut(self, node)
+    }
+    fn visit_block_mut(&mut self, node: &mut AST) {
+        walk_block_mut(self, node)
+    }
+    fn visit_if_mut(&mut self, node: &mut AST) {
+        walk_if_mut(self, node)
+    }
+    fn visit_function_mut(&mut self, node: &mut AST) {
+        walk_function_mut(self, node)
+    }
+    fn visit_var_mut(&mut self, node: &mut AST) {
+        walk_var_mut(self, node)
+    }
+    fn visit_assign_mut(&mut self, node: &mut AST) {
+        walk_assign_mut(self, node)
+    }
+    fn visit_while_mut(&mut self, node: &mut AST) {
+        walk_while_mut(self, node)
+    }
+    fn visit_undefined_mut(&mut self, node: &mut AST) {
+        walk_undefined_mut(self, node)
+    }
+    fn visit_null_mut(&mut self, node: &mut AST) {
+        walk_null_mut(self, node)
+    }
+    fn visit_main_mut(&mut self, node: &mut AST) {
+        walk_main_mut(self, node)
+    }
+}
+
+pub fn walk_assert_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Assert(condition) = node else {
+        panic!("Expected Assert node, got: {:?}", node)
+    };
+    condition.visit_mut(v);
+}
+
+pub fn walk_print_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Print(value) = node else {
+        panic!("Expected Print node, got: {:?}", node)
+    };
+    value.visit_mut(v);
+}
+
+pub fn walk_array_length_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::ArrayLength(array) = node else {
+        panic!("Expected ArrayLength node, got: {:?}", node)
+    };
+    array.visit_mut(v);
+}
+
+pub fn walk_array_lookup_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::ArrayLookup { array, index } = node else {
+        panic!("Expected ArrayLookup node, got: {:?}", node)
+    };
+    array.visit_mut(v);
+    index.visit_mut(v);
+}
+
+pub fn walk_array_literal_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::ArrayLiteral(items) = node else {
+        panic!("Expected ArrayLiteral node, got: {:?}", node)
+    };
+    for child in items {
+        child.visit_mut(v);
+    }
+}
+
+pub fn walk_boolean_mut<V: VisitorMut + ?Sized>(_v: &mut V, _node: &mut AST) {}
+
+pub fn walk_number_mut<V: VisitorMut + ?Sized>(_v: &mut V, _node: &mut AST) {}
+
+pub fn walk_id_mut<V: VisitorMut + ?Sized>(_v: &mut V, _node: &mut AST) {}
+
+pub fn walk_not_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Not(term) = node else {
+        panic!("Expected Not node, got: {:?}", node)
+    };
+    term.visit_mut(v);
+}
+
+pub fn walk_equal_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Equal { left, right } = node else {
+        panic!("Expected Equal node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_not_equal_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::NotEqual { left, right } = node else {
+        panic!("Expected NotEqual node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_add_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Add { left, right } = node else {
+        panic!("Expected Add node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_subtract_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Subtract { left, right } = node else {
+        panic!("Expected Subtract node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_multiply_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Multiply { left, right } = node else {
+        panic!("Expected Multiply node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_divide_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Divide { left, right } = node else {
+        panic!("Expected Divide node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_less_than_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::LessThan { left, right } = node else {
+        panic!("Expected LessThan node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_greater_than_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::GreaterThan { left, right } = node else {
+        panic!("Expected GreaterThan node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_less_than_equal_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::LessThanEqual { left, right } = node else {
+        panic!("Expected LessThanEqual node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_greater_than_equal_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::GreaterThanEqual { left, right } = node else {
+        panic!("Expected GreaterThanEqual node, got: {:?}", node)
+    };
+    left.visit_mut(v);
+    right.visit_mut(v);
+}
+
+pub fn walk_call_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Call { args, .. } = node else {
+        panic!("Expected Call node, got: {:?}", node)
+    };
+    for child in args {
+        child.visit_mut(v);
+    }
+}
+
+pub fn walk_return_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Return { term } = node else {
+        panic!("Expected Return node, got: {:?}", node)
+    };
+    term.visit_mut(v);
+}
+
+pub fn walk_block_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Block(statements) = node else {
+        panic!("Expected Block node, got: {:?}", node)
+    };
+    for child in statements {
+        child.visit_mut(v);
+    }
+}
+
+pub fn walk_if_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::IfNode {
+        conditional,
+        consequence,
+        alternative,
+    } = node
+    else {
+        panic!("Expected IfNode node, got: {:?}", node)
+    };
+    conditional.visit_mut(v);
+    consequence.visit_mut(v);
+    alternative.visit_mut(v);
+}
+
+pub fn walk_function_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Function { body, .. } = node else {
+        panic!("Expected Function node, got: {:?}", node)
+    };
+    body.visit_mut(v);
+}
+
+pub fn walk_var_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Var { value, .. } = node else {
+        panic!("Expected Var node, got: {:?}", node)
+    };
+    value.visit_mut(v);
+}
+
+pub fn walk_assign_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Assign { value, .. } = node else {
+        panic!("Expected Assign node, got: {:?}", node)
+    };
+    value.visit_mut(v);
+}
+
+pub fn walk_while_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::While { conditional, body } = node else {
+        panic!("Expected While node, got: {:?}", node)
+    };
+    conditional.visit_mut(v);
+    body.visit_mut(v);
+}
+
+pub fn walk_undefined_mut<V: VisitorMut + ?Sized>(_v: &mut V, _node: &mut AST) {}
+
+pub fn walk_null_mut<V: VisitorMut + ?Sized>(_v: &mut V, _node: &mut AST) {}
+
+pub fn walk_main_mut<V: VisitorMut + ?Sized>(v: &mut V, node: &mut AST) {
+    let AST::Main(statements) = node else {
+        panic!("Expected Main node, got: {:?}", node)
+    };
+    for child in statements {
+        child.visit_mut(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::ProgramGenerator;
+    use crate::parser::parse;
+
+    /// Renames a variable wherever it is declared, assigned or read.
+    struct Rename(&'static str, &'static str);
+
+    impl VisitorMut for Rename {
+        fn visit_id_mut(&mut self, node: &mut AST) {
+            if *node == AST::Id(self.0.to_string()) {
+                *node = AST::Id(self.1.to_string());
+            }
+        }
+
+        fn visit_var_mut(&mut self, node: &mut AST) {
+            self.rename_target(node);
+            walk_var_mut(self, node)
+        }
+
+        fn visit_assign_mut(&mut self, node: &mut AST) {
+            self.rename_target(node);
+            walk_assign_mut(self, node)
+        }
+    }
+
+    impl Rename {
+        fn rename_target(&self, node: &mut AST) {
+            if let AST::Var { name, .. } | AST::Assign { name, .. } = node {
+                if name == self.0 {
+                    *name = self.1.to_string();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rewrite_in_place() {
+        let mut ast = parse("var x = 1; while (x < 3) { x = x + 1; } print(length([x]));")
+            .expect("Parser failed");
+        ast.visit_mut(&mut Rename("x", "y"));
+        assert_eq!(
+            parse("var y = 1; while (y < 3) { y = y + 1; } print(length([y]));")
+                .expect("Parser failed"),
+            ast
+        );
+    }
+
+    #[test]
+    fn default_methods_change_nothing() {
+        struct Nothing;
+        impl VisitorMut for Nothing {}
+        for seed in 0..50 {
+            let expected = ProgramGenerator::new(seed).program();
+            let mut ast = expected.clone();
+            ast.visit_mut(&mut Nothing);
+            assert_eq!(expected, ast);
+        }
+    }
+}
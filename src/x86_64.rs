@@ -0,0 +1,458 @@
+//! The x86-64 assembly `x86_64_code_generator` produces, as data.
+//!
+//! Like `arm`, code is a list of `Line`s, which print in the AT&T syntax of
+//! the GNU assembler: the source operand first and the destination last,
+//! with `q` suffixes for 64-bit operations. Conditions are `arm`'s, printed
+//! under their x86 names.
+
+use crate::arm;
+pub use crate::arm::Condition;
+use std::fmt;
+
+/// A general-purpose register, by its number in the encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Register(pub u8);
+
+pub const RAX: Register = Register(0);
+pub const RCX: Register = Register(1);
+pub const RDX: Register = Register(2);
+pub const RBX: Register = Register(3);
+pub const RSP: Register = Register(4);
+pub const RBP: Register = Register(5);
+pub const RSI: Register = Register(6);
+pub const RDI: Register = Register(7);
+pub const R8: Register = Register(8);
+pub const R9: Register = Register(9);
+
+/// The registers the first six arguments of a call are passed in.
+pub const ARGUMENTS: [Register; 6] = [RDI, RSI, RDX, RCX, R8, R9];
+
+const NAMES: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
+const BYTE_NAMES: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+
+impl Register {
+    /// The name of the lowest byte of the register, without the `%`.
+    pub fn byte_name(self) -> &'static str {
+        BYTE_NAMES[self.0 as usize]
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "%{}", NAMES[self.0 as usize])
+    }
+}
+
+/// The name of `condition` in `j`, `set` and `cmov` mnemonics.
+///
+/// # Panics
+///
+/// For `Al`: only `jmp` is unconditional.
+pub fn condition_name(condition: Condition) -> &'static str {
+    match condition {
+        Condition::Eq => "e",
+        Condition::Ne => "ne",
+        Condition::Hs => "ae",
+        Condition::Lo => "b",
+        Condition::Mi => "s",
+        Condition::Pl => "ns",
+        Condition::Vs => "o",
+        Condition::Vc => "no",
+        Condition::Hi => "a",
+        Condition::Ls => "be",
+        Condition::Ge => "ge",
+        Condition::Lt => "l",
+        Condition::Gt => "g",
+        Condition::Le => "le",
+        Condition::Al => panic!("x86-64 has no always condition"),
+    }
+}
+
+/// A memory operand.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// `offset(base)`.
+    Offset(Register, i32),
+    /// `offset(base, index, scale)`: `base + index * scale + offset`, with
+    /// a scale of 1, 2, 4 or 8.
+    Indexed(Register, Register, u8, i32),
+    /// `label(%rip)`: a label, addressed relative to the instruction.
+    Label(String),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Offset(base, 0) => write!(f, "({})", base),
+            Address::Offset(base, offset) => write!(f, "{}({})", offset, base),
+            Address::Indexed(base, index, scale, 0) => {
+                write!(f, "({}, {}, {})", base, index, scale)
+            }
+            Address::Indexed(base, index, scale, offset) => {
+                write!(f, "{}({}, {}, {})", offset, base, index, scale)
+            }
+            Address::Label(label) => write!(f, "{}(%rip)", label),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Operand {
+    /// At most 32 bits, sign-extended, except as the source of a `mov` to a
+    /// register.
+    Immediate(i64),
+    Register(Register),
+    Memory(Address),
+}
+
+impl From<Register> for Operand {
+    fn from(register: Register) -> Operand {
+        Operand::Register(register)
+    }
+}
+
+impl From<Address> for Operand {
+    fn from(address: Address) -> Operand {
+        Operand::Memory(address)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Immediate(value) => write!(f, "${}", value),
+            Operand::Register(register) => write!(f, "{}", register),
+            Operand::Memory(address) => write!(f, "{}", address),
+        }
+    }
+}
+
+/// The two-operand instructions that combine `src` into `dest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    /// Signed multiply, keeping the low 64 bits.
+    Imul,
+}
+
+impl ArithmeticOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            ArithmeticOp::Add => "addq",
+            ArithmeticOp::Sub => "subq",
+            ArithmeticOp::Imul => "imulq",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    /// `movq`, or `movabsq` for an immediate that doesn't fit 32 bits.
+    Move {
+        dest: Register,
+        src: Operand,
+    },
+    Store {
+        src: Register,
+        address: Address,
+    },
+    /// `leaq`: computes an address without accessing it.
+    LoadAddress {
+        dest: Register,
+        address: Address,
+    },
+    Arithmetic {
+        op: ArithmeticOp,
+        dest: Register,
+        src: Operand,
+    },
+    /// `cqto`: sign-extends `%rax` into `%rdx`, for `idivq`.
+    SignExtend,
+    /// `idivq`: divides `%rdx:%rax` by the register, leaving the quotient in
+    /// `%rax`. Faults on zero and on an overflowing quotient.
+    Divide(Register),
+    /// `cmpq`: sets the flags from `left - right`. AT&T syntax prints
+    /// `right` first.
+    Compare {
+        left: Register,
+        right: Operand,
+    },
+    /// `set<cc>`: sets the lowest byte of `dest` to whether `condition`
+    /// holds, leaving the rest.
+    SetIf {
+        dest: Register,
+        condition: Condition,
+    },
+    /// `movzbq`: zero-extends the lowest byte of the register over all of
+    /// it.
+    ZeroExtendByte(Register),
+    /// `cmov<cc>q`: moves `src` to `dest` if `condition` holds.
+    MoveIf {
+        dest: Register,
+        src: Register,
+        condition: Condition,
+    },
+    Push(Register),
+    Pop(Register),
+    Jump {
+        condition: Condition,
+        target: String,
+    },
+    Call(String),
+    Return,
+}
+
+impl Instruction {
+    pub fn mov(dest: Register, src: impl Into<Operand>) -> Instruction {
+        Instruction::Move {
+            dest,
+            src: src.into(),
+        }
+    }
+
+    pub fn store(src: Register, address: Address) -> Instruction {
+        Instruction::Store { src, address }
+    }
+
+    pub fn add(dest: Register, src: impl Into<Operand>) -> Instruction {
+        Instruction::Arithmetic {
+            op: ArithmeticOp::Add,
+            dest,
+            src: src.into(),
+        }
+    }
+
+    pub fn sub(dest: Register, src: impl Into<Operand>) -> Instruction {
+        Instruction::Arithmetic {
+            op: ArithmeticOp::Sub,
+            dest,
+            src: src.into(),
+        }
+    }
+
+    pub fn cmp(left: Register, right: impl Into<Operand>) -> Instruction {
+        Instruction::Compare {
+            left,
+            right: right.into(),
+        }
+    }
+
+    pub fn jmp(target: &str) -> Instruction {
+        Instruction::Jump {
+            condition: Condition::Al,
+            target: target.to_string(),
+        }
+    }
+
+    pub fn call(target: &str) -> Instruction {
+        Instruction::Call(target.to_string())
+    }
+
+    /// The same jump, taken only under `condition`.
+    ///
+    /// # Panics
+    ///
+    /// If the instruction isn't a jump.
+    pub fn when(mut self, new: Condition) -> Instruction {
+        match &mut self {
+            Instruction::Jump { condition, .. } => *condition = new,
+            _ => panic!("{} can't be conditional", self),
+        }
+        self
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Move {
+                dest,
+                src: src @ Operand::Immediate(value),
+            } if i32::try_from(*value).is_err() => write!(f, "movabsq {}, {}", src, dest),
+            Instruction::Move { dest, src } => write!(f, "movq {}, {}", src, dest),
+            Instruction::Store { src, address } => write!(f, "movq {}, {}", src, address),
+            Instruction::LoadAddress { dest, address } => {
+                write!(f, "leaq {}, {}", address, dest)
+            }
+            Instruction::Arithmetic { op, dest, src } => {
+                write!(f, "{} {}, {}", op.name(), src, dest)
+            }
+            Instruction::SignExtend => write!(f, "cqto"),
+            Instruction::Divide(register) => write!(f, "idivq {}", register),
+            Instruction::Compare { left, right } => write!(f, "cmpq {}, {}", right, left),
+            Instruction::SetIf { dest, condition } => {
+                write!(f, "set{} %{}", condition_name(*condition), dest.byte_name())
+            }
+            Instruction::ZeroExtendByte(register) => {
+                write!(f, "movzbq %{}, {}", register.byte_name(), register)
+            }
+            Instruction::MoveIf {
+                dest,
+                src,
+                condition,
+            } => write!(f, "cmov{}q {}, {}", condition_name(*condition), src, dest),
+            Instruction::Push(register) => write!(f, "pushq {}", register),
+            Instruction::Pop(register) => write!(f, "popq {}", register),
+            Instruction::Jump {
+                condition: Condition::Al,
+                target,
+            } => write!(f, "jmp {}", target),
+            Instruction::Jump { condition, target } => {
+                write!(f, "j{} {}", condition_name(*condition), target)
+            }
+            Instruction::Call(target) => write!(f, "call {}", target),
+            Instruction::Return => write!(f, "ret"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Directive {
+    /// Makes a function visible to the linker.
+    Global(String),
+    /// A string followed by a zero byte.
+    Asciz(String),
+    /// Switches to the named section, with its flags and type.
+    Section(String),
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Directive::Global(name) => write!(f, "{}", arm::Directive::Global(name.clone())),
+            Directive::Asciz(text) => write!(f, "{}", arm::Directive::Asciz(text.clone())),
+            Directive::Section(section) => write!(f, ".section {}", section),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Line {
+    Label(String),
+    Directive(Directive),
+    Instruction(Instruction),
+}
+
+impl From<Instruction> for Line {
+    fn from(instruction: Instruction) -> Line {
+        Line::Instruction(instruction)
+    }
+}
+
+impl From<Directive> for Line {
+    fn from(directive: Directive) -> Line {
+        Line::Directive(directive)
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Label(name) => write!(f, "{}:", name),
+            // Functions are set apart by a blank line.
+            Line::Directive(directive @ Directive::Global(_)) => write!(f, "\n{}", directive),
+            Line::Directive(directive) => write!(f, "\t{}", directive),
+            Line::Instruction(instruction) => write!(f, "\t{}", instruction),
+        }
+    }
+}
+
+/// Renders lines as assembly source.
+pub fn print(lines: &[Line]) -> String {
+    let mut out = String::new();
+    for line in lines {
+        out.push_str(&line.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printing() {
+        let lines: Vec<Line> = vec![
+            Directive::Global("main".to_string()).into(),
+            Line::Label("main".to_string()),
+            Instruction::Push(RBP).into(),
+            Instruction::mov(RBP, RSP).into(),
+            Instruction::sub(RSP, Operand::Immediate(48)).into(),
+            Instruction::store(RDI, Address::Offset(RBP, -48)).into(),
+            Instruction::mov(RAX, Address::Indexed(RCX, RAX, 8, 8)).into(),
+            Instruction::mov(RAX, Operand::Immediate(4294967296)).into(),
+            Instruction::mov(RAX, Operand::Immediate(-1)).into(),
+            Instruction::LoadAddress {
+                dest: RDI,
+                address: Address::Label(".Lfmt".to_string()),
+            }
+            .into(),
+            Instruction::Arithmetic {
+                op: ArithmeticOp::Imul,
+                dest: RAX,
+                src: RCX.into(),
+            }
+            .into(),
+            Instruction::SignExtend.into(),
+            Instruction::Divide(RSI).into(),
+            Instruction::cmp(RCX, RAX).into(),
+            Instruction::SetIf {
+                dest: RAX,
+                condition: Condition::Lt,
+            }
+            .into(),
+            Instruction::ZeroExtendByte(RAX).into(),
+            Instruction::MoveIf {
+                dest: RDI,
+                src: RCX,
+                condition: Condition::Eq,
+            }
+            .into(),
+            Instruction::jmp(".L1").when(Condition::Hs).into(),
+            Instruction::jmp(".L2").into(),
+            Directive::Asciz("%ld\n".to_string()).into(),
+            Instruction::call("printf").into(),
+            Instruction::Pop(RBP).into(),
+            Instruction::Return.into(),
+            Directive::Section(".note.GNU-stack,\"\",@progbits".to_string()).into(),
+        ];
+        assert_eq!(
+            "
+.global main
+main:
+	pushq %rbp
+	movq %rsp, %rbp
+	subq $48, %rsp
+	movq %rdi, -48(%rbp)
+	movq 8(%rcx, %rax, 8), %rax
+	movabsq $4294967296, %rax
+	movq $-1, %rax
+	leaq .Lfmt(%rip), %rdi
+	imulq %rcx, %rax
+	cqto
+	idivq %rsi
+	cmpq %rax, %rcx
+	setl %al
+	movzbq %al, %rax
+	cmoveq %rcx, %rdi
+	jae .L1
+	jmp .L2
+	.asciz \"%ld\\n\"
+	call printf
+	popq %rbp
+	ret
+	.section .note.GNU-stack,\"\",@progbits
+",
+            print(&lines)
+        );
+    }
+}
@@ -0,0 +1,763 @@
+//! Generates x86-64 code from the tree, for the System V calling convention
+//! of Linux, so that programs can run natively with the host's C library.
+//! Integers have 64 bits and division is signed, as in
+//! `aarch64_code_generator`.
+//!
+//! Values are computed in `%rax`, and temporaries take 16-byte slots so
+//! that `%rsp` is 16-byte aligned at every call. A frame holds the six
+//! argument registers below the saved `%rbp`, stored as the parameters
+//! whether the function takes them or not, and then the locals.
+
+use crate::arm_code_generator::calls_itself_in_tail_position;
+use crate::ast::AST;
+use crate::error::CompileError;
+use crate::parser::parse;
+use crate::visitor::{AstVisitor, Visitor};
+use crate::x86_64::{
+    self, Address, ArithmeticOp, Condition, Directive, Instruction, Line, Operand, Register,
+    ARGUMENTS, RAX, RBP, RBX, RCX, RDI, RDX, RSI, RSP,
+};
+use std::collections::HashMap;
+
+/// The bytes the parameters take below `%rbp`.
+const PARAMETERS_SIZE: i32 = 8 * ARGUMENTS.len() as i32;
+
+#[derive(Default)]
+pub struct X86_64CodeGenerator {
+    locals: HashMap<String, i32>,
+    next_local_offset: i32,
+    label_counter: usize,
+    /// The function being generated, its number of parameters and the label
+    /// following its prologue, if it calls itself in tail position.
+    self_tail_call: Option<(String, usize, String)>,
+}
+
+/// Pushes `register` in a slot of its own.
+fn push(register: Register) -> [Line; 2] {
+    [
+        Instruction::sub(RSP, Operand::Immediate(16)).into(),
+        Instruction::store(register, Address::Offset(RSP, 0)).into(),
+    ]
+}
+
+/// Pops what `push` pushed into `register`.
+fn pop(register: Register) -> [Line; 2] {
+    [
+        Instruction::mov(register, Address::Offset(RSP, 0)).into(),
+        Instruction::add(RSP, Operand::Immediate(16)).into(),
+    ]
+}
+
+impl X86_64CodeGenerator {
+    /// Evaluates `left` into `%rcx` and `right` into `%rax`.
+    fn visit_infix_operands(
+        &mut self,
+        left: &AST,
+        right: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        left.visit(self, writer)?;
+        writer.extend(push(RAX));
+        right.visit(self, writer)?;
+        writer.extend(pop(RCX));
+        Ok(())
+    }
+
+    fn emit_fn_prologue(&mut self, writer: &mut Vec<Line>) {
+        writer.push(Instruction::Push(RBP).into());
+        writer.push(Instruction::mov(RBP, RSP).into());
+        let size = Operand::Immediate(PARAMETERS_SIZE as i64);
+        writer.push(Instruction::sub(RSP, size).into());
+        for (i, register) in ARGUMENTS.into_iter().enumerate() {
+            let parameter = Address::Offset(RBP, 8 * i as i32 - PARAMETERS_SIZE);
+            writer.push(Instruction::store(register, parameter).into());
+        }
+    }
+
+    /// Drops the frame and returns, with whatever is in `%rax`.
+    fn emit_return(&mut self, writer: &mut Vec<Line>) {
+        writer.push(Instruction::mov(RSP, RBP).into());
+        writer.push(Instruction::Pop(RBP).into());
+        writer.push(Instruction::Return.into());
+    }
+
+    /// Evaluates the arguments of a call into the argument registers.
+    fn emit_arguments(&mut self, args: &[AST], writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        if args.len() > ARGUMENTS.len() {
+            return Err(CompileError::CodeGenError(format!(
+                "More than {} arguments are not supported in function calls",
+                ARGUMENTS.len()
+            )));
+        }
+        if args.is_empty() {
+            return Ok(());
+        }
+        let size = Operand::Immediate(16 * args.len().div_ceil(2) as i64);
+        writer.push(Instruction::sub(RSP, size.clone()).into());
+        for (i, arg) in args.iter().enumerate() {
+            arg.visit(self, writer)?;
+            writer.push(Instruction::store(RAX, Address::Offset(RSP, 8 * i as i32)).into());
+        }
+        for (i, register) in ARGUMENTS.into_iter().take(args.len()).enumerate() {
+            let slot = Address::Offset(RSP, 8 * i as i32);
+            writer.push(Instruction::mov(register, slot).into());
+        }
+        writer.push(Instruction::add(RSP, size).into());
+        Ok(())
+    }
+
+    /// Generates `return callee(args)` without growing the stack, like
+    /// `ArmCodeGenerator` does.
+    fn emit_tail_call(
+        &mut self,
+        callee: &str,
+        args: &[AST],
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        self.emit_arguments(args, writer)?;
+        match &self.self_tail_call {
+            Some((name, parameters, label)) if name == callee && *parameters == args.len() => {
+                for (i, register) in ARGUMENTS.into_iter().take(args.len()).enumerate() {
+                    let parameter = Address::Offset(RBP, 8 * i as i32 - PARAMETERS_SIZE);
+                    writer.push(Instruction::store(register, parameter).into());
+                }
+                writer.push(
+                    Instruction::LoadAddress {
+                        dest: RSP,
+                        address: Address::Offset(RBP, -PARAMETERS_SIZE),
+                    }
+                    .into(),
+                );
+                writer.push(Instruction::jmp(label).into());
+            }
+            _ => {
+                writer.push(Instruction::mov(RSP, RBP).into());
+                writer.push(Instruction::Pop(RBP).into());
+                writer.push(Instruction::jmp(callee).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Jumps to `label` when `condition` is `when`, comparing the operands
+    /// of a comparison directly.
+    fn emit_branch(
+        &mut self,
+        condition: &AST,
+        when: bool,
+        label: &str,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let (left, right, holds) = match condition {
+            AST::Not(term) => return self.emit_branch(term, !when, label, writer),
+            AST::Equal { left, right } => (left, right, Condition::Eq),
+            AST::NotEqual { left, right } => (left, right, Condition::Ne),
+            AST::LessThan { left, right } => (left, right, Condition::Lt),
+            AST::GreaterThan { left, right } => (left, right, Condition::Gt),
+            AST::LessThanEqual { left, right } => (left, right, Condition::Le),
+            AST::GreaterThanEqual { left, right } => (left, right, Condition::Ge),
+            _ => {
+                condition.visit(self, writer)?;
+                writer.push(Instruction::cmp(RAX, Operand::Immediate(0)).into());
+                let taken = if when { Condition::Ne } else { Condition::Eq };
+                writer.push(Instruction::jmp(label).when(taken).into());
+                return Ok(());
+            }
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::cmp(RCX, RAX).into());
+        let taken = if when { holds } else { holds.inverse() };
+        writer.push(Instruction::jmp(label).when(taken).into());
+        Ok(())
+    }
+
+    /// Sets `%rax` to 1 if comparing the operands gives `condition`, and to
+    /// 0 otherwise.
+    fn emit_comparison(
+        &mut self,
+        left: &AST,
+        right: &AST,
+        condition: Condition,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        self.visit_infix_operands(left, right, writer)?;
+        writer.extend(set_if(Instruction::cmp(RCX, RAX), condition));
+        Ok(())
+    }
+
+    fn new_label(&mut self) -> String {
+        self.label_counter += 1;
+        format!(".L{}", self.label_counter)
+    }
+
+    fn local(&self, name: &str) -> Result<Address, CompileError> {
+        self.locals
+            .get(name)
+            .map(|offset| Address::Offset(RBP, *offset))
+            .ok_or_else(|| CompileError::CodeGenError(format!("Undefined variable: {}", name)))
+    }
+}
+
+/// Sets `%rax` to whether the flags set by `compare` give `condition`.
+fn set_if(compare: Instruction, condition: Condition) -> [Line; 3] {
+    [
+        compare.into(),
+        Instruction::SetIf {
+            dest: RAX,
+            condition,
+        }
+        .into(),
+        Instruction::ZeroExtendByte(RAX).into(),
+    ]
+}
+
+/// Generates x86-64 code for an already parsed program.
+pub fn generate_lines(ast: &AST) -> Result<Vec<Line>, CompileError> {
+    let mut generator: X86_64CodeGenerator = Default::default();
+    let mut lines = Vec::new();
+    ast.visit(&mut generator, &mut lines)?;
+    // Linkers otherwise take the stack to be executable, and warn about it.
+    let note = ".note.GNU-stack,\"\",@progbits".to_string();
+    lines.push(Directive::Section(note).into());
+    Ok(lines)
+}
+
+/// Generates x86-64 assembly for an already parsed program.
+pub fn generate(ast: &AST) -> Result<String, CompileError> {
+    Ok(x86_64::print(&generate_lines(ast)?))
+}
+
+/// Parses `source` and generates x86-64 assembly for it.
+pub fn compile(source: &str) -> Result<String, CompileError> {
+    generate(&parse(source)?)
+}
+
+impl Visitor<(), Vec<Line>> for X86_64CodeGenerator {
+    fn visit_assert(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Assert(condition) = node else {
+            panic!("Expected Assert node, got: {:?}", node)
+        };
+        condition.visit(self, writer)?;
+        writer.push(Instruction::cmp(RAX, Operand::Immediate(1)).into());
+        writer.push(Instruction::mov(RDI, Operand::Immediate('F' as i64)).into());
+        writer.push(Instruction::mov(RCX, Operand::Immediate('T' as i64)).into());
+        writer.push(
+            Instruction::MoveIf {
+                dest: RDI,
+                src: RCX,
+                condition: Condition::Eq,
+            }
+            .into(),
+        );
+        writer.push(Instruction::call("putchar").into());
+        Ok(())
+    }
+
+    fn visit_print(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Print(value) = node else {
+            panic!("Expected Print node, got: {:?}", node)
+        };
+        let fmt_label = format!(".Lprint_fmt_{}", self.label_counter);
+        let skip_label = format!(".Lskip_fmt_{}", self.label_counter);
+        self.label_counter += 1;
+
+        value.visit(self, writer)?;
+        writer.push(Instruction::jmp(&skip_label).into());
+        writer.push(Line::Label(fmt_label.clone()));
+        writer.push(Directive::Asciz("%ld\n".to_string()).into());
+        writer.push(Line::Label(skip_label));
+        writer.push(Instruction::mov(RSI, RAX).into());
+        writer.push(
+            Instruction::LoadAddress {
+                dest: RDI,
+                address: Address::Label(fmt_label),
+            }
+            .into(),
+        );
+        // Variadic functions take the number of vector registers used in
+        // %al.
+        writer.push(Instruction::mov(RAX, Operand::Immediate(0)).into());
+        writer.push(Instruction::call("printf").into());
+        Ok(())
+    }
+
+    fn visit_array_length(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::ArrayLength(array) = node else {
+            panic!("Expected ArrayLength node, got: {:?}", node)
+        };
+        array.visit(self, writer)?;
+        writer.push(Instruction::mov(RAX, Address::Offset(RAX, 0)).into());
+        Ok(())
+    }
+
+    fn visit_array_lookup(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::ArrayLookup { array, index } = node else {
+            panic!("Expected ArrayLookup node, got: {:?}", node)
+        };
+        self.visit_infix_operands(array, index, writer)?;
+        // Out of bounds, %rdx stays 0.
+        let end = self.new_label();
+        writer.push(Instruction::cmp(RAX, Address::Offset(RCX, 0)).into());
+        writer.push(Instruction::mov(RDX, Operand::Immediate(0)).into());
+        writer.push(Instruction::jmp(&end).when(Condition::Hs).into());
+        let element = Address::Indexed(RCX, RAX, 8, 8);
+        writer.push(Instruction::mov(RDX, element).into());
+        writer.push(Line::Label(end));
+        writer.push(Instruction::mov(RAX, RDX).into());
+        Ok(())
+    }
+
+    fn visit_array_literal(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::ArrayLiteral(array_items) = node else {
+            panic!("Expected ArrayLiteral node, got: {:?}", node)
+        };
+        let len = array_items.len() as i64;
+        writer.push(Instruction::mov(RDI, Operand::Immediate(8 * (len + 1))).into());
+        writer.push(Instruction::call("malloc").into());
+        writer.extend(push(RBX));
+        writer.push(Instruction::mov(RBX, RAX).into());
+        let length = Operand::Immediate(len);
+        writer.push(Instruction::mov(RAX, length).into());
+        writer.push(Instruction::store(RAX, Address::Offset(RBX, 0)).into());
+        for (i, item) in array_items.iter().enumerate() {
+            item.visit(self, writer)?;
+            let element = Address::Offset(RBX, 8 * (i as i32 + 1));
+            writer.push(Instruction::store(RAX, element).into());
+        }
+        writer.push(Instruction::mov(RAX, RBX).into());
+        writer.extend(pop(RBX));
+        Ok(())
+    }
+
+    fn visit_boolean(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Boolean(value) = node else {
+            panic!("Expected Boolean node, got: {:?}", node)
+        };
+        let value = Operand::Immediate(if *value { 1 } else { 0 });
+        writer.push(Instruction::mov(RAX, value).into());
+        Ok(())
+    }
+
+    fn visit_number(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Number(number) = node else {
+            panic!("Expected Number node, got: {:?}", node)
+        };
+        let value = Operand::Immediate(*number as i64);
+        writer.push(Instruction::mov(RAX, value).into());
+        Ok(())
+    }
+
+    fn visit_id(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Id(name) = node else {
+            panic!("Expected Id node, got: {:?}", node)
+        };
+        writer.push(Instruction::mov(RAX, self.local(name)?).into());
+        Ok(())
+    }
+
+    fn visit_not(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Not(term) = node else {
+            panic!("Expected Not node, got: {:?}", node)
+        };
+        term.visit(self, writer)?;
+        let compare = Instruction::cmp(RAX, Operand::Immediate(0));
+        writer.extend(set_if(compare, Condition::Eq));
+        Ok(())
+    }
+
+    fn visit_equal(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Equal { left, right } = node else {
+            panic!("Expected Equal node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Eq, writer)
+    }
+
+    fn visit_not_equal(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::NotEqual { left, right } = node else {
+            panic!("Expected NotEqual node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Ne, writer)
+    }
+
+    fn visit_add(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Add { left, right } = node else {
+            panic!("Expected Add node, got: {:?}", node)
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::add(RAX, RCX).into());
+        Ok(())
+    }
+
+    fn visit_subtract(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Subtract { left, right } = node else {
+            panic!("Expected Subtract node, got: {:?}", node)
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(Instruction::sub(RCX, RAX).into());
+        writer.push(Instruction::mov(RAX, RCX).into());
+        Ok(())
+    }
+
+    fn visit_multiply(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Multiply { left, right } = node else {
+            panic!("Expected Multiply node, got: {:?}", node)
+        };
+        self.visit_infix_operands(left, right, writer)?;
+        writer.push(
+            Instruction::Arithmetic {
+                op: ArithmeticOp::Imul,
+                dest: RAX,
+                src: RCX.into(),
+            }
+            .into(),
+        );
+        Ok(())
+    }
+
+    fn visit_divide(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Divide { left, right } = node else {
+            panic!("Expected Divide node, got: {:?}", node)
+        };
+        // `idivq` faults dividing by 0 or -1 where the other targets don't,
+        // so those multiply instead: by 0 to give 0, and by -1 to negate
+        // with wrapping.
+        self.visit_infix_operands(left, right, writer)?;
+        let divide = self.new_label();
+        let end = self.new_label();
+        writer.push(Instruction::mov(RSI, RAX).into());
+        writer.push(Instruction::mov(RAX, RCX).into());
+        // Only 0 and -1 are at most 1 once incremented, as unsigned numbers.
+        writer.push(
+            Instruction::LoadAddress {
+                dest: RDX,
+                address: Address::Offset(RSI, 1),
+            }
+            .into(),
+        );
+        writer.push(Instruction::cmp(RDX, Operand::Immediate(1)).into());
+        writer.push(Instruction::jmp(&divide).when(Condition::Hi).into());
+        writer.push(
+            Instruction::Arithmetic {
+                op: ArithmeticOp::Imul,
+                dest: RAX,
+                src: RSI.into(),
+            }
+            .into(),
+        );
+        writer.push(Instruction::jmp(&end).into());
+        writer.push(Line::Label(divide));
+        writer.push(Instruction::SignExtend.into());
+        writer.push(Instruction::Divide(RSI).into());
+        writer.push(Line::Label(end));
+        Ok(())
+    }
+
+    fn visit_less_than(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::LessThan { left, right } = node else {
+            panic!("Expected LessThan node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Lt, writer)
+    }
+
+    fn visit_greater_than(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::GreaterThan { left, right } = node else {
+            panic!("Expected GreaterThan node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Gt, writer)
+    }
+
+    fn visit_less_than_equal(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::LessThanEqual { left, right } = node else {
+            panic!("Expected LessThanEqual node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Le, writer)
+    }
+
+    fn visit_greater_than_equal(
+        &mut self,
+        node: &AST,
+        writer: &mut Vec<Line>,
+    ) -> Result<(), CompileError> {
+        let AST::GreaterThanEqual { left, right } = node else {
+            panic!("Expected GreaterThanEqual node, got: {:?}", node)
+        };
+        self.emit_comparison(left, right, Condition::Ge, writer)
+    }
+
+    fn visit_call(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Call { args, callee } = node else {
+            panic!("Expected Call node, got: {:?}", node)
+        };
+        self.emit_arguments(args, writer)?;
+        writer.push(Instruction::call(callee).into());
+        Ok(())
+    }
+
+    fn visit_return(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Return { term } = node else {
+            panic!("Expected Return node, got: {:?}", node)
+        };
+        if let AST::Call { callee, args } = term.as_ref() {
+            return self.emit_tail_call(callee, args, writer);
+        }
+        term.visit(self, writer)?;
+        self.emit_return(writer);
+        Ok(())
+    }
+
+    fn visit_block(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Block(statements) = node else {
+            panic!("Expected Block node, got: {:?}", node)
+        };
+        for statement in statements {
+            statement.visit(self, writer)?;
+        }
+        Ok(())
+    }
+
+    fn visit_if(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::IfNode {
+            conditional,
+            consequence,
+            alternative,
+        } = node
+        else {
+            panic!("Expected IfNode node, got: {:?}", node)
+        };
+        let if_false_label = self.new_label();
+        let end_if_label = self.new_label();
+        self.emit_branch(conditional, false, &if_false_label, writer)?;
+        consequence.visit(self, writer)?;
+        writer.push(Instruction::jmp(&end_if_label).into());
+        writer.push(Line::Label(if_false_label));
+        alternative.visit(self, writer)?;
+        writer.push(Line::Label(end_if_label));
+        Ok(())
+    }
+
+    fn visit_function(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Function {
+            name,
+            parameters,
+            body,
+        } = node
+        else {
+            panic!("Expected Function node, got: {:?}", node)
+        };
+        if parameters.len() > ARGUMENTS.len() {
+            return Err(CompileError::CodeGenError(format!(
+                "More than {} params is not supported",
+                ARGUMENTS.len()
+            )));
+        }
+        writer.push(Directive::Global(name.clone()).into());
+        writer.push(Line::Label(name.clone()));
+        self.emit_fn_prologue(writer);
+        let self_tail_call = if calls_itself_in_tail_position(name, body) {
+            let label = self.new_label();
+            writer.push(Line::Label(label.clone()));
+            Some((name.clone(), parameters.len(), label))
+        } else {
+            None
+        };
+
+        let locals = parameters
+            .iter()
+            .enumerate()
+            .map(|(i, parameter)| (parameter.clone(), 8 * i as i32 - PARAMETERS_SIZE))
+            .collect();
+        // Labels are shared by the whole program, so the counter carries on
+        // from the enclosing scope.
+        let mut generator = X86_64CodeGenerator {
+            locals,
+            next_local_offset: -PARAMETERS_SIZE,
+            label_counter: self.label_counter,
+            self_tail_call,
+        };
+        body.visit(&mut generator, writer)?;
+        self.label_counter = generator.label_counter;
+        // Without a `return`, functions return 0 like `undefined`.
+        writer.push(Instruction::mov(RAX, Operand::Immediate(0)).into());
+        self.emit_return(writer);
+        Ok(())
+    }
+
+    fn visit_var(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Var { name, value } = node else {
+            panic!("Expected Var node, got: {:?}", node)
+        };
+        value.visit(self, writer)?;
+        writer.extend(push(RAX));
+        self.next_local_offset -= 16;
+        self.locals.insert(name.to_string(), self.next_local_offset);
+        Ok(())
+    }
+
+    fn visit_assign(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Assign { name, value } = node else {
+            panic!("Expected Assign node, got: {:?}", node)
+        };
+        value.visit(self, writer)?;
+        writer.push(Instruction::store(RAX, self.local(name)?).into());
+        Ok(())
+    }
+
+    fn visit_while(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::While { conditional, body } = node else {
+            panic!("Expected While node, got: {:?}", node)
+        };
+        let loop_start = self.new_label();
+        let loop_end = self.new_label();
+        writer.push(Line::Label(loop_start.clone()));
+        self.emit_branch(conditional, false, &loop_end, writer)?;
+        body.visit(self, writer)?;
+        writer.push(Instruction::jmp(&loop_start).into());
+        writer.push(Line::Label(loop_end));
+        Ok(())
+    }
+
+    fn visit_undefined(&mut self, _: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        writer.push(Instruction::mov(RAX, Operand::Immediate(0)).into());
+        Ok(())
+    }
+
+    fn visit_null(&mut self, _: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        writer.push(Instruction::mov(RAX, Operand::Immediate(0)).into());
+        Ok(())
+    }
+
+    fn visit_main(&mut self, node: &AST, writer: &mut Vec<Line>) -> Result<(), CompileError> {
+        let AST::Main(statements) = node else {
+            panic!("Expected Main, got: {:?}", node)
+        };
+        writer.push(Directive::Global("main".to_string()).into());
+        writer.push(Line::Label("main".to_string()));
+        self.emit_fn_prologue(writer);
+        self.next_local_offset = -PARAMETERS_SIZE;
+        for statement in statements {
+            statement.visit(self, writer)?;
+        }
+        writer.push(Instruction::mov(RAX, Operand::Immediate(0)).into());
+        self.emit_return(writer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::ProgramGenerator;
+    use crate::{aarch64_code_generator, aarch64_emulator, native};
+
+    /// Runs the code natively, or returns `None` if this machine can't.
+    fn run(ast: &AST) -> Option<String> {
+        if !native::AVAILABLE {
+            return None;
+        }
+        let mut stdout = vec![];
+        let status = native::run(&generate_lines(ast).expect("Compile failed"), &mut stdout)
+            .expect("Run failed");
+        assert_eq!(0, status);
+        Some(String::from_utf8(stdout).unwrap())
+    }
+
+    fn run_source(source: &str) -> Option<String> {
+        run(&parse(source).expect("Parse error"))
+    }
+
+    #[test]
+    fn integers_have_64_bits() {
+        if let Some(output) = run_source(
+            "function main() {
+                print(4294967296 * 2);
+                print(0 - 1);
+                assert(4294967296 > 1);
+            }",
+        ) {
+            assert_eq!("8589934592\n-1\nT", output);
+        }
+    }
+
+    #[test]
+    fn division_does_not_fault() {
+        if let Some(output) = run_source(
+            "function main() {
+                var min = 0 - 9223372036854775807 - 1;
+                print((0 - 7) / 2);
+                print(7 / (0 - 1));
+                print(7 / 0);
+                print(min / (0 - 1));
+            }",
+        ) {
+            assert_eq!("-3\n-7\n0\n-9223372036854775808\n", output);
+        }
+    }
+
+    #[test]
+    fn six_arguments() {
+        if let Some(output) = run_source(
+            "function f(a, b, c, d, e, g) {
+                return ((((a * 10 + b) * 10 + c) * 10 + d) * 10 + e) * 10 + g;
+            }
+            function main() { print(f(1, 2, 3, 4, 5, 6)); }",
+        ) {
+            assert_eq!("123456\n", output);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        assert!(matches!(
+            compile("function main() { f(1, 2, 3, 4, 5, 6, 7); }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+        assert!(matches!(
+            compile("function f(a, b, c, d, e, f, g) { }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+        assert!(matches!(
+            compile("function main() { x = 1; }"),
+            Err(CompileError::CodeGenError(_))
+        ));
+    }
+
+    #[test]
+    fn generated_programs_match_aarch64() {
+        for seed in 0..10 {
+            let program = ProgramGenerator::new(seed).program();
+            let Some(output) = run(&program) else {
+                return;
+            };
+            let mut expected = vec![];
+            let lines = aarch64_code_generator::generate_lines(&program).unwrap();
+            aarch64_emulator::run(&lines, &mut expected, 100_000_000).unwrap();
+            assert_eq!(
+                String::from_utf8(expected).unwrap(),
+                output,
+                "seed {}:\n{}",
+                seed,
+                program
+            );
+        }
+    }
+}